@@ -0,0 +1,74 @@
+/*
+ * btree.h - the C API of the btree crate, built with the `capi` feature.
+ *
+ * Every function but btree_close returns a status code, BTREE_OK on success.
+ * The codes are stable, new ones are only ever appended.
+ *
+ * Keys and values are NUL-terminated UTF-8 strings of at most 10 bytes.
+ * A tree handle must not be used from several threads at once.
+ */
+#ifndef BTREE_H
+#define BTREE_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BTREE_OK 0
+#define BTREE_NOT_FOUND 1
+#define BTREE_ALREADY_EXISTS 2
+#define BTREE_KEY_TOO_LARGE 3
+#define BTREE_VALUE_TOO_LARGE 4
+#define BTREE_LOCKED 5
+#define BTREE_READ_ONLY 6
+#define BTREE_INVALID_FILE 7
+/* A NULL pointer, a string not in UTF-8 or an invalid option. */
+#define BTREE_INVALID_ARGUMENT 8
+#define BTREE_BUFFER_TOO_SMALL 9
+#define BTREE_BUSY 10
+#define BTREE_ERROR 11
+
+typedef struct btree btree_t;
+
+/* Returning non-zero stops the iteration. The strings only live for the duration of the call. */
+typedef int (*btree_iterate_callback)(const char *key, const char *value, void *context);
+
+/*
+ * Opens the tree of a file for writing: a new tree of a given b parameter if create is
+ * non-zero, the existing one otherwise. The handle is stored in *out.
+ */
+int btree_open(const char *path, size_t b, int create, btree_t **out);
+
+/* Opens the existing tree of a file for reading only. */
+int btree_open_read_only(const char *path, btree_t **out);
+
+/* Closes a tree, NULL is ignored. */
+void btree_close(btree_t *tree);
+
+/* Sets a key to a value. */
+int btree_put(btree_t *tree, const char *key, const char *value);
+
+/*
+ * Copies the value of a key into a buffer of a given capacity, NUL-terminated, and stores
+ * its length in *len. BTREE_BUFFER_TOO_SMALL is returned, *len being set, if the value
+ * and its terminator do not fit.
+ */
+int btree_get(btree_t *tree, const char *key, char *value, size_t capacity, size_t *len);
+
+/* Deletes a key, BTREE_NOT_FOUND if it is absent. */
+int btree_delete(btree_t *tree, const char *key);
+
+/*
+ * Calls a callback with every pair whose key falls in [start, end), in key order,
+ * until it returns non-zero. A NULL start or end leaves the range unbounded.
+ */
+int btree_iterate(btree_t *tree, const char *start, const char *end,
+                  btree_iterate_callback callback, void *context);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BTREE_H */
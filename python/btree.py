@@ -0,0 +1,194 @@
+"""Python bindings of the btree crate, loaded with ctypes through its C API (see include/btree.h).
+
+Build the library with the capi feature first:
+
+    cargo rustc --release --features capi --crate-type cdylib
+
+then point BTREE_LIBRARY at it, or leave it in target/release:
+
+    from btree import BTree
+
+    with BTree("/tmp/db") as tree:
+        tree["a"] = "shalom"
+        print(tree["a"], dict(tree.items(prefix="a")))
+"""
+import ctypes
+import os
+
+_STATUS = [
+    "ok",
+    "key not found",
+    "key already exists",
+    "key too large",
+    "value too large",
+    "database locked",
+    "read only",
+    "invalid file",
+    "invalid argument",
+    "buffer too small",
+    "busy",
+    "error",
+]
+_NOT_FOUND = 1
+# Values are at most 10 bytes, plus their terminator.
+_VALUE_CAPACITY = 11
+
+_CALLBACK = ctypes.CFUNCTYPE(
+    ctypes.c_int, ctypes.c_char_p, ctypes.c_char_p, ctypes.c_void_p
+)
+
+
+def _load():
+    default = os.path.join(
+        os.path.dirname(os.path.abspath(__file__)),
+        "..",
+        "target",
+        "release",
+        "libbtree.dylib" if os.uname().sysname == "Darwin" else "libbtree.so",
+    )
+    lib = ctypes.CDLL(os.environ.get("BTREE_LIBRARY", default))
+    handle = ctypes.POINTER(ctypes.c_void_p)
+    lib.btree_open.argtypes = [
+        ctypes.c_char_p,
+        ctypes.c_size_t,
+        ctypes.c_int,
+        ctypes.POINTER(handle),
+    ]
+    lib.btree_open_read_only.argtypes = [ctypes.c_char_p, ctypes.POINTER(handle)]
+    lib.btree_close.argtypes = [handle]
+    lib.btree_close.restype = None
+    lib.btree_put.argtypes = [handle, ctypes.c_char_p, ctypes.c_char_p]
+    lib.btree_get.argtypes = [
+        handle,
+        ctypes.c_char_p,
+        ctypes.c_char_p,
+        ctypes.c_size_t,
+        ctypes.POINTER(ctypes.c_size_t),
+    ]
+    lib.btree_delete.argtypes = [handle, ctypes.c_char_p]
+    lib.btree_iterate.argtypes = [
+        handle,
+        ctypes.c_char_p,
+        ctypes.c_char_p,
+        _CALLBACK,
+        ctypes.c_void_p,
+    ]
+    return lib, handle
+
+
+_lib, _handle = _load()
+
+
+class BTreeError(Exception):
+    """BTreeError carries the status code of a failed call."""
+
+    def __init__(self, status):
+        message = _STATUS[status] if status < len(_STATUS) else "unknown error"
+        super().__init__(message)
+        self.status = status
+
+
+def _check(status):
+    if status != 0:
+        raise BTreeError(status)
+
+
+class BTree:
+    """BTree is a persistent sorted mapping of strings to strings.
+
+    The file is created unless it exists, opened for reading only with read_only=True.
+    Keys iterate in sorted order. A tree must not be used from several threads at once.
+    """
+
+    def __init__(self, path, b=50, read_only=False):
+        self._tree = _handle()
+        path = os.fsencode(path)
+        if read_only:
+            _check(_lib.btree_open_read_only(path, ctypes.byref(self._tree)))
+        else:
+            create = int(not os.path.exists(path))
+            _check(_lib.btree_open(path, b, create, ctypes.byref(self._tree)))
+
+    def close(self):
+        if self._tree:
+            _lib.btree_close(self._tree)
+            self._tree = _handle()
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def __getitem__(self, key):
+        value = ctypes.create_string_buffer(_VALUE_CAPACITY)
+        length = ctypes.c_size_t()
+        status = _lib.btree_get(
+            self._tree, key.encode(), value, _VALUE_CAPACITY, ctypes.byref(length)
+        )
+        if status == _NOT_FOUND:
+            raise KeyError(key)
+        _check(status)
+        return value.value.decode()
+
+    def __setitem__(self, key, value):
+        _check(_lib.btree_put(self._tree, key.encode(), value.encode()))
+
+    def __delitem__(self, key):
+        status = _lib.btree_delete(self._tree, key.encode())
+        if status == _NOT_FOUND:
+            raise KeyError(key)
+        _check(status)
+
+    def __contains__(self, key):
+        try:
+            self[key]
+            return True
+        except KeyError:
+            return False
+
+    def get(self, key, default=None):
+        try:
+            return self[key]
+        except KeyError:
+            return default
+
+    def items(self, prefix=None, start=None, end=None):
+        """items returns the pairs of the keys in [start, end) starting with a prefix, sorted."""
+        prefix = prefix or ""
+        start = max(start or "", prefix)
+        pairs = []
+
+        def collect(key, value, _context):
+            key = key.decode()
+            if not key.startswith(prefix):
+                return 1
+            pairs.append((key, value.decode()))
+            return 0
+
+        callback = _CALLBACK(collect)
+        _check(
+            _lib.btree_iterate(
+                self._tree,
+                start.encode(),
+                end.encode() if end is not None else None,
+                callback,
+                None,
+            )
+        )
+        return pairs
+
+    def keys(self, prefix=None):
+        return [key for key, _ in self.items(prefix=prefix)]
+
+    def values(self, prefix=None):
+        return [value for _, value in self.items(prefix=prefix)]
+
+    def __iter__(self):
+        return iter(self.keys())
+
+    def __len__(self):
+        return len(self.items())
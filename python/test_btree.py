@@ -0,0 +1,57 @@
+"""Tests of the Python bindings, run with pytest once the library is built (see btree.py)."""
+import pytest
+
+from btree import BTree, BTreeError
+
+
+def test_put_get(tmp_path):
+    with BTree(tmp_path / "db") as tree:
+        tree["a"] = "shalom"
+        tree["b"] = "hello"
+        assert tree["a"] == "shalom"
+        assert tree["b"] == "hello"
+        assert "a" in tree
+        assert tree.get("c", "none") == "none"
+        with pytest.raises(KeyError):
+            tree["c"]
+
+
+def test_overwrite_and_delete(tmp_path):
+    with BTree(tmp_path / "db") as tree:
+        tree["a"] = "shalom"
+        tree["a"] = "hello"
+        assert tree["a"] == "hello"
+        del tree["a"]
+        assert "a" not in tree
+        with pytest.raises(KeyError):
+            del tree["a"]
+
+
+def test_iterate(tmp_path):
+    with BTree(tmp_path / "db", b=2) as tree:
+        for i in reversed(range(100)):
+            tree[f"key{i:03}"] = f"value{i}"
+        assert list(tree) == [f"key{i:03}" for i in range(100)]
+        assert len(tree) == 100
+        assert tree.items(prefix="key09") == [
+            (f"key{i:03}", f"value{i}") for i in range(90, 100)
+        ]
+        assert tree.keys(prefix="key01")[:2] == ["key010", "key011"]
+        assert tree.items(start="key005", end="key007") == [
+            ("key005", "value5"),
+            ("key006", "value6"),
+        ]
+
+
+def test_reopen(tmp_path):
+    path = tmp_path / "db"
+    with BTree(path) as tree:
+        tree["a"] = "shalom"
+    with BTree(path) as tree:
+        assert tree["a"] == "shalom"
+        tree["b"] = "hello"
+    with BTree(path, read_only=True) as tree:
+        assert tree.items() == [("a", "shalom"), ("b", "hello")]
+        with pytest.raises(BTreeError) as error:
+            tree["c"] = "world"
+        assert str(error.value) == "read only"
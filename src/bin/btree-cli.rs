@@ -0,0 +1,549 @@
+//! btree-cli runs basic operations against a tree file:
+//!
+//! ```text
+//! btree-cli get <file> <key>
+//! btree-cli put <file> <key> <value>
+//! btree-cli delete <file> <key>
+//! btree-cli scan <file> [--prefix <prefix>]
+//! btree-cli stats <file>
+//! btree-cli verify <file>
+//! btree-cli page <file> <offset>
+//! btree-cli wal-dump <logfile> [<file>]
+//! btree-cli dot <file>
+//! btree-cli dump <file>
+//! btree-cli load <file>
+//! btree-cli migrate [--b <b>] [--page-size <bytes>] <src> <dst>
+//! btree-cli salvage <src> <dst>
+//! btree-cli bench <file> [--keys <n>] [--ops <n>] [--value-size <bytes>]
+//!                        [--distribution sequential|uniform|zipf] [--reads <percent>]
+//! ```
+//!
+//! Reads open the tree for reading only, so they may run next to the process writing it.
+//! stats prints the header of the file and the shape of the tree: its height, its pages by type
+//! and how full they are, its free pages (left behind by copy-on-write) and its largest entries.
+//! wal-dump prints the records of a root log (the `wal` file next to the tree file): the LSN,
+//! timestamp and root of every commit, and given the tree file the keys every commit changed.
+//! dump writes the pairs of the tree to the standard output as a dump (see `BTree::export_dump`),
+//! load imports a dump read from the standard input.
+//! migrate bulk loads the pairs, buckets and sequences of a tree into a new tree file built with
+//! new layout parameters (the b parameter and the page size of the source by default), of pages
+//! of any of the sizes of `SUPPORTED_PAGE_SIZES`.
+//! The commands opening a tree open it as of the page size of its file.
+//! salvage rebuilds a damaged tree file into a new one out of the pairs of its intact leaves,
+//! printing the pages and the parts of the tree it skipped (see `btree::salvage::salvage`).
+//! bench loads keys into the tree (created if the file does not exist) and then runs a mix of
+//! searches and puts over them, reporting the throughput and latency percentiles of each.
+use btree::btree::{
+    BTree, BTreeBuilder, SizedBTree, FORMAT_VERSION, PAGE_SIZE, SUPPORTED_PAGE_SIZES,
+};
+use btree::error::Error;
+use btree::inspect::{dump_log, dump_page};
+use btree::node_type::{Key, KeyValuePair};
+use btree::verify::verify;
+use std::ops::Bound;
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage:
+    btree-cli get <file> <key>
+    btree-cli put <file> <key> <value>
+    btree-cli delete <file> <key>
+    btree-cli scan <file> [--prefix <prefix>]
+    btree-cli stats <file>
+    btree-cli verify <file>
+    btree-cli page <file> <offset>
+    btree-cli wal-dump <logfile> [<file>]
+    btree-cli dot <file>
+    btree-cli dump <file>
+    btree-cli load <file>
+    btree-cli migrate [--b <b>] [--page-size <bytes>] <src> <dst>
+    btree-cli salvage <src> <dst>
+    btree-cli bench <file> [--keys <n>] [--ops <n>] [--value-size <bytes>]
+                           [--distribution sequential|uniform|zipf] [--reads <percent>]";
+
+/// with_tree! runs a body over the tree of a `SizedBTree`, whichever its page size.
+macro_rules! with_tree {
+    ($tree:expr, |$name:ident| $body:expr) => {
+        match $tree {
+            SizedBTree::Pages4096(mut $name) => $body,
+            SizedBTree::Pages8192(mut $name) => $body,
+            SizedBTree::Pages16384(mut $name) => $body,
+        }
+    };
+}
+
+/// The b parameter of the trees created by bench, the widest nodes whose pairs fit a page.
+const BENCH_B: usize = 50;
+/// The exponent of the zipf distribution, that of YCSB.
+const ZIPF_EXPONENT: f64 = 0.99;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let res = match args.as_slice() {
+        ["get", file, key] => open(file, true).and_then(|tree| {
+            with_tree!(tree, |tree| {
+                println!("{}", tree.search(key.to_string())?.value);
+                Ok(())
+            })
+        }),
+        ["put", file, key, value] => open(file, false).and_then(|tree| {
+            with_tree!(tree, |tree| {
+                tree.put(KeyValuePair::new(key.to_string(), value.to_string()))?;
+                Ok(())
+            })
+        }),
+        ["delete", file, key] => open(file, false)
+            .and_then(|tree| with_tree!(tree, |tree| tree.delete(Key(key.to_string())))),
+        ["scan", file] => {
+            open(file, true).and_then(|tree| with_tree!(tree, |tree| scan(&mut tree, "")))
+        }
+        ["scan", file, "--prefix", prefix] => {
+            open(file, true).and_then(|tree| with_tree!(tree, |tree| scan(&mut tree, prefix)))
+        }
+        ["stats", file] => {
+            open(file, true).and_then(|tree| with_tree!(tree, |tree| stats(&mut tree)))
+        }
+        ["verify", file] => verify(Path::new(file)).map(|report| {
+            for problem in report.problems.iter() {
+                println!("{}", problem);
+            }
+            println!(
+                "{} pages, {} live, {} free: {} problems",
+                report.pages,
+                report.live_pages,
+                report.free_pages,
+                report.problems.len()
+            );
+            if !report.is_ok() {
+                exit(1);
+            }
+        }),
+        ["page", file, offset] => match offset.parse() {
+            Ok(offset) => dump_page(Path::new(file), offset, &mut std::io::stdout()),
+            Err(_) => Err(Error::InvalidFormat("page offset")),
+        },
+        ["wal-dump", log] => dump_log(Path::new(log), None, &mut std::io::stdout()),
+        ["wal-dump", log, file] => dump_log(
+            Path::new(log),
+            Some(Path::new(file)),
+            &mut std::io::stdout(),
+        ),
+        ["dot", file] => open(file, true).and_then(|tree| {
+            with_tree!(tree, |tree| {
+                print!("{}", tree.to_dot()?);
+                Ok(())
+            })
+        }),
+        ["dump", file] => open(file, true).and_then(|tree| {
+            with_tree!(tree, |tree| {
+                tree.export_dump(&mut std::io::BufWriter::new(std::io::stdout().lock()))?;
+                Ok(())
+            })
+        }),
+        ["load", file] => open(file, false).and_then(|tree| {
+            with_tree!(tree, |tree| {
+                tree.import_dump(std::io::BufReader::new(std::io::stdin().lock()))?;
+                tree.sync()
+            })
+        }),
+        ["migrate", args @ ..] => match MigrateOptions::parse(args) {
+            Some(options) => migrate(&options),
+            None => {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        },
+        ["salvage", src, dst] => salvage(src, dst),
+        ["bench", file, options @ ..] => match BenchOptions::parse(options) {
+            Some(options) => bench(file, &options),
+            None => {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("btree-cli: {}", e);
+        exit(1);
+    }
+}
+
+/// open opens an existing tree file of any page size, for reading only or for writing.
+fn open(file: &str, read_only: bool) -> Result<SizedBTree, Error> {
+    BTreeBuilder::new()
+        .path(file)
+        .read_only(read_only)
+        .open_existing(!read_only)
+        .build_any()
+}
+
+/// stats prints the header of a tree file and the shape of its tree.
+fn stats<const N: usize>(tree: &mut BTree<N>) -> Result<(), Error> {
+    let header = tree.file_header()?;
+    let list = |items: Vec<String>| match items.is_empty() {
+        true => "-".to_string(),
+        false => items.join(", "),
+    };
+    println!("format version: {}", FORMAT_VERSION);
+    println!("page size:      {}", N);
+    println!("b parameter:    {}", header.b);
+    println!("buckets:        {}", list(header.buckets.clone()));
+    let sequences = header.sequences.iter();
+    let sequences = sequences.map(|(name, last)| format!("{}={}", name, last));
+    println!("sequences:      {}", list(sequences.collect()));
+    let tags = header.tags.iter();
+    let tags = tags.map(|(name, root)| format!("{}@{}", name, root.0));
+    println!("tags:           {}", list(tags.collect()));
+    println!("replicated lsn: {}", header.replicated_lsn);
+    println!("features:       {}", list(header.feature_names()));
+
+    let stats = tree.stats()?;
+    println!("height:         {}", stats.height);
+    println!("internal pages: {}", stats.internal_pages);
+    println!("leaf pages:     {}", stats.leaf_pages);
+    println!("entries:        {}", stats.entries);
+    println!("free pages:     {}", stats.free_pages);
+    println!("overflow pages: {}", stats.overflow_pages);
+    println!("total bytes:    {}", stats.total_bytes);
+    match stats.largest_key {
+        Some(key) => println!("largest key:    {:?} ({} bytes)", key, key.len()),
+        None => println!("largest key:    -"),
+    }
+    match stats.largest_value {
+        Some(kv) => println!(
+            "largest value:  {:?} ({} bytes) of {:?}",
+            kv.value,
+            kv.value.len(),
+            kv.key
+        ),
+        None => println!("largest value:  -"),
+    }
+    println!("fill factor:");
+    let most = stats
+        .fill_histogram
+        .iter()
+        .max()
+        .copied()
+        .unwrap_or(0)
+        .max(1);
+    for (tenth, pages) in stats.fill_histogram.iter().enumerate() {
+        let range = format!("{}-{}%", tenth * 10, tenth * 10 + 10);
+        let bar = "#".repeat(pages * 40 / most);
+        println!("    {:<8} {:>8} {}", range, pages, bar);
+    }
+    Ok(())
+}
+
+/// scan prints the pairs whose keys start with a prefix, one pair per line.
+fn scan<const N: usize>(tree: &mut BTree<N>, prefix: &str) -> Result<(), Error> {
+    let pairs = tree.range((Bound::Included(prefix.to_string()), Bound::Unbounded))?;
+    for kv in pairs.iter().take_while(|kv| kv.key.starts_with(prefix)) {
+        println!("{}\t{}", kv.key, kv.value);
+    }
+    Ok(())
+}
+
+/// MigrateOptions are the options of `btree-cli migrate`.
+struct MigrateOptions<'a> {
+    b: Option<usize>,
+    page_size: Option<usize>,
+    src: &'a str,
+    dst: &'a str,
+}
+
+impl<'a> MigrateOptions<'a> {
+    /// parse parses the options and files of migrate, None if they are malformed.
+    fn parse(args: &[&'a str]) -> Option<MigrateOptions<'a>> {
+        let (options, files) = args.split_at(args.len().checked_sub(2)?);
+        let mut parsed = MigrateOptions {
+            b: None,
+            page_size: None,
+            src: files[0],
+            dst: files[1],
+        };
+        for pair in options.chunks(2) {
+            match pair {
+                ["--b", b] => parsed.b = Some(b.parse().ok()?),
+                ["--page-size", size] => parsed.page_size = Some(size.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(parsed)
+    }
+}
+
+/// migrate copies a tree into a new tree file of new layout parameters.
+fn migrate(options: &MigrateOptions) -> Result<(), Error> {
+    if let Some(size) = options.page_size {
+        if !SUPPORTED_PAGE_SIZES.contains(&size) {
+            eprintln!(
+                "btree-cli: pages are of {:?} bytes, not {}",
+                SUPPORTED_PAGE_SIZES, size
+            );
+            exit(1);
+        }
+    }
+    if Path::new(options.dst).exists() {
+        eprintln!("btree-cli: {} already exists", options.dst);
+        exit(1);
+    }
+    let source = open(options.src, true)?;
+    with_tree!(source, |source| migrate_tree(&mut source, options))
+}
+
+/// migrate_tree copies a tree of pages of N bytes into a new tree file, see `migrate`.
+fn migrate_tree<const N: usize>(
+    source: &mut BTree<N>,
+    options: &MigrateOptions,
+) -> Result<(), Error> {
+    let b = options.b.unwrap_or(source.b_parameter());
+    let (dst, page_size) = (Path::new(options.dst), options.page_size.unwrap_or(N));
+    let stats = match page_size {
+        size if size == 2 * PAGE_SIZE => source
+            .migrate_to_sized::<{ 2 * PAGE_SIZE }>(dst, b)?
+            .stats()?,
+        size if size == 4 * PAGE_SIZE => source
+            .migrate_to_sized::<{ 4 * PAGE_SIZE }>(dst, b)?
+            .stats()?,
+        _ => source.migrate_to_sized::<PAGE_SIZE>(dst, b)?.stats()?,
+    };
+    println!(
+        "migrated {} entries into {} (b {}, {} byte pages, height {})",
+        stats.entries, options.dst, b, page_size, stats.height
+    );
+    Ok(())
+}
+
+fn salvage(src: &str, dst: &str) -> Result<(), Error> {
+    if Path::new(dst).exists() {
+        eprintln!("btree-cli: {} already exists", dst);
+        exit(1);
+    }
+    let report = btree::salvage::salvage(Path::new(src), Path::new(dst))?;
+    for problem in report.skipped.iter() {
+        println!("skipped {}", problem);
+    }
+    println!(
+        "salvaged {} entries into {} (b {}): {} from the tree, {} from older pages, {} pages skipped",
+        report.recovered + report.recovered_from_orphans,
+        dst,
+        report.b,
+        report.recovered,
+        report.recovered_from_orphans,
+        report.skipped.len()
+    );
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Distribution {
+    Sequential,
+    Uniform,
+    Zipf,
+}
+
+/// BenchOptions are the options of `btree-cli bench`.
+struct BenchOptions {
+    keys: u64,
+    ops: u64,
+    value_size: usize,
+    distribution: Distribution,
+    /// The percentage of searches among the operations, the rest being puts.
+    reads: u64,
+}
+
+impl BenchOptions {
+    /// parse parses the options following the file, None if they are malformed.
+    fn parse(args: &[&str]) -> Option<BenchOptions> {
+        let mut options = BenchOptions {
+            keys: 100_000,
+            ops: 100_000,
+            value_size: 10,
+            distribution: Distribution::Uniform,
+            reads: 90,
+        };
+        for pair in args.chunks(2) {
+            match pair {
+                ["--keys", n] => options.keys = n.parse().ok()?,
+                ["--ops", n] => options.ops = n.parse().ok()?,
+                ["--value-size", n] => options.value_size = n.parse().ok()?,
+                ["--reads", n] => options.reads = n.parse().ok().filter(|n| *n <= 100)?,
+                ["--distribution", "sequential"] => options.distribution = Distribution::Sequential,
+                ["--distribution", "uniform"] => options.distribution = Distribution::Uniform,
+                ["--distribution", "zipf"] => options.distribution = Distribution::Zipf,
+                _ => return None,
+            }
+        }
+        Some(options).filter(|options| options.keys > 0)
+    }
+}
+
+/// bench loads keys into a tree and runs a mix of searches and puts over them.
+fn bench(file: &str, options: &BenchOptions) -> Result<(), Error> {
+    let path = Path::new(file);
+    let mut tree = BTreeBuilder::new()
+        .path(path)
+        .b_parameter(BENCH_B)
+        .open_existing(path.exists())
+        .build()?;
+    let value = "v".repeat(options.value_size);
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    // Load the keys in order, or shuffled unless the keys are read sequentially.
+    let mut order: Vec<u64> = (0..options.keys).collect();
+    if options.distribution != Distribution::Sequential {
+        for i in (1..order.len()).rev() {
+            order.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+    }
+    let mut load = Vec::with_capacity(order.len());
+    let start = Instant::now();
+    for &i in order.iter() {
+        let op = Instant::now();
+        tree.put(KeyValuePair::new(bench_key(i), value.clone()))?;
+        load.push(op.elapsed());
+    }
+    tree.sync()?;
+    report("load", &mut load, Some(start.elapsed()));
+
+    let mut keys = KeyChooser::new(options.distribution, order);
+    let (mut reads, mut writes) = (Vec::new(), Vec::new());
+    let start = Instant::now();
+    for _ in 0..options.ops {
+        let key = bench_key(keys.next(&mut rng));
+        let op = Instant::now();
+        if rng.below(100) < options.reads {
+            tree.search(key)?;
+            reads.push(op.elapsed());
+        } else {
+            tree.put(KeyValuePair::new(key, value.clone()))?;
+            writes.push(op.elapsed());
+        }
+    }
+    tree.sync()?;
+    let elapsed = start.elapsed();
+    let mut ops = [reads.as_slice(), writes.as_slice()].concat();
+    report("run", &mut ops, Some(elapsed));
+    report("search", &mut reads, None);
+    report("put", &mut writes, None);
+    Ok(())
+}
+
+/// bench_key returns the i-th key of a benchmark, keys sort in the order of their numbers.
+fn bench_key(i: u64) -> String {
+    format!("{:010}", i)
+}
+
+/// report prints the latency percentiles of some operations, and their throughput given
+/// how long the phase they were part of took.
+fn report(name: &str, latencies: &mut [Duration], elapsed: Option<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let throughput = match elapsed {
+        Some(elapsed) => format!(
+            "{:.0} ops/s",
+            latencies.len() as f64 / elapsed.as_secs_f64()
+        ),
+        None => String::new(),
+    };
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<7}{:>9} ops {:>12}   p50 {:>9.1?}  p90 {:>9.1?}  p99 {:>9.1?}  p99.9 {:>9.1?}  max {:>9.1?}",
+        name,
+        latencies.len(),
+        throughput,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1]
+    );
+}
+
+/// Rng is a xorshift64* generator, plenty for choosing keys.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// below returns a number in [0, n).
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// unit returns a number in [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// KeyChooser chooses the keys of the operations following a distribution.
+enum KeyChooser {
+    Sequential {
+        next: u64,
+        keys: u64,
+    },
+    Uniform {
+        keys: u64,
+    },
+    /// The cumulative probabilities of the ranks of the keys, the keys being ranked in the
+    /// (shuffled) order they were loaded in so the popular ones are scattered over the tree.
+    Zipf {
+        cumulative: Vec<f64>,
+        ranked: Vec<u64>,
+    },
+}
+
+impl KeyChooser {
+    /// new creates a chooser of the keys in the order they were loaded in.
+    fn new(distribution: Distribution, order: Vec<u64>) -> KeyChooser {
+        let keys = order.len() as u64;
+        match distribution {
+            Distribution::Sequential => KeyChooser::Sequential { next: 0, keys },
+            Distribution::Uniform => KeyChooser::Uniform { keys },
+            Distribution::Zipf => {
+                let mut sum = 0.0;
+                let mut cumulative: Vec<f64> = (1..=keys)
+                    .map(|rank| {
+                        sum += 1.0 / (rank as f64).powf(ZIPF_EXPONENT);
+                        sum
+                    })
+                    .collect();
+                cumulative.iter_mut().for_each(|p| *p /= sum);
+                KeyChooser::Zipf {
+                    cumulative,
+                    ranked: order,
+                }
+            }
+        }
+    }
+
+    fn next(&mut self, rng: &mut Rng) -> u64 {
+        match self {
+            KeyChooser::Sequential { next, keys } => {
+                let key = *next;
+                *next = (*next + 1) % *keys;
+                key
+            }
+            KeyChooser::Uniform { keys } => rng.below(*keys),
+            KeyChooser::Zipf { cumulative, ranked } => {
+                let u = rng.unit();
+                let rank = cumulative.partition_point(|p| *p < u);
+                ranked[rank.min(ranked.len() - 1)]
+            }
+        }
+    }
+}
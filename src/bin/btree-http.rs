@@ -0,0 +1,269 @@
+//! btree-http serves a tree file over HTTP, for services not written in Rust:
+//!
+//! ```text
+//! btree-http <file> [address]
+//!
+//! GET    /keys/<key>                      the value of a key
+//! PUT    /keys/<key>                      sets a key to the request body
+//! DELETE /keys/<key>                      deletes a key
+//! GET    /keys?start=<key>&end=<key>      the pairs of a range as a JSON array, end excluded
+//! GET    /keys?prefix=<prefix>            the pairs whose keys start with a prefix
+//! GET    /metrics                         the metrics of the process (with the metrics feature)
+//! ```
+//!
+//! The address defaults to 127.0.0.1:8080, the tree is created if the file does not exist.
+//! Every connection is served by a thread of its own reading the tree without locks, writes
+//! are answered once durable, the writes of concurrent requests sharing a group commit.
+use btree::btree::BTreeBuilder;
+use btree::durability::GroupCommit;
+use btree::error::Error;
+use btree::node_type::{Key, KeyValuePair};
+use btree::shared::{Reader, SharedBTree};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+/// The b parameter of new trees, the widest nodes whose pairs fit a page.
+const DEFAULT_B: usize = 50;
+/// How often the writes of concurrent requests are synced together.
+const GROUP_COMMIT_INTERVAL: Duration = Duration::from_millis(5);
+/// Request bodies are values, larger ones are refused.
+const MAX_BODY: usize = 4096;
+
+/// Response is the status and body of a response.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+
+    fn empty(status: &'static str) -> Response {
+        Response::text(status, String::new())
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (file, address) = match args.as_slice() {
+        [file] => (file.as_str(), DEFAULT_ADDRESS),
+        [file, address] => (file.as_str(), address.as_str()),
+        _ => {
+            eprintln!("usage: btree-http <file> [address]");
+            exit(2);
+        }
+    };
+    if let Err(e) = serve(file, address) {
+        eprintln!("btree-http: {}", e);
+        exit(1);
+    }
+}
+
+fn serve(file: &str, address: &str) -> Result<(), Error> {
+    let path = Path::new(file);
+    let tree = BTreeBuilder::new()
+        .path(path)
+        .b_parameter(DEFAULT_B)
+        .open_existing(path.exists())
+        .build()?;
+    let tree = SharedBTree::new(tree)?;
+    let group_commit = GroupCommit::start(tree.clone(), GROUP_COMMIT_INTERVAL);
+    let listener = TcpListener::bind(address)?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let tree = tree.clone();
+        thread::spawn(move || {
+            let mut reader = tree.reader();
+            // A connection the client hung up on is simply dropped.
+            let _ = handle(stream, &tree, &mut reader);
+        });
+    }
+    group_commit.stop()
+}
+
+/// handle serves a single request, closing the connection afterwards.
+fn handle(stream: TcpStream, tree: &SharedBTree, reader: &mut Reader) -> std::io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut input)? {
+        Some((method, target, body)) => match route(tree, reader, &method, &target, body) {
+            Ok(response) => response,
+            Err(Error::KeyNotFound { .. }) => Response::empty("404 Not Found"),
+            Err(e @ Error::KeyOverflowError) | Err(e @ Error::ValueOverflowError) => {
+                Response::text("413 Payload Too Large", format!("{}", e))
+            }
+            Err(e) => Response::text("500 Internal Server Error", format!("{}", e)),
+        },
+        None => Response::empty("400 Bad Request"),
+    };
+    let mut output = stream;
+    write!(
+        output,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    output.flush()
+}
+
+/// read_request reads the method, target and body of a request, None if it is malformed.
+fn read_request(input: &mut impl BufRead) -> std::io::Result<Option<(String, String, String)>> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Ok(None),
+    };
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(len) if len <= MAX_BODY => content_length = len,
+                    _ => return Ok(None),
+                }
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    input.read_exact(&mut body)?;
+    Ok(String::from_utf8(body)
+        .ok()
+        .map(|body| (method, target, body)))
+}
+
+fn route(
+    tree: &SharedBTree,
+    reader: &mut Reader,
+    method: &str,
+    target: &str,
+    body: String,
+) -> Result<Response, Error> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    #[cfg(feature = "metrics")]
+    if (method, path) == ("GET", "/metrics") {
+        return Ok(Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: btree::metrics::metrics().render(),
+        });
+    }
+    if path == "/keys" {
+        return match method {
+            "GET" => range(reader, query),
+            _ => Ok(Response::empty("405 Method Not Allowed")),
+        };
+    }
+    let key = match path.strip_prefix("/keys/").and_then(percent_decode) {
+        Some(key) if !key.is_empty() => key,
+        _ => return Ok(Response::empty("404 Not Found")),
+    };
+    match method {
+        "GET" => Ok(Response::text("200 OK", reader.search(key)?.value)),
+        "PUT" => {
+            tree.put_durable(KeyValuePair::new(key, body))?.wait()?;
+            Ok(Response::empty("204 No Content"))
+        }
+        "DELETE" => {
+            tree.delete_durable(Key(key))?.wait()?;
+            Ok(Response::empty("204 No Content"))
+        }
+        _ => Ok(Response::empty("405 Method Not Allowed")),
+    }
+}
+
+/// range serves the pairs of a range of keys, given by a start and an end or by a prefix.
+fn range(reader: &mut Reader, query: &str) -> Result<Response, Error> {
+    let (mut start, mut end, mut prefix) = (Bound::Unbounded, Bound::Unbounded, None);
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = match percent_decode(value) {
+            Some(value) => value,
+            None => return Ok(Response::empty("400 Bad Request")),
+        };
+        match name {
+            "start" => start = Bound::Included(value),
+            "end" => end = Bound::Excluded(value),
+            "prefix" => prefix = Some(value),
+            _ => return Ok(Response::empty("400 Bad Request")),
+        }
+    }
+    if let Some(prefix) = prefix.as_ref() {
+        start = Bound::Included(prefix.clone());
+    }
+    let pairs = reader.range((start, end))?;
+    let pairs: Vec<String> = pairs
+        .iter()
+        .take_while(|kv| {
+            prefix
+                .as_ref()
+                .is_none_or(|prefix| kv.key.starts_with(prefix))
+        })
+        .map(|kv| {
+            format!(
+                "{{\"key\":{},\"value\":{}}}",
+                json_string(&kv.key),
+                json_string(&kv.value)
+            )
+        })
+        .collect();
+    Ok(Response {
+        status: "200 OK",
+        content_type: "application/json",
+        body: format!("[{}]", pairs.join(",")),
+    })
+}
+
+/// percent_decode decodes a percent-encoded component of a URL, None if it is malformed.
+fn percent_decode(raw: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut iter = raw.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,307 @@
+//! btree-resp serves a tree file over the Redis protocol (RESP), so Redis clients can be
+//! pointed at it:
+//!
+//! ```text
+//! btree-resp <file> [address]
+//! ```
+//!
+//! The address defaults to 127.0.0.1:6379, the tree is created if the file does not exist.
+//! The commands served are PING, GET, SET, DEL and SCAN (with MATCH and COUNT), every
+//! connection is served by a thread of its own reading the tree without locks.
+use btree::btree::BTreeBuilder;
+use btree::error::Error;
+use btree::node_type::{Key, KeyValuePair};
+use btree::shared::{Reader, SharedBTree};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::exit;
+use std::thread;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6379";
+/// The b parameter of new trees, the widest nodes whose pairs fit a page.
+const DEFAULT_B: usize = 50;
+/// The number of keys SCAN returns per call unless told otherwise.
+const DEFAULT_COUNT: usize = 10;
+/// Commands of more strings, and strings of more bytes, are refused before anything is
+/// allocated for them. Keys and values are far shorter, as are the commands served.
+const MAX_COMMAND_LEN: usize = 1024;
+const MAX_BULK_LEN: usize = 4096;
+/// Lines (an inline command, or the header of a command or of a string) of more bytes are refused.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Reply is a reply of the protocol.
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(usize),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(message) => write!(out, "-ERR {}\r\n", message),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(Some(s)) => write!(out, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write(out))
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (file, address) = match args.as_slice() {
+        [file] => (file.as_str(), DEFAULT_ADDRESS),
+        [file, address] => (file.as_str(), address.as_str()),
+        _ => {
+            eprintln!("usage: btree-resp <file> [address]");
+            exit(2);
+        }
+    };
+    if let Err(e) = serve(file, address) {
+        eprintln!("btree-resp: {}", e);
+        exit(1);
+    }
+}
+
+fn serve(file: &str, address: &str) -> Result<(), Error> {
+    let path = Path::new(file);
+    let tree = BTreeBuilder::new()
+        .path(path)
+        .b_parameter(DEFAULT_B)
+        .open_existing(path.exists())
+        .build()?;
+    let tree = SharedBTree::new(tree)?;
+    let listener = TcpListener::bind(address)?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let tree = tree.clone();
+        thread::spawn(move || {
+            let reader = tree.reader();
+            // A connection ends once the client hangs up or stops speaking the protocol.
+            let _ = handle(stream, &tree, reader);
+        });
+    }
+    Ok(())
+}
+
+fn handle(stream: TcpStream, tree: &SharedBTree, mut reader: Reader) -> std::io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+    loop {
+        let command = match read_command(&mut input) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            // The rest of the input cannot be told apart from commands, the connection ends.
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                Reply::Error(format!("Protocol error: {}", e)).write(&mut output)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let reply = match execute(tree, &mut reader, &command) {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("{}", e)),
+        };
+        reply.write(&mut output)?;
+        // Pipelined commands are answered together.
+        if input.buffer().is_empty() {
+            output.flush()?;
+        }
+    }
+    output.flush()
+}
+
+/// read_command reads a command: an array of bulk strings, or an inline command
+/// of words separated by spaces. It returns None once the client hangs up.
+fn read_command(input: &mut impl BufRead) -> std::io::Result<Option<Vec<String>>> {
+    let line = match read_line(input)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix('*') {
+        Some(count) => parse_len(count, MAX_COMMAND_LEN, "invalid multibulk length")?,
+        None => return Ok(Some(line.split_whitespace().map(String::from).collect())),
+    };
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = read_line(input)?.ok_or_else(|| protocol_error("unexpected end of input"))?;
+        let len = header
+            .strip_prefix('$')
+            .ok_or_else(|| protocol_error("expected '$'"))?;
+        let len = parse_len(len, MAX_BULK_LEN, "invalid bulk length")?;
+        // The string is followed by CRLF.
+        let size = len
+            .checked_add(2)
+            .ok_or_else(|| protocol_error("invalid bulk length"))?;
+        let mut raw = vec![0u8; size];
+        input.read_exact(&mut raw)?;
+        raw.truncate(len);
+        command.push(String::from_utf8(raw).map_err(|_| protocol_error("invalid UTF-8"))?);
+    }
+    Ok(Some(command))
+}
+
+fn read_line(input: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = Read::take(&mut *input, MAX_LINE_LEN as u64).read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(protocol_error("too big line"));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// parse_len parses the length of a command or of a string, refusing lengths over a bound.
+fn parse_len(raw: &str, max: usize, message: &'static str) -> std::io::Result<usize> {
+    match raw.parse() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(protocol_error(message)),
+    }
+}
+
+fn protocol_error(message: &'static str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn execute(tree: &SharedBTree, reader: &mut Reader, command: &[String]) -> Result<Reply, Error> {
+    let name = command.first().map(|name| name.to_ascii_uppercase());
+    let args = command.get(1..).unwrap_or_default();
+    match (name.as_deref(), args) {
+        (Some("PING"), []) => Ok(Reply::Status("PONG")),
+        (Some("GET"), [key]) => match reader.search(key.clone()) {
+            Ok(kv) => Ok(Reply::Bulk(Some(kv.value))),
+            Err(Error::KeyNotFound { .. }) => Ok(Reply::Bulk(None)),
+            Err(e) => Err(e),
+        },
+        (Some("SET"), [key, value]) => {
+            tree.put(KeyValuePair::new(key.clone(), value.clone()))?;
+            Ok(Reply::Status("OK"))
+        }
+        (Some("DEL"), keys) if !keys.is_empty() => {
+            let mut deleted = 0;
+            for key in keys {
+                match tree.delete(Key(key.clone())) {
+                    Ok(()) => deleted += 1,
+                    Err(Error::KeyNotFound { .. }) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(deleted))
+        }
+        (Some("SCAN"), [cursor, options @ ..]) => scan(reader, cursor, options),
+        // Sent by redis-cli on start up.
+        (Some("COMMAND"), _) => Ok(Reply::Array(Vec::new())),
+        (Some(name), _) => Ok(Reply::Error(format!(
+            "unknown command or wrong number of arguments for '{}'",
+            name
+        ))),
+        (None, _) => Ok(Reply::Error("empty command".to_string())),
+    }
+}
+
+/// scan serves SCAN, the cursor being the position of the next key to look at in key order.
+fn scan(reader: &mut Reader, cursor: &str, options: &[String]) -> Result<Reply, Error> {
+    let cursor: usize = match cursor.parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Reply::Error("invalid cursor".to_string())),
+    };
+    let (mut pattern, mut count) = ("*", DEFAULT_COUNT);
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("MATCH") => pattern = value,
+            [name, value] if name.eq_ignore_ascii_case("COUNT") => match value.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::Error("invalid count".to_string())),
+            },
+            _ => return Ok(Reply::Error("syntax error".to_string())),
+        }
+    }
+    let pairs = reader.range(..)?;
+    let end = (cursor + count).min(pairs.len());
+    let keys = pairs
+        .get(cursor..end)
+        .unwrap_or_default()
+        .iter()
+        .filter(|kv| glob_match(pattern.as_bytes(), kv.key.as_bytes()))
+        .map(|kv| Reply::Bulk(Some(kv.key.clone())))
+        .collect();
+    let next = if end >= pairs.len() { 0 } else { end };
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string())),
+        Reply::Array(keys),
+    ]))
+}
+
+/// glob_match matches a key against a pattern of '*' (any run of characters),
+/// '?' (any character) and literal characters.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], key) || (!key.is_empty() && glob_match(pattern, &key[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &key[1..]),
+        (Some(p), Some(k)) if p == k => glob_match(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Cursor, Write};
+
+    #[test]
+    fn oversize_commands_are_refused() -> Result<(), Error> {
+        let command = read_command(&mut Cursor::new("*2\r\n$3\r\nGET\r\n$1\r\na\r\n"))?;
+        assert_eq!(command, Some(vec!["GET".to_string(), "a".to_string()]));
+        for input in [
+            "*9999999999999\r\n",
+            "*1\r\n$18446744073709551615\r\n",
+            "*1\r\n$99999999999999999999\r\n",
+            "*1\r\n$-1\r\n",
+            &"x".repeat(MAX_LINE_LEN + 1),
+        ] {
+            let e = read_command(&mut Cursor::new(input)).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+
+        // The server replies with an error and keeps serving the other connections.
+        let tree = SharedBTree::new(BTreeBuilder::new().in_memory(true).b_parameter(2).build()?)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let reader = tree.reader();
+                let _ = handle(stream, &tree, reader);
+            }
+        });
+        let reply = |request: &str| -> Result<String, Error> {
+            let mut stream = TcpStream::connect(address)?;
+            stream.write_all(request.as_bytes())?;
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            Ok(line)
+        };
+        assert_eq!(
+            reply("*9999999999999\r\n")?,
+            "-ERR Protocol error: invalid multibulk length\r\n"
+        );
+        assert_eq!(
+            reply("*1\r\n$18446744073709551615\r\n")?,
+            "-ERR Protocol error: invalid bulk length\r\n"
+        );
+        assert_eq!(reply("PING\r\n")?, "+PONG\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use crate::btree::BTree;
+use crate::bucket::Bucket;
+use crate::encoding::{from_digits, to_digits};
+use crate::error::Error;
+use crate::index::SEPARATOR;
+use crate::node_type::{Key, KeyValuePair};
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+/// The bits of every chunk of a bitmap, as many as the base-64 digits of a value hold,
+/// and the digits of the index of a chunk in the key holding it.
+const CHUNK_BITS: u64 = 60;
+const CHUNK_DIGITS: usize = 3;
+const VALUE_DIGITS: usize = 10;
+
+/// Bitmap is a compressed set of IDs: like a roaring bitmap it is cut into chunks
+/// of consecutive IDs, only the chunks holding IDs are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    chunks: BTreeMap<u64, u64>,
+}
+
+impl Bitmap {
+    pub fn new() -> Bitmap {
+        Bitmap::default()
+    }
+
+    /// insert adds an ID to the bitmap, returning whether it was absent.
+    pub fn insert(&mut self, id: u64) -> bool {
+        let chunk = self.chunks.entry(id / CHUNK_BITS).or_insert(0);
+        let absent = *chunk & (1 << (id % CHUNK_BITS)) == 0;
+        *chunk |= 1 << (id % CHUNK_BITS);
+        absent
+    }
+
+    /// contains returns whether an ID is in the bitmap.
+    pub fn contains(&self, id: u64) -> bool {
+        self.chunks
+            .get(&(id / CHUNK_BITS))
+            .is_some_and(|chunk| chunk & (1 << (id % CHUNK_BITS)) != 0)
+    }
+
+    /// len returns the number of IDs in the bitmap.
+    pub fn len(&self) -> u64 {
+        self.chunks
+            .values()
+            .map(|chunk| chunk.count_ones() as u64)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// ids returns the IDs in the bitmap, in order.
+    pub fn ids(&self) -> Vec<u64> {
+        self.chunks
+            .iter()
+            .flat_map(|(idx, chunk)| {
+                (0..CHUNK_BITS)
+                    .filter(move |bit| chunk & (1 << bit) != 0)
+                    .map(move |bit| idx * CHUNK_BITS + bit)
+            })
+            .collect()
+    }
+
+    /// or adds the IDs of another bitmap to the bitmap.
+    pub fn or(&mut self, other: &Bitmap) {
+        for (idx, chunk) in other.chunks.iter() {
+            *self.chunks.entry(*idx).or_insert(0) |= chunk;
+        }
+    }
+
+    /// and keeps the IDs of the bitmap that are in another bitmap as well.
+    pub fn and(&mut self, other: &Bitmap) {
+        self.chunks = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .filter_map(|(idx, chunk)| {
+                let chunk = chunk & other.chunks.get(&idx).copied().unwrap_or(0);
+                (chunk != 0).then_some((idx, chunk))
+            })
+            .collect();
+    }
+}
+
+/// Bitmaps is a handle to named bitmaps kept in a bucket of a tree, every chunk of a bitmap
+/// is a pair of its own (keyed by the name followed by the index of the chunk) so updating
+/// an ID rewrites its chunk alone and bitmaps are combined chunk by chunk as keys are scanned.
+pub struct Bitmaps<'a> {
+    bucket: Bucket<'a>,
+}
+
+impl<'a> Bitmaps<'a> {
+    pub fn new(tree: &'a mut BTree, bucket: &str) -> Result<Bitmaps<'a>, Error> {
+        Ok(Bitmaps {
+            bucket: tree.open_bucket(bucket)?,
+        })
+    }
+
+    /// insert adds an ID to a bitmap, returning whether it was absent.
+    pub fn insert(&mut self, name: &str, id: u64) -> Result<bool, Error> {
+        let key = chunk_key(name, id / CHUNK_BITS)?;
+        let chunk = self.chunk(&key)?;
+        let updated = chunk | (1 << (id % CHUNK_BITS));
+        if updated != chunk {
+            let value = to_digits(updated, VALUE_DIGITS);
+            self.bucket.put(KeyValuePair::new(key, value))?;
+        }
+        Ok(updated != chunk)
+    }
+
+    /// remove removes an ID from a bitmap, returning whether it was present.
+    pub fn remove(&mut self, name: &str, id: u64) -> Result<bool, Error> {
+        let key = chunk_key(name, id / CHUNK_BITS)?;
+        let chunk = self.chunk(&key)?;
+        let updated = chunk & !(1 << (id % CHUNK_BITS));
+        match updated {
+            _ if updated == chunk => return Ok(false),
+            0 => self.bucket.delete(Key(key))?,
+            _ => {
+                let value = to_digits(updated, VALUE_DIGITS);
+                self.bucket.put(KeyValuePair::new(key, value))?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// get returns a bitmap, empty if there is no bitmap of the name.
+    pub fn get(&mut self, name: &str) -> Result<Bitmap, Error> {
+        self.union(name.to_string()..=name.to_string())
+    }
+
+    /// union returns the union of the bitmaps whose names fall in the given range.
+    pub fn union<R: RangeBounds<String>>(&mut self, names: R) -> Result<Bitmap, Error> {
+        let mut union = Bitmap::new();
+        for (_, idx, chunk) in self.chunks(names)? {
+            *union.chunks.entry(idx).or_insert(0) |= chunk;
+        }
+        Ok(union)
+    }
+
+    /// intersection returns the intersection of the bitmaps whose names fall in the given range,
+    /// empty if there are none.
+    pub fn intersection<R: RangeBounds<String>>(&mut self, names: R) -> Result<Bitmap, Error> {
+        let mut bitmaps = BTreeMap::<String, Bitmap>::new();
+        for (name, idx, chunk) in self.chunks(names)? {
+            bitmaps.entry(name).or_default().chunks.insert(idx, chunk);
+        }
+        let mut bitmaps = bitmaps.into_values();
+        let mut intersection = bitmaps.next().unwrap_or_default();
+        for bitmap in bitmaps {
+            intersection.and(&bitmap);
+        }
+        Ok(intersection)
+    }
+
+    /// chunk returns the chunk stored under a key, empty if absent.
+    fn chunk(&mut self, key: &str) -> Result<u64, Error> {
+        match self.bucket.search(key.to_string()) {
+            Ok(kv) => from_digits(&kv.value),
+            Err(Error::KeyNotFound { .. }) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// chunks returns the chunks of the bitmaps whose names fall in the given range,
+    /// along with their names and indexes.
+    fn chunks<R: RangeBounds<String>>(
+        &mut self,
+        names: R,
+    ) -> Result<Vec<(String, u64, u64)>, Error> {
+        // The keys of the chunks of a bitmap follow its name, which is where a range
+        // of names starts and ends in a range of keys.
+        let next = char::from(SEPARATOR as u8 + 1);
+        let start = match names.start_bound() {
+            Bound::Included(name) => Bound::Included(name.clone()),
+            Bound::Excluded(name) => Bound::Included(format!("{}{}", name, next)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match names.end_bound() {
+            Bound::Included(name) => Bound::Excluded(format!("{}{}", name, next)),
+            Bound::Excluded(name) => Bound::Excluded(name.clone()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.bucket
+            .range((start, end))?
+            .into_iter()
+            .map(|kv| {
+                let (name, idx) = kv
+                    .key
+                    .split_once(SEPARATOR)
+                    .ok_or(Error::InvalidFormat("bitmap key"))?;
+                Ok((name.to_string(), from_digits(idx)?, from_digits(&kv.value)?))
+            })
+            .collect()
+    }
+}
+
+/// chunk_key returns the key of a chunk of a bitmap.
+fn chunk_key(name: &str, idx: u64) -> Result<String, Error> {
+    if name.contains(SEPARATOR) || idx >= 1 << (6 * CHUNK_DIGITS) {
+        return Err(Error::KeyOverflowError);
+    }
+    Ok(format!(
+        "{}{}{}",
+        name,
+        SEPARATOR,
+        to_digits(idx, CHUNK_DIGITS)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn bitmaps_work() -> Result<(), Error> {
+        use crate::bitmap::Bitmaps;
+        use crate::btree::BTreeBuilder;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/bitmaps")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/bitmaps/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut tags = Bitmaps::new(&mut btree, "tags")?;
+        for id in [1, 5, 61, 200] {
+            tags.insert("red", id)?;
+        }
+        for id in [5, 61, 7000] {
+            tags.insert("reef", id)?;
+        }
+        tags.insert("blue", 1)?;
+        assert!(!tags.insert("red", 5)?);
+        assert!(tags.remove("red", 200)?);
+        assert!(!tags.remove("red", 200)?);
+
+        assert_eq!(tags.get("red")?.ids(), vec![1, 5, 61]);
+        assert!(tags.get("green")?.is_empty());
+        // Tags starting with "re".
+        let re = || "re".to_string().."rf".to_string();
+        assert_eq!(tags.union(re())?.ids(), vec![1, 5, 61, 7000]);
+        assert_eq!(tags.intersection(re())?.ids(), vec![5, 61]);
+        assert_eq!(tags.union(..)?.len(), 4);
+        assert!(tags.get("blue")?.contains(1));
+        Ok(())
+    }
+}
@@ -1,44 +1,177 @@
+use crate::bucket::Bucket;
+use crate::csv::CsvOptions;
+use crate::diff::Difference;
 use crate::error::Error;
+use crate::export::{read_dump_record, read_json_line, write_dump_record, write_json_line};
+use crate::header::{self, Header};
+use crate::index::{entries_of, IndexDefinition};
+use crate::lock::{WriterLock, DEFAULT_LEASE};
+use crate::maintenance::{
+    compaction_target, discard_compaction, export_tree, has_compaction_marker,
+    remove_compaction_marker, resume_compaction, write_compaction_marker, CompactedTree,
+    Compaction,
+};
 use crate::node::Node;
 use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
 use crate::page::Page;
+use crate::page_layout::{is_node_page, FEATURE_BPLUS_LAYOUT, FEATURE_BUCKETS, KEY_SIZE};
 use crate::pager::Pager;
-use crate::wal::Wal;
+use crate::replication::{Change, Changes, ReplicationRecord, ReplicationStream};
+use crate::slow_log::SlowLog;
+use crate::stats::{measure, TreeStats};
+use crate::store::{MemoryStore, PageStore};
+use crate::value::ValueRef;
+use crate::verify::check_tree;
+use crate::view::ReadView;
+use crate::wal::{read_commit_marker, remove_commit_marker, write_commit_marker, MarkedRoot, Wal};
+use crate::watch::{Event, StructuralEvent, StructureWatchers, Subscription, Watchers};
 use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
-use std::path::Path;
+use std::io::{BufRead, Read, Write};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 /// B+Tree properties.
-pub const MAX_BRANCHING_FACTOR: usize = 200;
+pub use crate::page_layout::{
+    FORMAT_VERSION, MAX_BRANCHING_FACTOR, MAX_B_PARAMETER, MAX_FILE_SIZE, PAGE_SIZE,
+    SUPPORTED_PAGE_SIZES,
+};
 pub const NODE_KEYS_LIMIT: usize = MAX_BRANCHING_FACTOR - 1;
+pub use crate::pager::DEFAULT_NODE_CACHE_SIZE;
+
+/// SWAP_TIMEOUT bounds the wait of a read-only tree for the writer to finish swapping
+/// a compacted file in, see `committed_root`.
+const SWAP_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// BTree struct represents an on-disk B+tree.
 /// Each node is persisted in the table file, the leaf nodes contain the values.
-pub struct BTree {
-    pager: Pager,
+/// Pages are of N bytes, one of `SUPPORTED_PAGE_SIZES` (see `BTreeBuilder::build_sized`).
+pub struct BTree<const N: usize = PAGE_SIZE> {
+    pager: Pager<N>,
     b: usize,
     wal: Wal,
+    /// The writer lock, held as long as the tree is open for writing.
+    lock: Option<WriterLock>,
+    /// The number of times the tree file was replaced by a compaction.
+    generation: u64,
+    /// The leaf the last search ended at, see `Finger`.
+    finger: Option<Finger>,
+    watchers: Watchers,
+    structure_watchers: StructureWatchers,
+    /// The size (in bytes) of the log past which writes stall until it is checkpointed.
+    wal_limit: Option<u64>,
+    /// Fail writes with `Busy` rather than stall them.
+    non_blocking: bool,
+    /// The logs of the roots of the open buckets, see `open_bucket`.
+    buckets: Vec<(String, Wal)>,
+    /// The buckets taken out of the tree to be written to apart from it, see `detach_bucket`.
+    detached: Vec<String>,
+    /// The last values generated by the sequences of the tree, see `generate_id`.
+    sequences: Vec<(String, u64)>,
+    /// The tagged roots of the tree, see `tag`.
+    tags: Vec<(String, Offset)>,
+    /// The LSN of the last replication record applied to the tree.
+    replicated_lsn: u64,
+    /// The secondary indexes of the tree, see `register_index`.
+    indexes: Vec<IndexDefinition>,
+    /// The log of slow operations, see `BTreeBuilder::slow_operation_threshold`.
+    slow_log: Option<SlowLog>,
+    /// The fallbacks to earlier roots made when the tree was opened, see `root_recoveries`.
+    root_recoveries: Vec<RootRecovery>,
+    /// The failure which halted the writes of the tree (if any), see `halted`.
+    halted: Option<String>,
+    /// Whether a group of writes is underway, whose roots are committed together, see `atomically`.
+    grouped: bool,
+}
+
+/// RootRecovery reports the fallback of a tree opened with `open_existing` (or of one of its
+/// buckets) from a committed root failing validation to the newest earlier committed root
+/// passing it, see `BTree::root_recoveries`. The writes committed in between are lost.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootRecovery {
+    /// The bucket whose root was recovered, None for the tree itself.
+    pub bucket: Option<String>,
+    /// The LSN and the offset of the committed root which failed validation.
+    pub failed_lsn: u64,
+    pub failed_root: usize,
+    /// The LSN and the offset of the committed root fallen back to.
+    pub recovered_lsn: u64,
+    pub recovered_root: usize,
 }
 
 /// BtreeBuilder is a Builder for the BTree struct.
 pub struct BTreeBuilder {
     /// Path to the tree file.
-    path: &'static Path,
+    path: PathBuf,
     /// The BTree parameter, an inner node contains no more than 2*b-1 keys and no less than b-1 keys
     /// and no more than 2*b children and no less than b children.
     b: usize,
+    /// Open an existing tree for reading only.
+    read_only: bool,
+    /// Open an existing tree for writing rather than creating a new one.
+    open_existing: bool,
+    /// How long the writer lock stays valid without being renewed.
+    lock_lease: Duration,
+    wal_limit: Option<u64>,
+    non_blocking: bool,
+    /// Hold the tree in memory rather than in a file.
+    in_memory: bool,
+    /// The page size the tree is expected to have, see `page_size`.
+    page_size: Option<usize>,
+    slow_operation_threshold: Option<Duration>,
+    /// Where slow operations are logged, the standard error by default.
+    slow_operation_log: Option<Arc<Mutex<Box<dyn Write + Send>>>>,
+    /// Opens handles of the stores of the pages and of the roots of the tree, see `stores`.
+    stores: Option<Box<StoresFn>>,
+    /// Truncate the torn pages an opened tree file ends with, see `truncate_torn_pages`.
+    truncate_torn_pages: bool,
+    /// Validate every page read and every mutation, see `strict`.
+    strict: bool,
+    /// The size the tree file may grow to, see `max_file_size`.
+    max_file_size: Option<u64>,
+    /// The number of internal nodes cached, see `node_cache_size`.
+    node_cache_size: Option<usize>,
 }
 
+type StoresFn = dyn Fn() -> (Box<dyn PageStore>, Box<dyn PageStore>);
+
 impl BTreeBuilder {
     pub fn new() -> BTreeBuilder {
         BTreeBuilder {
-            path: Path::new(""),
+            path: PathBuf::new(),
             b: 0,
+            read_only: false,
+            open_existing: false,
+            lock_lease: DEFAULT_LEASE,
+            wal_limit: None,
+            non_blocking: false,
+            // The browser has no file system to keep trees in.
+            in_memory: cfg!(all(target_arch = "wasm32", target_os = "unknown")),
+            page_size: None,
+            slow_operation_threshold: None,
+            slow_operation_log: None,
+            stores: None,
+            truncate_torn_pages: false,
+            strict: false,
+            max_file_size: None,
+            node_cache_size: None,
         }
     }
 
-    pub fn path(mut self, path: &'static Path) -> BTreeBuilder {
-        self.path = path;
+    /// from_toml returns a builder configured by a TOML file (see `config::Options` for its
+    /// settings), which may be configured further before building. Settings this build
+    /// cannot follow, such as encryption, are refused rather than ignored.
+    #[cfg(feature = "config")]
+    pub fn from_toml(path: &Path) -> Result<BTreeBuilder, Error> {
+        crate::config::Options::parse(&std::fs::read_to_string(path)?)?.builder()
+    }
+
+    pub fn path(mut self, path: impl Into<PathBuf>) -> BTreeBuilder {
+        self.path = path.into();
         self
     }
 
@@ -47,41 +180,698 @@ impl BTreeBuilder {
         self
     }
 
+    /// read_only opens an existing tree, possibly written to by another process, for reading only.
+    /// Only a single process may have a tree open for writing (enforced by a lock file next to
+    /// the tree file) while any number of processes may read it; readers look up the latest
+    /// committed root on each operation and so observe every write once it is committed.
+    /// Readers also follow the writer onto the file a compaction swaps in under the path,
+    /// which every read checks for, waiting for a swap under way to finish (see `refresh`).
+    /// The b parameter of a read-only tree is taken from the file header.
+    pub fn read_only(mut self, read_only: bool) -> BTreeBuilder {
+        self.read_only = read_only;
+        self
+    }
+
+    /// open_existing opens an existing tree for writing, picking up its committed roots (and those
+    /// of its buckets) where the last writer left them, rather than creating a new tree over it.
+    /// The b parameter is taken from the file header. Indexes have to be registered again.
+    pub fn open_existing(mut self, open_existing: bool) -> BTreeBuilder {
+        self.open_existing = open_existing;
+        self
+    }
+
+    /// lock_lease sets how long the writer lock stays valid without being renewed.
+    /// The writer renews the lock in the background as long as the tree is open, a lock
+    /// left behind by a crashed writer is reclaimed once its lease expires. A writer whose
+    /// lock could not be renewed in time fails its writes with `DatabaseLocked` from then on.
+    pub fn lock_lease(mut self, lease: Duration) -> BTreeBuilder {
+        self.lock_lease = lease;
+        self
+    }
+
+    /// wal_limit bounds the size (in bytes) of the root log, once the log grows past the limit
+    /// every write stalls until the log is checkpointed (see `BTree::checkpoint`).
+    /// A blocking writer checkpoints the log itself before going on with the write.
+    pub fn wal_limit(mut self, bytes: u64) -> BTreeBuilder {
+        self.wal_limit = Some(bytes);
+        self
+    }
+
+    /// non_blocking makes writes fail with `Busy` rather than stall once the log is over its limit.
+    pub fn non_blocking(mut self, non_blocking: bool) -> BTreeBuilder {
+        self.non_blocking = non_blocking;
+        self
+    }
+
+    /// in_memory builds a new tree held in memory rather than in a file (the path is ignored),
+    /// lost once the tree is dropped. Trees are always held in memory on wasm32-unknown-unknown.
+    /// A tree held in memory cannot be compacted.
+    pub fn in_memory(mut self, in_memory: bool) -> BTreeBuilder {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// page_size sets the page size the tree is expected to have, such as the one of a
+    /// configuration file: the page size of a new tree built by `build_any`, and the one an
+    /// opened tree file has to have. Page sizes other than those of `SUPPORTED_PAGE_SIZES` are
+    /// refused, as is a page size other than the one of the tree a `build_sized` builds.
+    pub fn page_size(mut self, bytes: usize) -> BTreeBuilder {
+        self.page_size = Some(bytes);
+        self
+    }
+
+    /// truncate_torn_pages truncates the torn pages a crash left at the end of the file of a tree
+    /// opened with `open_existing` (see `BTree::torn_tail`), returning the tree to the last state
+    /// its whole pages hold. Without it such a file is refused with `TornPages`, naming them.
+    pub fn truncate_torn_pages(mut self, truncate: bool) -> BTreeBuilder {
+        self.truncate_torn_pages = truncate;
+        self
+    }
+
+    /// strict validates every node page the tree reads (see `Page::validate`, its checks of
+    /// the pages read at open aside) and the whole tree after every mutation (see
+    /// `BTree::check_invariants`), failing with `Corruption` or `InvariantViolation` as soon as
+    /// a broken page or invariant shows up. It is much slower, for qualifying the crate against
+    /// a workload rather than for production. Pages carry no checksums to verify.
+    pub fn strict(mut self, strict: bool) -> BTreeBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// max_file_size caps the growth of the tree file (or of the store of its pages) to a size
+    /// in bytes: a write which would append a page past it fails with `DatabaseFull`, leaving
+    /// the tree as it was. A file already past the cap fails its next write. Deletes append
+    /// pages too, compact the tree (see `BTree::compact`) to reclaim space. Files are never
+    /// grown past `MAX_FILE_SIZE`, cap or not.
+    pub fn max_file_size(mut self, bytes: u64) -> BTreeBuilder {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// node_cache_size sets the number of internal nodes the tree keeps decoded, the most
+    /// recently used ones, so the walks down the tree do not decode the pages of its top levels
+    /// over and over (`DEFAULT_NODE_CACHE_SIZE` unless set, none at 0). Leaves are not cached.
+    pub fn node_cache_size(mut self, nodes: usize) -> BTreeBuilder {
+        self.node_cache_size = Some(nodes);
+        self
+    }
+
+    /// stores keeps the tree in given stores, of its pages and of the log of its roots, rather than
+    /// in files (the path is ignored), such as a `FaultyStore` testing crash recovery. Every tree
+    /// built is given a handle of the stores, a new tree (over whatever was in the stores) unless
+    /// `open_existing` is set. Like those of a tree held in memory, buckets are held in memory
+    /// and the tree cannot be compacted.
+    pub fn stores<P, R>(mut self, pages: P, roots: R) -> BTreeBuilder
+    where
+        P: PageStore + Clone + 'static,
+        R: PageStore + Clone + 'static,
+    {
+        self.stores = Some(Box::new(move || {
+            (Box::new(pages.clone()), Box::new(roots.clone()))
+        }));
+        self
+    }
+
+    /// slow_operation_threshold logs every insert, search, delete and sync taking longer than
+    /// a threshold (e.g. 50ms), along with its key, the pages it read and wrote and how long
+    /// the reads, writes and syncs of the tree file took. Slow operations are logged to the
+    /// standard error (see `slow_operation_log`) and, with the `tracing` feature, as warnings.
+    /// The browser has no clock to time operations with, the threshold is ignored there.
+    pub fn slow_operation_threshold(mut self, threshold: Duration) -> BTreeBuilder {
+        self.slow_operation_threshold = Some(threshold);
+        self
+    }
+
+    /// slow_operation_log logs slow operations to a writer rather than the standard error.
+    pub fn slow_operation_log(mut self, log: impl Write + Send + 'static) -> BTreeBuilder {
+        self.slow_operation_log = Some(Arc::new(Mutex::new(Box::new(log))));
+        self
+    }
+
+    /// slow_log returns the log of slow operations of the trees built, if any.
+    fn slow_log(&self) -> Option<SlowLog> {
+        if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+            return None;
+        }
+        let threshold = self.slow_operation_threshold?;
+        let log = match &self.slow_operation_log {
+            Some(log) => log.clone(),
+            None => {
+                let stderr: Box<dyn Write + Send> = Box::new(std::io::stderr());
+                Arc::new(Mutex::new(stderr))
+            }
+        };
+        Some(SlowLog::new(threshold, log))
+    }
+
+    /// build_from_map builds a new tree holding the pairs of an in-memory map, bulk loaded.
+    pub fn build_from_map(&self, map: BTreeMap<String, String>) -> Result<BTree, Error> {
+        let mut tree = self.build()?;
+        tree.bulk_load(
+            map.into_iter()
+                .map(|(key, value)| KeyValuePair::new(key, value))
+                .collect(),
+        )?;
+        Ok(tree)
+    }
+
+    /// validate checks the options of the builder before building a tree, so a misconfigured
+    /// tree fails to build with the option at fault rather than failing its first operations:
+    /// the b parameter of a new tree (whose nodes must fit a page), the page size, the path
+    /// (the directory of a new tree file must be writable, an opened tree file must exist)
+    /// and the options which cannot go together.
+    fn validate<const N: usize>(&self) -> Result<(), Error> {
+        if self.read_only && self.open_existing {
+            return Err(Error::ConflictingOptions("read_only and open_existing"));
+        }
+        if self.read_only && (self.stores.is_some() || self.in_memory) {
+            return Err(Error::ConflictingOptions(
+                "read_only and a tree held in memory or in stores",
+            ));
+        }
+        for bytes in self.page_size.into_iter().chain([N]) {
+            if !SUPPORTED_PAGE_SIZES.contains(&bytes) {
+                return Err(Error::InvalidPageSize(bytes));
+            }
+        }
+        if self.page_size.is_some_and(|bytes| bytes != N) {
+            return Err(Error::ConflictingOptions(
+                "page_size and the page size of the tree built",
+            ));
+        }
+        let max_b = Page::<N>::MAX_B_PARAMETER;
+        if !self.read_only && !self.open_existing && !(2..=max_b).contains(&self.b) {
+            return Err(Error::InvalidBParameter(self.b));
+        }
+        if self.stores.is_some() || self.in_memory {
+            return Ok(());
+        }
+        if self.path.as_os_str().is_empty() {
+            return Err(Error::InvalidPath);
+        }
+        let writable = |path: &Path| {
+            std::fs::metadata(path).is_ok_and(|metadata| !metadata.permissions().readonly())
+        };
+        if self.read_only || self.open_existing {
+            if !self.path.is_file() {
+                return Err(Error::InvalidPath);
+            }
+            if self.open_existing && !writable(&self.path) {
+                return Err(Error::PathNotWritable);
+            }
+            return Ok(());
+        }
+        match self.path.parent() {
+            Some(directory) if directory.as_os_str().is_empty() || writable(directory) => Ok(()),
+            _ => Err(Error::PathNotWritable),
+        }
+    }
+
+    /// build builds a tree of pages of `PAGE_SIZE` bytes, see `build_sized`.
     pub fn build(&self) -> Result<BTree, Error> {
-        if self.path.to_string_lossy() == "" {
-            return Err(Error::UnexpectedError);
+        self.build_sized()
+    }
+
+    /// build_sized builds a tree of pages of N bytes, one of `SUPPORTED_PAGE_SIZES`. A tree file
+    /// opened has to have pages of N bytes, otherwise `PageSizeMismatch` is returned:
+    /// `build_any` opens a tree file of whichever page size it has.
+    pub fn build_sized<const N: usize>(&self) -> Result<BTree<N>, Error> {
+        let mut tree = self.build_tree()?;
+        tree.pager.set_strict(self.strict);
+        if let Some(bytes) = self.max_file_size {
+            tree.pager.set_max_size(bytes);
         }
-        if self.b == 0 {
-            return Err(Error::UnexpectedError);
+        if let Some(nodes) = self.node_cache_size {
+            tree.pager.set_node_cache_size(nodes);
         }
+        Ok(tree)
+    }
 
-        let mut pager = Pager::new(self.path)?;
-        let root = Node::new(NodeType::Leaf(vec![]), true, None);
-        let root_offset = pager.write_page(Page::try_from(&root)?)?;
+    /// build_any builds a tree of the page size set by `page_size` (`PAGE_SIZE` by default),
+    /// or opens a tree file of the page size its header records, dispatching to the
+    /// `build_sized` of the page size.
+    pub fn build_any(&self) -> Result<SizedBTree, Error> {
+        let opened = (self.read_only || self.open_existing)
+            && self.stores.is_none()
+            && !self.in_memory
+            && self.path.is_file();
+        let page_size = match opened {
+            true => {
+                let found = header::page_size(&self.path)?;
+                if let Some(expected) = self.page_size.filter(|expected| *expected != found) {
+                    return Err(Error::PageSizeMismatch { expected, found });
+                }
+                found
+            }
+            false => self.page_size.unwrap_or(PAGE_SIZE),
+        };
+        if !SUPPORTED_PAGE_SIZES.contains(&page_size) {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        with_page_size!(page_size, N => self.build_sized::<N>().map(SizedBTree::from))
+    }
+
+    /// build_tree builds or opens the tree, see `build_sized`.
+    fn build_tree<const N: usize>(&self) -> Result<BTree<N>, Error> {
+        self.validate::<N>()?;
+        if let Some(stores) = &self.stores {
+            let (pages, roots) = stores();
+            return self.build_on_stores(pages, roots);
+        }
+        if self.in_memory {
+            let (pages, roots) = (Box::new(MemoryStore::new()), Box::new(MemoryStore::new()));
+            return self.build_on_stores(pages, roots);
+        }
         let parent_directory = self.path.parent().unwrap_or_else(|| Path::new("/tmp"));
-        let mut wal = Wal::new(parent_directory.to_path_buf())?;
+        if self.read_only {
+            if has_compaction_marker(&self.path) {
+                return Err(Error::Unsupported(
+                    "reading a tree whose compaction was interrupted, open it for writing first",
+                ));
+            }
+            let pager = Pager::open_read_only(&self.path)?;
+            let header = Header::decode(&pager.get_page(&Offset(0))?)?;
+            let wal = Wal::open_read_only(parent_directory.to_path_buf())?;
+            return Ok(self.tree(pager, header, wal, None, Vec::new()));
+        }
+        if self.open_existing {
+            let lock = WriterLock::acquire(&self.path, self.lock_lease)?;
+            let compacted_roots = resume_compaction(&self.path)?;
+            let pager = Pager::open(&self.path)?;
+            let mut header = Header::decode(&pager.get_page(&Offset(0))?)?;
+            let mut buckets = Vec::new();
+            for name in std::mem::take(&mut header.buckets) {
+                let wal = Wal::open_bucket(parent_directory.to_path_buf(), &name)?;
+                buckets.push((name, wal));
+            }
+            let wal = Wal::open(parent_directory.to_path_buf())?;
+            let mut tree = self.tree(pager, header, wal, Some(lock), buckets);
+            if let Some(roots) = compacted_roots {
+                tree.commit_compacted_roots(roots)?;
+                remove_compaction_marker(&self.path)?;
+            }
+            if let Some(marked) = read_commit_marker(&self.path)? {
+                tree.commit_marked_roots(marked)?;
+            }
+            remove_commit_marker(&self.path)?;
+            tree.recover_roots()?;
+            tree.check_torn_tail(self.truncate_torn_pages)?;
+            return Ok(tree);
+        }
+        let lock = WriterLock::acquire(&self.path, self.lock_lease)?;
+        // A compaction (or a commit) left behind by a tree formerly at the path is not to be resumed.
+        discard_compaction(&self.path)?;
+        remove_commit_marker(&self.path)?;
+        let pager = Pager::new(&self.path)?;
+        let wal = Wal::new(parent_directory.to_path_buf())?;
+        self.new_tree(pager, wal, lock)
+    }
+
+    /// build_on_stores builds a tree kept in stores rather than files, see `stores`.
+    fn build_on_stores<const N: usize>(
+        &self,
+        pages: Box<dyn PageStore>,
+        roots: Box<dyn PageStore>,
+    ) -> Result<BTree<N>, Error> {
+        if self.open_existing {
+            let pager = Pager::from_store(pages, Path::new(""))?;
+            let header = Header::decode(&pager.get_page(&Offset(0))?)?;
+            let wal = Wal::from_store(roots)?;
+            // The logs of the buckets were held in memory, their roots are gone.
+            let mut tree = self.tree(
+                pager,
+                header,
+                wal,
+                Some(WriterLock::in_memory()),
+                Vec::new(),
+            );
+            tree.recover_roots()?;
+            tree.check_torn_tail(self.truncate_torn_pages)?;
+            return Ok(tree);
+        }
+        let (mut pages, mut roots) = (pages, roots);
+        pages.truncate(0)?;
+        roots.truncate(0)?;
+        let pager = Pager::from_store(pages, Path::new(""))?;
+        let wal = Wal::from_store(roots)?;
+        self.new_tree(pager, wal, WriterLock::in_memory())
+    }
+
+    /// new_tree writes a new empty tree to an empty pager and log.
+    fn new_tree<const N: usize>(
+        &self,
+        mut pager: Pager<N>,
+        mut wal: Wal,
+        lock: WriterLock,
+    ) -> Result<BTree<N>, Error> {
+        let header = Header::new(self.b);
+        pager.write_page(header.encode()?)?;
+        let root = Node::new(NodeType::Leaf(vec![]), true, None);
+        let root_offset = pager.write_page(Page::encode(&root)?)?;
         wal.set_root(root_offset)?;
+        Ok(self.tree(pager, header, wal, Some(lock), Vec::new()))
+    }
 
-        Ok(BTree {
+    /// tree returns the tree of the pages of a pager and of the roots of a log, as of the header
+    /// of its file and along with the logs of its buckets. A tree without the writer lock is
+    /// open for reading only.
+    fn tree<const N: usize>(
+        &self,
+        pager: Pager<N>,
+        header: Header,
+        wal: Wal,
+        lock: Option<WriterLock>,
+        buckets: Vec<(String, Wal)>,
+    ) -> BTree<N> {
+        let writable = lock.is_some();
+        BTree {
             pager,
-            b: self.b,
+            b: header.b,
             wal,
-        })
+            lock,
+            generation: 0,
+            finger: None,
+            watchers: Watchers::default(),
+            structure_watchers: StructureWatchers::default(),
+            wal_limit: self.wal_limit.filter(|_| writable),
+            non_blocking: self.non_blocking && writable,
+            buckets,
+            detached: Vec::new(),
+            sequences: header.sequences,
+            tags: header.tags,
+            replicated_lsn: header.replicated_lsn,
+            indexes: Vec::new(),
+            slow_log: self.slow_log(),
+            root_recoveries: Vec::new(),
+            halted: None,
+            grouped: false,
+        }
+    }
+}
+
+/// SizedBTree is a tree of any of the page sizes of `SUPPORTED_PAGE_SIZES`, see
+/// `BTreeBuilder::build_any`.
+pub enum SizedBTree {
+    Pages4096(BTree<PAGE_SIZE>),
+    Pages8192(BTree<{ 2 * PAGE_SIZE }>),
+    Pages16384(BTree<{ 4 * PAGE_SIZE }>),
+}
+
+impl SizedBTree {
+    /// page_size returns the page size of the tree.
+    pub fn page_size(&self) -> usize {
+        match self {
+            SizedBTree::Pages4096(_) => PAGE_SIZE,
+            SizedBTree::Pages8192(_) => 2 * PAGE_SIZE,
+            SizedBTree::Pages16384(_) => 4 * PAGE_SIZE,
+        }
+    }
+}
+
+impl From<BTree<PAGE_SIZE>> for SizedBTree {
+    fn from(tree: BTree<PAGE_SIZE>) -> SizedBTree {
+        SizedBTree::Pages4096(tree)
+    }
+}
+
+impl From<BTree<{ 2 * PAGE_SIZE }>> for SizedBTree {
+    fn from(tree: BTree<{ 2 * PAGE_SIZE }>) -> SizedBTree {
+        SizedBTree::Pages8192(tree)
+    }
+}
+
+impl From<BTree<{ 4 * PAGE_SIZE }>> for SizedBTree {
+    fn from(tree: BTree<{ 4 * PAGE_SIZE }>) -> SizedBTree {
+        SizedBTree::Pages16384(tree)
+    }
+}
+
+/// ConflictPolicy decides what `BTree::merge_from` does with a key present in both trees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the pair of this tree.
+    KeepExisting,
+    /// Replace the pair of this tree with the merged one.
+    Overwrite,
+    /// Fail the merge with `DuplicateKey`, before writing anything.
+    Fail,
+}
+
+/// RestorePoint is a past moment of a tree, see `BTree::restore_to`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// The state as of the commit of a given log sequence number.
+    Lsn(u64),
+    /// The state as of a given time, in milliseconds since the epoch.
+    Timestamp(u64),
+}
+
+/// Rewrite is what writing a pair does with the pair of the same key the tree holds already.
+enum Rewrite<'a> {
+    /// Refuse the write with `DuplicateKey`.
+    Refuse,
+    /// Replace the pair.
+    Replace,
+    /// Replace the value with the one an update computes from it (None if the key is absent),
+    /// ignoring the value of the pair written. The value written is kept, see `written`.
+    Update {
+        update: &'a mut dyn FnMut(Option<&str>) -> Result<String, Error>,
+        value: Option<String>,
+    },
+}
+
+impl Rewrite<'_> {
+    /// resolve returns the pair to write given the value of its key the leaf holds (if any).
+    fn resolve(&mut self, kv: KeyValuePair, current: Option<&str>) -> Result<KeyValuePair, Error> {
+        match self {
+            Rewrite::Refuse if current.is_some() => Err(Error::DuplicateKey { key: kv.key }),
+            Rewrite::Refuse | Rewrite::Replace => Ok(kv),
+            Rewrite::Update { update, value } => {
+                let kv = KeyValuePair::new(kv.key, update(current)?);
+                kv.check_size()?;
+                *value = Some(kv.value.clone());
+                Ok(kv)
+            }
+        }
+    }
+
+    /// written returns the pair a write wrote, given the pair it was asked to write.
+    fn written(&self, kv: KeyValuePair) -> KeyValuePair {
+        match self {
+            Rewrite::Update {
+                value: Some(value), ..
+            } => KeyValuePair::new(kv.key, value.clone()),
+            _ => kv,
+        }
+    }
+}
+
+/// Collects the pairs of a tree into an in-memory map. The tree is borrowed mutably
+/// as every read of the tree looks up its latest root in the log.
+impl TryFrom<&mut BTree> for BTreeMap<String, String> {
+    type Error = Error;
+    fn try_from(tree: &mut BTree) -> Result<BTreeMap<String, String>, Error> {
+        Ok(tree
+            .range(..)?
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect())
     }
 }
 
 impl Default for BTreeBuilder {
     // A default BTreeBuilder provides a builder with:
-    // - b parameter set to 200
+    // - b parameter set to 100, the widest nodes fitting a page
     // - path set to '/tmp/db'.
     fn default() -> Self {
         BTreeBuilder::new()
-            .b_parameter(200)
+            .b_parameter(MAX_BRANCHING_FACTOR / 2)
             .path(Path::new("/tmp/db"))
     }
 }
 
-impl BTree {
+impl<const N: usize> BTree<N> {
+    /// check_writable fails mutations of a tree opened for reading only.
+    fn check_writable(&self) -> Result<(), Error> {
+        match (&self.lock, &self.halted) {
+            (None, _) => Err(Error::ReadOnly),
+            (Some(_), Some(cause)) => Err(Error::Halted {
+                cause: cause.clone(),
+            }),
+            (Some(lock), None) => lock.check_held(),
+        }
+    }
+
+    /// check_lease fails a commit with `DatabaseLocked` once the writer lock is no longer
+    /// guaranteed to be held (see `WriterLock::is_held`), as another writer may reclaim it.
+    fn check_lease(&self) -> Result<(), Error> {
+        match &self.lock {
+            Some(lock) => lock.check_held(),
+            None => Err(Error::ReadOnly),
+        }
+    }
+
+    /// halted returns the failure which halted the writes of the tree, if any. A write failing
+    /// on the file or on a page which does not hold what it should (see `Error::is_fatal`)
+    /// halts the writes of the tree, which fail with `Halted` from then on, so a tree failing
+    /// mid-write is not written further. Reads go on; reopen the tree to write again.
+    pub fn halted(&self) -> Option<&str> {
+        self.halted.as_deref()
+    }
+
+    /// halt_on_failure halts the writes of the tree if a write failed fatally.
+    fn halt_on_failure<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &res {
+            if e.is_fatal() && self.halted.is_none() {
+                debug!(error = %e, "halted writes");
+                self.halted = Some(e.to_string());
+            }
+        }
+        res
+    }
+
+    /// commit_root commits a new root of the tree, checking the tree in strict mode.
+    /// The root of a write within a group of writes is committed along with the group.
+    fn commit_root(&mut self, root: Offset) -> Result<(), Error> {
+        if self.grouped {
+            self.wal.defer_root(root);
+            return Ok(());
+        }
+        self.check_lease()?;
+        let res = self.wal.set_root(root).and_then(|_| self.check_mutation());
+        self.halt_on_failure(res)
+    }
+
+    /// check_invariants checks the structure of the tree, of its buckets and of its tags from their
+    /// committed roots down, failing with `InvariantViolation` on the first problem found: the keys
+    /// of every node are in order and within the bounds the separators of its parent set, nodes
+    /// hold no more keys than b allows and internal nodes one child more than keys, only roots are
+    /// marked as such, leaves are all at the same depth and no page is reached twice. With the
+    /// `invariants` feature debug builds check the tree after every mutation.
+    pub fn check_invariants(&mut self) -> Result<(), Error> {
+        let mut roots = vec![("the tree".to_string(), self.committed_root()?)];
+        for (name, wal) in self.buckets.iter_mut() {
+            roots.push((format!("bucket {}", name), wal.get_root()?));
+        }
+        for (name, root) in self.tags.iter() {
+            roots.push((format!("tag {}", name), root.clone()));
+        }
+        for (tree, root) in roots {
+            check_root(&self.pager, self.b, tree, &root)?;
+        }
+        Ok(())
+    }
+
+    /// check_mutation checks the root a mutation committed of a strict tree, or of any tree
+    /// in debug builds with the `invariants` feature, see `check_invariants`. It reads through
+    /// a reader of its own, leaving the I/O statistics of the tree alone.
+    fn check_mutation(&mut self) -> Result<(), Error> {
+        if self.pager.strict() || cfg!(all(debug_assertions, feature = "invariants")) {
+            check_root(
+                &self.pager.reader()?,
+                self.b,
+                "the tree".to_string(),
+                &self.wal.get_root()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// root_recoveries returns the fallbacks to earlier roots made when the tree was opened,
+    /// empty unless the committed root of the tree or of a bucket failed validation.
+    pub fn root_recoveries(&self) -> &[RootRecovery] {
+        &self.root_recoveries
+    }
+
+    /// recover_roots falls back, for the tree and each of its buckets, from a committed root
+    /// failing validation (see `valid_root`) to the newest earlier root of its log passing it,
+    /// logging that root anew so the log ends with a valid root. A checkpoint leaves a log with
+    /// a single root, a log without a valid root to fall back to is refused with `Corruption`.
+    fn recover_roots(&mut self) -> Result<(), Error> {
+        let mut wals = vec![(None, &mut self.wal)];
+        for (name, wal) in self.buckets.iter_mut() {
+            wals.push((Some(name.clone()), wal));
+        }
+        for (bucket, wal) in wals {
+            let records = wal.records()?;
+            let failed = match records.last() {
+                Some(last) if !valid_root(&self.pager, &last.root)? => last,
+                _ => continue,
+            };
+            let mut recovered = None;
+            for record in records.iter().rev() {
+                if valid_root(&self.pager, &record.root)? {
+                    recovered = Some(record);
+                    break;
+                }
+            }
+            let recovered = recovered.ok_or(Error::Corruption {
+                offset: failed.root.0,
+                reason: "a committed root failing validation, without an earlier one",
+            })?;
+            debug!(
+                failed = failed.root.0,
+                recovered = recovered.root.0,
+                "fall back to an earlier root"
+            );
+            wal.set_root(recovered.root.clone())?;
+            self.root_recoveries.push(RootRecovery {
+                bucket,
+                failed_lsn: failed.lsn,
+                failed_root: failed.root.0,
+                recovered_lsn: recovered.lsn,
+                recovered_root: recovered.root.0,
+            });
+        }
+        Ok(())
+    }
+
+    /// torn_tail returns the offset and the length of the torn pages a crash left at the end of
+    /// the tree file, if any: a partially written page, along with the whole pages before it
+    /// which do not decode as a node. Pages carry no checksums, a torn page which happens to
+    /// decode as a node goes unnoticed.
+    pub fn torn_tail(&self) -> Result<Option<(usize, usize)>, Error> {
+        self.pager.torn_tail()
+    }
+
+    /// check_torn_tail refuses a tree file ending with torn pages, or truncates them.
+    /// A committed root among the torn pages means committed writes were lost (the tree was
+    /// not synced before the crash), truncating would not bring them back: such a file is
+    /// refused as corrupt either way.
+    fn check_torn_tail(&mut self, truncate: bool) -> Result<(), Error> {
+        let Some((offset, len)) = self.pager.torn_tail()? else {
+            return Ok(());
+        };
+        let mut roots = vec![self.wal.get_root()?];
+        for (_, wal) in self.buckets.iter_mut() {
+            roots.push(wal.get_root()?);
+        }
+        if let Some(Offset(root)) = roots.into_iter().find(|root| root.0 >= offset) {
+            return Err(Error::Corruption {
+                offset: root,
+                reason: "a committed root among the torn pages",
+            });
+        }
+        if !truncate {
+            return Err(Error::TornPages { offset, len });
+        }
+        debug!(offset, len, "truncate torn pages");
+        self.pager.truncate(offset)
+    }
+
+    /// check_backpressure stalls a write while the log is over its limit,
+    /// either checkpointing the log or failing with `Busy` in non-blocking mode.
+    fn check_backpressure(&mut self) -> Result<(), Error> {
+        match self.wal_limit {
+            // A group of writes logs a root per keyspace at most, once it commits.
+            Some(limit) if !self.grouped && self.wal.size()? >= limit => match self.non_blocking {
+                true => Err(Error::Busy),
+                false => self.checkpoint(),
+            },
+            _ => Ok(()),
+        }
+    }
+
     fn is_node_full(&self, node: &Node) -> Result<bool, Error> {
         match &node.node_type {
             NodeType::Leaf(pairs) => Ok(pairs.len() == (2 * self.b - 1)),
@@ -100,448 +890,4626 @@ impl BTree {
     }
 
     /// insert a key value pair possibly splitting nodes along the way.
+    /// A key already present is refused with `DuplicateKey`, see `put` to replace its value.
     pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
+        let logged = self.slow_log.is_some().then(|| kv.key.clone());
+        self.timed("insert", logged.as_deref(), |tree| {
+            tree.observed(|tree| tree.write_pair(kv, Rewrite::Refuse))
+        })
+        .map(|_| ())
+    }
+
+    /// put inserts a key value pair replacing the value of the key if it is already present,
+    /// the replaced pair is returned.
+    pub fn put(&mut self, kv: KeyValuePair) -> Result<Option<KeyValuePair>, Error> {
+        let logged = self.slow_log.is_some().then(|| kv.key.clone());
+        self.timed("insert", logged.as_deref(), |tree| {
+            tree.observed(|tree| tree.write_pair(kv, Rewrite::Replace))
+        })
+    }
+
+    /// timed runs an operation, logging it if it is slow (see `BTreeBuilder::slow_operation_threshold`).
+    fn timed<T>(
+        &mut self,
+        operation: &'static str,
+        key: Option<&str>,
+        body: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let slow_log = match self.slow_log.clone() {
+            Some(slow_log) => slow_log,
+            None => return body(self),
+        };
+        let (start, before) = (std::time::Instant::now(), self.pager.io());
+        let res = body(self);
+        slow_log.record(operation, key, start.elapsed(), before, self.pager.io());
+        res
+    }
+
+    /// increment adds a delta to the counter stored (in decimal) under a key, creating it at zero
+    /// if absent, and returns its new value. The counter is read and written by a single walk
+    /// down the tree, committed as a single write, so a crash leaves the counter either before
+    /// or after the increment.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64, Error> {
+        let mut value = 0i64;
+        let mut update = |current: Option<&str>| {
+            let current = match current {
+                Some(current) => current
+                    .parse::<i64>()
+                    .map_err(|_| Error::InvalidFormat("counter value"))?,
+                None => 0,
+            };
+            value = current
+                .checked_add(delta)
+                .ok_or(Error::ValueOverflowError)?;
+            Ok(value.to_string())
+        };
+        let logged = self.slow_log.is_some().then(|| key.clone());
+        let kv = KeyValuePair::new(key, String::new());
+        self.timed("insert", logged.as_deref(), |tree| {
+            tree.observed(|tree| {
+                tree.write_pair(
+                    kv,
+                    Rewrite::Update {
+                        update: &mut update,
+                        value: None,
+                    },
+                )
+            })
+        })?;
+        Ok(value)
+    }
+
+    /// write_pair writes a key value pair possibly splitting nodes along the way,
+    /// rewriting the pair of the same key (if any) as the rewrite says.
+    /// The entries of the pair in the secondary indexes are committed along with it.
+    fn write_pair(
+        &mut self,
+        kv: KeyValuePair,
+        mut rewrite: Rewrite<'_>,
+    ) -> Result<Option<KeyValuePair>, Error> {
+        if !self.indexes.is_empty() && !self.grouped {
+            return self.group(|tree| tree.write_pair(kv, rewrite));
+        }
+        debug_span!("insert", key = %kv.key, replace = !matches!(rewrite, Rewrite::Refuse));
+        count!(inserts);
+        time!(insert_latency);
+        self.check_writable()?;
+        // Refuse an oversize pair before any page is copied for it.
+        kv.check_size()?;
+        self.check_backpressure()?;
+        let watched = match self.watchers.watches(&kv.key) {
+            true => Some(kv.clone()),
+            false => None,
+        };
+        let indexed = match self.indexes.is_empty() {
+            true => None,
+            false => Some(kv.clone()),
+        };
+        let (new_root_offset, replaced) =
+            self.staged(|tree| tree.insert_into_root(kv, &mut rewrite))?;
+        let watched = watched.map(|kv| rewrite.written(kv));
+        let indexed = indexed.map(|kv| rewrite.written(kv));
+        if let Some(kv) = &indexed {
+            // The group the write runs in drops its pages should the pair violate a unique index.
+            self.check_unique(kv)?;
+        }
+        // finish by setting the root to its new copy.
+        self.commit_root(new_root_offset)?;
+        if let Some(kv) = watched {
+            self.watchers.notify(match replaced {
+                Some(_) => Event::Update(kv),
+                None => Event::Insert(kv),
+            });
+        }
+        if let Some(kv) = indexed {
+            self.update_indexes(replaced.as_ref(), Some(&kv))?;
+        }
+        Ok(replaced)
+    }
+
+    /// insert_into_root writes a key value pair to a copy of the root, splitting it first
+    /// if it is full, returning the offset of the copy and the pair replaced (if any).
+    fn insert_into_root(
+        &mut self,
+        kv: KeyValuePair,
+        rewrite: &mut Rewrite<'_>,
+    ) -> Result<(Offset, Option<KeyValuePair>), Error> {
         let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let new_root_offset: Offset;
-        let mut new_root: Node;
-        let mut root = Node::try_from(root_page)?;
-        if self.is_node_full(&root)? {
-            // split the root creating a new root and child nodes along the way.
-            new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
-            // write the new root to disk to aquire an offset for the new root.
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
-            // set the old roots parent to the new root.
-            root.parent_offset = Some(new_root_offset.clone());
-            root.is_root = false;
-            // split the old root.
-            let (median, sibling) = root.split(self.b)?;
-            // write the old root with its new data to disk in a *new* location.
-            let old_root_offset = self.pager.write_page(Page::try_from(&root)?)?;
-            // write the newly created sibling to disk.
-            let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-            // update the new root with its children and key.
-            new_root.node_type =
-                NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
-            // write the new_root to disk.
-            self.pager
-                .write_page_at_offset(Page::try_from(&new_root)?, &new_root_offset)?;
+        let key = kv.key.as_str();
+        let read_root =
+            |e: Error| e.in_context("inserting", key, "read of the root", root_offset.0, 0);
+        let root = match self.pager.cached_node(&root_offset) {
+            Some(root) => root,
+            None => {
+                let root_page = self.pager.get_page(&root_offset).map_err(read_root)?;
+                if self.leaf_has_room(&root_page).map_err(read_root)? {
+                    return self
+                        .insert_into_leaf_page(root_page, &kv, rewrite)
+                        .map_err(|e| {
+                            e.in_context("inserting", key, "write of a leaf", root_offset.0, 0)
+                        });
+                }
+                self.pager.decode(root_page).map_err(read_root)?
+            }
+        };
+        let (mut new_root, new_root_offset) = if self.is_node_full(&root)? {
+            debug!(offset = root_offset.0, keys = 2 * self.b - 1, "split root");
+            self.split_root(root).map_err(|e| {
+                e.in_context("inserting", key, "split of the root", root_offset.0, 0)
+            })?
         } else {
-            new_root = root.clone();
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
-        }
+            let offset = Page::encode(&root)
+                .and_then(|page| self.pager.write_page(page))
+                .map_err(|e| {
+                    e.in_context("inserting", key, "copy of the root", root_offset.0, 0)
+                })?;
+            (root, offset)
+        };
         // continue recursively.
-        self.insert_non_full(&mut new_root, new_root_offset.clone(), kv)?;
-        // finish by setting the root to its new copy.
-        self.wal.set_root(new_root_offset)
+        let replaced =
+            self.insert_non_full(&mut new_root, new_root_offset.clone(), kv, rewrite, 0)?;
+        Ok((new_root_offset, replaced))
+    }
+
+    /// leaf_has_room returns whether a page holds a leaf which is not full.
+    fn leaf_has_room(&self, page: &Page<N>) -> Result<bool, Error> {
+        Ok(page.leaf_len()?.is_some_and(|len| len < 2 * self.b - 1))
+    }
+
+    /// insert_into_leaf_page inserts a pair into a copy of a page holding a leaf with room for it
+    /// (see `leaf_has_room`), editing the cells of the page in place rather than decoding the
+    /// leaf and serializing it back, returning the offset of the copy and the pair replaced.
+    /// A leaf this insert wrote already (see `Pager::is_staged`) is written over rather than copied.
+    fn insert_into_leaf_page(
+        &mut self,
+        mut page: Page<N>,
+        kv: &KeyValuePair,
+        rewrite: &mut Rewrite<'_>,
+    ) -> Result<(Offset, Option<KeyValuePair>), Error> {
+        let replaced = match rewrite {
+            Rewrite::Refuse => page.insert_into_leaf(kv, false)?,
+            Rewrite::Replace => page.insert_into_leaf(kv, true)?,
+            Rewrite::Update { .. } => {
+                let current = match page.find_in_leaf(&kv.key)? {
+                    Some(range) => Some(page.str_at(range)?.to_string()),
+                    None => None,
+                };
+                let kv = rewrite.resolve(kv.clone(), current.as_deref())?;
+                page.insert_into_leaf(&kv, true)?
+            }
+        };
+        let offset = Offset(page.offset());
+        if self.pager.is_staged(&offset) {
+            self.pager.write_page_at_offset(page, &offset)?;
+            return Ok((offset, replaced));
+        }
+        Ok((self.pager.write_page(page)?, replaced))
+    }
+
+    /// split_root splits a full root under a new root, returning the new root and its offset.
+    fn split_root(&mut self, mut root: Node) -> Result<(Node, Offset), Error> {
+        // split the root creating a new root and child nodes along the way.
+        let mut new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
+        // write the new root to disk to aquire an offset for the new root.
+        let new_root_offset = self.pager.write_page(Page::encode(&new_root)?)?;
+        // set the old roots parent to the new root.
+        root.parent_offset = Some(new_root_offset.clone());
+        root.is_root = false;
+        // split the old root.
+        let (median, sibling) = root.split(self.b)?;
+        count!(splits);
+        // write the old root with its new data to disk in a *new* location.
+        let old_root_offset = self.pager.write_page(Page::encode(&root)?)?;
+        // write the newly created sibling to disk.
+        let sibling_offset = self.pager.write_page(Page::encode(&sibling)?)?;
+        self.structure_watchers.record(StructuralEvent::Split {
+            offset: old_root_offset.clone(),
+            sibling: sibling_offset.clone(),
+        });
+        // update the new root with its children and key.
+        new_root.node_type =
+            NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
+        // write the new_root to disk.
+        self.pager
+            .write_page_at_offset(Page::encode(&new_root)?, &new_root_offset)?;
+        Ok((new_root, new_root_offset))
+    }
+
+    /// split_child splits a full child of a node (already copied to a new offset), writing both
+    /// halves, returning the median key and the sibling holding the larger keys and its offset.
+    fn split_child(
+        &mut self,
+        child: &mut Node,
+        new_child_offset: &Offset,
+    ) -> Result<(Key, Node, Offset), Error> {
+        // split will split the child at b leaving the [0, b-1] keys
+        // while moving the set of [b, 2b-1] keys to the sibling.
+        let (median, sibling) = child.split(self.b)?;
+        count!(splits);
+        self.pager
+            .write_page_at_offset(Page::encode(&*child)?, new_child_offset)?;
+        // Write the newly created sibling to disk.
+        let sibling_offset = self.pager.write_page(Page::encode(&sibling)?)?;
+        self.structure_watchers.record(StructuralEvent::Split {
+            offset: new_child_offset.clone(),
+            sibling: sibling_offset.clone(),
+        });
+        Ok((median, sibling, sibling_offset))
     }
 
     /// insert_non_full (recursively) finds a node rooted at a given non-full node.
     /// to insert a given key-value pair. Here we assume the node is
     /// already a copy of an existing node in a copy-on-write root to node traversal.
+    /// If replace is set an existing pair of the same key is replaced and returned.
+    /// The level of the node (the root's being 0) is named by the errors of the insert.
     fn insert_non_full(
         &mut self,
         node: &mut Node,
         node_offset: Offset,
         kv: KeyValuePair,
-    ) -> Result<(), Error> {
+        rewrite: &mut Rewrite<'_>,
+        level: usize,
+    ) -> Result<Option<KeyValuePair>, Error> {
         match &mut node.node_type {
             NodeType::Leaf(ref mut pairs) => {
-                let idx = pairs.binary_search(&kv).unwrap_or_else(|x| x);
-                pairs.insert(idx, kv);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, &node_offset)
+                let key = kv.key.clone();
+                let found = pairs.binary_search(&kv);
+                let current = found.ok().map(|idx| pairs[idx].value.as_str());
+                // The pages copied on the way down are staged, the insert discards them.
+                let kv = rewrite.resolve(kv, current)?;
+                let replaced = match found {
+                    Ok(idx) => Some(std::mem::replace(&mut pairs[idx], kv)),
+                    Err(idx) => {
+                        pairs.insert(idx, kv);
+                        None
+                    }
+                };
+                Page::encode(&*node)
+                    .and_then(|page| self.pager.write_page_at_offset(page, &node_offset))
+                    .map_err(|e| {
+                        e.in_context("inserting", &key, "write of a leaf", node_offset.0, level)
+                    })?;
+                Ok(replaced)
             }
             NodeType::Internal(ref mut children, ref mut keys) => {
                 let idx = keys
-                    .binary_search(&Key(kv.key.clone()))
+                    .binary_search_by(|key| key.0.cmp(&kv.key))
                     .unwrap_or_else(|x| x);
                 let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
-                let child_page = self.pager.get_page(&child_offset)?;
-                let mut child = Node::try_from(child_page)?;
+                let in_child = |step| {
+                    let (key, offset) = (&kv.key, child_offset.0);
+                    move |e: Error| e.in_context("inserting", key, step, offset, level + 1)
+                };
+                let mut child = match self.pager.cached_node(&child_offset) {
+                    Some(child) => child,
+                    None => {
+                        let child_page = self
+                            .pager
+                            .get_page(&child_offset)
+                            .map_err(in_child("read of a node"))?;
+                        if self
+                            .leaf_has_room(&child_page)
+                            .map_err(in_child("read of a node"))?
+                        {
+                            let (new_child_offset, replaced) = self
+                                .insert_into_leaf_page(child_page, &kv, rewrite)
+                                .map_err(in_child("write of a leaf"))?;
+                            children[idx] = new_child_offset;
+                            Page::encode(&*node)
+                                .and_then(|page| {
+                                    self.pager.write_page_at_offset(page, &node_offset)
+                                })
+                                .map_err(|e| {
+                                    e.in_context(
+                                        "inserting",
+                                        &kv.key,
+                                        "write of a node",
+                                        node_offset.0,
+                                        level,
+                                    )
+                                })?;
+                            return Ok(replaced);
+                        }
+                        self.pager
+                            .decode(child_page)
+                            .map_err(in_child("read of a node"))?
+                    }
+                };
                 // Copy each branching-node on the root-to-leaf walk.
                 // write_page appends the given page to the db file thus creating a new node.
-                let new_child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+                // The halves of a root this insert split are copies already.
+                let new_child_offset = match self.pager.is_staged(&child_offset) {
+                    true => child_offset.clone(),
+                    false => Page::encode(&child)
+                        .and_then(|page| self.pager.write_page(page))
+                        .map_err(in_child("copy of a node"))?,
+                };
                 // Assign copied child at the proper place.
                 children[idx] = new_child_offset.to_owned();
                 if self.is_node_full(&child)? {
-                    // split will split the child at b leaving the [0, b-1] keys
-                    // while moving the set of [b, 2b-1] keys to the sibling.
-                    let (median, mut sibling) = child.split(self.b)?;
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
-                    // Write the newly created sibling to disk.
-                    let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
+                    let step = match child.node_type {
+                        NodeType::Leaf(_) => "split of a leaf",
+                        _ => "split of an internal node",
+                    };
+                    debug!(offset = child_offset.0, keys = 2 * self.b - 1, "split node");
+                    let (median, mut sibling, sibling_offset) = self
+                        .split_child(&mut child, &new_child_offset)
+                        .map_err(in_child(step))?;
                     // Siblings keys are larger than the splitted child thus need to be inserted
                     // at the next index.
                     children.insert(idx + 1, sibling_offset.clone());
                     keys.insert(idx, median.clone());
 
                     // Write the parent page to disk.
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                    Page::encode(&*node)
+                        .and_then(|page| self.pager.write_page_at_offset(page, &node_offset))
+                        .map_err(|e| {
+                            e.in_context(
+                                "inserting",
+                                &kv.key,
+                                "write of a node",
+                                node_offset.0,
+                                level,
+                            )
+                        })?;
                     // Continue recursively.
                     if kv.key <= median.0 {
-                        self.insert_non_full(&mut child, new_child_offset, kv)
+                        self.insert_non_full(&mut child, new_child_offset, kv, rewrite, level + 1)
                     } else {
-                        self.insert_non_full(&mut sibling, sibling_offset, kv)
+                        self.insert_non_full(&mut sibling, sibling_offset, kv, rewrite, level + 1)
                     }
                 } else {
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                    self.insert_non_full(&mut child, new_child_offset, kv)
+                    Page::encode(&*node)
+                        .and_then(|page| self.pager.write_page_at_offset(page, &node_offset))
+                        .map_err(|e| {
+                            e.in_context(
+                                "inserting",
+                                &kv.key,
+                                "write of a node",
+                                node_offset.0,
+                                level,
+                            )
+                        })?;
+                    self.insert_non_full(&mut child, new_child_offset, kv, rewrite, level + 1)
                 }
             }
             NodeType::Unexpected => Err(Error::UnexpectedError),
         }
     }
 
-    /// search searches for a specific key in the BTree.
-    pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
+    /// bulk_load builds the tree bottom-up from a set of key-value pairs.
+    /// The pairs are sorted and cut into leaves, disjoint key ranges of leaves are serialized
+    /// concurrently on separate threads and the internal levels are then stitched on top of them
+    /// under a new root. bulk_load is meant for initial loads and expects the tree to be empty.
+    pub fn bulk_load(&mut self, mut pairs: Vec<KeyValuePair>) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_backpressure()?;
         let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let root = Node::try_from(root_page)?;
-        self.search_node(root, &key)
-    }
+        let root = self.pager.get_node(&root_offset)?;
+        match root.node_type {
+            NodeType::Leaf(ref existing) if existing.is_empty() => (),
+            _ => return Err(Error::TreeNotEmpty),
+        }
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        pairs.iter().try_for_each(KeyValuePair::check_size)?;
+        pairs.sort();
+        if let Some(w) = pairs.windows(2).find(|w| w[0].key == w[1].key) {
+            return Err(Error::DuplicateKey {
+                key: w[0].key.clone(),
+            });
+        }
 
-    /// search_node recursively searches a sub tree rooted at node for a key.
-    fn search_node(&mut self, node: Node, search: &str) -> Result<KeyValuePair, Error> {
-        match node.node_type {
-            NodeType::Internal(children, keys) => {
-                let idx = keys
-                    .binary_search(&Key(search.to_string()))
-                    .unwrap_or_else(|x| x);
-                // Retrieve child page from disk and deserialize.
-                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?;
-                let page = self.pager.get_page(child_offset)?;
-                let child_node = Node::try_from(page)?;
-                self.search_node(child_node, search)
+        let indexed = match self.indexes.is_empty() {
+            true => Vec::new(),
+            false => pairs.clone(),
+        };
+        // The tree is empty, only the loaded pairs themselves may violate a unique index.
+        for index in self.indexes.iter().filter(|index| index.unique) {
+            if has_duplicates(index, &indexed) {
+                return Err(Error::UniqueViolation);
             }
-            NodeType::Leaf(pairs) => {
-                if let Ok(idx) =
-                    pairs.binary_search_by_key(&search.to_string(), |pair| pair.key.clone())
-                {
-                    return Ok(pairs[idx].clone());
-                }
-                Err(Error::KeyNotFound)
+        }
+        let res = build_from_sorted(&mut self.pager, self.b, pairs, &mut || Ok(()));
+        let root_offset = self.halt_on_failure(res)?;
+        self.commit_root(root_offset)?;
+        for kv in indexed.iter() {
+            self.update_indexes(None, Some(kv))?;
+        }
+        Ok(())
+    }
+
+    /// export_json writes every pair of the tree to a writer as JSON lines, in key order,
+    /// a `{"key":"...","value":"..."}` object per line. It returns the number of pairs written.
+    pub fn export_json(&mut self, writer: &mut impl Write) -> Result<usize, Error> {
+        let pairs = self.range(..)?;
+        for kv in pairs.iter() {
+            write_json_line(writer, kv)?;
+        }
+        writer.flush()?;
+        Ok(pairs.len())
+    }
+
+    /// import_json writes every pair read off JSON lines (see `export_json`) to the tree,
+    /// the last line of a key winning, and returns the number of lines read. Sorted input
+    /// with unique keys is bulk loaded into an empty tree. Blank lines are skipped.
+    pub fn import_json(&mut self, reader: impl BufRead) -> Result<usize, Error> {
+        self.check_writable()?;
+        let mut pairs = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                pairs.push(read_json_line(&line)?);
             }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
         }
+        self.load(pairs)
     }
 
-    /// delete deletes a given key from the tree.
-    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        // Shadow the new root and rewrite it.
-        let mut new_root = Node::try_from(root_page)?;
-        let new_root_page = Page::try_from(&new_root)?;
-        let new_root_offset = self.pager.write_page(new_root_page)?;
-        self.delete_key_from_subtree(key, &mut new_root, &new_root_offset)?;
-        self.wal.set_root(new_root_offset)
+    /// export_dump writes every pair of the tree to a writer as a dump, in key order, and
+    /// returns the number of pairs written. A dump is a plain sequence of records with no header,
+    /// the length of the key (a big-endian u32) and its bytes followed by those of the value,
+    /// for moving data between this crate and other stores.
+    pub fn export_dump(&mut self, writer: &mut impl Write) -> Result<usize, Error> {
+        let pairs = self.range(..)?;
+        for kv in pairs.iter() {
+            write_dump_record(writer, kv)?;
+        }
+        writer.flush()?;
+        Ok(pairs.len())
     }
 
-    /// delete key from subtree recursively traverses a tree rooted at a node in certain offset
-    /// until it finds the given key and delete the key-value pair. Here we assume the node is
-    /// already a copy of an existing node in a copy-on-write root to node traversal.
-    fn delete_key_from_subtree(
+    /// import_dump writes every pair of a dump (see `export_dump`) to the tree, the last record
+    /// of a key winning, and returns the number of records read. Keys and values have to be UTF-8
+    /// and fit the tree. Sorted input with unique keys is bulk loaded into an empty tree.
+    pub fn import_dump(&mut self, mut reader: impl Read) -> Result<usize, Error> {
+        self.check_writable()?;
+        let mut pairs = Vec::new();
+        while let Some(kv) = read_dump_record(&mut reader)? {
+            pairs.push(kv);
+        }
+        self.load(pairs)
+    }
+
+    /// import_csv writes a pair per record of CSV data to the tree, keyed by the field of a key
+    /// column and valued by the fields of the value columns, formatted as a record of CSV of
+    /// the same dialect. The last record of a key wins, sorted input with unique keys is bulk
+    /// loaded into an empty tree. It returns the number of records read.
+    pub fn import_csv(
         &mut self,
-        key: Key,
-        node: &mut Node,
-        node_offset: &Offset,
-    ) -> Result<(), Error> {
-        match &mut node.node_type {
-            NodeType::Leaf(ref mut pairs) => {
-                let key_idx = pairs
-                    .binary_search_by_key(&key, |kv| Key(kv.key.clone()))
-                    .map_err(|_| Error::KeyNotFound)?;
-                pairs.remove(key_idx);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                // Check for underflow - if it occures,
-                // we need to merge with a sibling.
-                // this can only occur if node is not the root (as it cannot "underflow").
-                // continue recoursively up the tree.
-                self.borrow_if_needed(node.to_owned(), &key)?;
-            }
-            NodeType::Internal(children, keys) => {
-                let node_idx = keys.binary_search(&key).unwrap_or_else(|x| x);
+        mut reader: impl Read,
+        key_column: usize,
+        value_columns: &[usize],
+        options: &CsvOptions,
+    ) -> Result<usize, Error> {
+        self.check_writable()?;
+        let mut data = String::new();
+        reader.read_to_string(&mut data)?;
+        let mut records = options.parse(&data)?.into_iter();
+        if options.header() {
+            records.next();
+        }
+        let mut pairs = Vec::new();
+        for record in records {
+            let field =
+                |column: usize| record.get(column).ok_or(Error::InvalidFormat("CSV record"));
+            let values = value_columns
+                .iter()
+                .map(|column| field(*column).map(String::as_str))
+                .collect::<Result<Vec<_>, Error>>()?;
+            pairs.push(KeyValuePair::new(
+                field(key_column)?.clone(),
+                options.format(&values),
+            ));
+        }
+        self.load(pairs)
+    }
+
+    /// export_csv writes every pair of the tree to a writer as CSV, in key order, the key
+    /// followed by the fields of the value (see `import_csv`). It returns the number of pairs written.
+    /// A value which is not a single record of CSV of the dialect fails the export with
+    /// `InvalidFormat` before anything is written, as do values of different numbers of fields
+    /// with a header, whose columns are named `key` and `value` (`value1`, `value2`... for
+    /// values of several fields).
+    pub fn export_csv(
+        &mut self,
+        writer: &mut impl Write,
+        options: &CsvOptions,
+    ) -> Result<usize, Error> {
+        let pairs = self.range(..)?;
+        let mut records = Vec::with_capacity(pairs.len());
+        for kv in pairs.iter() {
+            let mut fields = match options.parse(&kv.value)?.as_mut_slice() {
+                [] => vec![String::new()],
+                [fields] => std::mem::take(fields),
+                _ => return Err(Error::InvalidFormat("CSV value")),
+            };
+            fields.insert(0, kv.key.clone());
+            records.push(fields);
+        }
+        if options.header() {
+            let columns = records.first().map_or(2, Vec::len);
+            if records.iter().any(|fields| fields.len() != columns) {
+                return Err(Error::InvalidFormat("CSV value"));
+            }
+            let mut header = vec!["key".to_string()];
+            match columns {
+                2 => header.push("value".to_string()),
+                _ => header.extend((1..columns).map(|i| format!("value{}", i))),
+            }
+            records.insert(0, header);
+        }
+        for fields in records.iter() {
+            let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+            writeln!(writer, "{}", options.format(&fields))?;
+        }
+        writer.flush()?;
+        Ok(pairs.len())
+    }
+
+    /// b_parameter returns the b parameter the tree was built with.
+    pub fn b_parameter(&self) -> usize {
+        self.b
+    }
+
+    /// migrate_to copies the tree, its buckets and its sequences (as of their committed roots)
+    /// into a new tree file built with another b parameter, bulk loading every keyspace, and
+    /// returns the new tree. This is the supported path for changing the layout of a tree.
+    /// Tags and the replicated LSN are left behind as they name versions of the old file,
+    /// and indexes have to be registered again.
+    pub fn migrate_to(&mut self, path: &Path, b: usize) -> Result<BTree<N>, Error> {
+        self.migrate_to_sized(path, b)
+    }
+
+    /// migrate_to_sized migrates the tree like `migrate_to`, into a new tree file of pages of M bytes.
+    pub fn migrate_to_sized<const M: usize>(
+        &mut self,
+        path: &Path,
+        b: usize,
+    ) -> Result<BTree<M>, Error> {
+        let mut target = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(b)
+            .build_sized::<M>()?;
+        target.bulk_load(self.range(..)?)?;
+        let header = Header::decode(&self.pager.get_page(&Offset(0))?)?;
+        for name in header.buckets.iter() {
+            let pairs = self.open_bucket(name)?.range(..)?;
+            target.open_bucket(name)?;
+            let root = build_from_sorted(&mut target.pager, b, pairs, &mut || Ok(()))?;
+            let (_, wal) = target.buckets.last_mut().ok_or(Error::UnexpectedError)?;
+            wal.set_root(root)?;
+        }
+        target.sequences = header.sequences;
+        target.write_header()?;
+        target.sync()?;
+        Ok(target)
+    }
+
+    /// load writes imported pairs to the tree, bulk loading sorted pairs with unique keys
+    /// into an empty tree, and returns their number.
+    fn load(&mut self, pairs: Vec<KeyValuePair>) -> Result<usize, Error> {
+        let imported = pairs.len();
+        let sorted = pairs.windows(2).all(|pair| pair[0].key < pair[1].key);
+        if sorted && self.range(..)?.is_empty() {
+            self.bulk_load(pairs)?;
+        } else {
+            for kv in pairs {
+                self.put(kv)?;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// merge_from ingests every pair of the tree in another file, resolving the keys
+    /// present in both trees by a conflict policy, and returns the number of pairs written.
+    /// The pairs of both trees are merged in key order and the tree is rebuilt from them
+    /// bottom-up (see `bulk_load`), committed as a single write (see `atomically`).
+    pub fn merge_from(&mut self, path: &Path, policy: ConflictPolicy) -> Result<usize, Error> {
+        self.check_writable()?;
+        self.check_backpressure()?;
+        let pager = Pager::<N>::open_read_only(path)?;
+        Header::decode(&pager.get_page(&Offset(0))?)?;
+        let parent_directory = path.parent().unwrap_or_else(|| Path::new("/tmp"));
+        let root_offset = Wal::open_read_only(parent_directory.to_path_buf())?.get_root()?;
+        let mut theirs = Vec::new();
+        scan_sub_tree(&pager, &root_offset, &(..), &mut |kv| theirs.push(kv))?;
+
+        let mut mine = self.range(..)?.into_iter().peekable();
+        let mut pairs = Vec::with_capacity(theirs.len());
+        // The pairs written, along with the pairs of this tree they replace.
+        let mut written = Vec::new();
+        for kv in theirs {
+            while let Some(existing) = mine.next_if(|existing| existing.key < kv.key) {
+                pairs.push(existing);
+            }
+            match mine.next_if(|existing| existing.key == kv.key) {
+                Some(existing) => match policy {
+                    ConflictPolicy::Fail => {
+                        return Err(Error::DuplicateKey { key: existing.key });
+                    }
+                    ConflictPolicy::KeepExisting => pairs.push(existing),
+                    ConflictPolicy::Overwrite => {
+                        written.push((Some(existing), kv.clone()));
+                        pairs.push(kv);
+                    }
+                },
+                None => {
+                    written.push((None, kv.clone()));
+                    pairs.push(kv);
+                }
+            }
+        }
+        pairs.extend(mine);
+        if written.is_empty() {
+            return Ok(0);
+        }
+        self.atomically(|tree| {
+            let res = build_from_sorted(&mut tree.pager, tree.b, pairs, &mut || Ok(()));
+            let root_offset = tree.halt_on_failure(res)?;
+            tree.commit_root(root_offset)?;
+            for (replaced, kv) in written.iter() {
+                if !tree.indexes.is_empty() {
+                    tree.check_unique(kv)?;
+                    tree.update_indexes(replaced.as_ref(), Some(kv))?;
+                }
+                if tree.watchers.watches(&kv.key) {
+                    tree.watchers.notify(match replaced {
+                        Some(_) => Event::Update(kv.clone()),
+                        None => Event::Insert(kv.clone()),
+                    });
+                }
+            }
+            Ok(written.len())
+        })
+    }
+
+    /// sync makes every committed write durable, flushing the written pages before the roots
+    /// pointing at them so a logged root never points at pages missing from the disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let res = self.timed("sync", None, |tree| tree.sync_files());
+        // A failed fsync may have dropped the dirty pages it failed to write.
+        self.halt_on_failure(res)
+    }
+
+    fn sync_files(&mut self) -> Result<(), Error> {
+        self.pager.sync()?;
+        self.wal.sync()?;
+        for (_, wal) in self.buckets.iter() {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// checkpoint truncates the root log down to the currently committed root,
+    /// making the tree durable first as the older roots cannot be recovered afterwards.
+    /// Checkpoints can be taken manually (e.g. during maintenance windows)
+    /// or in the background by a `Checkpointer`.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_ungrouped("checkpointing within a group of writes")?;
+        self.sync()?;
+        self.wal.checkpoint()?;
+        for (_, wal) in self.buckets.iter_mut() {
+            wal.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// wal_size returns the size of the root log in bytes.
+    pub(crate) fn wal_size(&self) -> Result<u64, Error> {
+        self.wal.size()
+    }
+
+    /// compact rewrites the live part of the tree into a fresh file, packing the leaves
+    /// and reclaiming the pages left behind by previous copy-on-write versions of the tree.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        let compacted = self.begin_compaction()?.run(&mut || Ok(()))?;
+        self.finish_compaction(compacted).map(|_| ())
+    }
+
+    /// root_offset returns the offset of the currently committed root.
+    pub(crate) fn root_offset(&mut self) -> Result<Offset, Error> {
+        self.committed_root()
+    }
+
+    /// roots returns the roots of the trees of the file: the tree, its buckets and its tags.
+    fn roots(&mut self) -> Result<Vec<Offset>, Error> {
+        let mut roots = vec![self.committed_root()?];
+        for (_, wal) in self.buckets.iter_mut() {
+            roots.push(wal.get_root()?);
+        }
+        roots.extend(self.tags.iter().map(|(_, root)| root.clone()));
+        Ok(roots)
+    }
+
+    /// stats returns the stats of the tree, measured by a traversal of the tree.
+    /// The pages of the buckets and of the tags count towards the live pages of the file only.
+    pub fn stats(&mut self) -> Result<TreeStats, Error> {
+        let mut stats = TreeStats::default();
+        let mut roots = self.roots()?.into_iter();
+        let root_offset = roots.next().ok_or(Error::UnexpectedError)?;
+        let mut live_pages = HashSet::new();
+        measure(
+            &self.pager,
+            self.b,
+            &root_offset,
+            1,
+            &mut stats,
+            &mut live_pages,
+        )?;
+        for root in roots {
+            measure(
+                &self.pager,
+                self.b,
+                &root,
+                1,
+                &mut TreeStats::default(),
+                &mut live_pages,
+            )?;
+        }
+        stats.total_bytes = self.pager.size()?;
+        // The first page of the file holds the header.
+        let pages = (stats.total_bytes as usize / N).saturating_sub(1);
+        stats.free_pages = pages.saturating_sub(live_pages.len());
+        Ok(stats)
+    }
+
+    /// file_header reads the header of the tree file, as last written (by the writer, for a
+    /// read-only tree).
+    pub fn file_header(&mut self) -> Result<Header, Error> {
+        Header::decode(&self.pager.get_page(&Offset(0))?)
+    }
+
+    /// header returns the file header of the tree.
+    fn header(&self) -> Header {
+        let buckets: Vec<String> = self
+            .buckets
+            .iter()
+            .map(|(name, _)| name.clone())
+            .chain(self.detached.iter().cloned())
+            .collect();
+        Header {
+            b: self.b,
+            features: match buckets.is_empty() {
+                true => FEATURE_BPLUS_LAYOUT,
+                false => FEATURE_BPLUS_LAYOUT | FEATURE_BUCKETS,
+            },
+            buckets,
+            sequences: self.sequences.clone(),
+            tags: self.tags.clone(),
+            replicated_lsn: self.replicated_lsn,
+        }
+    }
+
+    /// begin_compaction prepares a compaction of the tree (and its buckets)
+    /// as of the currently committed roots.
+    pub(crate) fn begin_compaction(&mut self) -> Result<Compaction<N>, Error> {
+        self.check_writable()?;
+        self.check_ungrouped("compacting within a group of writes")?;
+        if self.pager.is_in_memory() {
+            return Err(Error::Unsupported("compacting a tree held in memory"));
+        }
+        if !self.detached.is_empty() {
+            return Err(Error::Unsupported(
+                "compacting a tree whose buckets are written to apart from it",
+            ));
+        }
+        Compaction::new(
+            self.roots()?,
+            self.pager.reader()?,
+            &compaction_target(self.pager.path()),
+            &self.header(),
+        )
+    }
+
+    /// finish_compaction swaps the tree file for the compacted one and commits its root.
+    /// If the tree was written to since the compaction began the compacted file is stale,
+    /// it is discarded and false is returned. The roots of the compacted file are written to
+    /// a marker before the swap, so a swap interrupted by a crash is resumed on the next open.
+    pub(crate) fn finish_compaction(&mut self, compacted: CompactedTree<N>) -> Result<bool, Error> {
+        let CompactedTree {
+            snapshot,
+            mut pager,
+            roots,
+        } = compacted;
+        if self.roots()? != snapshot {
+            std::fs::remove_file(pager.path())?;
+            return Ok(false);
+        }
+        let path = self.pager.path().to_path_buf();
+        write_compaction_marker(&path, &roots)?;
+        pager.rename_to(&path)?;
+        pager.set_strict(self.pager.strict());
+        pager.set_max_size(self.pager.max_size());
+        pager.set_node_cache_size(self.pager.node_cache_size());
+        self.pager = pager;
+        self.generation += 1;
+        self.commit_compacted_roots(roots)?;
+        remove_compaction_marker(&path)?;
+        Ok(true)
+    }
+
+    /// commit_compacted_roots commits the roots of a compacted file swapped in for the tree file:
+    /// that of the tree, then those of its buckets and of its tags, in the order of `roots`.
+    fn commit_compacted_roots(&mut self, roots: Vec<Offset>) -> Result<(), Error> {
+        let mut roots = roots.into_iter();
+        self.wal
+            .set_root(roots.next().ok_or(Error::UnexpectedError)?)?;
+        // The roots logged before the compaction point into the replaced file.
+        self.wal.checkpoint()?;
+        for (_, wal) in self.buckets.iter_mut() {
+            wal.set_root(roots.next().ok_or(Error::UnexpectedError)?)?;
+            wal.checkpoint()?;
+        }
+        for ((_, tagged), root) in self.tags.iter_mut().zip(roots) {
+            *tagged = root;
+        }
+        if !self.tags.is_empty() {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    /// restore_to writes the tree as of a past moment into a new tree file, in another directory,
+    /// and returns the LSN of the commit restored. Past roots are found in the root log: the moment
+    /// must follow the last checkpoint (or compaction) of the log, as earlier roots are gone.
+    pub fn restore_to(&mut self, point: RestorePoint, target: &Path) -> Result<u64, Error> {
+        let record = self
+            .wal
+            .records()?
+            .into_iter()
+            .take_while(|record| match point {
+                RestorePoint::Lsn(lsn) => record.lsn <= lsn,
+                RestorePoint::Timestamp(timestamp) => record.timestamp <= timestamp,
+            })
+            .last()
+            .ok_or_else(|| Error::KeyNotFound {
+                key: match point {
+                    RestorePoint::Lsn(lsn) => format!("lsn {}", lsn),
+                    RestorePoint::Timestamp(timestamp) => format!("timestamp {}", timestamp),
+                },
+            })?;
+        export_tree(self.pager.reader()?, record.root, self.b, target)?;
+        Ok(record.lsn)
+    }
+
+    /// replication_stream returns the stream of the records of the commits of the tree
+    /// following a given LSN (0 streams every commit from the empty tree), for a follower to apply.
+    /// The commit of the LSN must still be in the root log, otherwise the follower is too far
+    /// behind (the log was checkpointed since) and `KeyNotFound` is returned. The stream from
+    /// LSN 0 starts with the oldest commit of the log, its snapshot record holding every pair of the tree.
+    /// Buckets are not replicated.
+    pub fn replication_stream(&mut self, from_lsn: u64) -> Result<ReplicationStream<N>, Error> {
+        let pager = self.pager.reader()?;
+        let mut records = self.wal.records()?;
+        if from_lsn == 0 {
+            return Ok(ReplicationStream::new(pager, None, records));
+        }
+        let idx = records
+            .iter()
+            .position(|record| record.lsn == from_lsn)
+            .ok_or_else(|| Error::KeyNotFound {
+                key: format!("lsn {}", from_lsn),
+            })?;
+        let following = records.split_off(idx + 1);
+        let base = records.pop().map(|record| record.root);
+        Ok(ReplicationStream::new(pager, base, following))
+    }
+
+    /// changes_since returns the key-level changes committed to the tree following a given LSN
+    /// (0 for every change), in commit order, each along with the LSN of its commit.
+    /// Like `replication_stream` it returns `KeyNotFound` once the LSN left the root log.
+    pub fn changes_since(&mut self, lsn: u64) -> Result<Changes<N>, Error> {
+        Ok(Changes::new(self.replication_stream(lsn)?))
+    }
+
+    /// apply_replication_record applies a record of the replication stream of a leader,
+    /// turning the tree into a follower of the leader. Records must be applied in order of their
+    /// LSNs with no gaps, any other record is refused with `Conflict`. The changes of a record
+    /// are committed as a single write (see `atomically`), and its LSN persisted once they are,
+    /// re-applying a record after a crash is harmless.
+    ///
+    /// An empty follower yet to apply any record bootstraps from the snapshot record starting
+    /// the stream from LSN 0 (see `replication_stream`), whatever its LSN: the record holds every
+    /// pair of the leader as of its commit, the commits before it may have been checkpointed away.
+    pub fn apply_replication_record(&mut self, record: &ReplicationRecord) -> Result<(), Error> {
+        self.check_writable()?;
+        if record.lsn != self.replicated_lsn + 1 {
+            let bootstrap = record.snapshot
+                && self.replicated_lsn == 0
+                && self.first_if(&mut |_, _| true)?.is_none();
+            if !bootstrap {
+                return Err(Error::Conflict);
+            }
+        }
+        self.atomically(|tree| {
+            for change in record.changes.iter() {
+                match change {
+                    Change::Put(kv) => tree.put(kv.clone()).map(|_| ())?,
+                    Change::Delete(key) => match tree.delete(key.clone()) {
+                        Err(Error::KeyNotFound { .. }) => (),
+                        res => res?,
+                    },
+                }
+            }
+            Ok(())
+        })?;
+        self.sync()?;
+        self.replicated_lsn = record.lsn;
+        self.write_header()
+    }
+
+    /// catch_up applies the records of a replication stream (see `apply_replication_record`)
+    /// and returns the LSN of the last record applied.
+    pub fn catch_up<I>(&mut self, records: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = Result<ReplicationRecord, Error>>,
+    {
+        for record in records {
+            self.apply_replication_record(&record?)?;
+        }
+        Ok(self.replicated_lsn)
+    }
+
+    /// replicated_lsn returns the LSN of the last replication record applied to the tree,
+    /// which is where the replication stream of the leader resumes.
+    pub fn replicated_lsn(&self) -> u64 {
+        self.replicated_lsn
+    }
+
+    /// open_bucket opens a bucket: an independent keyspace of its own, sharing the file of the tree.
+    /// The bucket is created (and added to the bucket catalog in the file header) if it does
+    /// not exist yet. Every bucket keeps the log of its roots next to the one of the tree.
+    pub fn open_bucket(&mut self, name: &str) -> Result<Bucket<'_, N>, Error> {
+        if name.is_empty()
+            || name.len() > KEY_SIZE
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error::InvalidBucketName);
+        }
+        if self.detached.iter().any(|bucket| bucket == name) {
+            return Err(Error::Unsupported(
+                "opening a bucket written to apart from its tree",
+            ));
+        }
+        if !self.buckets.iter().any(|(bucket, _)| bucket == name) {
+            let parent_directory = self
+                .pager
+                .path()
+                .parent()
+                .unwrap_or_else(|| Path::new("/tmp"))
+                .to_path_buf();
+            let wal = match self.lock {
+                // A read-only tree opens the buckets the writer created.
+                None => {
+                    let header = Header::decode(&self.pager.get_page(&Offset(0))?)?;
+                    if !header.buckets.iter().any(|bucket| bucket == name) {
+                        return Err(Error::InvalidBucketName);
+                    }
+                    Wal::open_read_only_bucket(parent_directory, name)?
+                }
+                Some(_) => {
+                    self.check_ungrouped("creating a bucket within a group of writes")?;
+                    let mut wal = match self.pager.is_in_memory() {
+                        true => Wal::in_memory(),
+                        false => Wal::new_bucket(parent_directory, name)?,
+                    };
+                    let root = Node::new(NodeType::Leaf(vec![]), true, None);
+                    wal.set_root(self.pager.write_page(Page::encode(&root)?)?)?;
+                    self.buckets.push((name.to_string(), wal));
+                    self.write_header()?;
+                    return Ok(Bucket::new(self, name.to_string()));
+                }
+            };
+            self.buckets.push((name.to_string(), wal));
+        }
+        Ok(Bucket::new(self, name.to_string()))
+    }
+
+    /// detach_bucket takes a bucket out of the tree into a tree of its own, so the bucket can be
+    /// written to concurrently to the tree and to the other buckets taken out (see
+    /// `SharedBTree::bucket`) until `attach_bucket` hands it back. Both trees append their pages
+    /// to the file through pagers sharing its page allocator, see `Pager::writer`. The bucket is
+    /// created if it does not exist yet. Indexes stay with their tree, as do the buckets
+    /// of a tree whose structure is watched.
+    pub(crate) fn detach_bucket(&mut self, name: &str) -> Result<BTree<N>, Error> {
+        self.check_writable()?;
+        self.check_ungrouped("detaching a bucket within a group of writes")?;
+        if self.indexes.iter().any(|index| index.name == name) {
+            return Err(Error::Unsupported("detaching an index from its tree"));
+        }
+        if self.structure_watchers.is_watched() {
+            return Err(Error::Unsupported(
+                "detaching a bucket from a tree whose structure is watched",
+            ));
+        }
+        self.open_bucket(name)?;
+        let pager = self.pager.writer()?;
+        let idx = self
+            .buckets
+            .iter()
+            .position(|(bucket, _)| bucket == name)
+            .ok_or(Error::InvalidBucketName)?;
+        let (name, wal) = self.buckets.remove(idx);
+        self.detached.push(name);
+        Ok(BTree {
+            pager,
+            b: self.b,
+            wal,
+            // The tree the bucket was taken out of holds the lock of the file.
+            lock: Some(WriterLock::in_memory()),
+            generation: self.generation,
+            finger: None,
+            watchers: Watchers::default(),
+            structure_watchers: StructureWatchers::default(),
+            wal_limit: self.wal_limit,
+            non_blocking: self.non_blocking,
+            buckets: Vec::new(),
+            detached: Vec::new(),
+            sequences: Vec::new(),
+            tags: Vec::new(),
+            replicated_lsn: 0,
+            indexes: Vec::new(),
+            slow_log: self.slow_log.clone(),
+            root_recoveries: Vec::new(),
+            halted: None,
+            grouped: false,
+        })
+    }
+
+    /// attach_bucket hands a bucket taken out by `detach_bucket` back to the tree.
+    pub(crate) fn attach_bucket(&mut self, name: &str, bucket: BTree<N>) {
+        self.detached.retain(|detached| detached != name);
+        self.buckets.push((name.to_string(), bucket.wal));
+    }
+
+    /// generate_id returns the next value of a named sequence, starting at 1.
+    /// The values of a sequence only ever increase, the last one is persisted in the file header
+    /// (and synced) before it is returned so a value is never handed out twice, even across crashes.
+    pub fn generate_id(&mut self, name: &str) -> Result<u64, Error> {
+        self.check_writable()?;
+        if name.len() > KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        let id = match self.sequences.iter_mut().find(|(seq, _)| seq == name) {
+            Some((_, last)) => {
+                *last += 1;
+                *last
+            }
+            None => {
+                self.sequences.push((name.to_string(), 1));
+                1
+            }
+        };
+        self.write_header()?;
+        Ok(id)
+    }
+
+    /// tag names the currently committed root of the tree, keeping the version of the tree
+    /// it points at around (across compactions too) until the tag is removed.
+    /// Tags are kept in the file header.
+    pub fn tag(&mut self, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_ungrouped("tagging within a group of writes")?;
+        if name.len() > KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        if self.tags.iter().any(|(tag, _)| tag == name) {
+            return Err(Error::DuplicateKey {
+                key: name.to_string(),
+            });
+        }
+        let root = self.wal.get_root()?;
+        // The pages of a tagged root must be durable before the header names it.
+        self.pager.sync()?;
+        self.tags.push((name.to_string(), root));
+        if let Err(e) = self.write_header() {
+            self.tags.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// remove_tag removes a tag, the version of the tree it named is gone once the file is compacted.
+    pub fn remove_tag(&mut self, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let idx = self
+            .tags
+            .iter()
+            .position(|(tag, _)| tag == name)
+            .ok_or_else(|| Error::KeyNotFound {
+                key: name.to_string(),
+            })?;
+        self.tags.remove(idx);
+        self.write_header()
+    }
+
+    /// tags returns the names of the tags of the tree, in order of creation.
+    /// A reader finds the tags created since it opened the tree as well.
+    pub fn tags(&mut self) -> Result<Vec<String>, Error> {
+        self.refresh_tags()?;
+        Ok(self.tags.iter().map(|(tag, _)| tag.clone()).collect())
+    }
+
+    /// open_tag returns a read-only view of the version of the tree named by a tag.
+    pub fn open_tag(&mut self, name: &str) -> Result<ReadView<N>, Error> {
+        self.refresh_tags()?;
+        let (_, root) = self
+            .tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .ok_or_else(|| Error::KeyNotFound {
+                key: name.to_string(),
+            })?;
+        Ok(ReadView::new(root.clone(), Arc::new(self.pager.reader()?)))
+    }
+
+    /// rollback_to_tag commits the root named by a tag as the root of the tree, undoing every write
+    /// to the tree since it was tagged. Buckets (and so secondary indexes) are left as they are.
+    pub fn rollback_to_tag(&mut self, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let (_, root) = self
+            .tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .ok_or_else(|| Error::KeyNotFound {
+                key: name.to_string(),
+            })?;
+        let root = root.clone();
+        self.commit_root(root)
+    }
+
+    /// refresh advances a tree opened for reading to the latest state committed by the writer
+    /// and returns its LSN. Reads always follow the latest root of the log, and the file itself
+    /// once a compaction replaced it, refresh re-reads what is only read on open: the file header
+    /// (tags, sequences and the replicated LSN). A writer is always up to date.
+    pub fn refresh(&mut self) -> Result<u64, Error> {
+        if self.lock.is_none() {
+            let pager = self.pager.reader()?;
+            if !pager.same_file(&self.pager)? {
+                self.pager = pager;
+                self.generation += 1;
+            }
+            let header = Header::decode(&self.pager.get_page(&Offset(0))?)?;
+            self.b = header.b;
+            self.sequences = header.sequences;
+            self.tags = header.tags;
+            self.replicated_lsn = header.replicated_lsn;
+        }
+        Ok(self.wal.last_record()?.lsn)
+    }
+
+    /// committed_root returns the root last committed to the log. A tree opened for reading
+    /// follows the writer onto the file a compaction swapped in under its path (see `refresh`):
+    /// the root is only returned once the file it points into is found still in place, with no
+    /// swap under way, after the root was read. A swap under way for longer than `SWAP_TIMEOUT`
+    /// (the writer crashed in the middle of it) fails the read with `Timeout`.
+    fn committed_root(&mut self) -> Result<Offset, Error> {
+        if self.lock.is_some() {
+            return self.wal.get_root();
+        }
+        let start = std::time::Instant::now();
+        loop {
+            let root = self.wal.get_root()?;
+            if has_compaction_marker(self.pager.path()) {
+                if start.elapsed() > SWAP_TIMEOUT {
+                    return Err(Error::Timeout);
+                }
+                thread::sleep(Duration::from_millis(1));
+            } else if self.pager.is_replaced()? {
+                self.refresh()?;
+            } else {
+                return Ok(root);
+            }
+        }
+    }
+
+    /// refresh_tags re-reads the tags of a tree opened for reading, the writer knows them all.
+    fn refresh_tags(&mut self) -> Result<(), Error> {
+        if self.lock.is_none() {
+            self.tags = Header::decode(&self.pager.get_page(&Offset(0))?)?.tags;
+        }
+        Ok(())
+    }
+
+    /// write_header rewrites the file header in place and syncs it.
+    fn write_header(&mut self) -> Result<(), Error> {
+        let header = self.header().encode()?;
+        self.pager.write_page_at_offset(header, &Offset(0))?;
+        self.pager.sync()
+    }
+
+    /// register_index registers a secondary index of the tree, from then on every write to the tree
+    /// updates the index (kept in the bucket of the same name) along with the write, see `atomically`.
+    /// The pairs already in the tree are indexed right away.
+    pub fn register_index(&mut self, index: IndexDefinition) -> Result<(), Error> {
+        if self
+            .indexes
+            .iter()
+            .any(|existing| existing.name == index.name)
+        {
+            return Err(Error::DuplicateKey { key: index.name });
+        }
+        self.open_bucket(&index.name)?;
+        if self.lock.is_some() {
+            let pairs = self.range(..)?;
+            if index.unique && has_duplicates(&index, &pairs) {
+                return Err(Error::UniqueViolation);
+            }
+            self.group(|tree| {
+                for kv in pairs {
+                    for entry in index.entries(&kv) {
+                        tree.in_bucket(&index.name, |tree| tree.put(entry))?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    /// get_by_index returns the pairs of the tree an index maps a given index key to,
+    /// in order of their keys.
+    pub fn get_by_index(&mut self, index: &str, key: &str) -> Result<Vec<KeyValuePair>, Error> {
+        if !self.indexes.iter().any(|existing| existing.name == index) {
+            return Err(Error::InvalidBucketName);
+        }
+        let entries = self.in_bucket(index, |tree| tree.range(entries_of(key)))?;
+        entries
+            .into_iter()
+            .map(|entry| self.search(entry.value))
+            .collect()
+    }
+
+    /// search_tokens returns the pairs of the tree an inverted index maps every one of
+    /// a set of tokens to (see `IndexDefinition::tokenized`), in order of their keys.
+    pub fn search_tokens(
+        &mut self,
+        index: &str,
+        tokens: &[&str],
+    ) -> Result<Vec<KeyValuePair>, Error> {
+        if !self.indexes.iter().any(|existing| existing.name == index) {
+            return Err(Error::InvalidBucketName);
+        }
+        let mut matches: Option<Vec<String>> = None;
+        for token in tokens {
+            let entries = self.in_bucket(index, |tree| tree.range(entries_of(token)))?;
+            let keys = entries.into_iter().map(|entry| entry.value);
+            matches = Some(match matches {
+                None => keys.collect(),
+                Some(matched) => keys.filter(|key| matched.contains(key)).collect(),
+            });
+        }
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|key| self.search(key))
+            .collect()
+    }
+
+    /// check_unique fails with `UniqueViolation` if a unique index already maps the index key
+    /// of a pair about to be written to another primary key.
+    fn check_unique(&mut self, kv: &KeyValuePair) -> Result<(), Error> {
+        let indexes = std::mem::take(&mut self.indexes);
+        let res = indexes
+            .iter()
+            .filter(|index| index.unique)
+            .try_for_each(|index| {
+                index.index_keys(kv).iter().try_for_each(|index_key| {
+                    let entries =
+                        self.in_bucket(&index.name, |tree| tree.range(entries_of(index_key)))?;
+                    match entries.iter().any(|entry| entry.value != kv.key) {
+                        true => Err(Error::UniqueViolation),
+                        false => Ok(()),
+                    }
+                })
+            });
+        self.indexes = indexes;
+        res
+    }
+
+    /// update_indexes replaces the index entries of the old version of a pair (if any)
+    /// with the entries of its new version (if any).
+    fn update_indexes(
+        &mut self,
+        old: Option<&KeyValuePair>,
+        new: Option<&KeyValuePair>,
+    ) -> Result<(), Error> {
+        let indexes = std::mem::take(&mut self.indexes);
+        let res = indexes.iter().try_for_each(|index| {
+            let old_entries = old.map(|kv| index.entries(kv)).unwrap_or_default();
+            let new_entries = new.map(|kv| index.entries(kv)).unwrap_or_default();
+            if old_entries == new_entries {
+                return Ok(());
+            }
+            self.in_bucket(&index.name, |tree| {
+                for entry in old_entries.iter().filter(|e| !new_entries.contains(e)) {
+                    tree.delete(Key(entry.key.clone()))?;
+                }
+                for entry in new_entries.iter().filter(|e| !old_entries.contains(e)) {
+                    tree.put(entry.clone())?;
+                }
+                Ok(())
+            })
+        });
+        self.indexes = indexes;
+        res
+    }
+
+    /// in_bucket runs an operation of the tree against a bucket, by swapping in
+    /// the log of the roots of the bucket for the duration of the operation.
+    /// Buckets have no watchers or indexes of their own.
+    pub(crate) fn in_bucket<T>(
+        &mut self,
+        name: &str,
+        operation: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let idx = self
+            .buckets
+            .iter()
+            .position(|(bucket, _)| bucket == name)
+            .ok_or(Error::InvalidBucketName)?;
+        std::mem::swap(&mut self.wal, &mut self.buckets[idx].1);
+        let watchers = std::mem::take(&mut self.watchers);
+        let indexes = std::mem::take(&mut self.indexes);
+        let res = operation(self);
+        self.watchers = watchers;
+        self.indexes = indexes;
+        std::mem::swap(&mut self.wal, &mut self.buckets[idx].1);
+        res
+    }
+
+    /// generation returns the number of times the tree file was replaced by a compaction.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// reader opens an additional read-only handle to the current tree file.
+    pub(crate) fn reader(&self) -> Result<Pager<N>, Error> {
+        self.pager.reader()
+    }
+
+    /// read_view returns a view pinned to the currently committed root of the tree,
+    /// the view observes the same version of the tree for as long as it is held.
+    pub fn read_view(&mut self) -> Result<ReadView<N>, Error> {
+        Ok(ReadView::new(
+            self.committed_root()?,
+            Arc::new(self.pager.reader()?),
+        ))
+    }
+
+    /// diff reports the keys whose values differ between this tree and another, in key order,
+    /// see `ReadView::diff`.
+    pub fn diff<F>(&mut self, other: &mut BTree<N>, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Difference),
+    {
+        self.read_view()?.diff(&other.read_view()?, callback)
+    }
+
+    /// content_eq checks whether this tree holds the same pairs as another,
+    /// see `ReadView::content_eq`.
+    pub fn content_eq(&mut self, other: &mut BTree<N>) -> Result<bool, Error> {
+        self.read_view()?.content_eq(&other.read_view()?)
+    }
+
+    /// search searches for a specific key in the BTree.
+    pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
+        let logged = self.slow_log.is_some().then(|| key.clone());
+        self.timed("search", logged.as_deref(), |tree| tree.search_key(key))
+    }
+
+    /// search_value returns the value of a key like `search`, as a slice of the page of its leaf
+    /// shared by reference counting (see `ValueRef`) rather than as a `String` of its own.
+    pub fn search_value(&mut self, key: &str) -> Result<ValueRef<N>, Error> {
+        let logged = self.slow_log.is_some().then(|| key.to_string());
+        self.timed("search", logged.as_deref(), |tree| {
+            debug_span!("search", key = %key);
+            count!(searches);
+            time!(search_latency);
+            let root_offset = tree.committed_root()?;
+            let (leaf, value) = search_from_finger(
+                &tree.pager,
+                &root_offset,
+                tree.generation,
+                key,
+                &mut tree.finger,
+            )?;
+            match value {
+                Some(value) => Ok(ValueRef::new(Arc::new(leaf), value)),
+                None => {
+                    tree.pager.recycle(leaf);
+                    Err(Error::KeyNotFound {
+                        key: key.to_string(),
+                    })
+                }
+            }
+        })
+    }
+
+    fn search_key(&mut self, key: String) -> Result<KeyValuePair, Error> {
+        debug_span!("search", key = %key);
+        count!(searches);
+        time!(search_latency);
+        let root_offset = self.committed_root()?;
+        let (leaf, value) = search_from_finger(
+            &self.pager,
+            &root_offset,
+            self.generation,
+            &key,
+            &mut self.finger,
+        )?;
+        pair_in_leaf(&self.pager, leaf, value, &key)
+    }
+
+    /// range returns all the key-value pairs whose keys fall in the given range, in ascending order.
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<KeyValuePair>, Error> {
+        let root_offset = self.committed_root()?;
+        let mut pairs = Vec::new();
+        scan_sub_tree(&self.pager, &root_offset, &range, &mut |kv| pairs.push(kv))?;
+        Ok(pairs)
+    }
+
+    /// par_range scans a large key range on multiple threads.
+    /// The range is split along internal node boundaries into disjoint subtrees which are
+    /// scanned concurrently by multiple threads.
+    /// Every matching pair is fed to the given callback, pairs within a partition arrive
+    /// in ascending order but partitions are not ordered with respect to each other.
+    pub fn par_range<R, F>(&mut self, range: R, callback: F) -> Result<(), Error>
+    where
+        R: RangeBounds<String> + Sync,
+        F: Fn(KeyValuePair) + Sync,
+    {
+        let num_threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        // Descend level by level until there are enough subtrees to go around.
+        let mut partitions = vec![self.committed_root()?];
+        while partitions.len() < num_threads {
+            let mut children_offsets = Vec::new();
+            for offset in &partitions {
+                match self.pager.get_node(offset)?.node_type {
+                    NodeType::Internal(children, keys) => children_offsets.extend(
+                        children
+                            .into_iter()
+                            .enumerate()
+                            .filter(|(idx, _)| child_overlaps(&keys, *idx, &range))
+                            .map(|(_, child_offset)| child_offset),
+                    ),
+                    NodeType::Leaf(_) => children_offsets.push(offset.clone()),
+                    NodeType::Unexpected => return Err(Error::UnexpectedError),
+                }
+            }
+            if children_offsets == partitions {
+                break;
+            }
+            partitions = children_offsets;
+        }
+
+        let partitions_per_thread = partitions.len().div_ceil(num_threads).max(1);
+        let groups: Vec<&[Offset]> = partitions.chunks(partitions_per_thread).collect();
+        let (pager, range, callback) = (&self.pager, &range, &callback);
+        thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    scope.spawn(move || {
+                        for offset in group {
+                            scan_sub_tree(pager, offset, range, &mut |kv| callback(kv))?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().map_err(|_| Error::ThreadPanicked)?)
+        })
+    }
+
+    /// delete deletes a given key from the tree.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        let logged = self.slow_log.is_some().then(|| key.0.clone());
+        self.timed("delete", logged.as_deref(), |tree| {
+            tree.observed(|tree| tree.delete_key(key))
+        })
+    }
+
+    fn delete_key(&mut self, key: Key) -> Result<(), Error> {
+        // The entries of the pair in the secondary indexes are deleted along with it.
+        if !self.indexes.is_empty() && !self.grouped {
+            return self.group(|tree| tree.delete_key(key));
+        }
+        debug_span!("delete", key = %key.0);
+        count!(deletes);
+        time!(delete_latency);
+        self.check_writable()?;
+        self.check_backpressure()?;
+        let root_offset = self.committed_root()?;
+        let event = match self.watchers.watches(&key.0) {
+            true => Some(Event::Delete(key.clone())),
+            false => None,
+        };
+        let indexed = match self.indexes.is_empty() {
+            true => None,
+            false => Some(search_sub_tree(&self.pager, &root_offset, &key.0)?),
+        };
+        let new_root_offset = self.staged(|tree| tree.delete_from_root(key, &root_offset))?;
+        self.commit_root(new_root_offset)?;
+        if let Some(event) = event {
+            self.watchers.notify(event);
+        }
+        if let Some(kv) = indexed {
+            self.update_indexes(Some(&kv), None)?;
+        }
+        Ok(())
+    }
+
+    /// delete_from_root deletes a key from a copy of a root, returning the offset of the copy
+    /// (or of the copy of its only child, should it be left with one).
+    fn delete_from_root(&mut self, key: Key, root_offset: &Offset) -> Result<Offset, Error> {
+        let mut new_root = self
+            .pager
+            .get_node(root_offset)
+            .map_err(|e| e.in_context("deleting", &key.0, "read of the root", root_offset.0, 0))?;
+        // Shadow the new root and rewrite it.
+        let mut new_root_offset = Page::encode(&new_root)
+            .and_then(|page| self.pager.write_page(page))
+            .map_err(|e| e.in_context("deleting", &key.0, "copy of the root", root_offset.0, 0))?;
+        self.delete_key_from_subtree(&key, &mut new_root, &new_root_offset, 0)?;
+        // If the root was left with a single child following a merge
+        // the child becomes the new root and the tree shrinks by a level.
+        // A merged child this delete wrote is written over in place.
+        if let NodeType::Internal(children, _) = &new_root.node_type {
+            if children.len() == 1 {
+                let only_child = &children[0];
+                new_root_offset = self
+                    .pager
+                    .get_node(only_child)
+                    .and_then(|mut child| {
+                        child.is_root = true;
+                        child.parent_offset = None;
+                        let page = Page::encode(&child)?;
+                        if !self.pager.is_staged(only_child) {
+                            return self.pager.write_page(page);
+                        }
+                        self.pager.write_page_at_offset(page, only_child)?;
+                        Ok(only_child.clone())
+                    })
+                    .map_err(|e| {
+                        e.in_context("deleting", &key.0, "shrink of the root", children[0].0, 1)
+                    })?;
+            }
+        }
+        Ok(new_root_offset)
+    }
+
+    /// rename moves the pair of a key to another key, keeping its value, as a single write
+    /// (see `atomically`): a crash or a failure leaves either the old key or the new one.
+    /// It fails with `DuplicateKey` (leaving the tree untouched) if the new key is taken.
+    /// The value is copied into the leaf of the new key rather than moved, values always fit
+    /// their leaf so there are no overflow pages to move.
+    pub fn rename(&mut self, old: String, new: String) -> Result<(), Error> {
+        self.atomically(|tree| {
+            let kv = tree.search(old)?;
+            match tree.search(new.clone()) {
+                Ok(_) => return Err(Error::DuplicateKey { key: new }),
+                Err(Error::KeyNotFound { .. }) => (),
+                Err(e) => return Err(e),
+            }
+            tree.insert(KeyValuePair::new(new, kv.value))?;
+            tree.delete(Key(kv.key))
+        })
+    }
+
+    /// pop_first_if removes and returns the pair with the smallest key matching a predicate,
+    /// or None if no pair matches.
+    pub fn pop_first_if<P>(&mut self, mut pred: P) -> Result<Option<KeyValuePair>, Error>
+    where
+        P: FnMut(&str, &str) -> bool,
+    {
+        self.check_writable()?;
+        match self.first_if(&mut pred)? {
+            Some(kv) => {
+                self.delete(Key(kv.key.clone()))?;
+                Ok(Some(kv))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// first_if returns the pair with the smallest key matching a predicate.
+    pub(crate) fn first_if<P>(&mut self, pred: &mut P) -> Result<Option<KeyValuePair>, Error>
+    where
+        P: FnMut(&str, &str) -> bool,
+    {
+        let root_offset = self.committed_root()?;
+        find_first(&self.pager, &root_offset, pred)
+    }
+
+    /// watch_prefix subscribes to the inserts and deletes of the keys starting with a prefix,
+    /// events are delivered once the change is committed.
+    pub fn watch_prefix(&mut self, prefix: String) -> Subscription {
+        self.watchers.subscribe(prefix)
+    }
+
+    /// watch_structure subscribes to the structural events of the tree (splits, merges, pages
+    /// allocated and freed and root changes) of every committed insert and delete,
+    /// delivered once the change is committed, for tools visualizing or auditing the tree.
+    pub fn watch_structure(&mut self) -> Subscription<StructuralEvent> {
+        self.structure_watchers.subscribe()
+    }
+
+    /// staged runs the page writes of a write held back in memory (see `Pager::stage`),
+    /// writing them to the file only once it succeeds: a failed write leaves the pages of the
+    /// file, and the offset the next page is appended at, as they were.
+    fn staged<T>(
+        &mut self,
+        body: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.pager.stage();
+        let res = match body(self) {
+            Ok(res) => self.pager.apply_staged().map(|_| res),
+            Err(e) => {
+                self.pager.discard_staged();
+                Err(e)
+            }
+        };
+        self.halt_on_failure(res)
+    }
+
+    /// atomically runs a group of writes to the tree and to its open buckets (see `open_bucket`)
+    /// as a single write: the pages they copy are staged until the group succeeds, and the roots
+    /// they leave are then committed together, so a crash leaves either every write of the group
+    /// or none of them. A group failing (or any of its writes a group goes on past) is rolled
+    /// back, leaving the file and the watchers as they were. The reads of the group observe
+    /// its writes, a group within a group is part of it.
+    ///
+    /// A group committing the roots of several keyspaces makes them durable before returning:
+    /// their roots are written to a marker (see `write_commit_marker`) logged again on open
+    /// should a crash interrupt logging them. Readers of other handles may observe the roots
+    /// being logged one after the other. Buckets cannot be created, nor the tree tagged,
+    /// checkpointed or compacted, within a group.
+    pub fn atomically<T>(
+        &mut self,
+        body: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.check_writable()?;
+        self.observed(|tree| tree.group(body))
+    }
+
+    /// group runs a group of writes, see `atomically`.
+    fn group<T>(
+        &mut self,
+        body: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if self.grouped {
+            return body(self);
+        }
+        self.grouped = true;
+        self.watchers.hold();
+        let res = self.staged(body);
+        self.grouped = false;
+        let res = res.and_then(|res| self.commit_group().map(|_| res));
+        match res {
+            Ok(_) => self.watchers.release(),
+            Err(_) => {
+                self.watchers.discard();
+                self.wal.discard_deferred();
+                for (_, wal) in self.buckets.iter_mut() {
+                    wal.discard_deferred();
+                }
+                // The finger may point into the pages of the group.
+                self.finger = None;
+            }
+        }
+        res
+    }
+
+    /// commit_group commits the roots left by a group of writes, see `atomically`.
+    fn commit_group(&mut self) -> Result<(), Error> {
+        self.check_lease()?;
+        let mut marked = Vec::new();
+        if let Some((lsn, root)) = self.wal.deferred() {
+            marked.push(MarkedRoot {
+                bucket: String::new(),
+                lsn,
+                root,
+            });
+        }
+        for (bucket, wal) in self.buckets.iter() {
+            if let Some((lsn, root)) = wal.deferred() {
+                marked.push(MarkedRoot {
+                    bucket: bucket.clone(),
+                    lsn,
+                    root,
+                });
+            }
+        }
+        let path = self.pager.path().to_path_buf();
+        let marker = marked.len() > 1 && !self.pager.is_in_memory();
+        let res = (|| {
+            if marker {
+                // The pages of the roots must be durable before the marker names them.
+                self.pager.sync()?;
+                write_commit_marker(&path, &marked)?;
+            }
+            self.wal.commit_deferred()?;
+            for (_, wal) in self.buckets.iter_mut() {
+                wal.commit_deferred()?;
+            }
+            if marker {
+                self.sync_files()?;
+                remove_commit_marker(&path)?;
+            }
+            self.check_mutation()
+        })();
+        if let Err(e) = &res {
+            // The roots logged so far are left, and the marker logs the others on open.
+            if marker && self.halted.is_none() {
+                self.halted = Some(e.to_string());
+            }
+        }
+        self.halt_on_failure(res)
+    }
+
+    /// commit_marked_roots logs the roots of a group of writes whose commit was interrupted
+    /// by a crash (see `atomically`), those the logs did not log before the crash.
+    fn commit_marked_roots(&mut self, marked: Vec<MarkedRoot>) -> Result<(), Error> {
+        for MarkedRoot { bucket, lsn, root } in marked {
+            let wal = match bucket.is_empty() {
+                true => &mut self.wal,
+                false => match self.buckets.iter_mut().find(|(name, _)| *name == bucket) {
+                    Some((_, wal)) => wal,
+                    None => return Err(Error::InvalidBucketName),
+                },
+            };
+            if wal.last_record()?.lsn < lsn {
+                wal.set_root(root)?;
+            }
+        }
+        self.sync_files()
+    }
+
+    /// check_ungrouped fails operations which cannot be part of a group of writes (see
+    /// `atomically`) with `Unsupported`.
+    fn check_ungrouped(&self, operation: &'static str) -> Result<(), Error> {
+        match self.grouped {
+            true => Err(Error::Unsupported(operation)),
+            false => Ok(()),
+        }
+    }
+
+    /// observed runs a write, notifying the structure watchers of the changes it committed.
+    fn observed<T>(
+        &mut self,
+        body: impl FnOnce(&mut BTree<N>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        // The writes of a group are observed as a single one, see `atomically`.
+        if self.grouped || !self.structure_watchers.is_watched() {
+            return body(self);
+        }
+        let (old_root, first_new) = (self.wal.get_root()?, self.pager.cursor());
+        let res = body(self);
+        let new_root = self.wal.get_root()?;
+        if new_root == old_root {
+            self.structure_watchers.discard();
+            return res;
+        }
+        let last_new = self.pager.cursor();
+        for offset in (first_new.0..last_new.0).step_by(N) {
+            self.structure_watchers
+                .record(StructuralEvent::PageAllocated(Offset(offset)));
+        }
+        for offset in self.unreachable_pages(&old_root, &new_root, &first_new)? {
+            self.structure_watchers
+                .record(StructuralEvent::PageFreed(offset));
+        }
+        self.structure_watchers
+            .record(StructuralEvent::RootChanged {
+                old: old_root,
+                new: new_root,
+            });
+        self.structure_watchers.notify();
+        res
+    }
+
+    /// unreachable_pages returns the pages of the tree rooted at an old root, and the pages
+    /// written since a first new offset, which the tree rooted at a new root does not reach.
+    /// Pages are never shared by two parents, the walk of the old tree stops at the old pages
+    /// the new pages point at rather than walking the whole tree.
+    fn unreachable_pages(
+        &self,
+        old_root: &Offset,
+        new_root: &Offset,
+        first_new: &Offset,
+    ) -> Result<Vec<Offset>, Error> {
+        let (mut kept, mut new) = (HashSet::new(), HashSet::new());
+        let mut stack = vec![new_root.clone()];
+        while let Some(offset) = stack.pop() {
+            if offset.0 < first_new.0 {
+                kept.insert(offset.0);
+            } else if new.insert(offset.0) {
+                if let NodeType::Internal(children, _) = self.pager.get_node(&offset)?.node_type {
+                    stack.extend(children);
+                }
+            }
+        }
+        let mut unreachable = Vec::new();
+        let mut stack = vec![old_root.clone()];
+        while let Some(offset) = stack.pop() {
+            if kept.contains(&offset.0) {
+                continue;
+            }
+            if let NodeType::Internal(children, _) = self.pager.get_node(&offset)?.node_type {
+                stack.extend(children);
+            }
+            unreachable.push(offset);
+        }
+        // The copies a write made and copied again before committing.
+        unreachable.extend(
+            (first_new.0..self.pager.cursor().0)
+                .step_by(N)
+                .filter(|offset| !new.contains(offset))
+                .map(Offset),
+        );
+        Ok(unreachable)
+    }
+
+    /// delete key from subtree recursively traverses a tree rooted at a node in certain offset
+    /// until it finds the given key and delete the key-value pair. Here we assume the node is
+    /// already a copy of an existing node in a copy-on-write root to node traversal.
+    /// On return the node holds its updated contents, which lets the caller
+    /// rebalance it against its siblings if it underflows.
+    fn delete_key_from_subtree(
+        &mut self,
+        key: &Key,
+        node: &mut Node,
+        node_offset: &Offset,
+        level: usize,
+    ) -> Result<(), Error> {
+        let step = match &mut node.node_type {
+            NodeType::Leaf(ref mut pairs) => {
+                let key_idx = pairs
+                    .binary_search_by(|kv| kv.key.cmp(&key.0))
+                    .map_err(|_| Error::KeyNotFound { key: key.0.clone() })?;
+                pairs.remove(key_idx);
+                "write of a leaf"
+            }
+            NodeType::Internal(children, keys) => {
+                let node_idx = keys.binary_search(key).unwrap_or_else(|x| x);
                 // Retrieve child page from disk and deserialize,
                 // copy over the child page and continue recursively.
                 let child_offset = children.get(node_idx).ok_or(Error::UnexpectedError)?;
-                let child_page = self.pager.get_page(child_offset)?;
-                let mut child_node = Node::try_from(child_page)?;
+                let in_child = |step| {
+                    let offset = child_offset.0;
+                    move |e: Error| e.in_context("deleting", &key.0, step, offset, level + 1)
+                };
+                let mut child_node = self
+                    .pager
+                    .get_node(child_offset)
+                    .map_err(in_child("read of a node"))?;
                 // Fix the parent_offset as the child node is a child of a copied parent
                 // in a copy-on-write root to leaf traversal.
-                // This is important for the case of a node underflow which might require a leaf to root traversal.
                 child_node.parent_offset = Some(node_offset.to_owned());
-                let new_child_page = Page::try_from(&child_node)?;
-                let new_child_offset = self.pager.write_page(new_child_page)?;
+                let new_child_offset = Page::encode(&child_node)
+                    .and_then(|page| self.pager.write_page(page))
+                    .map_err(in_child("copy of a node"))?;
                 // Assign the new pointer in the parent and continue reccoursively.
                 children[node_idx] = new_child_offset.to_owned();
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                return self.delete_key_from_subtree(key, &mut child_node, &new_child_offset);
+                self.delete_key_from_subtree(key, &mut child_node, &new_child_offset, level + 1)?;
+                // Check for underflow - if it occures, we borrow from
+                // or merge with a sibling of the child.
+                if self.is_node_underflow(&child_node)? {
+                    self.rebalance(children, keys, node_idx, child_node, node_offset)
+                        .map_err(|e| {
+                            e.in_context("deleting", &key.0, "rebalance", node_offset.0, level)
+                        })?;
+                }
+                "write of a node"
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        Page::encode(&*node)
+            .and_then(|page| self.pager.write_page_at_offset(page, node_offset))
+            .map_err(|e| e.in_context("deleting", &key.0, step, node_offset.0, level))
+    }
+
+    /// rebalance fixes an underflowing child at idx of an internal node, given by its children
+    /// and keys, by borrowing from a sibling or merging the two if the sibling cannot spare an entry.
+    /// The child is a copy already (written by this delete), it is written over in place, merged
+    /// or not. The sibling is copied over before being modified so the previous version of the
+    /// tree is left intact; the caller writes back the parent.
+    fn rebalance(
+        &mut self,
+        children: &mut Vec<Offset>,
+        keys: &mut Vec<Key>,
+        idx: usize,
+        mut child: Node,
+        parent_offset: &Offset,
+    ) -> Result<(), Error> {
+        // Prefer the left sibling, the leftmost child can only use its right sibling.
+        let sibling_idx = match idx > 0 {
+            true => idx - 1,
+            false => idx + 1,
+        };
+        let sibling_offset = children.get(sibling_idx).ok_or(Error::UnexpectedError)?;
+        let mut sibling = self.pager.get_node(sibling_offset)?;
+        sibling.parent_offset = Some(parent_offset.to_owned());
+        // The separator between the left and the right node of the pair.
+        let sep_idx = cmp::min(idx, sibling_idx);
+
+        if self.can_lend(&sibling)? {
+            match (&mut child.node_type, &mut sibling.node_type) {
+                (NodeType::Leaf(pairs), NodeType::Leaf(sibling_pairs)) => {
+                    if sibling_idx < idx {
+                        let kv = sibling_pairs.pop().ok_or(Error::UnexpectedError)?;
+                        pairs.insert(0, kv);
+                        let max = sibling_pairs.last().ok_or(Error::UnexpectedError)?;
+                        keys[sep_idx] = Key(max.key.clone());
+                    } else {
+                        let kv = sibling_pairs.remove(0);
+                        keys[sep_idx] = Key(kv.key.clone());
+                        pairs.push(kv);
+                    }
+                }
+                (
+                    NodeType::Internal(offsets, child_keys),
+                    NodeType::Internal(sibling_offsets, sibling_keys),
+                ) => {
+                    // Rotate an entry through the separator in the parent.
+                    if sibling_idx < idx {
+                        let offset = sibling_offsets.pop().ok_or(Error::UnexpectedError)?;
+                        let key = sibling_keys.pop().ok_or(Error::UnexpectedError)?;
+                        offsets.insert(0, offset);
+                        child_keys.insert(0, std::mem::replace(&mut keys[sep_idx], key));
+                    } else {
+                        let offset = sibling_offsets.remove(0);
+                        let key = sibling_keys.remove(0);
+                        offsets.push(offset);
+                        child_keys.push(std::mem::replace(&mut keys[sep_idx], key));
+                    }
+                }
+                _ => return Err(Error::UnexpectedError),
+            }
+            self.pager
+                .write_page_at_offset(Page::encode(&child)?, &children[idx])?;
+            children[sibling_idx] = self.pager.write_page(Page::encode(&sibling)?)?;
+            return Ok(());
+        }
+
+        let child_offset = children[idx].clone();
+        let (left, right) = match sibling_idx < idx {
+            true => (sibling, child),
+            false => (child, sibling),
+        };
+        let separator = keys.remove(sep_idx);
+        let merged = self.merge(left, right, separator)?;
+        self.pager
+            .write_page_at_offset(Page::encode(&merged)?, &child_offset)?;
+        let right = children.remove(sep_idx + 1);
+        let left = std::mem::replace(&mut children[sep_idx], child_offset);
+        self.structure_watchers.record(StructuralEvent::Merge {
+            left,
+            right,
+            offset: children[sep_idx].clone(),
+        });
+        Ok(())
+    }
+
+    /// can_lend returns whether a node can give up an entry to a sibling without underflowing.
+    fn can_lend(&self, node: &Node) -> Result<bool, Error> {
+        match &node.node_type {
+            NodeType::Leaf(pairs) => Ok(pairs.len() > self.b - 1),
+            NodeType::Internal(_, keys) => Ok(keys.len() > self.b - 1),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    // merges two *sibling* nodes given the key separating them in their parent, it assumes the following:
+    // 1. the two nodes are of the same type.
+    // 2. the two nodes do not accumulate to an overflow,
+    // i.e. |first.keys| + |second.keys| <= [2*(b-1) for keys or 2*b for offsets].
+    #[allow(clippy::useless_conversion)]
+    fn merge(&self, first: Node, second: Node, separator: Key) -> Result<Node, Error> {
+        match first.node_type {
+            NodeType::Leaf(first_pairs) => {
+                if let NodeType::Leaf(second_pairs) = second.node_type {
+                    let merged_pairs: Vec<KeyValuePair> = first_pairs
+                        .into_iter()
+                        .chain(second_pairs.into_iter())
+                        .collect();
+                    let node_type = NodeType::Leaf(merged_pairs);
+                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
+                } else {
+                    Err(Error::UnexpectedError)
+                }
+            }
+            NodeType::Internal(first_offsets, first_keys) => {
+                if let NodeType::Internal(second_offsets, second_keys) = second.node_type {
+                    // The separator moves down from the parent in between the two key sets.
+                    let merged_keys: Vec<Key> = first_keys
+                        .into_iter()
+                        .chain(std::iter::once(separator))
+                        .chain(second_keys)
+                        .collect();
+                    let merged_offsets: Vec<Offset> = first_offsets
+                        .into_iter()
+                        .chain(second_offsets.into_iter())
+                        .collect();
+                    let node_type = NodeType::Internal(merged_offsets, merged_keys);
+                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
+                } else {
+                    Err(Error::UnexpectedError)
+                }
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its offset.
+    fn print_sub_tree(&mut self, prefix: String, offset: Offset) -> Result<(), Error> {
+        println!("{}Node at offset: {}", prefix, offset.0);
+        let curr_prefix = format!("{}|->", prefix);
+        let page = self.pager.get_page(&offset)?;
+        let node = Node::try_from(page)?;
+        match node.node_type {
+            NodeType::Internal(children, keys) => {
+                println!("{}Keys: {:?}", curr_prefix, keys);
+                println!("{}Children: {:?}", curr_prefix, children);
+                let child_prefix = format!("{}   |  ", prefix);
+                for child_offset in children {
+                    self.print_sub_tree(child_prefix.clone(), child_offset)?;
+                }
+                Ok(())
+            }
+            NodeType::Leaf(pairs) => {
+                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
+                Ok(())
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// dot_sub_tree appends the Graphviz nodes and edges of the subtree rooted at a node.
+    fn dot_sub_tree(&self, offset: &Offset, dot: &mut String) -> Result<(), Error> {
+        match self.pager.get_node(offset)?.node_type {
+            NodeType::Internal(children, keys) => {
+                // A port per child, in between the separators.
+                let mut fields = vec!["<c0>".to_string()];
+                for (idx, Key(key)) in keys.iter().enumerate() {
+                    fields.push(dot_escape(key));
+                    fields.push(format!("<c{}>", idx + 1));
+                }
+                dot.push_str(&format!(
+                    "  n{} [label=\"{}\"];\n",
+                    offset.0,
+                    fields.join("|")
+                ));
+                for (idx, child_offset) in children.iter().enumerate() {
+                    dot.push_str(&format!(
+                        "  n{}:c{} -> n{};\n",
+                        offset.0, idx, child_offset.0
+                    ));
+                    self.dot_sub_tree(child_offset, dot)?;
+                }
+                Ok(())
+            }
+            NodeType::Leaf(pairs) => {
+                let fields: Vec<String> = pairs
+                    .iter()
+                    .map(|kv| format!("{}: {}", dot_escape(&kv.key), dot_escape(&kv.value)))
+                    .collect();
+                dot.push_str(&format!(
+                    "  n{} [label=\"{{{}}}\", style=filled, fillcolor=lightgrey];\n",
+                    offset.0,
+                    fields.join("|")
+                ));
+                Ok(())
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// to_dot returns a Graphviz description of the tree: a record per node, holding the keys
+    /// of internal nodes in between the ports of their children and the pairs of leaves,
+    /// with an edge per child. Nodes are named after their offsets.
+    pub fn to_dot(&mut self) -> Result<String, Error> {
+        let root_offset = self.committed_root()?;
+        let mut dot = "digraph btree {\n  node [shape=record];\n".to_string();
+        self.dot_sub_tree(&root_offset, &mut dot)?;
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// debug_print renders the tree level by level from the root down, a line per level listing
+    /// its nodes from left to right by offset: internal nodes with their separator keys in angle
+    /// brackets, leaves with their keys in square brackets.
+    pub fn debug_print(&mut self, out: &mut impl Write) -> Result<(), Error> {
+        let mut level = vec![self.committed_root()?];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut line = Vec::new();
+            let mut next_level = Vec::new();
+            for offset in level.iter() {
+                match self.pager.get_node(offset)?.node_type {
+                    NodeType::Internal(children, keys) => {
+                        let keys: Vec<&str> = keys.iter().map(|Key(key)| key.as_str()).collect();
+                        line.push(format!("@{} <{}>", offset.0, keys.join(", ")));
+                        next_level.extend(children);
+                    }
+                    NodeType::Leaf(pairs) => {
+                        let keys: Vec<&str> = pairs.iter().map(|kv| kv.key.as_str()).collect();
+                        line.push(format!("@{} [{}]", offset.0, keys.join(", ")));
+                    }
+                    NodeType::Unexpected => return Err(Error::UnexpectedError),
+                }
+            }
+            writeln!(out, "level {}: {}", depth, line.join(" "))?;
+            level = next_level;
+            depth += 1;
+        }
+        Ok(())
+    }
+
+    /// print is a helper for recursively printing the tree.
+    pub fn print(&mut self) -> Result<(), Error> {
+        println!();
+        let root_offset = self.committed_root()?;
+        self.print_sub_tree("".to_string(), root_offset)
+    }
+}
+
+/// dot_escape escapes the characters with a meaning in the labels of Graphviz records.
+fn dot_escape(raw: &str) -> String {
+    raw.chars()
+        .flat_map(|c| match c {
+            '|' | '{' | '}' | '<' | '>' | '"' | '\\' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+/// build_from_sorted appends a tree holding the given sorted and unique pairs to the pager
+/// and returns the offset of its root. The throttle is invoked after every page written,
+/// failing it interrupts the build.
+pub(crate) fn build_from_sorted<const N: usize>(
+    pager: &mut Pager<N>,
+    b: usize,
+    pairs: Vec<KeyValuePair>,
+    throttle: &mut dyn FnMut() -> Result<(), Error>,
+) -> Result<Offset, Error> {
+    if pairs.is_empty() {
+        let root = Node::new(NodeType::Leaf(vec![]), true, None);
+        return pager.write_page(Page::encode(&root)?);
+    }
+    // Lay out the tree level by level from the leaves up,
+    // the tree is appended to the file as a single contiguous run of pages
+    // which allows us to know the offset of every node (and its parent) in advance.
+    let mut levels = vec![even_chunks(pairs.len(), 2 * b - 1)];
+    while levels[levels.len() - 1].len() > 1 {
+        let num_nodes = levels[levels.len() - 1].len();
+        levels.push(even_chunks(num_nodes, 2 * b));
+    }
+    pager.reserve(levels.iter().map(Vec::len).sum())?;
+    let mut level_offsets = Vec::with_capacity(levels.len());
+    let mut next_offset = pager.cursor().0;
+    for level in &levels {
+        level_offsets.push(next_offset);
+        next_offset = level
+            .len()
+            .checked_mul(N)
+            .and_then(|len| next_offset.checked_add(len))
+            .ok_or(Error::OffsetOverflow {
+                offset: next_offset,
+            })?;
+    }
+    let parent_offsets = |level: usize| -> Vec<Option<Offset>> {
+        match levels.get(level + 1) {
+            Some(parents) => parents
+                .iter()
+                .enumerate()
+                .flat_map(|(idx, num_children)| {
+                    let parent = Offset(level_offsets[level + 1] + idx * N);
+                    vec![Some(parent); *num_children]
+                })
+                .collect(),
+            None => vec![None],
+        }
+    };
+
+    let mut remaining = pairs.into_iter();
+    let leaves: Vec<Node> = levels[0]
+        .iter()
+        .zip(parent_offsets(0))
+        .map(|(num_pairs, parent_offset)| {
+            let pairs = remaining.by_ref().take(*num_pairs).collect();
+            Node::new(
+                NodeType::Leaf(pairs),
+                parent_offset.is_none(),
+                parent_offset,
+            )
+        })
+        .collect();
+    // The largest key of each subtree becomes its separator in the parent.
+    let mut max_keys: Vec<Key> = leaves
+        .iter()
+        .map(|leaf| match &leaf.node_type {
+            NodeType::Leaf(pairs) => Key(pairs[pairs.len() - 1].key.clone()),
+            _ => Key(String::new()),
+        })
+        .collect();
+
+    // Serialize disjoint ranges of leaves concurrently before appending them in order.
+    let num_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let leaves_per_thread = leaves.len().div_ceil(num_threads);
+    let pages = thread::scope(|scope| {
+        let handles: Vec<_> = leaves
+            .chunks(leaves_per_thread)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(Page::encode)
+                        .collect::<Result<Vec<Page<N>>, Error>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().map_err(|_| Error::ThreadPanicked)?)
+            .collect::<Result<Vec<Vec<Page<N>>>, Error>>()
+    })?;
+    for page in pages.into_iter().flatten() {
+        pager.write_page(page)?;
+        throttle()?;
+    }
+
+    for level in 1..levels.len() {
+        let mut first_child = 0;
+        let mut level_max_keys = Vec::with_capacity(levels[level].len());
+        for (num_children, parent_offset) in levels[level].iter().zip(parent_offsets(level)) {
+            let children = (first_child..first_child + num_children)
+                .map(|idx| Offset(level_offsets[level - 1] + idx * N))
+                .collect();
+            let keys = max_keys[first_child..first_child + num_children - 1].to_vec();
+            level_max_keys.push(max_keys[first_child + num_children - 1].clone());
+            let node = Node::new(
+                NodeType::Internal(children, keys),
+                parent_offset.is_none(),
+                parent_offset,
+            );
+            pager.write_page(Page::encode(&node)?)?;
+            throttle()?;
+            first_child += num_children;
+        }
+        max_keys = level_max_keys;
+    }
+    Ok(Offset(level_offsets[levels.len() - 1]))
+}
+
+/// valid_root validates a committed root: a page of the file decoding as a node marked as a
+/// root. Only the root page is read, see `BTree::check_invariants` for the whole tree.
+fn valid_root<const N: usize>(pager: &Pager<N>, root: &Offset) -> Result<bool, Error> {
+    if !is_node_page(root.0, pager.size()? as usize, N) {
+        return Ok(false);
+    }
+    Ok(pager
+        .get_page(root)?
+        .decode()
+        .is_ok_and(|node| node.is_root))
+}
+
+/// check_root checks a single tree from its root down, see `BTree::check_invariants`.
+fn check_root<const N: usize>(
+    pager: &Pager<N>,
+    b: usize,
+    tree: String,
+    root: &Offset,
+) -> Result<(), Error> {
+    match check_tree(pager, b, tree, root)?.into_iter().next() {
+        Some(problem) => Err(Error::InvariantViolation {
+            offset: problem.offset,
+            message: problem.message,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Finger is where the last search of a tree ended: the leaf it read, the root (and the
+/// generation of the tree file) it walked down from, and the bounds of the keys the walk routes
+/// to the leaf. A search of a key within the bounds starts from the leaf rather than the root.
+#[derive(Clone, Debug)]
+pub(crate) struct Finger {
+    root: Offset,
+    generation: u64,
+    leaf: Offset,
+    level: usize,
+    bounds: KeyBounds,
+}
+
+/// KeyBounds are the bounds of the keys routed to a node: larger than the lower bound and at
+/// most the upper one (a key equal to a separator belongs to the child left of it).
+#[derive(Clone, Debug, Default)]
+struct KeyBounds {
+    lower: Option<String>,
+    upper: Option<String>,
+}
+
+impl KeyBounds {
+    fn holds(&self, key: &str) -> bool {
+        self.lower.as_deref().is_none_or(|lower| key > lower)
+            && self.upper.as_deref().is_none_or(|upper| key <= upper)
+    }
+}
+
+/// search_sub_tree searches a sub tree rooted at a given offset for a key, walking down
+/// a node per level.
+pub(crate) fn search_sub_tree<const N: usize>(
+    pager: &Pager<N>,
+    offset: &Offset,
+    search: &str,
+) -> Result<KeyValuePair, Error> {
+    let (leaf, _, value) = find_leaf(pager, offset, 0, search, None)?;
+    pair_in_leaf(pager, leaf, value, search)
+}
+
+/// search_from_finger walks down the tree rooted at a given offset to the leaf a key belongs
+/// to like `find_leaf`, starting from the leaf of a finger should the key fall within its
+/// bounds, and leaves the finger at the leaf the walk ended at.
+fn search_from_finger<const N: usize>(
+    pager: &Pager<N>,
+    root: &Offset,
+    generation: u64,
+    search: &str,
+    finger: &mut Option<Finger>,
+) -> Result<(Page<N>, Option<Range<usize>>), Error> {
+    match finger {
+        Some(finger)
+            if finger.root == *root
+                && finger.generation == generation
+                && finger.bounds.holds(search) =>
+        {
+            let (leaf, _, value) = find_leaf(pager, &finger.leaf, finger.level, search, None)?;
+            Ok((leaf, value))
+        }
+        _ => {
+            let mut bounds = KeyBounds::default();
+            *finger = None;
+            let (leaf, level, value) = find_leaf(pager, root, 0, search, Some(&mut bounds))?;
+            *finger = Some(Finger {
+                root: root.clone(),
+                generation,
+                leaf: Offset(leaf.offset()),
+                level,
+                bounds,
+            });
+            Ok((leaf, value))
+        }
+    }
+}
+
+/// pair_in_leaf returns the pair of a key found in a leaf by `find_leaf`, recycling its page.
+fn pair_in_leaf<const N: usize>(
+    pager: &Pager<N>,
+    leaf: Page<N>,
+    value: Option<Range<usize>>,
+    search: &str,
+) -> Result<KeyValuePair, Error> {
+    let pair = match value {
+        Some(value) => leaf
+            .str_at(value)
+            .map(|value| KeyValuePair::new(search.to_string(), value.to_string())),
+        None => Err(Error::KeyNotFound {
+            key: search.to_string(),
+        }),
+    };
+    pager.recycle(leaf);
+    pair
+}
+
+/// find_leaf walks down from a node at a given offset and level to the leaf a key belongs to,
+/// a node per level, returning the page and the level of the leaf and the range of the value
+/// of the key in the page (if any, see `Page::find_in_leaf`) rather than decoding the leaf.
+/// Given bounds are narrowed down to the keys routed to the leaf on the way.
+fn find_leaf<const N: usize>(
+    pager: &Pager<N>,
+    offset: &Offset,
+    level: usize,
+    search: &str,
+    mut bounds: Option<&mut KeyBounds>,
+) -> Result<(Page<N>, usize, Option<Range<usize>>), Error> {
+    let (mut offset, mut level) = (offset.clone(), level);
+    loop {
+        let in_context =
+            |e: Error| e.in_context("searching", search, "read of a node", offset.0, level);
+        if let Some(route) = pager.cached_route(&offset, search, bounds.is_some()) {
+            if let Some(bounds) = bounds.as_deref_mut() {
+                if route.upper.is_some() {
+                    bounds.upper = route.upper;
+                }
+                if route.lower.is_some() {
+                    bounds.lower = route.lower;
+                }
+            }
+            offset = route.child;
+            level += 1;
+            continue;
+        }
+        let page = pager.get_page(&offset).map_err(in_context)?;
+        if page.leaf_len().map_err(in_context)?.is_some() {
+            let value = page.find_in_leaf(search).map_err(in_context)?;
+            return Ok((page, level, value));
+        }
+        let node = pager.decode(page).map_err(in_context)?;
+        match node.node_type {
+            NodeType::Internal(children, mut keys) => {
+                let idx = keys
+                    .binary_search_by(|key| key.0.as_str().cmp(search))
+                    .unwrap_or_else(|x| x);
+                if let Some(bounds) = bounds.as_deref_mut() {
+                    if idx < keys.len() {
+                        bounds.upper = Some(std::mem::take(&mut keys[idx].0));
+                    }
+                    if idx > 0 {
+                        bounds.lower = Some(std::mem::take(&mut keys[idx - 1].0));
+                    }
+                }
+                offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+                level += 1;
+            }
+            // Leaf pages are searched without being decoded, above.
+            NodeType::Leaf(_) | NodeType::Unexpected => return Err(Error::UnexpectedError),
+        }
+    }
+}
+
+/// has_duplicates returns whether an index maps more than one of the given pairs to the same index key.
+fn has_duplicates(index: &IndexDefinition, pairs: &[KeyValuePair]) -> bool {
+    let mut index_keys: Vec<String> = pairs.iter().flat_map(|kv| index.index_keys(kv)).collect();
+    let len = index_keys.len();
+    index_keys.sort();
+    index_keys.dedup();
+    index_keys.len() != len
+}
+
+/// find_first returns the first pair, in key order, of a tree rooted at a node
+/// in a certain offset that matches a predicate, visiting no more nodes than needed.
+fn find_first<const N: usize, P>(
+    pager: &Pager<N>,
+    offset: &Offset,
+    pred: &mut P,
+) -> Result<Option<KeyValuePair>, Error>
+where
+    P: FnMut(&str, &str) -> bool,
+{
+    match pager.get_node(offset)?.node_type {
+        NodeType::Internal(children, _) => {
+            for child_offset in children.iter() {
+                if let Some(kv) = find_first(pager, child_offset, pred)? {
+                    return Ok(Some(kv));
+                }
+            }
+            Ok(None)
+        }
+        NodeType::Leaf(pairs) => Ok(pairs.into_iter().find(|kv| pred(&kv.key, &kv.value))),
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+}
+
+/// scan_sub_tree recursively feeds the pairs of a sub tree rooted at a given offset
+/// which fall in the given range to a callback, in ascending order.
+pub(crate) fn scan_sub_tree<const N: usize, R, F>(
+    pager: &Pager<N>,
+    offset: &Offset,
+    range: &R,
+    callback: &mut F,
+) -> Result<(), Error>
+where
+    R: RangeBounds<String>,
+    F: FnMut(KeyValuePair),
+{
+    match pager.get_node(offset)?.node_type {
+        NodeType::Internal(children, keys) => {
+            for (idx, child_offset) in children.iter().enumerate() {
+                if child_overlaps(&keys, idx, range) {
+                    scan_sub_tree(pager, child_offset, range, callback)?;
+                }
+            }
+            Ok(())
+        }
+        NodeType::Leaf(pairs) => {
+            pairs
+                .into_iter()
+                .filter(|kv| range.contains(&kv.key))
+                .for_each(callback);
+            Ok(())
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+}
+
+/// child_overlaps checks whether the child at idx of an internal node with the given keys
+/// may hold keys in range; The child holds the keys in (keys[idx - 1], keys[idx]].
+fn child_overlaps<R: RangeBounds<String>>(keys: &[Key], idx: usize, range: &R) -> bool {
+    let above_start = match (keys.get(idx), range.start_bound()) {
+        (Some(Key(upper)), Bound::Included(start)) => upper >= start,
+        (Some(Key(upper)), Bound::Excluded(start)) => upper > start,
+        _ => true,
+    };
+    let below_end = match (idx.checked_sub(1).map(|i| &keys[i]), range.end_bound()) {
+        (Some(Key(lower)), Bound::Included(end)) | (Some(Key(lower)), Bound::Excluded(end)) => {
+            lower < end
+        }
+        _ => true,
+    };
+    above_start && below_end
+}
+
+/// even_chunks splits n items into the smallest number of chunks holding at most max items each,
+/// spreading the items as evenly as possible so no chunk is left underflowing.
+fn even_chunks(n: usize, max: usize) -> Vec<usize> {
+    let num_chunks = n.div_ceil(max);
+    (0..num_chunks)
+        .map(|idx| n / num_chunks + usize::from(idx < n % num_chunks))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn search_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/search")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/search/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+
+        let mut kv = btree.search("b".to_string())?;
+        assert_eq!(kv.key, "b");
+        assert_eq!(kv.value, "hello");
+
+        kv = btree.search("c".to_string())?;
+        assert_eq!(kv.key, "c");
+        assert_eq!(kv.value, "marhaba");
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/insert")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/insert/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+        btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
+        btree.insert(KeyValuePair::new("e".to_string(), "salam".to_string()))?;
+        btree.insert(KeyValuePair::new("f".to_string(), "hallo".to_string()))?;
+        btree.insert(KeyValuePair::new("g".to_string(), "Konnichiwa".to_string()))?;
+        btree.insert(KeyValuePair::new("h".to_string(), "Ni hao".to_string()))?;
+        btree.insert(KeyValuePair::new("i".to_string(), "Ciao".to_string()))?;
+
+        let mut kv = btree.search("a".to_string())?;
+        assert_eq!(kv.key, "a");
+        assert_eq!(kv.value, "shalom");
+
+        kv = btree.search("b".to_string())?;
+        assert_eq!(kv.key, "b");
+        assert_eq!(kv.value, "hello");
+
+        kv = btree.search("c".to_string())?;
+        assert_eq!(kv.key, "c");
+        assert_eq!(kv.value, "marhaba");
+
+        kv = btree.search("d".to_string())?;
+        assert_eq!(kv.key, "d");
+        assert_eq!(kv.value, "olah");
+
+        kv = btree.search("e".to_string())?;
+        assert_eq!(kv.key, "e");
+        assert_eq!(kv.value, "salam");
+
+        kv = btree.search("f".to_string())?;
+        assert_eq!(kv.key, "f");
+        assert_eq!(kv.value, "hallo");
+
+        kv = btree.search("g".to_string())?;
+        assert_eq!(kv.key, "g");
+        assert_eq!(kv.value, "Konnichiwa");
+
+        kv = btree.search("h".to_string())?;
+        assert_eq!(kv.key, "h");
+        assert_eq!(kv.value, "Ni hao");
+
+        kv = btree.search("i".to_string())?;
+        assert_eq!(kv.key, "i");
+        assert_eq!(kv.value, "Ciao");
+        Ok(())
+    }
+
+    #[test]
+    fn delete_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/delete")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/delete/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
+        btree.insert(KeyValuePair::new("e".to_string(), "salam".to_string()))?;
+        btree.insert(KeyValuePair::new("f".to_string(), "hallo".to_string()))?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+
+        let mut kv = btree.search("c".to_string())?;
+        assert_eq!(kv.key, "c");
+        assert_eq!(kv.value, "marhaba");
+
+        btree.delete(Key("c".to_string()))?;
+        let mut res = btree.search("c".to_string());
+        assert!(matches!(res, Err(Error::KeyNotFound { .. })));
+
+        kv = btree.search("d".to_string())?;
+        assert_eq!(kv.key, "d");
+        assert_eq!(kv.value, "olah");
+
+        btree.delete(Key("d".to_string()))?;
+        res = btree.search("d".to_string());
+        assert!(matches!(res, Err(Error::KeyNotFound { .. })));
+
+        btree.delete(Key("e".to_string()))?;
+        res = btree.search("e".to_string());
+        assert!(matches!(res, Err(Error::KeyNotFound { .. })));
+
+        btree.delete(Key("f".to_string()))?;
+        res = btree.search("f".to_string());
+        assert!(matches!(res, Err(Error::KeyNotFound { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/bulk_load")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/bulk_load/db"))
+            .b_parameter(2)
+            .build()?;
+        let pairs = (0..100)
+            .rev()
+            .map(|i| KeyValuePair::new(format!("{:03}", i), format!("v{}", i)))
+            .collect();
+        btree.bulk_load(pairs)?;
+
+        for i in 0..100 {
+            let kv = btree.search(format!("{:03}", i))?;
+            assert_eq!(kv.value, format!("v{}", i));
+        }
+
+        // The loaded tree is a regular tree which keeps accepting writes.
+        btree.insert(KeyValuePair::new("050a".to_string(), "between".to_string()))?;
+        let kv = btree.search("050a".to_string())?;
+        assert_eq!(kv.value, "between");
+        let kv = btree.search("051".to_string())?;
+        assert_eq!(kv.value, "v51");
+
+        let res = btree.bulk_load(vec![KeyValuePair::new("a".to_string(), "b".to_string())]);
+        assert!(matches!(res, Err(Error::TreeNotEmpty)));
+        Ok(())
+    }
+
+    #[test]
+    fn range_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/range")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/range/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in (0..50).rev() {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+
+        let pairs = btree.range("10".to_string().."20".to_string())?;
+        let keys: Vec<String> = pairs.into_iter().map(|kv| kv.key).collect();
+        let expected: Vec<String> = (10..20).map(|i| format!("{:02}", i)).collect();
+        assert_eq!(keys, expected);
+
+        let pairs = btree.range("45".to_string()..)?;
+        assert_eq!(pairs.len(), 5);
+        assert_eq!(btree.range(..)?.len(), 50);
+        Ok(())
+    }
+
+    #[test]
+    fn par_range_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+        use std::sync::Mutex;
+
+        std::fs::create_dir_all("/tmp/par_range")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/par_range/db"))
+            .b_parameter(2)
+            .build()?;
+        let pairs = (0..500)
+            .map(|i| KeyValuePair::new(format!("{:03}", i), format!("v{}", i)))
+            .collect();
+        btree.bulk_load(pairs)?;
+
+        let found = Mutex::new(Vec::new());
+        btree.par_range("100".to_string()..="399".to_string(), |kv| {
+            found.lock().unwrap().push(kv.key)
+        })?;
+        let mut found = found.into_inner().unwrap();
+        found.sort();
+        let expected: Vec<String> = (100..400).map(|i| format!("{:03}", i)).collect();
+        assert_eq!(found, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn compact_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/compact")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/compact/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+        btree.delete(Key("00".to_string()))?;
+        let before = std::fs::metadata("/tmp/compact/db")?.len();
+        btree.compact()?;
+        let after = std::fs::metadata("/tmp/compact/db")?.len();
+        assert!(after < before);
+
+        assert!(matches!(
+            btree.search("00".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        for i in 1..30 {
+            let kv = btree.search(format!("{:02}", i))?;
+            assert_eq!(kv.value, format!("v{}", i));
+        }
+        btree.insert(KeyValuePair::new("30".to_string(), "v30".to_string()))?;
+        assert_eq!(btree.search("30".to_string())?.value, "v30");
+        Ok(())
+    }
+
+    #[test]
+    fn single_writer_multiple_readers_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/single_writer")?;
+        let builder = BTreeBuilder::new()
+            .path(Path::new("/tmp/single_writer/db"))
+            .b_parameter(2);
+        let mut writer = builder.build()?;
+        writer.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        assert!(matches!(builder.build(), Err(Error::DatabaseLocked)));
+
+        let reader_builder = BTreeBuilder::new()
+            .path(Path::new("/tmp/single_writer/db"))
+            .read_only(true);
+        let mut reader = reader_builder.build()?;
+        let mut other_reader = reader_builder.build()?;
+        assert_eq!(reader.search("a".to_string())?.value, "shalom");
+        let res = reader.insert(KeyValuePair::new("b".to_string(), "hello".to_string()));
+        assert!(matches!(res, Err(Error::ReadOnly)));
+
+        // Readers observe writes once they are committed.
+        for i in 0..10 {
+            writer.insert(KeyValuePair::new(format!("k{}", i), format!("v{}", i)))?;
+        }
+        assert_eq!(reader.search("k9".to_string())?.value, "v9");
+        assert_eq!(other_reader.range(..)?.len(), 11);
+
+        // Dropping the writer releases the lock.
+        drop(writer);
+        builder.build()?;
+        Ok(())
+    }
+
+    #[test]
+    fn lost_lease_stops_writes() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/lost_lease")?;
+        let mut writer = BTreeBuilder::new()
+            .path(Path::new("/tmp/lost_lease/db"))
+            .b_parameter(2)
+            .build()?;
+        writer.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        writer
+            .lock
+            .as_mut()
+            .ok_or(Error::UnexpectedError)?
+            .fail_renewal();
+        let res = writer.put(KeyValuePair::new("b".to_string(), "hello".to_string()));
+        assert!(matches!(res, Err(Error::DatabaseLocked)));
+        let res = writer
+            .atomically(|tree| tree.put(KeyValuePair::new("b".to_string(), "hello".to_string())));
+        assert!(matches!(res, Err(Error::DatabaseLocked)));
+        // Reads go on.
+        assert_eq!(writer.search("a".to_string())?.value, "shalom");
+        assert!(matches!(
+            writer.search("b".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/refresh")?;
+        let mut writer = BTreeBuilder::new()
+            .path(Path::new("/tmp/refresh/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/refresh/db"))
+            .read_only(true)
+            .build()?;
+        for i in 0..20 {
+            writer.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        writer.generate_id("users")?;
+        assert!(reader.sequences.is_empty());
+        assert_eq!(reader.refresh()?, 21);
+        assert_eq!(reader.sequences, vec![("users".to_string(), 1)]);
+
+        // A compaction replaces the file the reader opened.
+        writer.compact()?;
+        writer.insert(KeyValuePair::new("20".to_string(), "v".to_string()))?;
+        reader.refresh()?;
+        assert_eq!(reader.range(..)?.len(), 21);
+        assert_eq!(reader.search("20".to_string())?.value, "v");
+        Ok(())
+    }
+
+    #[test]
+    fn reads_follow_a_compaction() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::maintenance::compaction_marker;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/reads_follow_a_compaction")?;
+        let path = Path::new("/tmp/reads_follow_a_compaction/db");
+        let mut writer = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..20 {
+            writer.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let mut reader = BTreeBuilder::new().path(path).read_only(true).build()?;
+        assert_eq!(reader.range(..)?.len(), 20);
+
+        // Without a refresh the reader still follows the writer onto the compacted file.
+        writer.compact()?;
+        writer.insert(KeyValuePair::new("20".to_string(), "w".to_string()))?;
+        assert_eq!(reader.search("20".to_string())?.value, "w");
+        assert_eq!(reader.range(..)?.len(), 21);
+        writer.compact()?;
+        assert_eq!(reader.read_view()?.range(..)?.len(), 21);
+
+        // A swap under way holds the reads back until it is done.
+        std::fs::write(compaction_marker(path), b"")?;
+        assert!(matches!(
+            reader.search("20".to_string()),
+            Err(Error::Timeout)
+        ));
+        std::fs::remove_file(compaction_marker(path))?;
+        assert_eq!(reader.search("20".to_string())?.value, "w");
+        Ok(())
+    }
+
+    #[test]
+    fn open_existing_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/open_existing")?;
+        let builder = BTreeBuilder::new().path(Path::new("/tmp/open_existing/db"));
+        let mut btree = builder.b_parameter(2).build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree
+            .open_bucket("users")?
+            .insert(KeyValuePair::new("a".to_string(), "b".to_string()))?;
+        btree.generate_id("users")?;
+        drop(btree);
+
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/open_existing/db"))
+            .open_existing(true)
+            .build()?;
+        assert_eq!(btree.range(..)?.len(), 20);
+        btree.delete(Key("03".to_string()))?;
+        btree.insert(KeyValuePair::new("20".to_string(), "v".to_string()))?;
+        assert_eq!(btree.generate_id("users")?, 2);
+        assert_eq!(
+            btree.open_bucket("users")?.search("a".to_string())?.value,
+            "b"
+        );
+        // LSNs carry on from the last commit.
+        assert_eq!(btree.refresh()?, 23);
+        drop(btree);
+
+        let mut reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/open_existing/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(reader.range(..)?.len(), 20);
+        assert!(matches!(
+            reader.search("03".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn build_validation_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, MAX_B_PARAMETER};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/build_validation")?;
+        let path = Path::new("/tmp/build_validation/db");
+        let cases = [
+            (BTreeBuilder::new().path(path), Error::InvalidBParameter(0)),
+            (
+                BTreeBuilder::new().path(path).b_parameter(1),
+                Error::InvalidBParameter(1),
+            ),
+            (
+                BTreeBuilder::new()
+                    .path(path)
+                    .b_parameter(MAX_B_PARAMETER + 1),
+                Error::InvalidBParameter(101),
+            ),
+            (
+                BTreeBuilder::new()
+                    .path(path)
+                    .b_parameter(2)
+                    .page_size(1000),
+                Error::InvalidPageSize(1000),
+            ),
+            (
+                BTreeBuilder::new()
+                    .path(path)
+                    .b_parameter(2)
+                    .page_size(8192),
+                Error::ConflictingOptions("page_size and the page size of the tree built"),
+            ),
+            (BTreeBuilder::new().b_parameter(2), Error::InvalidPath),
+            (
+                BTreeBuilder::new()
+                    .path(Path::new("/tmp/build_validation/missing"))
+                    .open_existing(true),
+                Error::InvalidPath,
+            ),
+            (
+                BTreeBuilder::new()
+                    .path(Path::new("/tmp/build_validation/no/such/directory/db"))
+                    .b_parameter(2),
+                Error::PathNotWritable,
+            ),
+            (
+                BTreeBuilder::new()
+                    .path(path)
+                    .read_only(true)
+                    .open_existing(true),
+                Error::ConflictingOptions("read_only and open_existing"),
+            ),
+        ];
+        for (builder, expected) in cases {
+            let res = builder.build();
+            assert_eq!(format!("{:?}", res.err()), format!("{:?}", Some(expected)));
+        }
+        BTreeBuilder::new()
+            .path(path)
+            .b_parameter(100)
+            .page_size(4096)
+            .build()?;
+        Ok(())
+    }
+
+    #[test]
+    fn page_sizes_work() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, SizedBTree};
+        use crate::node_type::KeyValuePair;
+        use crate::verify::verify;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/page_sizes")?;
+        std::fs::create_dir_all("/tmp/page_sizes_migrated")?;
+        let path = Path::new("/tmp/page_sizes/db");
+        let builder = BTreeBuilder::new().path(path).b_parameter(200);
+        let mut btree = match builder.page_size(8192).build_any()? {
+            SizedBTree::Pages8192(btree) => btree,
+            other => panic!("a tree of {} byte pages", other.page_size()),
+        };
+        for i in 0..2000 {
+            btree.insert(KeyValuePair::new(format!("{:04}", i), "v".to_string()))?;
+        }
+        btree.compact()?;
+        btree.check_invariants()?;
+        assert_eq!(std::fs::metadata(path)?.len() % 8192, 0);
+        assert_eq!(btree.stats()?.height, 2);
+        drop(btree);
+        assert!(verify(path)?.is_ok());
+
+        // A file is opened as of the page size of its header.
+        let reopened = || BTreeBuilder::new().path(path).open_existing(true);
+        match reopened().build_any()? {
+            SizedBTree::Pages8192(mut btree) => assert_eq!(btree.range(..)?.len(), 2000),
+            other => panic!("a tree of {} byte pages", other.page_size()),
+        }
+        let res = reopened().build();
+        assert!(matches!(
+            res,
+            Err(Error::PageSizeMismatch {
+                expected: 4096,
+                found: 8192
+            })
+        ));
+        assert!(matches!(
+            reopened().page_size(16384).build_any(),
+            Err(Error::PageSizeMismatch {
+                expected: 16384,
+                found: 8192
+            })
+        ));
+        let mut btree = reopened().build_sized::<8192>()?;
+        let mut migrated =
+            btree.migrate_to_sized::<4096>(Path::new("/tmp/page_sizes_migrated/db"), 100)?;
+        assert_eq!(migrated.range(..)?.len(), 2000);
+        drop(btree);
+
+        // The widest nodes grow along with the pages.
+        let res = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(201)
+            .build_sized::<8192>();
+        assert!(matches!(res, Err(Error::InvalidBParameter(201))));
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+
+        let path = Path::new("/tmp/in_memory/db");
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .in_memory(true)
+            .build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.delete(Key("07".to_string()))?;
+        btree
+            .open_bucket("users")?
+            .insert(KeyValuePair::new("a".to_string(), "b".to_string()))?;
+        assert_eq!(btree.range(..)?.len(), 49);
+        assert_eq!(btree.stats()?.entries, 49);
+        assert!(btree.compact().is_err());
+        // Nothing was written to the file system.
+        assert!(!path.exists());
+
+        let shared = SharedBTree::new(btree)?;
+        shared.put(KeyValuePair::new("07".to_string(), "w".to_string()))?;
+        assert_eq!(shared.reader().search("07".to_string())?.value, "w");
+        Ok(())
+    }
+
+    #[test]
+    fn to_dot_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/to_dot")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/to_dot/db"))
+            .b_parameter(2)
+            .build()?;
+        for key in ["a", "b", "c", "d|"] {
+            btree.insert(KeyValuePair::new(key.to_string(), "v".to_string()))?;
+        }
+        let root = btree.root_offset()?.0;
+        let dot = btree.to_dot()?;
+        assert!(dot.starts_with("digraph btree {"));
+        assert!(dot.contains(&format!("n{} [label=\"<c0>|b|<c1>\"];", root)));
+        assert!(dot.contains(&format!("n{}:c1 -> n", root)));
+        assert!(dot.contains("{c: v|d\\|: v}"));
+        Ok(())
+    }
+
+    #[test]
+    fn debug_print_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/debug_print")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/debug_print/db"))
+            .b_parameter(2)
+            .build()?;
+        for key in ["a", "b", "c", "d"] {
+            btree.insert(KeyValuePair::new(key.to_string(), "v".to_string()))?;
+        }
+        let mut out = Vec::new();
+        btree.debug_print(&mut out)?;
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            format!("level 0: @{} <b>", btree.root_offset()?.0)
+        );
+        assert!(lines[1].starts_with("level 1: @"));
+        assert!(lines[1].ends_with(" [a, b] @28672 [c, d]"));
+        Ok(())
+    }
+
+    #[test]
+    fn json_export_and_import_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::io::Cursor;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/json/source")?;
+        std::fs::create_dir_all("/tmp/json/target")?;
+        let mut source = BTreeBuilder::new()
+            .path(Path::new("/tmp/json/source/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            source.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        source.put(KeyValuePair::new("q\"t\\é".to_string(), "a\nb".to_string()))?;
+        let mut json = Vec::new();
+        assert_eq!(source.export_json(&mut json)?, 21);
+        let text = String::from_utf8(json.clone()).map_err(|_| Error::UTF8Error)?;
+        assert_eq!(text.lines().next(), Some(r#"{"key":"00","value":"v"}"#));
+        assert!(text.contains(r#"{"key":"q\"t\\é","value":"a\nb"}"#));
+
+        let mut target = BTreeBuilder::new()
+            .path(Path::new("/tmp/json/target/db"))
+            .b_parameter(3)
+            .build()?;
+        // Sorted input is bulk loaded.
+        assert_eq!(target.import_json(Cursor::new(json))?, 21);
+        assert!(target.content_eq(&mut source)?);
+
+        let unsorted = "{ \"value\": \"x\", \"key\": \"05\" }\n\n{\"key\":\"\\u00e9\\ud83d\\ude00\",\"value\":\"y\"}\n";
+        assert_eq!(target.import_json(Cursor::new(unsorted))?, 2);
+        assert_eq!(target.search("05".to_string())?.value, "x");
+        assert_eq!(target.search("é😀".to_string())?.value, "y");
+        assert!(target
+            .import_json(Cursor::new("{\"key\":\"a\"}\n"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dump_export_and_import_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::io::Cursor;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/dump/source")?;
+        std::fs::create_dir_all("/tmp/dump/target")?;
+        let mut source = BTreeBuilder::new()
+            .path(Path::new("/tmp/dump/source/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            source.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        source.put(KeyValuePair::new("é".to_string(), "".to_string()))?;
+        let mut dump = Vec::new();
+        assert_eq!(source.export_dump(&mut dump)?, 21);
+        assert_eq!(&dump[..11], b"\0\0\0\x0200\0\0\0\x01v");
+
+        let mut target = BTreeBuilder::new()
+            .path(Path::new("/tmp/dump/target/db"))
+            .b_parameter(3)
+            .build()?;
+        assert_eq!(target.import_dump(Cursor::new(&dump))?, 21);
+        assert!(target.content_eq(&mut source)?);
+
+        let unsorted = b"\0\0\0\x0205\0\0\0\x01x\0\0\0\x0204\0\0\0\x01y";
+        assert_eq!(target.import_dump(Cursor::new(unsorted))?, 2);
+        assert_eq!(target.search("05".to_string())?.value, "x");
+        assert_eq!(target.search("04".to_string())?.value, "y");
+        // A dump cut in the middle of a record or holding bytes other than UTF-8 is refused.
+        assert!(target.import_dump(Cursor::new(&dump[..14])).is_err());
+        assert!(matches!(
+            target.import_dump(Cursor::new(b"\0\0\0\x01\xff\0\0\0\0")),
+            Err(Error::UTF8Error)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_to_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/migrate_to/source")?;
+        std::fs::create_dir_all("/tmp/migrate_to/target")?;
+        let mut source = BTreeBuilder::new()
+            .path(Path::new("/tmp/migrate_to/source/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..30 {
+            source.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        source
+            .open_bucket("users")?
+            .insert(KeyValuePair::new("ada".to_string(), "1".to_string()))?;
+        source.generate_id("ids")?;
+
+        let mut target = source.migrate_to(Path::new("/tmp/migrate_to/target/db"), 5)?;
+        assert!(target.content_eq(&mut source)?);
+        assert_eq!(target.stats()?.height, 2);
+        assert_eq!(
+            target
+                .open_bucket("users")?
+                .search("ada".to_string())?
+                .value,
+            "1"
+        );
+        assert_eq!(target.generate_id("ids")?, 2);
+        drop(target);
+
+        let mut reopened = BTreeBuilder::new()
+            .path(Path::new("/tmp/migrate_to/target/db"))
+            .open_existing(true)
+            .build()?;
+        assert!(reopened.content_eq(&mut source)?);
+        assert_eq!(reopened.open_bucket("users")?.range(..)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn btree_map_conversion_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use std::collections::BTreeMap;
+        use std::convert::TryFrom;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/btree_map")?;
+        let map: BTreeMap<String, String> = (0..30)
+            .map(|i| (format!("{:02}", i), format!("v{}", i)))
+            .collect();
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_map/db"))
+            .b_parameter(2)
+            .build_from_map(map.clone())?;
+        assert_eq!(btree.search("17".to_string())?.value, "v17");
+        assert_eq!(BTreeMap::try_from(&mut btree)?, map);
+        Ok(())
+    }
+
+    #[test]
+    fn put_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/put")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/put/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "old".to_string()))?;
+        }
+        let replaced = btree.put(KeyValuePair::new("07".to_string(), "new".to_string()))?;
+        assert_eq!(
+            replaced,
+            Some(KeyValuePair::new("07".to_string(), "old".to_string()))
+        );
+        assert_eq!(btree.search("07".to_string())?.value, "new");
+        assert_eq!(btree.range(..)?.len(), 20);
+
+        let replaced = btree.put(KeyValuePair::new("20".to_string(), "new".to_string()))?;
+        assert_eq!(replaced, None);
+        assert_eq!(btree.range(..)?.len(), 21);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_refuses_duplicate_keys() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/insert_duplicate")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/insert_duplicate/db"))
+            .b_parameter(2)
+            .build()?;
+        let kv = |key: &str, value: &str| KeyValuePair::new(key.to_string(), value.to_string());
+        // A root leaf is edited in place, the leaves of a taller tree are split on the way down.
+        btree.insert(kv("a", "1"))?;
+        assert!(matches!(
+            btree.insert(kv("a", "2")),
+            Err(Error::DuplicateKey { key }) if key == "a"
+        ));
+        for i in 0..20 {
+            btree.insert(kv(&format!("{:02}", i), "v"))?;
+        }
+        let root = btree.wal.get_root()?;
+        for i in 0..20 {
+            assert!(matches!(
+                btree.insert(kv(&format!("{:02}", i), "w")),
+                Err(Error::DuplicateKey { .. })
+            ));
+        }
+        // Nothing was written, and the tree takes the next write.
+        assert_eq!(btree.wal.get_root()?, root);
+        btree.insert(kv("b", "3"))?;
+        btree.check_invariants()?;
+        assert_eq!(btree.search("a".to_string())?.value, "1");
+        assert_eq!(btree.search("07".to_string())?.value, "v");
+        assert_eq!(btree.range(..)?.len(), 22);
+        Ok(())
+    }
+
+    #[test]
+    fn pop_first_if_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/pop_first_if")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/pop_first_if/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..50 {
+            let state = if i % 3 == 0 { "done" } else { "todo" };
+            btree.insert(KeyValuePair::new(format!("{:02}", i), state.to_string()))?;
+        }
+        let mut popped = Vec::new();
+        while let Some(kv) = btree.pop_first_if(|_, v| v == "todo")? {
+            popped.push(kv.key);
+        }
+        let expected: Vec<String> = (0..50)
+            .filter(|i| i % 3 != 0)
+            .map(|i| format!("{:02}", i))
+            .collect();
+        assert_eq!(popped, expected);
+        assert_eq!(btree.range(..)?.len(), 17);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_rebalances_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/delete_rebalances")?;
+        for b in 2..5 {
+            let mut btree = BTreeBuilder::new()
+                .path(Path::new("/tmp/delete_rebalances/db"))
+                .b_parameter(b)
+                .build()?;
+            for i in 0..200 {
+                btree.insert(KeyValuePair::new(format!("{:03}", i), "v".to_string()))?;
             }
-            NodeType::Unexpected => return Err(Error::UnexpectedError),
+            // Delete in a scrambled order to exercise borrowing and merging on both sides.
+            let mut keys: Vec<usize> = (0..200).collect();
+            keys.sort_by_key(|k| (k * 7919) % 211);
+            for (deleted, k) in keys.iter().enumerate() {
+                btree.delete(Key(format!("{:03}", k)))?;
+                assert_eq!(btree.range(..)?.len(), 199 - deleted);
+            }
+            for k in keys {
+                assert!(matches!(
+                    btree.search(format!("{:03}", k)),
+                    Err(Error::KeyNotFound { .. })
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wal_backpressure_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::wal::RECORD_SIZE;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/wal_backpressure")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/wal_backpressure/db"))
+            .b_parameter(2)
+            .wal_limit(4 * RECORD_SIZE as u64)
+            .non_blocking(true)
+            .build()?;
+        for i in 0..3 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+        }
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("3".to_string(), "v".to_string())),
+            Err(Error::Busy)
+        ));
+        btree.checkpoint()?;
+        btree.insert(KeyValuePair::new("3".to_string(), "v".to_string()))?;
+        assert_eq!(btree.range(..)?.len(), 4);
+        drop(btree);
+
+        // A blocking writer checkpoints the log itself.
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/wal_backpressure/db"))
+            .b_parameter(2)
+            .wal_limit(4 * RECORD_SIZE as u64)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        assert_eq!(btree.range(..)?.len(), 20);
+        assert!(std::fs::metadata("/tmp/wal_backpressure/wal")?.len() <= 4 * RECORD_SIZE as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn generate_id_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/generate_id")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/generate_id/db"))
+            .b_parameter(2)
+            .build()?;
+        assert_eq!(btree.generate_id("users")?, 1);
+        assert_eq!(btree.generate_id("users")?, 2);
+        assert_eq!(btree.generate_id("orders")?, 1);
+        assert_eq!(btree.generate_id("users")?, 3);
+        btree.compact()?;
+        assert_eq!(btree.generate_id("users")?, 4);
+
+        let reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/generate_id/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(
+            reader.sequences,
+            vec![("users".to_string(), 4), ("orders".to_string(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn secondary_indexes_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::index::IndexDefinition;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/secondary_indexes")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/secondary_indexes/db"))
+            .b_parameter(2)
+            .build()?;
+        // Values are "<city>:<n>", the index maps cities to the keys living there.
+        btree.insert(KeyValuePair::new("alice".to_string(), "tlv:1".to_string()))?;
+        btree.register_index(IndexDefinition::new("by_city", |_, v| {
+            v.split(':').next().map(|city| city.to_string())
+        }))?;
+        btree.insert(KeyValuePair::new("bob".to_string(), "nyc:2".to_string()))?;
+        btree.insert(KeyValuePair::new("carol".to_string(), "tlv:3".to_string()))?;
+
+        let keys = |pairs: Vec<KeyValuePair>| -> Vec<String> {
+            pairs.into_iter().map(|kv| kv.key).collect()
+        };
+        assert_eq!(
+            keys(btree.get_by_index("by_city", "tlv")?),
+            vec!["alice", "carol"]
+        );
+        assert_eq!(keys(btree.get_by_index("by_city", "nyc")?), vec!["bob"]);
+
+        btree.put(KeyValuePair::new("alice".to_string(), "nyc:1".to_string()))?;
+        btree.delete(Key("bob".to_string()))?;
+        assert_eq!(keys(btree.get_by_index("by_city", "tlv")?), vec!["carol"]);
+        assert_eq!(keys(btree.get_by_index("by_city", "nyc")?), vec!["alice"]);
+        assert!(btree.get_by_index("by_city", "sf")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unique_indexes_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::index::IndexDefinition;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/unique_indexes")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/unique_indexes/db"))
+            .b_parameter(2)
+            .build()?;
+        // Values are e-mail handles, no two users may share one.
+        btree.insert(KeyValuePair::new("alice".to_string(), "al".to_string()))?;
+        btree.insert(KeyValuePair::new("bob".to_string(), "al".to_string()))?;
+        let by_email = || IndexDefinition::new("by_email", |_, v| Some(v.to_string())).unique(true);
+        assert!(matches!(
+            btree.register_index(by_email()),
+            Err(Error::UniqueViolation)
+        ));
+        btree.put(KeyValuePair::new("bob".to_string(), "bo".to_string()))?;
+        btree.register_index(by_email())?;
+
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("carol".to_string(), "al".to_string())),
+            Err(Error::UniqueViolation)
+        ));
+        assert!(matches!(
+            btree.put(KeyValuePair::new("bob".to_string(), "al".to_string())),
+            Err(Error::UniqueViolation)
+        ));
+        // The failed writes left the tree untouched.
+        assert_eq!(btree.search("bob".to_string())?.value, "bo");
+        assert!(matches!(
+            btree.search("carol".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        // Rewriting the same pair or freeing up the index key is fine.
+        btree.put(KeyValuePair::new("alice".to_string(), "al".to_string()))?;
+        btree.delete(Key("alice".to_string()))?;
+        btree.insert(KeyValuePair::new("carol".to_string(), "al".to_string()))?;
+        assert_eq!(btree.get_by_index("by_email", "al")?[0].key, "carol");
+        Ok(())
+    }
+
+    #[test]
+    fn indexes_commit_with_their_pairs() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::index::IndexDefinition;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/indexes_commit")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/indexes_commit/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.register_index(IndexDefinition::new("by_city", |_, v| {
+            v.split(':').next().map(|city| city.to_string())
+        }))?;
+        let logged = btree.wal.records()?.len();
+        btree.insert(KeyValuePair::new("alice".to_string(), "tlv:1".to_string()))?;
+        assert_eq!(btree.wal.records()?.len(), logged + 1);
+        assert!(!Path::new("/tmp/indexes_commit/db.commit").exists());
+
+        // The index entry does not fit in the file, the pair is not written either.
+        let (cursor, max_size) = (btree.pager.cursor(), btree.pager.max_size());
+        btree.pager.set_max_size((cursor.0 + PAGE_SIZE) as u64);
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("bob".to_string(), "nyc:2".to_string())),
+            Err(Error::DatabaseFull { .. })
+        ));
+        assert_eq!(btree.pager.cursor(), cursor);
+        assert!(matches!(
+            btree.search("bob".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert!(btree.get_by_index("by_city", "nyc")?.is_empty());
+        btree.pager.set_max_size(max_size);
+        btree.insert(KeyValuePair::new("bob".to_string(), "nyc:2".to_string()))?;
+        assert_eq!(btree.get_by_index("by_city", "nyc")?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn inverted_indexes_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::index::{words, IndexDefinition};
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/inverted_indexes")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/inverted_indexes/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.register_index(IndexDefinition::tokenized("by_word", words))?;
+        btree.insert(KeyValuePair::new("d1".to_string(), "Red fox".to_string()))?;
+        btree.insert(KeyValuePair::new("d2".to_string(), "red hen".to_string()))?;
+        btree.insert(KeyValuePair::new("d3".to_string(), "fox, fox".to_string()))?;
+
+        let keys = |pairs: Vec<KeyValuePair>| -> Vec<String> {
+            pairs.into_iter().map(|kv| kv.key).collect()
+        };
+        assert_eq!(
+            keys(btree.search_tokens("by_word", &["red"])?),
+            vec!["d1", "d2"]
+        );
+        assert_eq!(
+            keys(btree.search_tokens("by_word", &["fox"])?),
+            vec!["d1", "d3"]
+        );
+        assert_eq!(
+            keys(btree.search_tokens("by_word", &["red", "fox"])?),
+            vec!["d1"]
+        );
+
+        btree.put(KeyValuePair::new("d1".to_string(), "red".to_string()))?;
+        btree.delete(Key("d3".to_string()))?;
+        assert!(btree.search_tokens("by_word", &["fox"])?.is_empty());
+        assert_eq!(
+            keys(btree.search_tokens("by_word", &["red"])?),
+            vec!["d1", "d2"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn increment_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::watch::Event;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/increment")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/increment/db"))
+            .b_parameter(2)
+            .build()?;
+        assert_eq!(btree.increment("hits".to_string(), 5)?, 5);
+        assert_eq!(btree.increment("hits".to_string(), -7)?, -2);
+        assert_eq!(btree.search("hits".to_string())?.value, "-2");
+
+        btree.insert(KeyValuePair::new("name".to_string(), "bob".to_string()))?;
+        assert!(matches!(
+            btree.increment("name".to_string(), 1),
+            Err(Error::InvalidFormat(_))
+        ));
+
+        // Counters in full leaves and deeper down the tree, watched as they are updated.
+        let events = btree.watch_prefix("c".to_string());
+        for round in 1..=3 {
+            for i in 0..20 {
+                assert_eq!(btree.increment(format!("c{:02}", i), i)?, round * i);
+            }
+        }
+        assert_eq!(btree.search("c19".to_string())?.value, "57");
+        assert_eq!(
+            events.try_recv(),
+            Some(Event::Insert(KeyValuePair::new(
+                "c00".to_string(),
+                "0".to_string()
+            )))
+        );
+
+        // An increment the value no longer fits leaves the counter and the file as they were.
+        btree.put(KeyValuePair::new(
+            "c00".to_string(),
+            "9999999999".to_string(),
+        ))?;
+        let cursor = btree.pager.cursor();
+        assert!(matches!(
+            btree.increment("c00".to_string(), 1),
+            Err(Error::ValueOverflowError)
+        ));
+        assert_eq!(btree.pager.cursor(), cursor);
+        assert_eq!(btree.search("c00".to_string())?.value, "9999999999");
+        btree.check_invariants()?;
+        Ok(())
+    }
+
+    #[test]
+    fn stats_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/stats")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/stats/db"))
+            .b_parameter(2)
+            .build()?;
+        let stats = btree.stats()?;
+        assert_eq!((stats.height, stats.leaf_pages, stats.entries), (1, 1, 0));
+
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        let stats = btree.stats()?;
+        assert_eq!(stats.entries, 30);
+        assert!(stats.height > 2);
+        assert!(stats.internal_pages > 1 && stats.leaf_pages > stats.internal_pages);
+        assert!(stats.free_pages > 0);
+        assert_eq!(stats.overflow_pages, 0);
+        assert_eq!(stats.total_bytes as usize % PAGE_SIZE, 0);
+        let pages: usize = stats.fill_histogram.iter().sum();
+        assert_eq!(pages, stats.internal_pages + stats.leaf_pages);
+        // Splits leave the b-1 keys of 3 a page holds at most behind, a third of it.
+        assert!(stats.fill_histogram[3] > 0);
+        assert_eq!(stats.largest_key.as_deref(), Some("00"));
+        assert_eq!(stats.largest_value.map(|kv| kv.key), Some("10".to_string()));
+
+        // Compaction leaves the live pages of the tree only.
+        btree.compact()?;
+        let compacted = btree.stats()?;
+        assert_eq!(compacted.free_pages, 0);
+        assert_eq!(compacted.entries, 30);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/rename")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/rename/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("k{}", i), i.to_string()))?;
+        }
+        let commits = btree.replication_stream(0)?.count();
+        btree.rename("k3".to_string(), "moved".to_string())?;
+        // The pair is moved by a single commit.
+        assert_eq!(btree.replication_stream(0)?.count(), commits + 1);
+        assert_eq!(btree.search("moved".to_string())?.value, "3");
+        assert!(matches!(
+            btree.search("k3".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+
+        assert!(matches!(
+            btree.rename("k4".to_string(), "k5".to_string()),
+            Err(Error::DuplicateKey { .. })
+        ));
+        assert_eq!(btree.search("k4".to_string())?.value, "4");
+        assert!(matches!(
+            btree.rename("k3".to_string(), "k11".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert_eq!(btree.range(..)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_from_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, ConflictPolicy};
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/merge_from/other")?;
+        let mut other = BTreeBuilder::new()
+            .path(Path::new("/tmp/merge_from/other/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..10 {
+            other.insert(KeyValuePair::new(
+                format!("{:02}", i * 2),
+                "theirs".to_string(),
+            ))?;
+        }
+        other.sync()?;
+        let other_path = Path::new("/tmp/merge_from/other/db");
+
+        let open = |path: &'static str| -> Result<_, Error> {
+            let path = Path::new(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+            for i in 0..10 {
+                btree.insert(KeyValuePair::new(
+                    format!("{:02}", i * 3),
+                    "ours".to_string(),
+                ))?;
+            }
+            Ok(btree)
+        };
+        let value = |btree: &mut crate::btree::BTree, key: &str| -> Result<String, Error> {
+            Ok(btree.search(key.to_string())?.value)
+        };
+
+        // 00, 06, 12 and 18 are in both trees.
+        let mut keep = open("/tmp/merge_from/keep/db")?;
+        assert_eq!(
+            keep.merge_from(other_path, ConflictPolicy::KeepExisting)?,
+            6
+        );
+        assert_eq!(keep.range(..)?.len(), 16);
+        assert_eq!(value(&mut keep, "06")?, "ours");
+        assert_eq!(value(&mut keep, "04")?, "theirs");
+
+        // The merged pairs are loaded into a rebuilt tree, committed as a single root.
+        let mut overwrite = open("/tmp/merge_from/overwrite/db")?;
+        let logged = overwrite.wal.records()?.len();
+        assert_eq!(
+            overwrite.merge_from(other_path, ConflictPolicy::Overwrite)?,
+            10
+        );
+        assert_eq!(overwrite.wal.records()?.len(), logged + 1);
+        assert_eq!(value(&mut overwrite, "06")?, "theirs");
+        assert_eq!(value(&mut overwrite, "09")?, "ours");
+        assert_eq!(overwrite.range(..)?.len(), 16);
+        overwrite.check_invariants()?;
+
+        let mut fail = open("/tmp/merge_from/fail/db")?;
+        assert!(matches!(
+            fail.merge_from(other_path, ConflictPolicy::Fail),
+            Err(Error::DuplicateKey { .. })
+        ));
+        assert_eq!(fail.range(..)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn tags_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/tags")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/tags/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v1".to_string()))?;
+        }
+        btree.tag("v1")?;
+        assert!(matches!(btree.tag("v1"), Err(Error::DuplicateKey { .. })));
+        btree.put(KeyValuePair::new("03".to_string(), "v2".to_string()))?;
+        btree.delete(Key("04".to_string()))?;
+
+        // Tagged versions survive compactions.
+        btree.compact()?;
+        let tagged = btree.open_tag("v1")?;
+        assert_eq!(tagged.search("03".to_string())?.value, "v1");
+        assert_eq!(tagged.range(..)?.len(), 10);
+
+        let mut reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/tags/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(reader.tags()?, vec!["v1"]);
+        assert_eq!(reader.open_tag("v1")?.search("04".to_string())?.value, "v1");
+
+        btree.rollback_to_tag("v1")?;
+        assert_eq!(btree.search("03".to_string())?.value, "v1");
+        assert_eq!(btree.range(..)?.len(), 10);
+        btree.remove_tag("v1")?;
+        assert!(matches!(
+            btree.open_tag("v1"),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert!(reader.tags()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn restore_to_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, RestorePoint};
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+        use std::thread;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        std::fs::create_dir_all("/tmp/restore_to/source")?;
+        std::fs::create_dir_all("/tmp/restore_to/by_lsn")?;
+        std::fs::create_dir_all("/tmp/restore_to/by_time")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/restore_to/source/db"))
+            .b_parameter(2)
+            .build()?;
+        // The empty tree is the first commit, every insert the next one.
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "good".to_string()))?;
         }
+        thread::sleep(Duration::from_millis(5));
+        let before_mistake = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        thread::sleep(Duration::from_millis(5));
+        btree.put(KeyValuePair::new("03".to_string(), "bad".to_string()))?;
+        btree.delete(Key("04".to_string()))?;
+
+        let path = Path::new("/tmp/restore_to/by_lsn/db");
+        assert_eq!(btree.restore_to(RestorePoint::Lsn(6), path)?, 6);
+        let mut restored = BTreeBuilder::new().path(path).read_only(true).build()?;
+        assert_eq!(restored.range(..)?.len(), 5);
+
+        let path = Path::new("/tmp/restore_to/by_time/db");
+        assert_eq!(
+            btree.restore_to(RestorePoint::Timestamp(before_mistake), path)?,
+            11
+        );
+        let mut restored = BTreeBuilder::new().path(path).read_only(true).build()?;
+        assert_eq!(restored.search("03".to_string())?.value, "good");
+        assert_eq!(restored.search("04".to_string())?.value, "good");
+
+        // A checkpoint drops the history before it.
+        btree.checkpoint()?;
+        assert!(matches!(
+            btree.restore_to(RestorePoint::Lsn(6), path),
+            Err(Error::KeyNotFound { .. })
+        ));
         Ok(())
     }
 
-    /// borrow_if_needed checks the node for underflow (following a removal of a key),
-    /// if it underflows it is merged with a sibling node, and than called recoursively
-    /// up the tree. Since the downward root-to-leaf traversal was done using the copy-on-write
-    /// technique we are ensured that any merges will only be reflected in the copied parent in the path.
-    fn borrow_if_needed(&mut self, node: Node, key: &Key) -> Result<(), Error> {
-        if self.is_node_underflow(&node)? {
-            // Fetch the sibling from the parent -
-            // This could be quicker if we implement sibling pointers.
-            let parent_offset = node.parent_offset.clone().ok_or(Error::UnexpectedError)?;
-            let parent_page = self.pager.get_page(&parent_offset)?;
-            let mut parent_node = Node::try_from(parent_page)?;
-            // The parent has to be an "internal" node.
-            match parent_node.node_type {
-                NodeType::Internal(ref mut children, ref mut keys) => {
-                    let idx = keys.binary_search(key).unwrap_or_else(|x| x);
-                    // The sibling is in idx +- 1 as the above index led
-                    // the downward search to node.
-                    let sibling_idx;
-                    match idx > 0 {
-                        false => sibling_idx = idx + 1,
-                        true => sibling_idx = idx - 1,
-                    }
+    #[test]
+    fn torn_pages_are_detected_at_open() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs::OpenOptions;
+        use std::io::Write;
+        use std::path::Path;
 
-                    let sibling_offset = children.get(sibling_idx).ok_or(Error::UnexpectedError)?;
-                    let sibling_page = self.pager.get_page(sibling_offset)?;
-                    let sibling = Node::try_from(sibling_page)?;
-                    let merged_node = self.merge(node, sibling)?;
-                    let merged_node_offset =
-                        self.pager.write_page(Page::try_from(&merged_node)?)?;
-                    let merged_node_idx = cmp::min(idx, sibling_idx);
-                    // remove the old nodes.
-                    children.remove(merged_node_idx);
-                    // remove shifts nodes to the left.
-                    children.remove(merged_node_idx);
-                    // if the parent is the root, and there is a single child - the merged node -
-                    // we can safely replace the root with the child.
-                    if parent_node.is_root && children.is_empty() {
-                        self.wal.set_root(merged_node_offset)?;
-                        return Ok(());
-                    }
-                    // remove the keys that separated the two nodes from each other:
-                    keys.remove(idx);
-                    // write the new node in place.
-                    children.insert(merged_node_idx, merged_node_offset);
-                    // write the updated parent back to disk and continue up the tree.
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&parent_node)?, &parent_offset)?;
-                    return self.borrow_if_needed(parent_node, key);
-                }
-                _ => return Err(Error::UnexpectedError),
-            }
+        let path = Path::new("/tmp/torn_pages/db");
+        std::fs::create_dir_all("/tmp/torn_pages")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
         }
+        assert_eq!(btree.torn_tail()?, None);
+        drop(btree);
+
+        // A whole page of zeros and a partial page, as left by a crash mid-write.
+        let len = std::fs::metadata(path)?.len() as usize;
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(&[0x00; PAGE_SIZE])?;
+        file.write_all(&[0x01; 100])?;
+        drop(file);
+        let open = || BTreeBuilder::new().path(path).open_existing(true);
+        assert!(matches!(
+            open().build(),
+            Err(Error::TornPages { offset, len: torn }) if offset == len && torn == PAGE_SIZE + 100
+        ));
+
+        let mut btree = open().truncate_torn_pages(true).build()?;
+        assert_eq!(std::fs::metadata(path)?.len() as usize, len);
+        assert_eq!(btree.range(..)?.len(), 10);
+        btree.insert(KeyValuePair::new("z".to_string(), "v".to_string()))?;
+        assert_eq!(btree.search("z".to_string())?.value, "v");
         Ok(())
     }
 
-    // merges two *sibling* nodes, it assumes the following:
-    // 1. the two nodes are of the same type.
-    // 2. the two nodes do not accumulate to an overflow,
-    // i.e. |first.keys| + |second.keys| <= [2*(b-1) for keys or 2*b for offsets].
-    fn merge(&self, first: Node, second: Node) -> Result<Node, Error> {
-        match first.node_type {
-            NodeType::Leaf(first_pairs) => {
-                if let NodeType::Leaf(second_pairs) = second.node_type {
-                    let merged_pairs: Vec<KeyValuePair> = first_pairs
-                        .into_iter()
-                        .chain(second_pairs.into_iter())
-                        .collect();
-                    let node_type = NodeType::Leaf(merged_pairs);
-                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
-                } else {
-                    Err(Error::UnexpectedError)
-                }
-            }
-            NodeType::Internal(first_offsets, first_keys) => {
-                if let NodeType::Internal(second_offsets, second_keys) = second.node_type {
-                    let merged_keys: Vec<Key> = first_keys
-                        .into_iter()
-                        .chain(second_keys.into_iter())
-                        .collect();
-                    let merged_offsets: Vec<Offset> = first_offsets
-                        .into_iter()
-                        .chain(second_offsets.into_iter())
-                        .collect();
-                    let node_type = NodeType::Internal(merged_offsets, merged_keys);
-                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
-                } else {
-                    Err(Error::UnexpectedError)
-                }
-            }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+    #[test]
+    fn open_existing_recovers_an_earlier_root() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::IS_ROOT_OFFSET;
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+        use std::path::Path;
+
+        let path = Path::new("/tmp/recover_root/db");
+        std::fs::create_dir_all("/tmp/recover_root")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
         }
+        btree.insert(KeyValuePair::new("z".to_string(), "v".to_string()))?;
+        let root = btree.wal.get_root()?.0;
+        drop(btree);
+
+        // The latest root no longer marked as a root.
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start((root + IS_ROOT_OFFSET) as u64))?;
+        file.write_all(&[0x00])?;
+        drop(file);
+        let mut btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        let recoveries = btree.root_recoveries().to_vec();
+        assert_eq!(recoveries.len(), 1);
+        assert_eq!(recoveries[0].bucket, None);
+        assert_eq!(recoveries[0].failed_root, root);
+        assert!(recoveries[0].recovered_lsn < recoveries[0].failed_lsn);
+        assert_eq!(btree.range(..)?.len(), 10);
+        assert!(matches!(
+            btree.search("z".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        btree.check_invariants()?;
+        drop(btree);
+
+        // The recovered root was logged anew, the next open has nothing to recover.
+        let btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        assert!(btree.root_recoveries().is_empty());
+        Ok(())
     }
 
-    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its offset.
-    fn print_sub_tree(&mut self, prefix: String, offset: Offset) -> Result<(), Error> {
-        println!("{}Node at offset: {}", prefix, offset.0);
-        let curr_prefix = format!("{}|->", prefix);
-        let page = self.pager.get_page(&offset)?;
-        let node = Node::try_from(page)?;
-        match node.node_type {
-            NodeType::Internal(children, keys) => {
-                println!("{}Keys: {:?}", curr_prefix, keys);
-                println!("{}Children: {:?}", curr_prefix, children);
-                let child_prefix = format!("{}   |  ", prefix);
-                for child_offset in children {
-                    self.print_sub_tree(child_prefix.clone(), child_offset)?;
-                }
-                Ok(())
+    #[test]
+    fn atomically_commits_all_or_nothing() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::watch::Event;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/atomically")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/atomically/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.open_bucket("users")?;
+        let events = btree.watch_prefix("".to_string());
+        let kv = |key: &str| KeyValuePair::new(key.to_string(), "v".to_string());
+
+        // The writes of a group commit a single root per keyspace, and observe each other.
+        let logged = btree.wal.records()?.len();
+        btree.atomically(|tree| {
+            for i in 0..20 {
+                tree.insert(kv(&format!("{:02}", i)))?;
+                tree.open_bucket("users")?
+                    .insert(kv(&format!("{:02}", i)))?;
             }
-            NodeType::Leaf(pairs) => {
-                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
-                Ok(())
+            assert_eq!(tree.search("19".to_string())?.value, "v");
+            Ok(())
+        })?;
+        assert_eq!(btree.wal.records()?.len(), logged + 1);
+        assert_eq!(btree.open_bucket("users")?.range(..)?.len(), 20);
+        assert_eq!(events.try_recv(), Some(Event::Insert(kv("00"))));
+
+        // A failed group leaves the file, the roots and the watchers as they were.
+        while events.try_recv().is_some() {}
+        let cursor = btree.pager.cursor();
+        let res = btree.atomically(|tree| {
+            for i in 20..40 {
+                tree.insert(kv(&format!("{:02}", i)))?;
+                tree.open_bucket("users")?
+                    .delete(Key(format!("{:02}", i - 20)))?;
             }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
-        }
-    }
+            Err::<(), _>(Error::Conflict)
+        });
+        assert!(matches!(res, Err(Error::Conflict)));
+        assert_eq!(btree.pager.cursor(), cursor);
+        assert_eq!(btree.wal.records()?.len(), logged + 1);
+        assert_eq!(btree.range(..)?.len(), 20);
+        assert_eq!(btree.open_bucket("users")?.range(..)?.len(), 20);
+        assert_eq!(events.try_recv(), None);
 
-    /// print is a helper for recursively printing the tree.
-    pub fn print(&mut self) -> Result<(), Error> {
-        println!();
-        let root_offset = self.wal.get_root()?;
-        self.print_sub_tree("".to_string(), root_offset)
+        // A write failing within a group is rolled back, the group goes on past it.
+        btree.atomically(|tree| {
+            tree.insert(kv("a"))?;
+            assert!(matches!(
+                tree.insert(kv("00")),
+                Err(Error::DuplicateKey { .. })
+            ));
+            assert!(matches!(
+                tree.open_bucket("orders"),
+                Err(Error::Unsupported(_))
+            ));
+            tree.insert(kv("b"))
+        })?;
+        assert_eq!(btree.range(..)?.len(), 22);
+        btree.check_invariants()?;
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::error::Error;
 
     #[test]
-    fn search_works() -> Result<(), Error> {
+    fn atomically_survives_a_crash() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::KeyValuePair;
+        use crate::wal::{write_commit_marker, MarkedRoot, RECORD_SIZE};
+        use std::fs::OpenOptions;
+        use std::path::Path;
+
+        let path = Path::new("/tmp/atomically_crash/db");
+        std::fs::create_dir_all("/tmp/atomically_crash")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        btree.open_bucket("users")?;
+        btree.atomically(|tree| {
+            tree.insert(KeyValuePair::new("a".to_string(), "tree".to_string()))?;
+            tree.open_bucket("users")?
+                .insert(KeyValuePair::new("a".to_string(), "user".to_string()))
+        })?;
+        let mut marked = Vec::new();
+        let record = btree.wal.last_record()?;
+        marked.push(MarkedRoot {
+            bucket: String::new(),
+            lsn: record.lsn,
+            root: record.root,
+        });
+        let record = btree.buckets[0].1.last_record()?;
+        marked.push(MarkedRoot {
+            bucket: "users".to_string(),
+            lsn: record.lsn,
+            root: record.root,
+        });
+        drop(btree);
+
+        // A crash logged the root of the tree, not that of the bucket.
+        let wal = OpenOptions::new()
+            .write(true)
+            .open("/tmp/atomically_crash/wal.users")?;
+        wal.set_len(wal.metadata()?.len() - RECORD_SIZE as u64)?;
+        write_commit_marker(path, &marked)?;
+        let mut btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        assert_eq!(btree.search("a".to_string())?.value, "tree");
+        assert_eq!(
+            btree.open_bucket("users")?.search("a".to_string())?.value,
+            "user"
+        );
+        assert!(!Path::new("/tmp/atomically_crash/db.commit").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invariants_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node::Node;
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+        use crate::page::Page;
+        use std::convert::TryFrom;
         use std::path::Path;
 
+        std::fs::create_dir_all("/tmp/check_invariants")?;
         let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+            .path(Path::new("/tmp/check_invariants/db"))
             .b_parameter(2)
             .build()?;
-        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
-        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
-        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.tag("v1")?;
+        for i in (0..40).step_by(3) {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        btree.check_invariants()?;
 
-        let mut kv = btree.search("b".to_string())?;
-        assert_eq!(kv.key, "b");
-        assert_eq!(kv.value, "hello");
+        // A root holding its keys out of order.
+        let root = btree.wal.get_root()?;
+        let node = Node::new(
+            NodeType::Leaf(vec![
+                KeyValuePair::new("b".to_string(), "v".to_string()),
+                KeyValuePair::new("a".to_string(), "v".to_string()),
+            ]),
+            true,
+            None,
+        );
+        btree
+            .pager
+            .write_page_at_offset(Page::try_from(&node)?, &root)?;
+        assert!(matches!(
+            btree.check_invariants(),
+            Err(Error::InvariantViolation { offset, .. }) if offset == root.0
+        ));
+        Ok(())
+    }
 
-        kv = btree.search("c".to_string())?;
-        assert_eq!(kv.key, "c");
-        assert_eq!(kv.value, "marhaba");
+    #[test]
+    fn failed_writes_leave_the_file_untouched() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        let path = Path::new("/tmp/failed_writes/db");
+        std::fs::create_dir_all("/tmp/failed_writes")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        // A full root, split by the next insert before its leaf is written.
+        for i in 0..3 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+        }
+        let (root, len, cursor) = (
+            btree.wal.get_root()?,
+            std::fs::metadata(path)?.len(),
+            btree.pager.cursor(),
+        );
+        assert!(btree
+            .insert(KeyValuePair::new(
+                "3".to_string(),
+                "a value too long".to_string()
+            ))
+            .is_err());
+        assert!(matches!(
+            btree.delete(Key("9".to_string())),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert_eq!(btree.wal.get_root()?, root);
+        assert_eq!(std::fs::metadata(path)?.len(), len);
+        assert_eq!(btree.pager.cursor(), cursor);
 
+        btree.insert(KeyValuePair::new("3".to_string(), "v".to_string()))?;
+        assert_eq!(btree.range(..)?.len(), 4);
+        btree.check_invariants()?;
         Ok(())
     }
 
     #[test]
-    fn insert_works() -> Result<(), Error> {
-        use crate::btree::BTreeBuilder;
+    fn max_file_size_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, PAGE_SIZE};
         use crate::node_type::KeyValuePair;
         use std::path::Path;
 
+        let path = Path::new("/tmp/max_file_size/db");
+        std::fs::create_dir_all("/tmp/max_file_size")?;
+        let limit = 20 * PAGE_SIZE as u64;
         let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+            .path(path)
             .b_parameter(2)
+            .max_file_size(limit)
             .build()?;
-        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
-        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
-        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
-        btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
-        btree.insert(KeyValuePair::new("e".to_string(), "salam".to_string()))?;
-        btree.insert(KeyValuePair::new("f".to_string(), "hallo".to_string()))?;
-        btree.insert(KeyValuePair::new("g".to_string(), "Konnichiwa".to_string()))?;
-        btree.insert(KeyValuePair::new("h".to_string(), "Ni hao".to_string()))?;
-        btree.insert(KeyValuePair::new("i".to_string(), "Ciao".to_string()))?;
+        let mut inserted = 0;
+        let e = loop {
+            match btree.insert(KeyValuePair::new(
+                format!("{:02}", inserted),
+                "v".to_string(),
+            )) {
+                Ok(()) => inserted += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(e, Error::DatabaseFull { limit: l } if l == limit));
+        assert!(std::fs::metadata(path)?.len() <= limit);
+        assert_eq!(btree.range(..)?.len(), inserted);
+        btree.check_invariants()?;
+        Ok(())
+    }
 
-        let mut kv = btree.search("a".to_string())?;
-        assert_eq!(kv.key, "a");
-        assert_eq!(kv.value, "shalom");
+    /// Trees grow past 4 GiB, pages appended past it (to a sparse file) are read back once
+    /// the tree is reopened.
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn files_past_4_gib_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
 
-        kv = btree.search("b".to_string())?;
-        assert_eq!(kv.key, "b");
-        assert_eq!(kv.value, "hello");
+        let path = Path::new("/tmp/past_4_gib/db");
+        std::fs::create_dir_all("/tmp/past_4_gib")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "v".to_string()))?;
+        btree.pager.truncate(5 << 30)?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+        }
+        assert!(btree.wal.get_root()?.0 > 4 << 30);
+        drop(btree);
 
-        kv = btree.search("c".to_string())?;
-        assert_eq!(kv.key, "c");
-        assert_eq!(kv.value, "marhaba");
+        let mut btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        assert_eq!(btree.range(..)?.len(), 11);
+        btree.check_invariants()?;
+        drop(btree);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
 
-        kv = btree.search("d".to_string())?;
-        assert_eq!(kv.key, "d");
-        assert_eq!(kv.value, "olah");
+    #[test]
+    fn multibyte_keys_are_sized_in_bytes() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
 
-        kv = btree.search("e".to_string())?;
-        assert_eq!(kv.key, "e");
-        assert_eq!(kv.value, "salam");
+        let path = Path::new("/tmp/multibyte_keys/db");
+        std::fs::create_dir_all("/tmp/multibyte_keys")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        // Ten bytes of two byte chars, and of four byte emoji (with two bytes to spare).
+        let keys = [
+            "é".repeat(5),
+            "🦀🦀".to_string(),
+            "ü".repeat(4),
+            "ab🦀cd".to_string(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            btree.insert(KeyValuePair::new(key.clone(), "ü".repeat(5)))?;
+            // Splits along the way.
+            for j in 0..4 {
+                btree.insert(KeyValuePair::new(format!("{}{}", i, j), "v".to_string()))?;
+            }
+        }
+        // Three chars, but twelve bytes.
+        let cursor = btree.pager.cursor();
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("🦀🦀🦀".to_string(), "v".to_string())),
+            Err(Error::KeyOverflowError)
+        ));
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("a".to_string(), "ü".repeat(6))),
+            Err(Error::ValueOverflowError)
+        ));
+        assert_eq!(btree.pager.cursor(), cursor);
+        drop(btree);
 
-        kv = btree.search("f".to_string())?;
-        assert_eq!(kv.key, "f");
-        assert_eq!(kv.value, "hallo");
+        let mut btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        for key in keys.iter() {
+            assert_eq!(btree.search(key.clone())?.value, "ü".repeat(5));
+        }
+        assert_eq!(btree.range(..)?.len(), 20);
+        btree.check_invariants()?;
+        Ok(())
+    }
 
-        kv = btree.search("g".to_string())?;
-        assert_eq!(kv.key, "g");
-        assert_eq!(kv.value, "Konnichiwa");
+    #[test]
+    fn strict_trees_validate_reads() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node::Node;
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+        use crate::page::Page;
+        use std::convert::TryFrom;
+        use std::path::Path;
 
-        kv = btree.search("h".to_string())?;
-        assert_eq!(kv.key, "h");
-        assert_eq!(kv.value, "Ni hao");
+        let path = Path::new("/tmp/strict/db");
+        std::fs::create_dir_all("/tmp/strict")?;
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .strict(true)
+            .build()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        for i in (0..40).step_by(3) {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        assert_eq!(btree.range(..)?.len(), 26);
+        drop(btree);
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .open_existing(true)
+            .strict(true)
+            .build()?;
+        assert_eq!(btree.search("01".to_string())?.value, "v");
 
-        kv = btree.search("i".to_string())?;
-        assert_eq!(kv.key, "i");
-        assert_eq!(kv.value, "Ciao");
+        // A root holding its keys out of order fails the read.
+        let root = btree.wal.get_root()?;
+        let node = Node::new(
+            NodeType::Leaf(vec![
+                KeyValuePair::new("b".to_string(), "v".to_string()),
+                KeyValuePair::new("a".to_string(), "v".to_string()),
+            ]),
+            true,
+            None,
+        );
+        btree
+            .pager
+            .write_page_at_offset(Page::try_from(&node)?, &root)?;
+        let e = btree.search("a".to_string()).unwrap_err();
+        assert!(matches!(e.root_cause(), Error::Corruption { offset, .. } if *offset == root.0));
         Ok(())
     }
 
     #[test]
-    fn delete_works() -> Result<(), Error> {
+    fn errors_name_the_step_and_page_they_failed_at() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
-        use crate::error::Error;
-        use crate::node_type::{Key, KeyValuePair};
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page::Page;
+        use crate::page_layout::{LEAF_NODE_NUM_PAIRS_OFFSET, PAGE_SIZE};
+        use std::convert::TryFrom;
         use std::path::Path;
 
+        std::fs::create_dir_all("/tmp/error_context")?;
         let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+            .path(Path::new("/tmp/error_context/db"))
             .b_parameter(2)
             .build()?;
-        btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
-        btree.insert(KeyValuePair::new("e".to_string(), "salam".to_string()))?;
-        btree.insert(KeyValuePair::new("f".to_string(), "hallo".to_string()))?;
-        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
-        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
-        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+        for i in 0..5 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        // Break the leftmost child of the root by writing a count of pairs it cannot hold.
+        let root = btree.wal.get_root()?;
+        let child = match Node::try_from(btree.pager.get_page(&root)?)?.node_type {
+            NodeType::Internal(children, _) => children[0].clone(),
+            _ => return Err(Error::UnexpectedError),
+        };
+        let mut page = btree.pager.get_page(&child)?;
+        page.write_value_at_offset(
+            LEAF_NODE_NUM_PAIRS_OFFSET,
+            Page::<PAGE_SIZE>::MAX_LEAF_PAIRS + 1,
+        )?;
+        btree.pager.write_page_at_offset(page, &child)?;
 
-        let mut kv = btree.search("c".to_string())?;
-        assert_eq!(kv.key, "c");
-        assert_eq!(kv.value, "marhaba");
+        let e = btree
+            .insert(KeyValuePair::new("00a".to_string(), "v".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "read of a node at {:#x} (level 1) while inserting \"00a\": \
+                 corrupt page at offset {}: a count of pairs the page cannot hold",
+                child.0, child.0
+            )
+        );
+        assert!(matches!(e.root_cause(), Error::Corruption { offset, .. } if *offset == child.0));
+        let e = btree.search("00".to_string()).unwrap_err();
+        assert!(e.to_string().starts_with("read of a node at"));
+        assert!(e.to_string().contains("while searching \"00\""));
+        // Errors of the caller's making are left as they are.
+        assert!(matches!(
+            btree.search("99".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
 
-        btree.delete(Key("c".to_string()))?;
-        let mut res = btree.search("c".to_string());
-        assert!(matches!(res, Err(Error::KeyNotFound)));
+    #[test]
+    fn internal_nodes_are_cached() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page::Page;
+        use std::convert::TryFrom;
+        use std::path::Path;
 
-        kv = btree.search("d".to_string())?;
-        assert_eq!(kv.key, "d");
-        assert_eq!(kv.value, "olah");
+        std::fs::create_dir_all("/tmp/node_cache")?;
+        let path = Path::new("/tmp/node_cache/db");
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        // Walk down to another leaf in between, rather than starting from the leaf of "05".
+        btree.search("05".to_string())?;
+        btree.search("30".to_string())?;
+        // Only the leaf is read once the internal nodes above it are cached.
+        let before = btree.pager.io();
+        assert_eq!(btree.search("05".to_string())?.value, "v");
+        let after = btree.pager.io();
+        assert_eq!(after.pages_read - before.pages_read, 1);
+        assert!(after.node_cache_hits - before.node_cache_hits >= 2);
 
-        btree.delete(Key("d".to_string()))?;
-        res = btree.search("d".to_string());
-        assert!(matches!(res, Err(Error::KeyNotFound)));
+        // A node written over is dropped from the cache.
+        let root = btree.wal.get_root()?;
+        let node = Node::new(
+            NodeType::Leaf(vec![KeyValuePair::new("zz".to_string(), "w".to_string())]),
+            true,
+            None,
+        );
+        btree
+            .pager
+            .write_page_at_offset(Page::try_from(&node)?, &root)?;
+        assert_eq!(btree.search("zz".to_string())?.value, "w");
+        drop(btree);
 
-        btree.delete(Key("e".to_string()))?;
-        res = btree.search("e".to_string());
-        assert!(matches!(res, Err(Error::KeyNotFound)));
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .node_cache_size(0)
+            .build()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.search("05".to_string())?;
+        btree.search("30".to_string())?;
+        let before = btree.pager.io();
+        btree.search("05".to_string())?;
+        assert!(btree.pager.io().pages_read - before.pages_read >= 3);
+        assert_eq!(btree.pager.io().node_cache_hits, 0);
+        Ok(())
+    }
 
-        btree.delete(Key("f".to_string()))?;
-        res = btree.search("f".to_string());
-        assert!(matches!(res, Err(Error::KeyNotFound)));
+    #[test]
+    fn writes_append_only_the_pages_they_modify() -> Result<(), Error> {
+        use crate::btree::{BTree, BTreeBuilder, PAGE_SIZE};
+        use crate::node_type::{Key, KeyValuePair};
+
+        // Every page a write appends is written once and reached by the new root, but for the
+        // copy of a root a delete shrinks the tree below.
+        let check = |btree: &mut BTree, write: &dyn Fn(&mut BTree) -> Result<(), Error>| {
+            let (old_root, first_new) = (btree.wal.get_root()?, btree.pager.cursor());
+            let (height, written) = (btree.stats()?.height, btree.pager.io().pages_written);
+            write(btree)?;
+            let appended = (btree.pager.cursor().0 - first_new.0) / PAGE_SIZE;
+            assert_eq!(btree.pager.io().pages_written - written, appended as u64);
+            let new_root = btree.wal.get_root()?;
+            let dead = btree
+                .unreachable_pages(&old_root, &new_root, &first_new)?
+                .into_iter()
+                .filter(|offset| offset.0 >= first_new.0)
+                .count();
+            assert_eq!(dead, height.saturating_sub(btree.stats()?.height));
+            Ok::<(), Error>(())
+        };
+        let mut btree = BTreeBuilder::new().in_memory(true).b_parameter(2).build()?;
+        for i in 0..60 {
+            check(&mut btree, &|btree| {
+                btree.insert(KeyValuePair::new(
+                    format!("{:02}", i * 7 % 60),
+                    "v".to_string(),
+                ))
+            })?;
+        }
+        for i in 0..60 {
+            check(&mut btree, &|btree| btree.delete(Key(format!("{:02}", i))))?;
+        }
+        btree.check_invariants()?;
+        Ok(())
+    }
 
+    #[test]
+    fn searches_start_from_the_last_leaf() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+
+        let mut btree = BTreeBuilder::new()
+            .in_memory(true)
+            .b_parameter(2)
+            .node_cache_size(0)
+            .build()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let height = btree.stats()?.height as u64;
+        let pages_read = |btree: &mut crate::btree::BTree, key: &str| {
+            let before = btree.pager.io().pages_read;
+            let found = btree.search(key.to_string()).is_ok();
+            (btree.pager.io().pages_read - before, found)
+        };
+        assert_eq!(pages_read(&mut btree, "10"), (height, true));
+        // A key of the same leaf, present or not, is searched for in the leaf only.
+        assert_eq!(pages_read(&mut btree, "10"), (1, true));
+        assert_eq!(pages_read(&mut btree, "10a"), (1, false));
+        assert_eq!(pages_read(&mut btree, "30"), (height, true));
+        // A write commits another root, walked down from.
+        btree.insert(KeyValuePair::new("30a".to_string(), "v".to_string()))?;
+        assert_eq!(pages_read(&mut btree, "30a"), (height, true));
+        assert_eq!(pages_read(&mut btree, "30"), (1, true));
         Ok(())
     }
 }
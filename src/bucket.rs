@@ -0,0 +1,181 @@
+use crate::btree::BTree;
+use crate::error::Error;
+use crate::export::{read_export, write_export};
+use crate::node_type::{Key, KeyValuePair};
+use crate::page_layout::PAGE_SIZE;
+use crate::view::ReadView;
+use std::ops::RangeBounds;
+use std::path::Path;
+
+/// Bucket is a handle to a named keyspace of a tree (see `BTree::open_bucket`).
+/// A bucket is a tree of its own: its keys are independent of the keys of the tree
+/// and of any other bucket, while its nodes live in the same file as theirs.
+pub struct Bucket<'a, const N: usize = PAGE_SIZE> {
+    tree: &'a mut BTree<N>,
+    name: String,
+}
+
+impl<'a, const N: usize> Bucket<'a, N> {
+    pub(crate) fn new(tree: &'a mut BTree<N>, name: String) -> Bucket<'a, N> {
+        Bucket { tree, name }
+    }
+
+    /// name returns the name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// insert a key value pair into the bucket.
+    pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.insert(kv))
+    }
+
+    /// put inserts a key value pair into the bucket replacing the value of an existing key.
+    pub fn put(&mut self, kv: KeyValuePair) -> Result<Option<KeyValuePair>, Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.put(kv))
+    }
+
+    /// delete deletes a given key from the bucket.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.delete(key))
+    }
+
+    /// search searches for a specific key in the bucket.
+    pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.search(key))
+    }
+
+    /// range returns all the key-value pairs of the bucket whose keys fall in the given range,
+    /// in ascending order.
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<KeyValuePair>, Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.range(range))
+    }
+
+    /// pop_first_if removes and returns the pair of the bucket with the smallest key
+    /// matching a predicate, see `BTree::pop_first_if`.
+    pub fn pop_first_if<P>(&mut self, pred: P) -> Result<Option<KeyValuePair>, Error>
+    where
+        P: FnMut(&str, &str) -> bool,
+    {
+        self.tree
+            .in_bucket(&self.name, |tree| tree.pop_first_if(pred))
+    }
+
+    /// export_to writes the pairs of the bucket into a standalone, versioned export file,
+    /// which `import_from` loads into a bucket of the same name in any tree.
+    pub fn export_to(&mut self, path: &Path) -> Result<(), Error> {
+        let pairs = self.range(..)?;
+        write_export(path, &self.name, &pairs)
+    }
+
+    /// import_from loads the bucket exported into a file into a tree, creating the bucket
+    /// if it does not exist yet. The bucket is expected to be empty.
+    pub fn import_from(tree: &'a mut BTree<N>, path: &Path) -> Result<Bucket<'a, N>, Error> {
+        let (name, pairs) = read_export(path)?;
+        tree.open_bucket(&name)?;
+        tree.in_bucket(&name, |tree| tree.bulk_load(pairs))?;
+        Ok(Bucket::new(tree, name))
+    }
+
+    /// read_view returns a view pinned to the currently committed root of the bucket.
+    pub fn read_view(&mut self) -> Result<ReadView<N>, Error> {
+        self.tree.in_bucket(&self.name, |tree| tree.read_view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn buckets_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/buckets")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/buckets/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "tree".to_string()))?;
+        let mut users = btree.open_bucket("users")?;
+        for i in 0..20 {
+            users.insert(KeyValuePair::new(format!("{:02}", i), "user".to_string()))?;
+        }
+        users.insert(KeyValuePair::new("a".to_string(), "user".to_string()))?;
+        let mut orders = btree.open_bucket("orders")?;
+        orders.insert(KeyValuePair::new("a".to_string(), "order".to_string()))?;
+
+        // Every keyspace holds its own value of the same key.
+        assert_eq!(btree.search("a".to_string())?.value, "tree");
+        assert_eq!(btree.range(..)?.len(), 1);
+        let mut users = btree.open_bucket("users")?;
+        assert_eq!(users.search("a".to_string())?.value, "user");
+        assert_eq!(users.range(..)?.len(), 21);
+        users.delete(Key("a".to_string()))?;
+        assert_eq!(
+            btree.open_bucket("orders")?.search("a".to_string())?.value,
+            "order"
+        );
+
+        // Compaction carries the buckets over to the compacted file.
+        btree.compact()?;
+        assert_eq!(btree.open_bucket("users")?.range(..)?.len(), 20);
+
+        // Readers find the buckets in the catalog.
+        let mut reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/buckets/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(reader.open_bucket("users")?.range(..)?.len(), 20);
+        assert!(matches!(
+            reader.open_bucket("missing"),
+            Err(Error::InvalidBucketName)
+        ));
+        assert!(matches!(
+            btree.open_bucket("no/slashes"),
+            Err(Error::InvalidBucketName)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn export_and_import_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::bucket::Bucket;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/bucket_export/source")?;
+        std::fs::create_dir_all("/tmp/bucket_export/target")?;
+        let mut source = BTreeBuilder::new()
+            .path(Path::new("/tmp/bucket_export/source/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut tenant = source.open_bucket("acme")?;
+        for i in 0..20 {
+            tenant.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        tenant.export_to(Path::new("/tmp/bucket_export/acme.exp"))?;
+
+        let mut target = BTreeBuilder::new()
+            .path(Path::new("/tmp/bucket_export/target/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut imported =
+            Bucket::import_from(&mut target, Path::new("/tmp/bucket_export/acme.exp"))?;
+        assert_eq!(imported.name(), "acme");
+        assert_eq!(imported.range(..)?, source.open_bucket("acme")?.range(..)?);
+        // Importing into a bucket already holding pairs is refused.
+        assert!(matches!(
+            Bucket::import_from(&mut target, Path::new("/tmp/bucket_export/acme.exp")),
+            Err(Error::TreeNotEmpty)
+        ));
+        assert!(matches!(
+            Bucket::import_from(&mut target, Path::new("/tmp/bucket_export/target/db")),
+            Err(Error::InvalidHeader)
+        ));
+        Ok(())
+    }
+}
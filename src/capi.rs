@@ -0,0 +1,316 @@
+//! The C API of the crate, see `include/btree.h` for its documentation.
+//! Every function returns a status code, `BTREE_OK` on success; the codes are stable
+//! and new ones are only ever appended.
+use crate::btree::{BTree, BTreeBuilder};
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ops::Bound;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+pub const BTREE_OK: c_int = 0;
+pub const BTREE_NOT_FOUND: c_int = 1;
+pub const BTREE_ALREADY_EXISTS: c_int = 2;
+pub const BTREE_KEY_TOO_LARGE: c_int = 3;
+pub const BTREE_VALUE_TOO_LARGE: c_int = 4;
+pub const BTREE_LOCKED: c_int = 5;
+pub const BTREE_READ_ONLY: c_int = 6;
+pub const BTREE_INVALID_FILE: c_int = 7;
+pub const BTREE_INVALID_ARGUMENT: c_int = 8;
+pub const BTREE_BUFFER_TOO_SMALL: c_int = 9;
+pub const BTREE_BUSY: c_int = 10;
+pub const BTREE_ERROR: c_int = 11;
+
+/// The callback of `btree_iterate`, returning non-zero stops the iteration.
+pub type BTreeIterateCallback =
+    extern "C" fn(key: *const c_char, value: *const c_char, context: *mut c_void) -> c_int;
+
+/// status returns the status code of an error.
+fn status(e: &Error) -> c_int {
+    match e.root_cause() {
+        Error::KeyNotFound { .. } => BTREE_NOT_FOUND,
+        Error::DuplicateKey { .. } | Error::UniqueViolation => BTREE_ALREADY_EXISTS,
+        Error::KeyOverflowError => BTREE_KEY_TOO_LARGE,
+        Error::ValueOverflowError => BTREE_VALUE_TOO_LARGE,
+        Error::DatabaseLocked => BTREE_LOCKED,
+        Error::ReadOnly => BTREE_READ_ONLY,
+        Error::InvalidHeader
+        | Error::Corruption { .. }
+        | Error::UnsupportedVersion
+        | Error::PageSizeMismatch { .. }
+        | Error::UnsupportedFeature { .. }
+        | Error::TornPages { .. } => BTREE_INVALID_FILE,
+        Error::UTF8Error
+        | Error::InvalidBucketName
+        | Error::InvalidBParameter(_)
+        | Error::InvalidPageSize(_)
+        | Error::InvalidPath
+        | Error::PathNotWritable
+        | Error::ConflictingOptions(_)
+        | Error::InvalidConfiguration(_)
+        | Error::InvalidFormat(_)
+        | Error::InvalidArgument(_) => BTREE_INVALID_ARGUMENT,
+        Error::Busy | Error::Conflict | Error::Timeout | Error::Deadlock => BTREE_BUSY,
+        _ => BTREE_ERROR,
+    }
+}
+
+/// guard runs the body of an exported function, turning its errors into status codes
+/// and keeping panics from unwinding into C.
+fn guard(body: impl FnOnce() -> Result<(), Error>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => BTREE_OK,
+        Ok(Err(e)) => status(&e),
+        Err(_) => BTREE_ERROR,
+    }
+}
+
+/// string reads a NUL-terminated UTF-8 string passed from C.
+///
+/// # Safety
+/// s must be NULL or point to a NUL-terminated string.
+unsafe fn string(s: *const c_char) -> Result<String, Error> {
+    if s.is_null() {
+        return Err(Error::InvalidArgument("a NULL string"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(String::from)
+        .map_err(|_| Error::UTF8Error)
+}
+
+/// open opens a tree, handing it over to C.
+///
+/// # Safety
+/// path must point to a NUL-terminated string and out to a writable pointer.
+unsafe fn open(path: *const c_char, out: *mut *mut BTree, builder: BTreeBuilder) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(Error::InvalidArgument("a NULL out pointer"));
+        }
+        let tree = builder.path(string(path)?).build()?;
+        *out = Box::into_raw(Box::new(tree));
+        Ok(())
+    })
+}
+
+/// btree_open opens the tree of a file for writing, creating a new tree of a given b parameter
+/// if create is non-zero, opening the existing one otherwise.
+///
+/// # Safety
+/// path must point to a NUL-terminated string and out to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn btree_open(
+    path: *const c_char,
+    b: usize,
+    create: c_int,
+    out: *mut *mut BTree,
+) -> c_int {
+    let builder = BTreeBuilder::new()
+        .b_parameter(b)
+        .open_existing(create == 0);
+    open(path, out, builder)
+}
+
+/// btree_open_read_only opens the existing tree of a file for reading only.
+///
+/// # Safety
+/// path must point to a NUL-terminated string and out to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn btree_open_read_only(path: *const c_char, out: *mut *mut BTree) -> c_int {
+    open(path, out, BTreeBuilder::new().read_only(true))
+}
+
+/// btree_close closes a tree opened by `btree_open` or `btree_open_read_only`, NULL is ignored.
+///
+/// # Safety
+/// tree must be NULL or a tree not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn btree_close(tree: *mut BTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// btree_put sets a key to a value.
+///
+/// # Safety
+/// tree must be an open tree, key and value NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn btree_put(
+    tree: *mut BTree,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    guard(|| {
+        let tree = tree.as_mut().ok_or(Error::InvalidArgument("a NULL tree"))?;
+        tree.put(KeyValuePair::new(string(key)?, string(value)?))
+            .map(|_| ())
+    })
+}
+
+/// btree_get copies the value of a key into a buffer of a given capacity, NUL-terminated,
+/// and sets len to its length. `BTREE_BUFFER_TOO_SMALL` is returned (len being set)
+/// if the value and its terminator do not fit.
+///
+/// # Safety
+/// tree must be an open tree, key a NUL-terminated string, value a buffer of capacity bytes
+/// and len a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn btree_get(
+    tree: *mut BTree,
+    key: *const c_char,
+    value: *mut c_char,
+    capacity: usize,
+    len: *mut usize,
+) -> c_int {
+    let mut too_small = false;
+    let res = guard(|| {
+        let tree = tree.as_mut().ok_or(Error::InvalidArgument("a NULL tree"))?;
+        if value.is_null() || len.is_null() {
+            return Err(Error::InvalidArgument("a NULL value or len pointer"));
+        }
+        let kv = tree.search(string(key)?)?;
+        *len = kv.value.len();
+        if kv.value.len() >= capacity {
+            too_small = true;
+            return Ok(());
+        }
+        ptr::copy_nonoverlapping(kv.value.as_ptr() as *const c_char, value, kv.value.len());
+        *value.add(kv.value.len()) = 0;
+        Ok(())
+    });
+    match (res, too_small) {
+        (BTREE_OK, true) => BTREE_BUFFER_TOO_SMALL,
+        (res, _) => res,
+    }
+}
+
+/// btree_delete deletes a key.
+///
+/// # Safety
+/// tree must be an open tree and key a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn btree_delete(tree: *mut BTree, key: *const c_char) -> c_int {
+    guard(|| {
+        let tree = tree.as_mut().ok_or(Error::InvalidArgument("a NULL tree"))?;
+        tree.delete(Key(string(key)?))
+    })
+}
+
+/// btree_iterate calls a callback with every pair whose key falls in [start, end), in key
+/// order, until the callback returns non-zero. A NULL start or end leaves the range unbounded.
+/// The strings passed to the callback only live for the duration of the call.
+///
+/// # Safety
+/// tree must be an open tree, start and end NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn btree_iterate(
+    tree: *mut BTree,
+    start: *const c_char,
+    end: *const c_char,
+    callback: BTreeIterateCallback,
+    context: *mut c_void,
+) -> c_int {
+    guard(|| {
+        let tree = tree.as_mut().ok_or(Error::InvalidArgument("a NULL tree"))?;
+        let start = match start.is_null() {
+            true => Bound::Unbounded,
+            false => Bound::Included(string(start)?),
+        };
+        let end = match end.is_null() {
+            true => Bound::Unbounded,
+            false => Bound::Excluded(string(end)?),
+        };
+        for kv in tree.range((start, end))? {
+            // Keys and values are read off C strings or pages, neither holds a NUL.
+            let key = std::ffi::CString::new(kv.key).map_err(|_| Error::UTF8Error)?;
+            let value = std::ffi::CString::new(kv.value).map_err(|_| Error::UTF8Error)?;
+            if callback(key.as_ptr(), value.as_ptr(), context) != 0 {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn capi_works() -> Result<(), Error> {
+        use crate::capi::*;
+        use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+        extern "C" fn collect(key: *const c_char, value: *const c_char, ctx: *mut c_void) -> c_int {
+            let pairs = unsafe { &mut *(ctx as *mut Vec<(String, String)>) };
+            let (key, value) = unsafe { (CStr::from_ptr(key), CStr::from_ptr(value)) };
+            let pair = (key.to_string_lossy().into(), value.to_string_lossy().into());
+            pairs.push(pair);
+            (pairs.len() == 3) as c_int
+        }
+
+        std::fs::create_dir_all("/tmp/capi")?;
+        let path = CString::new("/tmp/capi/db").map_err(|_| Error::UTF8Error)?;
+        let s = |s: &str| CString::new(s).unwrap();
+        unsafe {
+            let mut tree = std::ptr::null_mut();
+            assert_eq!(
+                btree_open(path.as_ptr(), 2, 1, std::ptr::null_mut()),
+                BTREE_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                btree_open(std::ptr::null(), 2, 1, &mut tree),
+                BTREE_INVALID_ARGUMENT
+            );
+            assert_eq!(btree_open(path.as_ptr(), 2, 1, &mut tree), BTREE_OK);
+            for i in 0..10 {
+                let (key, value) = (s(&format!("k{}", i)), s(&format!("v{}", i)));
+                assert_eq!(btree_put(tree, key.as_ptr(), value.as_ptr()), BTREE_OK);
+            }
+            let big = s("0123456789a");
+            assert_eq!(
+                btree_put(tree, big.as_ptr(), big.as_ptr()),
+                BTREE_KEY_TOO_LARGE
+            );
+            assert_eq!(
+                btree_put(std::ptr::null_mut(), big.as_ptr(), big.as_ptr()),
+                BTREE_INVALID_ARGUMENT
+            );
+            assert_eq!(btree_delete(tree, s("k3").as_ptr()), BTREE_OK);
+            assert_eq!(btree_delete(tree, s("k3").as_ptr()), BTREE_NOT_FOUND);
+            btree_close(tree);
+
+            let mut reader = std::ptr::null_mut();
+            assert_eq!(btree_open_read_only(path.as_ptr(), &mut reader), BTREE_OK);
+            let mut buffer = [0 as c_char; 8];
+            let mut len = 0;
+            let key = s("k7");
+            let res = btree_get(reader, key.as_ptr(), buffer.as_mut_ptr(), 8, &mut len);
+            assert_eq!(res, BTREE_OK);
+            assert_eq!(CStr::from_ptr(buffer.as_ptr()).to_str(), Ok("v7"));
+            let res = btree_get(reader, key.as_ptr(), buffer.as_mut_ptr(), 2, &mut len);
+            assert_eq!((res, len), (BTREE_BUFFER_TOO_SMALL, 2));
+            let key = s("k3");
+            let res = btree_get(reader, key.as_ptr(), buffer.as_mut_ptr(), 8, &mut len);
+            assert_eq!(res, BTREE_NOT_FOUND);
+            let value = s("v");
+            assert_eq!(
+                btree_put(reader, key.as_ptr(), value.as_ptr()),
+                BTREE_READ_ONLY
+            );
+
+            let mut pairs: Vec<(String, String)> = Vec::new();
+            let ctx = &mut pairs as *mut Vec<(String, String)> as *mut c_void;
+            let start = s("k2");
+            let res = btree_iterate(reader, start.as_ptr(), std::ptr::null(), collect, ctx);
+            assert_eq!(res, BTREE_OK);
+            let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+            assert_eq!(keys, vec!["k2", "k4", "k5"]);
+            btree_close(reader);
+        }
+        Ok(())
+    }
+}
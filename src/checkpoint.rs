@@ -0,0 +1,111 @@
+use crate::btree::BTree;
+use crate::error::Error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// CheckpointConfig configures the background checkpointer.
+#[derive(Clone, Debug)]
+pub struct CheckpointConfig {
+    /// How often the size of the root log is checked.
+    pub interval: Duration,
+    /// The size (in bytes) of the root log past which it is checkpointed.
+    pub wal_size: u64,
+}
+
+impl Default for CheckpointConfig {
+    // A default CheckpointConfig checks the log every second
+    // and checkpoints it once it holds more than 64KiB.
+    fn default() -> Self {
+        CheckpointConfig {
+            interval: Duration::from_secs(1),
+            wal_size: 64 * 1024,
+        }
+    }
+}
+
+/// Checkpointer is a handle to a background thread checkpointing the root log of a tree
+/// (see `BTree::checkpoint`) whenever it grows past a size threshold,
+/// which keeps writers clear of the stalls of a log over its limit.
+pub struct Checkpointer {
+    stop: Sender<()>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl Checkpointer {
+    pub fn start(tree: Arc<Mutex<BTree>>, config: CheckpointConfig) -> Checkpointer {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(config.interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let mut tree = tree.lock().map_err(|_| Error::Poisoned)?;
+                    if tree.wal_size()? > config.wal_size {
+                        tree.checkpoint()?;
+                    }
+                }
+                _ => return Ok(()),
+            }
+        });
+        Checkpointer { stop, handle }
+    }
+
+    /// stop stops the checkpointer thread, returning the error it failed with (if any).
+    pub fn stop(self) -> Result<(), Error> {
+        // The thread might have already exited on error, dropping the receiving end.
+        let _ = self.stop.send(());
+        self.handle.join().map_err(|_| Error::ThreadPanicked)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn checkpointer_truncates_the_log_in_the_background() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::checkpoint::{CheckpointConfig, Checkpointer};
+        use crate::node_type::KeyValuePair;
+        use crate::wal::RECORD_SIZE;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/checkpointer")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/checkpointer/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+
+        let tree = Arc::new(Mutex::new(btree));
+        let checkpointer = Checkpointer::start(
+            tree.clone(),
+            CheckpointConfig {
+                interval: Duration::from_millis(5),
+                wal_size: 10 * RECORD_SIZE as u64,
+            },
+        );
+        let mut size = 0;
+        for _ in 0..200 {
+            thread::sleep(Duration::from_millis(5));
+            size = std::fs::metadata("/tmp/checkpointer/wal")?.len();
+            if size == RECORD_SIZE as u64 {
+                break;
+            }
+        }
+        checkpointer.stop()?;
+        assert_eq!(size, RECORD_SIZE as u64);
+
+        let mut btree = tree.lock().unwrap();
+        for i in 0..30 {
+            let kv = btree.search(format!("{:02}", i))?;
+            assert_eq!(kv.value, format!("v{}", i));
+        }
+        Ok(())
+    }
+}
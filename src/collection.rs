@@ -0,0 +1,213 @@
+use crate::btree::BTree;
+use crate::encoding::{from_digits, to_digits, KeyRange};
+use crate::error::Error;
+use crate::index::SEPARATOR;
+use crate::node_type::{Key, KeyValuePair};
+use std::ops::Bound;
+
+/// The digits of the position of an element of a list, and the position of the first element
+/// pushed to an empty list, halfway so the list can grow in both directions.
+const POSITION_DIGITS: usize = 4;
+const FIRST_POSITION: u64 = 1 << 23;
+
+/// elements_of returns the prefix of the keys of the elements of a collection
+/// along with the range of these keys.
+fn elements_of(name: &str) -> Result<(String, KeyRange), Error> {
+    if name.contains(SEPARATOR) {
+        return Err(Error::KeyOverflowError);
+    }
+    let prefix = format!("{}{}", name, SEPARATOR);
+    let next = char::from(SEPARATOR as u8 + 1);
+    let range = (
+        Bound::Included(prefix.clone()),
+        Bound::Excluded(format!("{}{}", name, next)),
+    );
+    Ok((prefix, range))
+}
+
+/// Set is a set of strings stored under a name, every element of which is a pair of its own
+/// (keyed by the name followed by the element) so adding or removing an element
+/// never rewrites the others.
+pub struct Set<'a> {
+    tree: &'a mut BTree,
+    prefix: String,
+    range: KeyRange,
+}
+
+impl<'a> Set<'a> {
+    pub fn new(tree: &'a mut BTree, name: &str) -> Result<Set<'a>, Error> {
+        let (prefix, range) = elements_of(name)?;
+        Ok(Set {
+            tree,
+            prefix,
+            range,
+        })
+    }
+
+    /// add adds an element to the set, returning whether it was absent.
+    pub fn add(&mut self, element: &str) -> Result<bool, Error> {
+        let kv = KeyValuePair::new(format!("{}{}", self.prefix, element), String::new());
+        Ok(self.tree.put(kv)?.is_none())
+    }
+
+    /// remove removes an element from the set, returning whether it was present.
+    pub fn remove(&mut self, element: &str) -> Result<bool, Error> {
+        match self.tree.delete(Key(format!("{}{}", self.prefix, element))) {
+            Ok(()) => Ok(true),
+            Err(Error::KeyNotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// contains returns whether an element is in the set.
+    pub fn contains(&mut self, element: &str) -> Result<bool, Error> {
+        match self.tree.search(format!("{}{}", self.prefix, element)) {
+            Ok(_) => Ok(true),
+            Err(Error::KeyNotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// members returns the elements of the set, in order.
+    pub fn members(&mut self) -> Result<Vec<String>, Error> {
+        let pairs = self.tree.range(self.range.clone())?;
+        Ok(pairs
+            .into_iter()
+            .map(|kv| kv.key[self.prefix.len()..].to_string())
+            .collect())
+    }
+}
+
+/// List is a list of strings stored under a name, every element of which is a pair of its own
+/// (keyed by the name followed by the position of the element) so pushing or popping
+/// an element at either end never rewrites the others.
+pub struct List<'a> {
+    tree: &'a mut BTree,
+    prefix: String,
+    range: KeyRange,
+}
+
+impl<'a> List<'a> {
+    pub fn new(tree: &'a mut BTree, name: &str) -> Result<List<'a>, Error> {
+        let (prefix, range) = elements_of(name)?;
+        Ok(List {
+            tree,
+            prefix,
+            range,
+        })
+    }
+
+    /// push_back appends an element to the end of the list.
+    pub fn push_back(&mut self, element: &str) -> Result<(), Error> {
+        let position = match self.last()? {
+            Some(kv) => self.position_of(&kv)?.checked_add(1),
+            None => Some(FIRST_POSITION),
+        };
+        self.insert_at(position, element)
+    }
+
+    /// push_front prepends an element to the start of the list.
+    pub fn push_front(&mut self, element: &str) -> Result<(), Error> {
+        let position = match self.first()? {
+            Some(kv) => self.position_of(&kv)?.checked_sub(1),
+            None => Some(FIRST_POSITION),
+        };
+        self.insert_at(position, element)
+    }
+
+    /// pop_back removes and returns the last element of the list, if any.
+    pub fn pop_back(&mut self) -> Result<Option<String>, Error> {
+        match self.last()? {
+            Some(kv) => {
+                self.tree.delete(Key(kv.key))?;
+                Ok(Some(kv.value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// pop_front removes and returns the first element of the list, if any.
+    pub fn pop_front(&mut self) -> Result<Option<String>, Error> {
+        let prefix = &self.prefix;
+        let popped = self.tree.pop_first_if(|key, _| key.starts_with(prefix))?;
+        Ok(popped.map(|kv| kv.value))
+    }
+
+    /// items returns the elements of the list, in order.
+    pub fn items(&mut self) -> Result<Vec<String>, Error> {
+        let pairs = self.tree.range(self.range.clone())?;
+        Ok(pairs.into_iter().map(|kv| kv.value).collect())
+    }
+
+    fn first(&mut self) -> Result<Option<KeyValuePair>, Error> {
+        let prefix = &self.prefix;
+        self.tree
+            .first_if(&mut |key: &str, _: &str| key.starts_with(prefix))
+    }
+
+    fn last(&mut self) -> Result<Option<KeyValuePair>, Error> {
+        Ok(self.tree.range(self.range.clone())?.pop())
+    }
+
+    fn position_of(&self, kv: &KeyValuePair) -> Result<u64, Error> {
+        from_digits(&kv.key[self.prefix.len()..])
+    }
+
+    /// insert_at writes an element at a position, None standing for a position past
+    /// either end of the range of positions.
+    fn insert_at(&mut self, position: Option<u64>, element: &str) -> Result<(), Error> {
+        match position {
+            Some(position) if position < 1 << (6 * POSITION_DIGITS) => {
+                let key = format!("{}{}", self.prefix, to_digits(position, POSITION_DIGITS));
+                self.tree
+                    .insert(KeyValuePair::new(key, element.to_string()))
+            }
+            _ => Err(Error::KeyOverflowError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn collections_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::collection::{List, Set};
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/collections")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/collections/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("tags".to_string(), "plain".to_string()))?;
+
+        let mut tags = Set::new(&mut btree, "tags")?;
+        assert!(tags.add("red")?);
+        assert!(tags.add("blue")?);
+        assert!(!tags.add("red")?);
+        assert!(tags.contains("blue")?);
+        assert!(tags.remove("blue")?);
+        assert!(!tags.remove("blue")?);
+        assert!(!tags.contains("blue")?);
+        assert_eq!(tags.members()?, vec!["red"]);
+
+        let mut queue = List::new(&mut btree, "q")?;
+        assert_eq!(queue.pop_front()?, None);
+        for i in 0..10 {
+            queue.push_back(&i.to_string())?;
+        }
+        queue.push_front("first")?;
+        assert_eq!(queue.pop_front()?, Some("first".to_string()));
+        assert_eq!(queue.pop_back()?, Some("9".to_string()));
+        assert_eq!(queue.pop_front()?, Some("0".to_string()));
+        assert_eq!(queue.items()?, vec!["1", "2", "3", "4", "5", "6", "7", "8"]);
+
+        // The elements live beside the pairs of the tree, which are left untouched.
+        assert_eq!(btree.search("tags".to_string())?.value, "plain");
+        Ok(())
+    }
+}
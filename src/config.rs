@@ -0,0 +1,188 @@
+//! Configuration of trees from a TOML file, see `BTreeBuilder::from_toml`, so deployments
+//! can configure a tree without recompiling:
+//!
+//! ```toml
+//! path = "/var/lib/app/db"
+//! b = 50
+//! open_existing = true
+//! page_size = 4096
+//! max_file_size = 1073741824
+//! node_cache_size = 64
+//!
+//! [durability]
+//! wal_limit = 1048576
+//! lock_lease_ms = 10000
+//!
+//! [logging]
+//! slow_operation_threshold_ms = 50
+//! ```
+use crate::btree::BTreeBuilder;
+use crate::error::Error;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options are the settings of a configuration file, every one of them optional.
+/// Unknown settings are refused, so a misspelled setting does not go unnoticed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    /// The path to the tree file.
+    pub path: Option<PathBuf>,
+    /// The b parameter of a new tree.
+    pub b: Option<usize>,
+    /// The page size, one of `SUPPORTED_PAGE_SIZES`, see `BTreeBuilder::page_size`.
+    pub page_size: Option<usize>,
+    /// The size the tree file may grow to in bytes, see `BTreeBuilder::max_file_size`.
+    pub max_file_size: Option<u64>,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub open_existing: bool,
+    #[serde(default)]
+    pub in_memory: bool,
+    /// The number of pages to cache. Pages are not cached by the tree (they are read through
+    /// the page cache of the operating system), only 0 is accepted.
+    pub cache_size: Option<usize>,
+    /// The number of internal nodes kept decoded, see `BTreeBuilder::node_cache_size`.
+    pub node_cache_size: Option<usize>,
+    #[serde(default)]
+    pub durability: Durability,
+    #[serde(default)]
+    pub logging: Logging,
+    /// The compression of pages, pages are not compressed: only "none" is accepted.
+    pub compression: Option<String>,
+    /// The encryption of pages, pages are not encrypted: only "none" is accepted, rather than
+    /// silently keeping the tree in plain text when encryption was asked for.
+    pub encryption: Option<String>,
+}
+
+/// Durability are the settings of the root log and of the writer lock.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Durability {
+    /// The bound of the size of the root log in bytes, see `BTreeBuilder::wal_limit`.
+    pub wal_limit: Option<u64>,
+    /// Fail writes with `Busy` rather than stall once the log is over its limit.
+    #[serde(default)]
+    pub non_blocking: bool,
+    /// How long the writer lock stays valid without being renewed, in milliseconds.
+    pub lock_lease_ms: Option<u64>,
+}
+
+/// Logging are the settings of the log of slow operations.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Logging {
+    /// Operations taking longer are logged, in milliseconds.
+    pub slow_operation_threshold_ms: Option<u64>,
+}
+
+impl Options {
+    /// parse parses the options of the TOML text of a configuration file.
+    pub fn parse(text: &str) -> Result<Options, Error> {
+        toml::from_str(text).map_err(|e| Error::InvalidConfiguration(e.message().to_string()))
+    }
+
+    /// builder returns a builder of trees following the options,
+    /// refusing the options which cannot be followed by this build.
+    pub fn builder(&self) -> Result<BTreeBuilder, Error> {
+        let unsupported = |setting: &Option<String>| {
+            setting
+                .as_deref()
+                .is_some_and(|setting| !setting.eq_ignore_ascii_case("none"))
+        };
+        if self.cache_size.is_some_and(|pages| pages != 0)
+            || unsupported(&self.compression)
+            || unsupported(&self.encryption)
+        {
+            return Err(Error::InvalidConfiguration(
+                "a page cache, compression or encryption is not supported".to_string(),
+            ));
+        }
+        let mut builder = BTreeBuilder::new()
+            .read_only(self.read_only)
+            .open_existing(self.open_existing)
+            .in_memory(self.in_memory)
+            .non_blocking(self.durability.non_blocking);
+        if let Some(path) = &self.path {
+            builder = builder.path(path);
+        }
+        if let Some(bytes) = self.page_size {
+            builder = builder.page_size(bytes);
+        }
+        if let Some(bytes) = self.max_file_size {
+            builder = builder.max_file_size(bytes);
+        }
+        if let Some(b) = self.b {
+            builder = builder.b_parameter(b);
+        }
+        if let Some(nodes) = self.node_cache_size {
+            builder = builder.node_cache_size(nodes);
+        }
+        if let Some(bytes) = self.durability.wal_limit {
+            builder = builder.wal_limit(bytes);
+        }
+        if let Some(lease) = self.durability.lock_lease_ms {
+            builder = builder.lock_lease(Duration::from_millis(lease));
+        }
+        if let Some(threshold) = self.logging.slow_operation_threshold_ms {
+            builder = builder.slow_operation_threshold(Duration::from_millis(threshold));
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn from_toml_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::config::Options;
+        use crate::node_type::KeyValuePair;
+
+        std::fs::create_dir_all("/tmp/config")?;
+        std::fs::write(
+            "/tmp/config/btree.toml",
+            r#"
+            path = "/tmp/config/db"
+            b = 2
+            page_size = 4096
+            node_cache_size = 16
+            compression = "none"
+
+            [durability]
+            wal_limit = 1048576
+            lock_lease_ms = 5000
+            "#,
+        )?;
+        let builder = BTreeBuilder::from_toml(std::path::Path::new("/tmp/config/btree.toml"))?;
+        let mut btree = builder.build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        assert_eq!(btree.b_parameter(), 2);
+        drop(btree);
+
+        let mut reopened = Options::parse("path = \"/tmp/config/db\"\nopen_existing = true")?
+            .builder()?
+            .build()?;
+        assert_eq!(reopened.search("a".to_string())?.value, "shalom");
+
+        assert!(matches!(
+            Options::parse("b = 2\ncache = 10"),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            Options::parse("b = 2\nin_memory = true\npage_size = 1000")?
+                .builder()?
+                .build(),
+            Err(Error::InvalidPageSize(1000))
+        ));
+        assert!(matches!(
+            Options::parse("encryption = \"aes-256-gcm\"")?.builder(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use crate::error::Error;
+
+/// CsvOptions describes the dialect of CSV data, see `BTree::import_csv`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    delimiter: char,
+    quote: char,
+    has_header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            delimiter: ',',
+            quote: '"',
+            has_header: true,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn new() -> CsvOptions {
+        CsvOptions::default()
+    }
+
+    /// delimiter sets the character separating the fields of a record, ',' by default.
+    pub fn delimiter(mut self, delimiter: char) -> CsvOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// quote sets the character quoting fields, '"' by default. Within a quoted field the
+    /// delimiter and line breaks are taken literally and a doubled quote stands for a quote.
+    pub fn quote(mut self, quote: char) -> CsvOptions {
+        self.quote = quote;
+        self
+    }
+
+    /// has_header sets whether the first record names the columns rather than holding data,
+    /// true by default.
+    pub fn has_header(mut self, has_header: bool) -> CsvOptions {
+        self.has_header = has_header;
+        self
+    }
+
+    pub(crate) fn header(&self) -> bool {
+        self.has_header
+    }
+
+    /// parse splits CSV data into its records, a record ending at every line break
+    /// out of quotes. Empty lines hold no record.
+    pub(crate) fn parse(&self, data: &str) -> Result<Vec<Vec<String>>, Error> {
+        let mut records = Vec::new();
+        let mut record = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        // Whether the field was quoted at all, an empty quoted field still makes a record.
+        let mut was_quoted = false;
+        let mut chars = data.chars().peekable();
+        while let Some(c) = chars.next() {
+            if quoted {
+                if c != self.quote || chars.next_if_eq(&self.quote).is_some() {
+                    field.push(c);
+                } else {
+                    quoted = false;
+                }
+            } else if c == self.quote && field.is_empty() {
+                quoted = true;
+                was_quoted = true;
+            } else if c == self.delimiter {
+                record.push(std::mem::take(&mut field));
+                was_quoted = false;
+            } else if c == '\n' || c == '\r' {
+                if c == '\r' {
+                    chars.next_if_eq(&'\n');
+                }
+                if !record.is_empty() || !field.is_empty() || was_quoted {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                was_quoted = false;
+            } else {
+                field.push(c);
+            }
+        }
+        if quoted {
+            return Err(Error::InvalidFormat("CSV"));
+        }
+        if !record.is_empty() || !field.is_empty() || was_quoted {
+            record.push(field);
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// format formats the fields of a record into a line of CSV,
+    /// quoting the fields holding delimiters, quotes or line breaks.
+    pub(crate) fn format(&self, fields: &[&str]) -> String {
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|field| {
+                if field.contains([self.delimiter, self.quote, '\n', '\r']) {
+                    let quote = self.quote.to_string();
+                    let escaped = field.replace(&quote, &quote.repeat(2));
+                    format!("{}{}{}", self.quote, escaped, self.quote)
+                } else {
+                    field.to_string()
+                }
+            })
+            .collect();
+        fields.join(&self.delimiter.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn csv_import_and_export_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::csv::CsvOptions;
+        use std::io::Cursor;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/csv")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/csv/db"))
+            .b_parameter(2)
+            .build()?;
+        let data = "id,name,city,age\n\
+                    3,dana,haifa,31\n\
+                    1,\"l, j\",tlv,40\r\n\
+                    \n\
+                    2,\"\"\"b\"\"\",\"x\ny\",7\n";
+        let options = CsvOptions::new();
+        assert_eq!(
+            btree.import_csv(Cursor::new(data), 0, &[1, 3], &options)?,
+            3
+        );
+        assert_eq!(btree.search("3".to_string())?.value, "dana,31");
+        assert_eq!(btree.search("1".to_string())?.value, "\"l, j\",40");
+        assert_eq!(btree.search("2".to_string())?.value, "\"\"\"b\"\"\",7");
+
+        let mut out = Vec::new();
+        assert_eq!(btree.export_csv(&mut out, &options)?, 3);
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        assert_eq!(
+            out,
+            "key,value1,value2\n1,\"l, j\",40\n2,\"\"\"b\"\"\",7\n3,dana,31\n"
+        );
+
+        // Other dialects, a missing column is refused.
+        let options = CsvOptions::new()
+            .delimiter(';')
+            .quote('\'')
+            .has_header(false);
+        let data = "4;'x;y'\n5;z\n";
+        assert_eq!(btree.import_csv(Cursor::new(data), 0, &[1], &options)?, 2);
+        assert_eq!(btree.search("4".to_string())?.value, "'x;y'");
+        assert!(btree
+            .import_csv(Cursor::new("6\n"), 0, &[1], &options)
+            .is_err());
+        assert!(btree
+            .import_csv(Cursor::new("6;'open\n"), 0, &[1], &options)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_export_round_trips() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::csv::CsvOptions;
+        use crate::node_type::KeyValuePair;
+        use std::io::Cursor;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/csv_round_trip/copy")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/csv_round_trip/db"))
+            .b_parameter(2)
+            .build()?;
+        let options = CsvOptions::new();
+        // A quote, a delimiter and a line break, in a value of a single field.
+        let data = "key,value\n\
+                    a,\"\"\"q,\n\"\n\
+                    b,plain\n";
+        assert_eq!(btree.import_csv(Cursor::new(data), 0, &[1], &options)?, 2);
+        assert_eq!(btree.search("a".to_string())?.value, "\"\"\"q,\n\"");
+
+        let mut out = Vec::new();
+        assert_eq!(btree.export_csv(&mut out, &options)?, 2);
+        assert_eq!(out, data.as_bytes());
+        let mut copy = BTreeBuilder::new()
+            .path(Path::new("/tmp/csv_round_trip/copy/db"))
+            .b_parameter(2)
+            .build()?;
+        copy.import_csv(Cursor::new(&out), 0, &[1], &options)?;
+        assert!(copy.content_eq(&mut btree)?);
+
+        // Values which are not a record of CSV, or which the header cannot name, are refused.
+        btree.put(KeyValuePair::new("c".to_string(), "\"a,\nb".to_string()))?;
+        assert!(matches!(
+            btree.export_csv(&mut Vec::new(), &options),
+            Err(Error::InvalidFormat(_))
+        ));
+        btree.put(KeyValuePair::new("c".to_string(), "x,y".to_string()))?;
+        assert!(matches!(
+            btree.export_csv(&mut Vec::new(), &options),
+            Err(Error::InvalidFormat(_))
+        ));
+        let mut out = Vec::new();
+        btree.export_csv(&mut out, &options.clone().has_header(false))?;
+        assert!(out.ends_with(b"\nc,x,y\n"));
+        Ok(())
+    }
+}
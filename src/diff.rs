@@ -0,0 +1,172 @@
+use crate::btree::scan_sub_tree;
+use crate::error::Error;
+use crate::node::Node;
+use crate::node_type::{KeyValuePair, NodeType, Offset};
+use crate::pager::Pager;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+/// Difference is a key whose value differs between two trees,
+/// None standing for a key missing from one of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub key: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// diff_sub_trees reports the differences between two subtrees, in key order, until the callback
+/// returns false; it returns whether every difference was reported.
+/// When both trees live in the same file a page reachable from both roots is never modified
+/// (see `ReadView`), so the subtrees under a shared page are identical and skipped.
+pub(crate) fn diff_sub_trees<const N: usize, F>(
+    left: (&Pager<N>, &Offset),
+    right: (&Pager<N>, &Offset),
+    same_file: bool,
+    callback: &mut F,
+) -> Result<bool, Error>
+where
+    F: FnMut(Difference) -> bool,
+{
+    if same_file && left.1 == right.1 {
+        return Ok(true);
+    }
+    if same_file {
+        let left_node = Node::try_from(left.0.get_page(left.1)?)?;
+        let right_node = Node::try_from(right.0.get_page(right.1)?)?;
+        // Nodes splitting their keys the same way are compared child by child.
+        if let (
+            NodeType::Internal(left_children, left_keys),
+            NodeType::Internal(right_children, right_keys),
+        ) = (&left_node.node_type, &right_node.node_type)
+        {
+            if left_keys == right_keys {
+                for (l, r) in left_children.iter().zip(right_children.iter()) {
+                    if !diff_sub_trees((left.0, l), (right.0, r), same_file, callback)? {
+                        return Ok(false);
+                    }
+                }
+                return Ok(true);
+            }
+        }
+    }
+    let mut left_pairs = Vec::new();
+    scan_sub_tree(left.0, left.1, &(..), &mut |kv| left_pairs.push(kv))?;
+    let mut right_pairs = Vec::new();
+    scan_sub_tree(right.0, right.1, &(..), &mut |kv| right_pairs.push(kv))?;
+    Ok(diff_pairs(left_pairs, right_pairs, callback))
+}
+
+/// diff_pairs reports the differences between two sorted sets of pairs, in key order,
+/// until the callback returns false; it returns whether every difference was reported.
+fn diff_pairs<F>(left: Vec<KeyValuePair>, right: Vec<KeyValuePair>, callback: &mut F) -> bool
+where
+    F: FnMut(Difference) -> bool,
+{
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let order = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) => l.key.cmp(&r.key),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return true,
+        };
+        let difference = match order {
+            Ordering::Less => left.next().map(|l| Difference {
+                key: l.key,
+                left: Some(l.value),
+                right: None,
+            }),
+            Ordering::Greater => right.next().map(|r| Difference {
+                key: r.key,
+                left: None,
+                right: Some(r.value),
+            }),
+            Ordering::Equal => match (left.next(), right.next()) {
+                (Some(l), Some(r)) if l.value != r.value => Some(Difference {
+                    key: l.key,
+                    left: Some(l.value),
+                    right: Some(r.value),
+                }),
+                _ => None,
+            },
+        };
+        if let Some(difference) = difference {
+            if !callback(difference) {
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn diff_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::diff::Difference;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/diff/left")?;
+        std::fs::create_dir_all("/tmp/diff/right")?;
+        let mut left = BTreeBuilder::new()
+            .path(Path::new("/tmp/diff/left/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut right = BTreeBuilder::new()
+            .path(Path::new("/tmp/diff/right/db"))
+            .b_parameter(3)
+            .build()?;
+        for i in 0..30 {
+            left.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+            right.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let before = left.read_view()?;
+        left.put(KeyValuePair::new("07".to_string(), "w".to_string()))?;
+        left.delete(Key("12".to_string()))?;
+        right.insert(KeyValuePair::new("30".to_string(), "v".to_string()))?;
+
+        let expected = vec![
+            Difference {
+                key: "07".to_string(),
+                left: Some("w".to_string()),
+                right: Some("v".to_string()),
+            },
+            Difference {
+                key: "12".to_string(),
+                left: None,
+                right: Some("v".to_string()),
+            },
+        ];
+        // Across files every pair is compared.
+        let mut differences = Vec::new();
+        left.diff(&mut right, |d| differences.push(d))?;
+        let mut against_right = expected.clone();
+        against_right.push(Difference {
+            key: "30".to_string(),
+            left: None,
+            right: Some("v".to_string()),
+        });
+        assert_eq!(differences, against_right);
+
+        // Versions of a tree share the subtrees no write touched.
+        let mut differences = Vec::new();
+        left.read_view()?.diff(&before, |d| differences.push(d))?;
+        assert_eq!(differences, expected);
+        let mut differences = Vec::new();
+        before.diff(&before, |d| differences.push(d))?;
+        assert!(differences.is_empty());
+
+        assert!(!left.content_eq(&mut right)?);
+        right.put(KeyValuePair::new("07".to_string(), "w".to_string()))?;
+        right.delete(Key("12".to_string()))?;
+        right.delete(Key("30".to_string()))?;
+        // Trees of different shapes holding the same pairs are equal.
+        assert!(left.content_eq(&mut right)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::Error;
+use crate::shared::SharedBTree;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Durability tracks which of the writes committed to a shared tree were fsynced.
+/// Every committed write is numbered in commit order, a sync of the tree makes
+/// every write numbered up to the number of the last write it covers durable at once.
+#[derive(Default)]
+pub(crate) struct Durability {
+    state: Mutex<DurabilityState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct DurabilityState {
+    /// The number of the last committed write.
+    written: u64,
+    /// The number of the last durable write.
+    synced: u64,
+    /// The wakers of the futures waiting for a write to become durable.
+    waiting: Vec<(u64, Waker)>,
+}
+
+impl Durability {
+    /// written numbers a newly committed write.
+    pub(crate) fn written(&self) -> Result<u64, Error> {
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+        state.written += 1;
+        Ok(state.written)
+    }
+
+    /// last_written returns the number of the last committed write.
+    pub(crate) fn last_written(&self) -> Result<u64, Error> {
+        Ok(self.state.lock().map_err(|_| Error::Poisoned)?.written)
+    }
+
+    /// synced marks every write up to a given number as durable,
+    /// waking up the futures and threads waiting for them.
+    pub(crate) fn synced(&self, seq: u64) -> Result<(), Error> {
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+        state.synced = state.synced.max(seq);
+        let synced = state.synced;
+        let (ready, waiting) = state.waiting.drain(..).partition(|(seq, _)| *seq <= synced);
+        state.waiting = waiting;
+        drop(state);
+        for (_, waker) in ready {
+            waker.wake();
+        }
+        self.synced.notify_all();
+        Ok(())
+    }
+}
+
+/// Durable is a future resolving once a write is durable, that is once a sync of the tree,
+/// possibly triggered by a later write or by a `GroupCommit`, covers the write.
+/// Threads not running an executor can block on it using `wait`.
+pub struct Durable {
+    durability: Arc<Durability>,
+    seq: u64,
+}
+
+impl Durable {
+    pub(crate) fn new(durability: Arc<Durability>, seq: u64) -> Durable {
+        Durable { durability, seq }
+    }
+
+    /// wait blocks until the write is durable.
+    pub fn wait(self) -> Result<(), Error> {
+        let mut state = self.durability.state.lock().map_err(|_| Error::Poisoned)?;
+        while state.synced < self.seq {
+            state = self
+                .durability
+                .synced
+                .wait(state)
+                .map_err(|_| Error::Poisoned)?;
+        }
+        Ok(())
+    }
+}
+
+impl Future for Durable {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match self.durability.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Poll::Ready(Err(Error::Poisoned)),
+        };
+        if state.synced >= self.seq {
+            return Poll::Ready(Ok(()));
+        }
+        state.waiting.push((self.seq, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+/// GroupCommit is a handle to a background thread syncing a shared tree periodically,
+/// so the writes committed in between are made durable by a single fsync.
+pub struct GroupCommit {
+    stop: Sender<()>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl GroupCommit {
+    pub fn start(tree: SharedBTree, interval: Duration) -> GroupCommit {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => tree.sync()?,
+                _ => return tree.sync(),
+            }
+        });
+        GroupCommit { stop, handle }
+    }
+
+    /// stop stops the group commit thread after a last sync,
+    /// returning the error it failed with (if any).
+    pub fn stop(self) -> Result<(), Error> {
+        // The thread might have already exited on error, dropping the receiving end.
+        let _ = self.stop.send(());
+        self.handle.join().map_err(|_| Error::ThreadPanicked)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use std::future::Future;
+
+    /// block_on runs a future to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+        use std::thread::{self, Thread};
+
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn durable_writes_resolve_on_group_commit() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::durability::GroupCommit;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/durable_writes")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/durable_writes/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+
+        // A sync covers every write committed before it.
+        let first = shared.put_durable(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        let second = shared.put_durable(KeyValuePair::new("b".to_string(), "2".to_string()))?;
+        shared.sync()?;
+        block_on(first)?;
+        second.wait()?;
+
+        let group_commit = GroupCommit::start(shared.clone(), Duration::from_millis(5));
+        let durable = shared.put_durable(KeyValuePair::new("c".to_string(), "3".to_string()))?;
+        block_on(durable)?;
+        group_commit.stop()?;
+        assert_eq!(shared.reader().search("c".to_string())?.value, "3");
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use crate::error::Error;
+use crate::page_layout::KEY_SIZE;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Bound;
+
+/// The range of the bytes of encoded fields: printable ASCII, plus one byte padding
+/// ascending strings (sorting before them) and one padding descending ones (sorting after them).
+const MIN_CHAR: u8 = 0x20;
+const MAX_CHAR: u8 = 0x7E;
+const ASCENDING_PAD: u8 = 0x1F;
+const DESCENDING_PAD: u8 = 0x7F;
+
+/// KeyRange is a range of encoded keys, to be scanned with `BTree::range`.
+pub type KeyRange = (Bound<String>, Bound<String>);
+
+/// Direction is the order in which the values of a field sort in the encoded keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+/// FieldKind is the type of a field of a composite key along with the (fixed) width
+/// it takes in the encoded keys, which keeps the fields following it aligned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    /// A string of printable ASCII characters, of up to width characters.
+    Str(usize),
+    /// An unsigned integer of up to width decimal digits.
+    Uint(usize),
+}
+
+/// Field is the value of a field of a composite key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    Str(String),
+    Uint(u64),
+}
+
+/// KeySchema encodes composite keys into tree keys which sort field by field,
+/// each field in its own direction; e.g. keys of a (user ascending, timestamp descending)
+/// schema list the entries of every user latest first.
+#[derive(Clone, Debug, Default)]
+pub struct KeySchema {
+    fields: Vec<(FieldKind, Direction)>,
+}
+
+impl KeySchema {
+    pub fn new() -> KeySchema {
+        KeySchema { fields: Vec::new() }
+    }
+
+    /// field appends a field to the schema.
+    pub fn field(mut self, kind: FieldKind, direction: Direction) -> KeySchema {
+        self.fields.push((kind, direction));
+        self
+    }
+
+    /// encode encodes the values of the fields of the schema into a key.
+    /// A prefix of the fields may be given, for seeking to the first key sharing it.
+    pub fn encode(&self, values: &[Field]) -> Result<String, Error> {
+        if values.len() > self.fields.len() {
+            return Err(Error::InvalidFormat("key fields"));
+        }
+        let mut key = Vec::new();
+        for ((kind, direction), value) in self.fields.iter().zip(values) {
+            let mut raw = match (kind, value) {
+                (FieldKind::Str(width), Field::Str(s)) => {
+                    if s.len() > *width || s.bytes().any(|b| !(MIN_CHAR..=MAX_CHAR).contains(&b)) {
+                        return Err(Error::KeyOverflowError);
+                    }
+                    let pad = match direction {
+                        Direction::Ascending => ASCENDING_PAD,
+                        Direction::Descending => DESCENDING_PAD,
+                    };
+                    let mut raw = s.as_bytes().to_vec();
+                    raw.resize(*width, pad);
+                    raw
+                }
+                (FieldKind::Uint(width), Field::Uint(n)) => {
+                    let digits = format!("{:0width$}", n, width = *width);
+                    if digits.len() > *width {
+                        return Err(Error::KeyOverflowError);
+                    }
+                    digits.into_bytes()
+                }
+                _ => return Err(Error::InvalidFormat("key fields")),
+            };
+            if *direction == Direction::Descending {
+                raw.iter_mut().for_each(invert);
+            }
+            key.extend(raw);
+        }
+        if key.len() > KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        String::from_utf8(key).map_err(|_| Error::UTF8Error)
+    }
+
+    /// decode decodes a key encoded by the schema back into the values of its fields.
+    pub fn decode(&self, key: &str) -> Result<Vec<Field>, Error> {
+        let mut raw = key.as_bytes();
+        let mut values = Vec::with_capacity(self.fields.len());
+        for (kind, direction) in self.fields.iter() {
+            let width = match kind {
+                FieldKind::Str(width) | FieldKind::Uint(width) => *width,
+            };
+            if raw.len() < width {
+                return Err(Error::InvalidFormat("encoded key"));
+            }
+            let mut field = raw[..width].to_vec();
+            raw = &raw[width..];
+            if *direction == Direction::Descending {
+                field.iter_mut().for_each(invert);
+            }
+            let field = String::from_utf8(field).map_err(|_| Error::UTF8Error)?;
+            values.push(match kind {
+                FieldKind::Str(_) => Field::Str(
+                    field
+                        .trim_end_matches([ASCENDING_PAD as char, DESCENDING_PAD as char])
+                        .to_string(),
+                ),
+                FieldKind::Uint(_) => Field::Uint(
+                    field
+                        .parse()
+                        .map_err(|_| Error::InvalidFormat("encoded key"))?,
+                ),
+            });
+        }
+        Ok(values)
+    }
+
+    /// prefix_range returns the range of the keys whose leading fields hold the given values,
+    /// in the order of the schema.
+    pub fn prefix_range(&self, values: &[Field]) -> Result<KeyRange, Error> {
+        let prefix = self.encode(values)?;
+        // Encoded keys are made of ASCII only, which sorts before any other character.
+        let end = format!("{}\u{80}", prefix);
+        Ok((Bound::Included(prefix), Bound::Excluded(end)))
+    }
+}
+
+/// invert reverses the order of a byte of an encoded field, padding included.
+fn invert(b: &mut u8) {
+    *b = ASCENDING_PAD + DESCENDING_PAD - *b;
+}
+
+/// The bits of every coordinate of a point.
+const COORDINATE_BITS: u32 = 16;
+/// The bits of every digit of a base-64 number.
+const DIGIT_BITS: u32 = 6;
+/// The first of the 64 consecutive ASCII characters base-64 numbers are written in.
+const FIRST_DIGIT: u8 = b'0';
+
+/// to_digits writes a number as a fixed-width number of base-64 digits, which sort as numbers do.
+pub(crate) fn to_digits(n: u64, width: usize) -> String {
+    (0..width)
+        .rev()
+        .map(|i| (FIRST_DIGIT + ((n >> (i as u32 * DIGIT_BITS)) & 0x3F) as u8) as char)
+        .collect()
+}
+
+/// from_digits reads a number written by `to_digits`.
+pub(crate) fn from_digits(digits: &str) -> Result<u64, Error> {
+    digits
+        .bytes()
+        .try_fold(0u64, |n, digit| match digit.checked_sub(FIRST_DIGIT) {
+            Some(value) if value < 1 << DIGIT_BITS => Ok(n << DIGIT_BITS | value as u64),
+            _ => Err(Error::InvalidFormat("encoded key")),
+        })
+}
+
+/// ZOrder encodes points of a 2D or 3D grid into keys following the Z-order (Morton) curve,
+/// which interleaves the bits of the coordinates so points close in space
+/// tend to be close in the tree as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZOrder {
+    dims: u32,
+}
+
+impl ZOrder {
+    /// new returns the encoder of a grid of 2 or 3 dimensions.
+    pub fn new(dims: usize) -> Result<ZOrder, Error> {
+        match dims {
+            2 | 3 => Ok(ZOrder { dims: dims as u32 }),
+            _ => Err(Error::Unsupported(
+                "z-order curves of other than 2 or 3 dimensions",
+            )),
+        }
+    }
+
+    /// encode returns the key of a point.
+    pub fn encode(&self, point: &[u16]) -> Result<String, Error> {
+        if point.len() != self.dims as usize {
+            return Err(Error::InvalidFormat("point"));
+        }
+        let mut code = 0u64;
+        for bit in 0..COORDINATE_BITS {
+            for (dim, coordinate) in point.iter().enumerate() {
+                code |= (((coordinate >> bit) & 1) as u64) << (bit * self.dims + dim as u32);
+            }
+        }
+        Ok(self.key_of(code))
+    }
+
+    /// decode returns the point of a key.
+    pub fn decode(&self, key: &str) -> Result<Vec<u16>, Error> {
+        if key.len() != self.key_len() {
+            return Err(Error::InvalidFormat("encoded key"));
+        }
+        let code = from_digits(key)?;
+        let mut point = vec![0u16; self.dims as usize];
+        for bit in 0..COORDINATE_BITS {
+            for (dim, coordinate) in point.iter_mut().enumerate() {
+                *coordinate |= (((code >> (bit * self.dims + dim as u32)) & 1) as u16) << bit;
+            }
+        }
+        Ok(point)
+    }
+
+    /// range_query decomposes the bounding box between two corners (inclusive) into
+    /// the ranges of keys covering exactly the points inside the box, in key order.
+    pub fn range_query(&self, min: &[u16], max: &[u16]) -> Result<Vec<KeyRange>, Error> {
+        let dims = self.dims as usize;
+        if min.len() != dims || max.len() != dims || min.iter().zip(max).any(|(lo, hi)| lo > hi) {
+            return Err(Error::InvalidFormat("range query"));
+        }
+        let mut codes = Vec::new();
+        self.decompose(min, max, &vec![0; dims], COORDINATE_BITS, 0, &mut codes);
+        Ok(codes
+            .into_iter()
+            .map(|(first, last)| {
+                (
+                    Bound::Included(self.key_of(first)),
+                    Bound::Included(self.key_of(last)),
+                )
+            })
+            .collect())
+    }
+
+    /// decompose appends to codes the ranges of the codes of the points of the box
+    /// inside the cell of 2^level points along every dimension starting at a corner
+    /// and at a code, merging adjacent ranges.
+    fn decompose(
+        &self,
+        min: &[u16],
+        max: &[u16],
+        corner: &[u32],
+        level: u32,
+        code: u64,
+        codes: &mut Vec<(u64, u64)>,
+    ) {
+        let side = 1u32 << level;
+        let mut bounds = corner.iter().zip(min.iter().zip(max));
+        if bounds
+            .clone()
+            .any(|(c, (lo, hi))| c + side - 1 < *lo as u32 || *c > *hi as u32)
+        {
+            return;
+        }
+        if bounds.all(|(c, (lo, hi))| *c >= *lo as u32 && c + side - 1 <= *hi as u32) {
+            let last = code + ((1u64 << (level * self.dims)) - 1);
+            match codes.last_mut() {
+                Some((_, end)) if *end + 1 == code => *end = last,
+                _ => codes.push((code, last)),
+            }
+            return;
+        }
+        let half = side / 2;
+        for child in 0..1u64 << self.dims {
+            let child_corner: Vec<u32> = corner
+                .iter()
+                .enumerate()
+                .map(|(dim, c)| c + ((child >> dim) & 1) as u32 * half)
+                .collect();
+            let child_code = code + (child << ((level - 1) * self.dims));
+            self.decompose(min, max, &child_corner, level - 1, child_code, codes);
+        }
+    }
+
+    /// key_len returns the length of the keys, just enough characters for all the bits of a code.
+    fn key_len(&self) -> usize {
+        ((COORDINATE_BITS * self.dims).div_ceil(DIGIT_BITS)) as usize
+    }
+
+    /// key_of writes the key of a code.
+    fn key_of(&self, code: u64) -> String {
+        to_digits(code, self.key_len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn composite_keys_sort_field_by_field() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::encoding::{Direction, Field, FieldKind, KeySchema};
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        let schema = KeySchema::new()
+            .field(FieldKind::Str(4), Direction::Ascending)
+            .field(FieldKind::Uint(4), Direction::Descending);
+        let key = |user: &str, ts| vec![Field::Str(user.to_string()), Field::Uint(ts)];
+
+        std::fs::create_dir_all("/tmp/composite_keys")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/composite_keys/db"))
+            .b_parameter(2)
+            .build()?;
+        for (user, ts) in [("bob", 3), ("al", 7), ("bob", 12), ("al", 1), ("bob", 5)] {
+            let kv = KeyValuePair::new(schema.encode(&key(user, ts))?, ts.to_string());
+            btree.insert(kv)?;
+        }
+
+        let entries = btree.range(schema.prefix_range(&[Field::Str("bob".to_string())])?)?;
+        let decoded = entries
+            .iter()
+            .map(|kv| schema.decode(&kv.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(decoded, vec![key("bob", 12), key("bob", 5), key("bob", 3)]);
+        // Users sort ascending, shorter names first.
+        assert_eq!(schema.decode(&btree.range(..)?[0].key)?, key("al", 7));
+
+        assert!(matches!(
+            schema.encode(&key("carol", 1)),
+            Err(Error::KeyOverflowError)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn z_order_range_queries_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::encoding::ZOrder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/z_order")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/z_order/db"))
+            .b_parameter(2)
+            .build()?;
+        let grid = ZOrder::new(2)?;
+        for x in 0..8 {
+            for y in 0..8 {
+                btree.insert(KeyValuePair::new(
+                    grid.encode(&[x, y])?,
+                    format!("{},{}", x, y),
+                ))?;
+            }
+        }
+        assert_eq!(grid.decode(&grid.encode(&[513, 7])?)?, vec![513, 7]);
+
+        let mut found = Vec::new();
+        for range in grid.range_query(&[2, 3], &[4, 6])? {
+            for kv in btree.range(range)? {
+                found.push(grid.decode(&kv.key)?);
+            }
+        }
+        found.sort();
+        let expected: Vec<Vec<u16>> = (2..=4)
+            .flat_map(|x| (3..=6).map(move |y| vec![x, y]))
+            .collect();
+        assert_eq!(found, expected);
+
+        let space = ZOrder::new(3)?;
+        assert_eq!(
+            space.decode(&space.encode(&[1, 65535, 300])?)?,
+            vec![1, 65535, 300]
+        );
+        assert_eq!(space.range_query(&[0, 0, 0], &[1, 1, 1])?.len(), 1);
+        Ok(())
+    }
+}
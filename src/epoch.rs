@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// UNPINNED marks a participant which is not reading at the moment.
+const UNPINNED: u64 = u64::MAX;
+
+/// Participant is a reader taking part in the epoch-based reclamation.
+pub struct Participant(Arc<AtomicU64>);
+
+/// Epochs implements epoch-based reclamation of objects shared with lock-free readers.
+/// Readers pin the current epoch for the duration of every read and the writer advances
+/// the epoch every time it retires an object (makes it unreachable to new readers).
+/// A retired object is reclaimed (dropped) only once every reader which could have observed it,
+/// that is every reader pinned to the epoch it was retired in or to an earlier one, has unpinned.
+/// Pinning and unpinning are single atomic stores so readers never wait on the writer.
+pub struct Epochs<T> {
+    global: AtomicU64,
+    participants: Mutex<Vec<Participant>>,
+    retired: Mutex<Vec<(u64, T)>>,
+}
+
+impl<T> Epochs<T> {
+    pub fn new() -> Epochs<T> {
+        Epochs {
+            global: AtomicU64::new(0),
+            participants: Mutex::new(Vec::new()),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// register registers a new reader.
+    pub fn register(&self) -> Participant {
+        let pinned = Arc::new(AtomicU64::new(UNPINNED));
+        if let Ok(mut participants) = self.participants.lock() {
+            participants.push(Participant(pinned.clone()));
+        }
+        Participant(pinned)
+    }
+
+    /// unregister stops tracking a reader which will not read anymore.
+    pub fn unregister(&self, participant: &Participant) {
+        if let Ok(mut participants) = self.participants.lock() {
+            participants.retain(|Participant(pinned)| !Arc::ptr_eq(pinned, &participant.0));
+        }
+    }
+
+    /// pin marks the reader as reading in the current epoch,
+    /// it has to be called before loading a shared object.
+    pub fn pin(&self, participant: &Participant) {
+        let epoch = self.global.load(Ordering::SeqCst);
+        participant.0.store(epoch, Ordering::SeqCst);
+    }
+
+    /// unpin marks the reader as done with any shared object it has loaded.
+    pub fn unpin(&self, participant: &Participant) {
+        participant.0.store(UNPINNED, Ordering::SeqCst);
+    }
+
+    /// retire hands over an object which was made unreachable to new readers for reclamation,
+    /// reclaiming any previously retired objects no reader can observe anymore along the way.
+    pub fn retire(&self, object: T) {
+        let epoch = self.global.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut retired) = self.retired.lock() {
+            retired.push((epoch, object));
+        }
+        self.reclaim();
+    }
+
+    /// reclaim drops the retired objects which were retired before the oldest pinned epoch.
+    pub fn reclaim(&self) {
+        let oldest_pinned = match self.participants.lock() {
+            Ok(participants) => participants
+                .iter()
+                .map(|Participant(pinned)| pinned.load(Ordering::SeqCst))
+                .min()
+                .unwrap_or(UNPINNED),
+            Err(_) => return,
+        };
+        if let Ok(mut retired) = self.retired.lock() {
+            retired.retain(|(epoch, _)| *epoch >= oldest_pinned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn retired_objects_outlive_pinned_readers() {
+        use crate::epoch::Epochs;
+        use std::sync::Arc;
+
+        let epochs = Epochs::new();
+        let object = Arc::new(());
+        let reader = epochs.register();
+
+        epochs.pin(&reader);
+        epochs.retire(object.clone());
+        epochs.reclaim();
+        // The reader might still be looking at the object.
+        assert_eq!(Arc::strong_count(&object), 2);
+
+        epochs.unpin(&reader);
+        epochs.reclaim();
+        assert_eq!(Arc::strong_count(&object), 1);
+
+        // Readers pinning after an object was retired cannot observe it.
+        let other = Arc::new(());
+        epochs.retire(other.clone());
+        epochs.pin(&reader);
+        epochs.reclaim();
+        assert_eq!(Arc::strong_count(&other), 1);
+        epochs.unregister(&reader);
+    }
+}
@@ -1,16 +1,224 @@
-#[derive(Debug)]
+use crate::page_layout::{MAX_B_PARAMETER, PAGE_SIZE, SUPPORTED_PAGE_SIZES};
+use alloc::boxed::Box;
+use alloc::string::String;
+
+/// Error is the error of every operation of the crate. Failures of the file system carry
+/// the underlying `std::io::Error` (see `source`), the other variants name the failure.
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    KeyNotFound,
-    KeyAlreadyExists,
+    /// A key (or a tag, or a position of the root log) the operation needs is absent.
+    #[error("key {key:?} not found")]
+    KeyNotFound { key: String },
+    /// A key (or a tag, or an index name) the operation would create is taken.
+    #[error("key {key:?} already exists")]
+    DuplicateKey { key: String },
+    #[error("the tree is not empty")]
+    TreeNotEmpty,
+    /// A broken invariant of the tree, such as a node of an unexpected type.
+    #[error("unexpected error")]
     UnexpectedError,
+    #[error("the key is longer than 10 bytes")]
     KeyOverflowError,
+    #[error("the value is longer than 10 bytes")]
     ValueOverflowError,
+    #[error("failed to read {0} from a page")]
     TryFromSliceError(&'static str),
+    #[error("invalid UTF-8")]
     UTF8Error,
+    #[error("invalid file header")]
+    InvalidHeader,
+    /// The cells of a node outgrow a page, or a write would run past the end of a page.
+    #[error("the page is full")]
+    PageFull,
+    /// A page of the tree file does not hold what it should, naming its offset in the file.
+    #[error("corrupt page at offset {offset}: {reason}")]
+    Corruption { offset: usize, reason: &'static str },
+    /// The tree file ends with pages a crash left torn, see `BTreeBuilder::truncate_torn_pages`.
+    #[error("the file ends with {len} bytes of torn pages from offset {offset}")]
+    TornPages { offset: usize, len: usize },
+    /// A broken invariant found by `BTree::check_invariants`, at the page of an offset.
+    #[error("invariant violated at page {offset}: {message}")]
+    InvariantViolation { offset: usize, message: String },
+    /// An offset past which pages would wrap around the offsets of a file (or of memory),
+    /// such as the end of the page at a corrupt offset or the offset of the next page appended.
+    #[error("offset {offset} overflows the offsets of the file")]
+    OffsetOverflow { offset: usize },
+    /// Appending a page would grow the tree file past its limit, see `BTreeBuilder::max_file_size`.
+    #[error("the tree file is full, it is limited to {limit} bytes")]
+    DatabaseFull { limit: u64 },
+    /// The tree file was written by a build using another format version or page size.
+    #[error("unsupported format version or page size")]
+    UnsupportedVersion,
+    /// The tree file uses a capability this build lacks, see `page_layout::FEATURES_OFFSET`.
+    #[error("the file uses {feature}, which this build does not support")]
+    UnsupportedFeature { feature: String },
+    /// Another writer holds the tree, or the lock of this writer lapsed (see `BTreeBuilder::lock_lease`).
+    #[error("the tree is locked by another writer")]
+    DatabaseLocked,
+    #[error("the tree is open for reading only")]
+    ReadOnly,
+    /// A write failed fatally earlier on, see `BTree::halted`, naming the failure.
+    #[error("the tree takes no more writes following a failure: {cause}")]
+    Halted { cause: String },
+    #[error("timed out")]
+    Timeout,
+    /// An operation stopped on request before it finished, such as a background compaction.
+    #[error("interrupted")]
+    Interrupted,
+    #[error("deadlock")]
+    Deadlock,
+    #[error("the transaction conflicts with a concurrent one")]
+    Conflict,
+    #[error("the root log is over its limit")]
+    Busy,
+    #[error("invalid bucket name")]
+    InvalidBucketName,
+    #[error("the value of a unique index is taken")]
+    UniqueViolation,
+    /// A b parameter whose full nodes do not fit a page, see `page_layout::MAX_B_PARAMETER`.
+    #[error(
+        "invalid b parameter {0}, b must be between 2 and {max} for {PAGE_SIZE} byte pages",
+        max = MAX_B_PARAMETER
+    )]
+    InvalidBParameter(usize),
+    /// A page size the tree is not built for, see `page_layout::SUPPORTED_PAGE_SIZES`.
+    #[error("invalid page size {0}, pages are of {sizes:?} bytes", sizes = SUPPORTED_PAGE_SIZES)]
+    InvalidPageSize(usize),
+    /// A tree file of pages of another size than the tree opening it.
+    #[error("the tree file has pages of {found} bytes, not {expected}")]
+    PageSizeMismatch { expected: usize, found: usize },
+    #[error("no tree file at the path")]
+    InvalidPath,
+    #[error("the path is not writable")]
+    PathNotWritable,
+    #[error("conflicting options: {0}")]
+    ConflictingOptions(&'static str),
+    /// A configuration file which does not parse or asks for what the build does not support.
+    #[error("invalid configuration: {0}")]
+    InvalidConfiguration(String),
+    /// Reading, writing or syncing a file failed.
+    #[cfg(feature = "std")]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// A lock of the tree was poisoned by a thread panicking while holding it.
+    #[error("a lock was poisoned by a panicking thread")]
+    Poisoned,
+    #[error("a background thread panicked")]
+    ThreadPanicked,
+    /// Malformed input, such as an imported dump or an encoded key, naming what it is.
+    #[error("invalid {0}")]
+    InvalidFormat(&'static str),
+    /// An argument a caller may not pass, such as a NULL pointer passed to the C API, naming it.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    /// An operation the tree does not support in its configuration, naming it.
+    #[error("unsupported: {0}")]
+    Unsupported(&'static str),
+    /// A fault a `FaultyStore` was programmed with, or any operation following its crash.
+    #[error("injected fault")]
+    InjectedFault,
+    /// An internal failure of an operation on a key, naming the step of the operation and the
+    /// page (and its level, the root's being 0) it failed at. See `root_cause` for the failure.
+    #[error("{step} at {offset:#x} (level {level}) while {operation} {key:?}: {source}")]
+    Context {
+        operation: &'static str,
+        key: String,
+        step: &'static str,
+        offset: usize,
+        level: usize,
+        source: Box<Error>,
+    },
 }
 
-impl std::convert::From<std::io::Error> for Error {
-    fn from(_e: std::io::Error) -> Error {
-        Error::UnexpectedError
+impl Error {
+    /// in_context wraps an internal failure (of the file, or of a page which does not hold what
+    /// it should) in `Context`. Errors of the caller's making, such as `KeyNotFound` or
+    /// `KeyOverflowError`, and errors already in context are left as they are.
+    #[cfg(feature = "std")]
+    pub(crate) fn in_context(
+        self,
+        operation: &'static str,
+        key: &str,
+        step: &'static str,
+        offset: usize,
+        level: usize,
+    ) -> Error {
+        if !self.is_internal() {
+            return self;
+        }
+        Error::Context {
+            operation,
+            key: String::from(key),
+            step,
+            offset,
+            level,
+            source: Box::new(self),
+        }
+    }
+
+    /// is_internal returns whether an error is an internal failure: of the file, or of a page
+    /// which does not hold what it should (rather than of the caller's making).
+    fn is_internal(&self) -> bool {
+        match self {
+            Error::UnexpectedError
+            | Error::TryFromSliceError(_)
+            | Error::UTF8Error
+            | Error::PageFull
+            | Error::Corruption { .. }
+            | Error::OffsetOverflow { .. }
+            | Error::InjectedFault => true,
+            #[cfg(feature = "std")]
+            Error::Io(_) => true,
+            _ => false,
+        }
+    }
+
+    /// is_fatal returns whether a write failing with an error may have left the tree file
+    /// damaged (or the tree damaged in it), that is on an internal failure or a broken invariant.
+    pub fn is_fatal(&self) -> bool {
+        let cause = self.root_cause();
+        cause.is_internal() || matches!(cause, Error::InvariantViolation { .. })
+    }
+
+    /// root_cause returns the failure an error in `Context` wraps, the error itself otherwise.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root_cause(),
+            e => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn errors_describe_themselves() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use std::error::Error as _;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/errors")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/errors/db"))
+            .b_parameter(2)
+            .build()?;
+        let e = btree.search("a".to_string()).unwrap_err();
+        assert_eq!(e.to_string(), "key \"a\" not found");
+        assert!(e.source().is_none());
+        assert!(matches!(e, Error::KeyNotFound { key } if key == "a"));
+
+        let e = Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(e.to_string(), "I/O error: permission denied");
+        assert!(e
+            .source()
+            .is_some_and(|source| source.is::<std::io::Error>()));
+        let res = BTreeBuilder::new().b_parameter(1).in_memory(true).build();
+        assert_eq!(
+            res.err().map(|e| e.to_string()).as_deref(),
+            Some("invalid b parameter 1, b must be between 2 and 100 for 4096 byte pages")
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,227 @@
+use crate::error::Error;
+use crate::node_type::KeyValuePair;
+use crate::page_layout::PAGE_SIZE;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The magic bytes and format version opening an export file.
+const EXPORT_MAGIC: [u8; 8] = *b"BTREEEXP";
+const EXPORT_VERSION: u64 = 1;
+
+/// write_export writes the pairs of a keyspace into a standalone export file:
+/// the magic bytes, the format version, the name of the keyspace and the number of pairs,
+/// followed by the pairs in order, every string prefixed by its length.
+pub(crate) fn write_export(path: &Path, name: &str, pairs: &[KeyValuePair]) -> Result<(), Error> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(&file);
+    writer.write_all(&EXPORT_MAGIC)?;
+    writer.write_all(&EXPORT_VERSION.to_be_bytes())?;
+    write_string(&mut writer, name)?;
+    writer.write_all(&(pairs.len() as u64).to_be_bytes())?;
+    for kv in pairs {
+        write_string(&mut writer, &kv.key)?;
+        write_string(&mut writer, &kv.value)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    Ok(())
+}
+
+/// read_export reads the name of the keyspace and the pairs of an export file.
+pub(crate) fn read_export(path: &Path) -> Result<(String, Vec<KeyValuePair>), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != EXPORT_MAGIC || read_u64(&mut reader)? != EXPORT_VERSION {
+        return Err(Error::InvalidHeader);
+    }
+    let name = read_string(&mut reader)?;
+    let len = read_u64(&mut reader)?;
+    let mut pairs = Vec::new();
+    for _ in 0..len {
+        let key = read_string(&mut reader)?;
+        let value = read_string(&mut reader)?;
+        pairs.push(KeyValuePair::new(key, value));
+    }
+    Ok((name, pairs))
+}
+
+/// write_dump_record writes a pair as a record of a dump: the length of the key as a
+/// big-endian u32 followed by its bytes, then the length and the bytes of the value.
+pub(crate) fn write_dump_record(writer: &mut impl Write, kv: &KeyValuePair) -> Result<(), Error> {
+    for field in [&kv.key, &kv.value] {
+        let len = u32::try_from(field.len()).map_err(|_| Error::InvalidFormat("dump record"))?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(field.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// read_dump_record reads the next record of a dump, None at its end. A dump ending
+/// in the middle of a record is refused.
+pub(crate) fn read_dump_record(reader: &mut impl Read) -> Result<Option<KeyValuePair>, Error> {
+    let mut len = [0u8; 4];
+    match reader.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut len[1..])?,
+    }
+    let key = read_dump_field(reader, u32::from_be_bytes(len))?;
+    reader.read_exact(&mut len)?;
+    let value = read_dump_field(reader, u32::from_be_bytes(len))?;
+    Ok(Some(KeyValuePair::new(key, value)))
+}
+
+fn read_dump_field(reader: &mut impl Read, len: u32) -> Result<String, Error> {
+    // The bytes of a field are read as they come rather than trusting a corrupt length.
+    let mut raw = Vec::new();
+    reader.take(len as u64).read_to_end(&mut raw)?;
+    if raw.len() != len as usize {
+        return Err(Error::InvalidFormat("dump record"));
+    }
+    String::from_utf8(raw).map_err(|_| Error::UTF8Error)
+}
+
+/// write_json_line writes a pair as a line of JSON: `{"key":"...","value":"..."}`.
+pub(crate) fn write_json_line(writer: &mut impl Write, kv: &KeyValuePair) -> Result<(), Error> {
+    writeln!(
+        writer,
+        "{{\"key\":{},\"value\":{}}}",
+        json_string(&kv.key),
+        json_string(&kv.value)
+    )?;
+    Ok(())
+}
+
+/// read_json_line reads a pair off a line of JSON: an object of the string
+/// fields "key" and "value" in any order, any other field is refused.
+pub(crate) fn read_json_line(line: &str) -> Result<KeyValuePair, Error> {
+    let mut chars = line.trim().chars().peekable();
+    let (mut key, mut value) = (None, None);
+    expect(&mut chars, '{')?;
+    loop {
+        let field = parse_json_string(&mut chars)?;
+        expect(&mut chars, ':')?;
+        let slot = match field.as_str() {
+            "key" => &mut key,
+            "value" => &mut value,
+            _ => return Err(Error::InvalidFormat("JSON line")),
+        };
+        *slot = Some(parse_json_string(&mut chars)?);
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => break,
+            _ => return Err(Error::InvalidFormat("JSON line")),
+        }
+    }
+    skip_whitespace(&mut chars);
+    match (key, value, chars.next()) {
+        (Some(key), Some(value), None) => Ok(KeyValuePair::new(key, value)),
+        _ => Err(Error::InvalidFormat("JSON line")),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Chars, expected: char) -> Result<(), Error> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        _ => Err(Error::InvalidFormat("JSON line")),
+    }
+}
+
+fn parse_json_string(chars: &mut Chars) -> Result<String, Error> {
+    expect(chars, '"')?;
+    let mut out = String::new();
+    loop {
+        match chars.next().ok_or(Error::InvalidFormat("JSON line"))? {
+            '"' => return Ok(out),
+            '\\' => match chars.next().ok_or(Error::InvalidFormat("JSON line"))? {
+                '"' => out.push('"'),
+                '\\' => out.push('\\'),
+                '/' => out.push('/'),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'u' => {
+                    let high = parse_hex4(chars)?;
+                    let code = if (0xD800..0xDC00).contains(&high) {
+                        // A surrogate pair.
+                        expect(chars, '\\')?;
+                        expect(chars, 'u')?;
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(Error::UTF8Error);
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        high
+                    };
+                    out.push(char::from_u32(code).ok_or(Error::UTF8Error)?);
+                }
+                _ => return Err(Error::InvalidFormat("JSON line")),
+            },
+            c => out.push(c),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Chars) -> Result<u32, Error> {
+    let hex: String = chars.take(4).collect();
+    if hex.len() != 4 {
+        return Err(Error::InvalidFormat("JSON line"));
+    }
+    u32::from_str_radix(&hex, 16).map_err(|_| Error::InvalidFormat("JSON line"))
+}
+
+pub(crate) fn write_string(writer: &mut impl Write, s: &str) -> Result<(), Error> {
+    writer.write_all(&(s.len() as u64).to_be_bytes())?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut raw = [0u8; 8];
+    reader.read_exact(&mut raw)?;
+    Ok(u64::from_be_bytes(raw))
+}
+
+pub(crate) fn read_string(reader: &mut impl Read) -> Result<String, Error> {
+    let len =
+        usize::try_from(read_u64(reader)?).map_err(|_| Error::InvalidFormat("encoded string"))?;
+    // No string of a tree outgrows a page, a longer one is corrupt.
+    if len > PAGE_SIZE {
+        return Err(Error::InvalidHeader);
+    }
+    let mut raw = vec![0u8; len];
+    reader.read_exact(&mut raw)?;
+    String::from_utf8(raw).map_err(|_| Error::UTF8Error)
+}
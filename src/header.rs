@@ -0,0 +1,283 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+use crate::page_layout::{
+    B_PARAMETER_OFFSET, CATALOG_LEN_OFFSET, CATALOG_OFFSET, FEATURES_OFFSET, FEATURE_BPLUS_LAYOUT,
+    FEATURE_NAMES, FORMAT_VERSION, FORMAT_VERSION_OFFSET, HEADER_PAGE_SIZE_OFFSET, KEY_SIZE, MAGIC,
+    MAGIC_OFFSET, MAGIC_SIZE, MAX_BUCKETS, MAX_SEQUENCES, MAX_TAGS, PAGE_SIZE,
+    REPLICATED_LSN_OFFSET, SEQUENCES_LEN_OFFSET, SEQUENCES_OFFSET, SEQUENCE_SIZE,
+    SUPPORTED_FEATURES, TAGS_LEN_OFFSET, TAGS_OFFSET, TAG_SIZE,
+};
+use crate::pager::Pager;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::str;
+
+/// Header describes the tree file, it is persisted in the first page of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The b parameter the tree was built with.
+    pub b: usize,
+    /// The bucket catalog: the names of the buckets of the file, in order of creation.
+    pub buckets: Vec<String>,
+    /// The sequences of the file along with the last value each of them generated.
+    pub sequences: Vec<(String, u64)>,
+    /// The tags of the file along with the roots they name, see `BTree::tag`.
+    pub tags: Vec<(String, Offset)>,
+    /// The LSN of the last replication record applied to the file, see `BTree::apply_replication_record`.
+    pub replicated_lsn: u64,
+    /// The feature bits of the file, see `page_layout::FEATURES_OFFSET`.
+    pub features: u64,
+}
+
+impl Header {
+    pub fn new(b: usize) -> Header {
+        Header {
+            b,
+            buckets: Vec::new(),
+            sequences: Vec::new(),
+            tags: Vec::new(),
+            replicated_lsn: 0,
+            features: FEATURE_BPLUS_LAYOUT,
+        }
+    }
+
+    /// feature_names returns the names of the feature bits set in the header.
+    pub fn feature_names(&self) -> Vec<String> {
+        (0..u64::BITS)
+            .map(|bit| 1 << bit)
+            .filter(|feature| self.features & feature != 0)
+            .map(feature_name)
+            .collect()
+    }
+}
+
+/// feature_name returns the name of a feature bit, naming unknown bits by their position.
+fn feature_name(feature: u64) -> String {
+    match FEATURE_NAMES.iter().find(|(bit, _)| *bit == feature) {
+        Some((_, name)) => name.to_string(),
+        None => format!("feature bit {}", feature.trailing_zeros()),
+    }
+}
+
+/// corrupt is the error of a header page holding something else than a header,
+/// the header being the first page of the file.
+fn corrupt(reason: &'static str) -> Error {
+    Error::Corruption { offset: 0, reason }
+}
+
+/// Serializes the header into a page of `PAGE_SIZE` bytes, see `Header::encode`.
+impl TryFrom<&Header> for Page {
+    type Error = Error;
+    fn try_from(header: &Header) -> Result<Page, Error> {
+        header.encode()
+    }
+}
+
+/// Deserializes the header from a page of `PAGE_SIZE` bytes, see `Header::decode`.
+impl TryFrom<&Page> for Header {
+    type Error = Error;
+    fn try_from(page: &Page) -> Result<Header, Error> {
+        Header::decode(page)
+    }
+}
+
+/// page_size returns the page size the header of a tree file records. Its first `PAGE_SIZE` bytes
+/// are read, the smallest page size supported, so the header page of any file starts with them.
+pub fn page_size(path: &Path) -> Result<usize, Error> {
+    let page = Pager::<PAGE_SIZE>::open_read_only(path)?.get_page(&Offset(0))?;
+    if page.get_ptr_from_offset(MAGIC_OFFSET, MAGIC_SIZE)? != MAGIC {
+        return Err(corrupt("no magic bytes"));
+    }
+    page.get_value_from_offset(HEADER_PAGE_SIZE_OFFSET)
+}
+
+impl Header {
+    /// encode serializes the header into a page, stamping it with the magic bytes,
+    /// the format version and the size of the page.
+    pub fn encode<const N: usize>(&self) -> Result<Page<N>, Error> {
+        let header = self;
+        let mut page = Page::new([0x00; N]);
+        page.write_bytes_at_offset(&MAGIC, MAGIC_OFFSET, MAGIC_SIZE)?;
+        page.write_value_at_offset(FORMAT_VERSION_OFFSET, FORMAT_VERSION)?;
+        page.write_value_at_offset(HEADER_PAGE_SIZE_OFFSET, N)?;
+        page.write_value_at_offset(B_PARAMETER_OFFSET, header.b)?;
+        if header.buckets.len() > MAX_BUCKETS {
+            return Err(Error::Unsupported("more than 64 buckets"));
+        }
+        page.write_value_at_offset(CATALOG_LEN_OFFSET, header.buckets.len())?;
+        for (idx, name) in header.buckets.iter().enumerate() {
+            let raw = name.as_bytes();
+            if raw.len() > KEY_SIZE {
+                return Err(Error::KeyOverflowError);
+            }
+            page.write_bytes_at_offset(raw, CATALOG_OFFSET + idx * KEY_SIZE, raw.len())?;
+        }
+        if header.sequences.len() > MAX_SEQUENCES {
+            return Err(Error::Unsupported("more than 128 sequences"));
+        }
+        page.write_value_at_offset(SEQUENCES_LEN_OFFSET, header.sequences.len())?;
+        for (idx, (name, value)) in header.sequences.iter().enumerate() {
+            let raw = name.as_bytes();
+            if raw.len() > KEY_SIZE {
+                return Err(Error::KeyOverflowError);
+            }
+            let offset = SEQUENCES_OFFSET + idx * SEQUENCE_SIZE;
+            page.write_bytes_at_offset(raw, offset, raw.len())?;
+            page.write_value_at_offset(offset + KEY_SIZE, *value as usize)?;
+        }
+        if header.tags.len() > MAX_TAGS {
+            return Err(Error::Unsupported("more than 32 tags"));
+        }
+        page.write_value_at_offset(TAGS_LEN_OFFSET, header.tags.len())?;
+        for (idx, (name, root)) in header.tags.iter().enumerate() {
+            let raw = name.as_bytes();
+            if raw.len() > KEY_SIZE {
+                return Err(Error::KeyOverflowError);
+            }
+            let offset = TAGS_OFFSET + idx * TAG_SIZE;
+            page.write_bytes_at_offset(raw, offset, raw.len())?;
+            page.write_value_at_offset(offset + KEY_SIZE, root.0)?;
+        }
+        page.write_value_at_offset(REPLICATED_LSN_OFFSET, header.replicated_lsn as usize)?;
+        page.write_value_at_offset(FEATURES_OFFSET, header.features as usize)?;
+        Ok(page)
+    }
+
+    /// decode deserializes the header from a page, refusing pages which were not written
+    /// as a header by a build using the same format version, and pages of another size.
+    pub fn decode<const N: usize>(page: &Page<N>) -> Result<Header, Error> {
+        if page.get_ptr_from_offset(MAGIC_OFFSET, MAGIC_SIZE)? != MAGIC {
+            return Err(corrupt("no magic bytes"));
+        }
+        if page.get_value_from_offset(FORMAT_VERSION_OFFSET)? != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+        let page_size = page.get_value_from_offset(HEADER_PAGE_SIZE_OFFSET)?;
+        if page_size != N {
+            return Err(Error::PageSizeMismatch {
+                expected: N,
+                found: page_size,
+            });
+        }
+        // The lowest feature bit this build lacks is named.
+        let features = page.get_value_from_offset(FEATURES_OFFSET)? as u64;
+        let unsupported = features & !SUPPORTED_FEATURES;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeature {
+                feature: feature_name(1 << unsupported.trailing_zeros()),
+            });
+        }
+        let b = page.get_value_from_offset(B_PARAMETER_OFFSET)?;
+        if !(2..=Page::<N>::MAX_B_PARAMETER).contains(&b) {
+            return Err(corrupt("a b parameter whose nodes do not fit a page"));
+        }
+        let num_buckets = page.get_value_from_offset(CATALOG_LEN_OFFSET)?;
+        if num_buckets > MAX_BUCKETS {
+            return Err(corrupt("too many buckets"));
+        }
+        let mut buckets = Vec::with_capacity(num_buckets);
+        for idx in 0..num_buckets {
+            let offset = CATALOG_OFFSET + idx * KEY_SIZE;
+            buckets.push(page.get_str_from_offset(
+                offset,
+                KEY_SIZE,
+                "a bucket name not in UTF-8",
+            )?);
+        }
+        let num_sequences = page.get_value_from_offset(SEQUENCES_LEN_OFFSET)?;
+        if num_sequences > MAX_SEQUENCES {
+            return Err(corrupt("too many sequences"));
+        }
+        let mut sequences = Vec::with_capacity(num_sequences);
+        for idx in 0..num_sequences {
+            let offset = SEQUENCES_OFFSET + idx * SEQUENCE_SIZE;
+            let name =
+                page.get_str_from_offset(offset, KEY_SIZE, "a sequence name not in UTF-8")?;
+            let value = page.get_value_from_offset(offset + KEY_SIZE)? as u64;
+            sequences.push((name, value));
+        }
+        let num_tags = page.get_value_from_offset(TAGS_LEN_OFFSET)?;
+        if num_tags > MAX_TAGS {
+            return Err(corrupt("too many tags"));
+        }
+        let mut tags = Vec::with_capacity(num_tags);
+        for idx in 0..num_tags {
+            let offset = TAGS_OFFSET + idx * TAG_SIZE;
+            let name = page.get_str_from_offset(offset, KEY_SIZE, "a tag name not in UTF-8")?;
+            let root = Offset(page.get_value_from_offset(offset + KEY_SIZE)?);
+            tags.push((name, root));
+        }
+        Ok(Header {
+            b,
+            buckets,
+            sequences,
+            tags,
+            replicated_lsn: page.get_value_from_offset(REPLICATED_LSN_OFFSET)? as u64,
+            features,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn header_to_page_and_back_works() -> Result<(), Error> {
+        use crate::header::Header;
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::page_layout::{
+            FEATURES_OFFSET, FEATURE_BPLUS_LAYOUT, FEATURE_BUCKETS, FEATURE_COMPRESSION,
+            FEATURE_ENCRYPTION, FORMAT_VERSION, FORMAT_VERSION_OFFSET, MAX_B_PARAMETER, PAGE_SIZE,
+        };
+        use std::convert::TryFrom;
+
+        let mut header = Header::new(7);
+        let page = Page::try_from(&header)?;
+        assert_eq!(Header::try_from(&page)?, header);
+
+        header.buckets = vec!["users".to_string(), "orders".to_string()];
+        header.sequences = vec![("orders".to_string(), 42)];
+        header.tags = vec![("v1".to_string(), Offset(8192))];
+        header.replicated_lsn = 7;
+        let page = Page::try_from(&header)?;
+        assert_eq!(Header::try_from(&page)?, header);
+
+        let res = Header::try_from(&Page::new([0x00; PAGE_SIZE]));
+        assert!(matches!(res, Err(Error::Corruption { offset: 0, .. })));
+        let mut page = Page::try_from(&header)?;
+        page.write_value_at_offset(FORMAT_VERSION_OFFSET, FORMAT_VERSION + 1)?;
+        assert!(matches!(
+            Header::try_from(&page),
+            Err(Error::UnsupportedVersion)
+        ));
+        let page = Page::try_from(&Header::new(MAX_B_PARAMETER + 1))?;
+        assert!(matches!(
+            Header::try_from(&page),
+            Err(Error::Corruption { offset: 0, .. })
+        ));
+
+        // Files using a capability the build lacks are refused, naming it.
+        header.features |= FEATURE_BUCKETS;
+        let page = Page::try_from(&header)?;
+        assert_eq!(
+            Header::try_from(&page)?.feature_names(),
+            ["the B+ layout", "buckets"]
+        );
+        for (features, feature) in [
+            (FEATURE_ENCRYPTION | FEATURE_COMPRESSION, "compression"),
+            (1 << 40, "feature bit 40"),
+        ] {
+            header.features = FEATURE_BPLUS_LAYOUT | features;
+            let res = Header::try_from(&Page::try_from(&header)?);
+            assert!(matches!(res, Err(Error::UnsupportedFeature { feature: f }) if f == feature));
+        }
+        // Files written before feature bits were recorded use none.
+        let mut page = Page::try_from(&Header::new(7))?;
+        page.write_value_at_offset(FEATURES_OFFSET, 0)?;
+        assert_eq!(Header::try_from(&page)?.features, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use crate::node_type::KeyValuePair;
+use std::ops::Bound;
+
+/// SEPARATOR separates the index key from the primary key in the keys of an index,
+/// it sorts before any printable character so the entries of an index key are contiguous.
+pub(crate) const SEPARATOR: char = '\u{1}';
+
+/// Extractor extracts the index keys of a key-value pair, no keys leave the pair out of the index.
+pub type Extractor = Box<dyn Fn(&str, &str) -> Vec<String> + Send + Sync>;
+
+/// IndexDefinition defines a secondary index of a tree, see `BTree::register_index`.
+pub struct IndexDefinition {
+    pub(crate) name: String,
+    pub(crate) extract: Extractor,
+    pub(crate) unique: bool,
+}
+
+impl IndexDefinition {
+    /// new defines an index named after the bucket holding its entries,
+    /// indexing the pairs of the tree by the key extracted from every pair.
+    pub fn new<F>(name: &str, extract: F) -> IndexDefinition
+    where
+        F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static,
+    {
+        IndexDefinition {
+            name: name.to_string(),
+            extract: Box::new(move |key, value| extract(key, value).into_iter().collect()),
+            unique: false,
+        }
+    }
+
+    /// tokenized defines an inverted index named after the bucket holding its entries,
+    /// indexing every pair of the tree by each of the tokens the tokenizer splits its value into.
+    pub fn tokenized<F>(name: &str, tokenize: F) -> IndexDefinition
+    where
+        F: Fn(&str) -> Vec<String> + Send + Sync + 'static,
+    {
+        IndexDefinition {
+            name: name.to_string(),
+            extract: Box::new(move |_, value| tokenize(value)),
+            unique: false,
+        }
+    }
+
+    /// unique makes the index map every index key to a single primary key,
+    /// a write mapping another primary key to the same index key fails with `UniqueViolation`.
+    pub fn unique(mut self, unique: bool) -> IndexDefinition {
+        self.unique = unique;
+        self
+    }
+
+    /// index_keys returns the distinct index keys of a pair of the tree, in order.
+    pub(crate) fn index_keys(&self, kv: &KeyValuePair) -> Vec<String> {
+        let mut index_keys = (self.extract)(&kv.key, &kv.value);
+        index_keys.sort();
+        index_keys.dedup();
+        index_keys
+    }
+
+    /// entries returns the pairs an index holds for a pair of the tree, one per index key.
+    /// An entry is keyed by the index key followed by the primary key (which keeps the entries
+    /// of different primary keys sharing an index key apart) and holds the primary key.
+    pub(crate) fn entries(&self, kv: &KeyValuePair) -> Vec<KeyValuePair> {
+        self.index_keys(kv)
+            .into_iter()
+            .map(|index_key| {
+                KeyValuePair::new(
+                    format!("{}{}{}", index_key, SEPARATOR, kv.key),
+                    kv.key.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// entries_of returns the range of the keys of the entries of an index key.
+pub(crate) fn entries_of(index_key: &str) -> (Bound<String>, Bound<String>) {
+    let next = char::from(SEPARATOR as u8 + 1);
+    (
+        Bound::Included(format!("{}{}", index_key, SEPARATOR)),
+        Bound::Excluded(format!("{}{}", index_key, next)),
+    )
+}
+
+/// words is a tokenizer for `IndexDefinition::tokenized` splitting a value into
+/// its lowercase alphanumeric words.
+pub fn words(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
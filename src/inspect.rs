@@ -0,0 +1,368 @@
+use crate::error::Error;
+use crate::header::{self, Header};
+use crate::node_type::{NodeType, Offset};
+use crate::page::Page;
+use crate::page_layout::{
+    INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET, KEY_SIZE,
+    LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET, PAGE_SIZE,
+    PARENT_POINTER_OFFSET, PTR_SIZE, VALUE_SIZE,
+};
+use crate::pager::Pager;
+use crate::replication::{Change, ReplicationStream};
+use crate::wal::{WalRecord, RECORD_SIZE};
+use std::io::Write;
+use std::path::Path;
+
+/// The bytes of a line of a hex dump.
+const DUMP_LINE: usize = 16;
+
+/// dump_page writes a hex dump of the page at an offset of a tree file followed by its decoding:
+/// the file header for the first page, otherwise the node header and the cells of the node.
+/// Pages are decoded field by field from their raw bytes, so a corrupted page is shown
+/// as far as it can be rather than refused. Pages are of the page size the header of the file
+/// records, of `PAGE_SIZE` bytes if the header cannot be read.
+pub fn dump_page(path: &Path, offset: usize, out: &mut impl Write) -> Result<(), Error> {
+    let page_size = header::page_size(path).unwrap_or(PAGE_SIZE);
+    with_page_size!(page_size, N => dump_sized_page::<N>(path, offset, out))
+}
+
+/// dump_sized_page dumps a page of a tree file of pages of N bytes, see `dump_page`.
+fn dump_sized_page<const N: usize>(
+    path: &Path,
+    offset: usize,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let len = std::fs::metadata(path)?.len() as usize;
+    if !offset.is_multiple_of(N) || offset.checked_add(N).is_none_or(|end| end > len) {
+        return Err(Error::InvalidFormat("page offset"));
+    }
+    let raw = Pager::<N>::open_read_only(path)?
+        .get_page(&Offset(offset))?
+        .get_data();
+    hex_dump(&raw, offset, out)?;
+    writeln!(out)?;
+    if offset == 0 {
+        match Header::decode(&Page::new(raw)) {
+            Ok(header) => {
+                writeln!(out, "file header")?;
+                writeln!(out, "  b:              {}", header.b)?;
+                writeln!(out, "  buckets:        {:?}", header.buckets)?;
+                writeln!(out, "  sequences:      {:?}", header.sequences)?;
+                let tags: Vec<_> = header
+                    .tags
+                    .iter()
+                    .map(|(name, root)| (name, root.0))
+                    .collect();
+                writeln!(out, "  tags:           {:?}", tags)?;
+                writeln!(out, "  replicated lsn: {}", header.replicated_lsn)?;
+                writeln!(out, "  features:       {:?}", header.feature_names())?;
+            }
+            Err(e) => writeln!(out, "invalid file header: {}", e)?,
+        }
+        return Ok(());
+    }
+
+    let node_type = raw[NODE_TYPE_OFFSET];
+    writeln!(out, "node header")?;
+    writeln!(out, "  is_root:        {:#04x}", raw[IS_ROOT_OFFSET])?;
+    let kind = match node_type {
+        0x01 => "internal",
+        0x02 => "leaf",
+        _ => "unknown",
+    };
+    writeln!(out, "  node type:      {:#04x} ({})", node_type, kind)?;
+    writeln!(
+        out,
+        "  parent offset:  {}",
+        value_at(&raw, PARENT_POINTER_OFFSET)
+    )?;
+    match node_type {
+        0x01 => {
+            let children = value_at(&raw, INTERNAL_NODE_NUM_CHILDREN_OFFSET);
+            writeln!(out, "  children:       {}", children)?;
+            let fit = Page::<N>::MAX_INTERNAL_CHILDREN;
+            let shown = overflow_note(children, fit, out)?;
+            for idx in 0..shown {
+                let at = INTERNAL_NODE_HEADER_SIZE + idx * PTR_SIZE;
+                writeln!(out, "  child #{}:  {}", idx, value_at(&raw, at))?;
+            }
+            let keys_start = INTERNAL_NODE_HEADER_SIZE + shown * PTR_SIZE;
+            for idx in 0..shown.saturating_sub(1) {
+                let key = slot(&raw, keys_start + idx * KEY_SIZE, KEY_SIZE);
+                writeln!(out, "  key #{}:    {:?} ({} bytes)", idx, key, key.len())?;
+            }
+        }
+        0x02 => {
+            let pairs = value_at(&raw, LEAF_NODE_NUM_PAIRS_OFFSET);
+            writeln!(out, "  pairs:          {}", pairs)?;
+            let fit = Page::<N>::MAX_LEAF_PAIRS;
+            for idx in 0..overflow_note(pairs, fit, out)? {
+                let at = LEAF_NODE_HEADER_SIZE + idx * (KEY_SIZE + VALUE_SIZE);
+                let key = slot(&raw, at, KEY_SIZE);
+                let value = slot(&raw, at + KEY_SIZE, VALUE_SIZE);
+                writeln!(
+                    out,
+                    "  pair #{}:   {:?} ({} bytes) => {:?} ({} bytes)",
+                    idx,
+                    key,
+                    key.len(),
+                    value,
+                    value.len()
+                )?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// dump_log writes the records of a root log, one line per commit: its LSN, its timestamp
+/// (milliseconds since the epoch) and the root it committed. Given the tree file of the log,
+/// the root is decoded and the keys every commit put and deleted are listed, found by comparing
+/// its root with the root of the commit preceding it (unknown for the first record of a log
+/// checkpointed since its first commit). A partially written record at the end of the log,
+/// left behind by a crash, is reported rather than refused.
+pub fn dump_log(log: &Path, tree: Option<&Path>, out: &mut impl Write) -> Result<(), Error> {
+    let page_size = tree.and_then(|tree| header::page_size(tree).ok());
+    with_page_size!(page_size.unwrap_or(PAGE_SIZE), N => dump_sized_log::<N>(log, tree, out))
+}
+
+/// dump_sized_log dumps a root log given the tree file of pages of N bytes, see `dump_log`.
+fn dump_sized_log<const N: usize>(
+    log: &Path,
+    tree: Option<&Path>,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let raw = std::fs::read(log)?;
+    let records = raw
+        .chunks_exact(RECORD_SIZE)
+        .map(WalRecord::from_bytes)
+        .collect::<Result<Vec<_>, Error>>()?;
+    let pager = tree.map(Pager::<N>::open_read_only).transpose()?;
+    let mut changes = match (&pager, records.first()) {
+        (Some(pager), Some(first)) => {
+            let (base, following) = match first.lsn {
+                1 => (None, records.clone()),
+                _ => (Some(first.root.clone()), records[1..].to_vec()),
+            };
+            Some(ReplicationStream::new(pager.reader()?, base, following).peekable())
+        }
+        _ => None,
+    };
+    for record in records.iter() {
+        write!(
+            out,
+            "lsn {:<8} at {}  root {}",
+            record.lsn, record.timestamp, record.root.0
+        )?;
+        if let Some(pager) = &pager {
+            match pager.get_page(&record.root).and_then(|page| page.decode()) {
+                Ok(node) => match node.node_type {
+                    NodeType::Internal(children, _) => {
+                        write!(out, " (internal, {} children)", children.len())?
+                    }
+                    NodeType::Leaf(pairs) => write!(out, " (leaf, {} pairs)", pairs.len())?,
+                    NodeType::Unexpected => write!(out, " (unknown)")?,
+                },
+                Err(e) => write!(out, " (unreadable: {:?})", e)?,
+            }
+        }
+        writeln!(out)?;
+        let stream = match changes.as_mut() {
+            Some(stream) => stream,
+            None => continue,
+        };
+        match stream.peek() {
+            Some(Ok(next)) if next.lsn == record.lsn => (),
+            Some(Err(_)) => {
+                if let Some(Err(e)) = stream.next() {
+                    writeln!(out, "  changes unreadable: {}", e)?;
+                }
+                continue;
+            }
+            _ => {
+                writeln!(out, "  changes unknown, the log was checkpointed since")?;
+                continue;
+            }
+        }
+        if let Some(Ok(committed)) = stream.next() {
+            for change in committed.changes.iter() {
+                match change {
+                    Change::Put(kv) => writeln!(out, "  put    {:?} => {:?}", kv.key, kv.value)?,
+                    Change::Delete(key) => writeln!(out, "  delete {:?}", key.0)?,
+                }
+            }
+        }
+    }
+    if let (Some(first), Some(last)) = (records.first(), records.last()) {
+        writeln!(
+            out,
+            "{} records, lsn {} to {}, over {} ms",
+            records.len(),
+            first.lsn,
+            last.lsn,
+            last.timestamp.saturating_sub(first.timestamp)
+        )?;
+    }
+    if !raw.len().is_multiple_of(RECORD_SIZE) {
+        writeln!(
+            out,
+            "a partial record of {} bytes at offset {}",
+            raw.len() % RECORD_SIZE,
+            raw.len() - raw.len() % RECORD_SIZE
+        )?;
+    }
+    if let Some(pager) = &pager {
+        if let Ok(header) = pager
+            .get_page(&Offset(0))
+            .and_then(|page| Header::decode(&page))
+        {
+            writeln!(out, "replicated lsn {}", header.replicated_lsn)?;
+        }
+    }
+    Ok(())
+}
+
+/// hex_dump writes raw bytes as lines of offsets, hex and printable characters,
+/// runs of zeroed lines are shown as a single '*'.
+fn hex_dump(raw: &[u8], base: usize, out: &mut impl Write) -> Result<(), Error> {
+    let mut skipping = false;
+    for (idx, line) in raw.chunks(DUMP_LINE).enumerate() {
+        if idx > 0 && line.iter().all(|b| *b == 0) {
+            if !skipping {
+                writeln!(out, "*")?;
+            }
+            skipping = true;
+            continue;
+        }
+        skipping = false;
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7E => *b as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(
+            out,
+            "{:08x}  {}  |{}|",
+            base + idx * DUMP_LINE,
+            hex.join(" "),
+            text
+        )?;
+    }
+    Ok(())
+}
+
+/// overflow_note notes a count of cells larger than a page fits,
+/// returning the number of cells to show.
+fn overflow_note(count: usize, fit: usize, out: &mut impl Write) -> Result<usize, Error> {
+    if count > fit {
+        writeln!(out, "  (the page fits no more than {} cells)", fit)?;
+    }
+    Ok(count.min(fit))
+}
+
+fn value_at(raw: &[u8], offset: usize) -> usize {
+    let mut bytes = [0u8; PTR_SIZE];
+    bytes.copy_from_slice(&raw[offset..offset + PTR_SIZE]);
+    usize::from_be_bytes(bytes)
+}
+
+/// slot returns the string of a key or value slot, without its zero padding.
+fn slot(raw: &[u8], offset: usize, size: usize) -> String {
+    let bytes = &raw[offset..offset + size];
+    let end = bytes.iter().rposition(|b| *b != 0).map_or(0, |idx| idx + 1);
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn dump_page_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::inspect::dump_page;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/dump_page")?;
+        let path = Path::new("/tmp/dump_page/db");
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        let root = btree.root_offset()?;
+
+        let mut out = Vec::new();
+        dump_page(path, root.0, &mut out)?;
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        assert!(out.contains("(leaf)"));
+        assert!(out.contains("pairs:          2"));
+        assert!(out.contains(r#"pair #1:   "b" (1 bytes) => "hello" (5 bytes)"#));
+
+        let mut out = Vec::new();
+        dump_page(path, 0, &mut out)?;
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        assert!(out.starts_with("00000000  42 54 52 45 45 44 42 00"));
+        assert!(out.contains("b:              2"));
+
+        assert!(dump_page(path, PAGE_SIZE + 1, &mut Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dump_log_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::inspect::dump_log;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::io::Write;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/dump_log")?;
+        let path = Path::new("/tmp/dump_log/db");
+        let log = Path::new("/tmp/dump_log/wal");
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        btree.delete(Key("a".to_string()))?;
+        btree.sync()?;
+
+        let mut out = Vec::new();
+        dump_log(log, Some(path), &mut out)?;
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("lsn 1 "));
+        assert!(lines[0].ends_with("(leaf, 0 pairs)"));
+        assert!(lines[2] == r#"  put    "a" => "shalom""#);
+        assert!(lines[4] == r#"  put    "b" => "hello""#);
+        assert!(lines[5].starts_with("lsn 4 ") && lines[5].ends_with("(leaf, 1 pairs)"));
+        assert!(lines[6] == r#"  delete "a""#);
+        assert!(lines[7].starts_with("4 records, lsn 1 to 4"));
+
+        // A torn record is reported, the records of a checkpointed log have no known changes.
+        btree.checkpoint()?;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(log)?
+            .write_all(&[0x00; 5])?;
+        let mut out = Vec::new();
+        dump_log(log, Some(path), &mut out)?;
+        let out = String::from_utf8(out).map_err(|_| Error::UTF8Error)?;
+        assert!(out.contains("changes unknown"));
+        assert!(out.contains("a partial record of 5 bytes at offset 24"));
+
+        let mut out = Vec::new();
+        dump_log(log, None, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)
+                .map_err(|_| Error::UTF8Error)?
+                .lines()
+                .count(),
+            3
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,105 @@
+//! The tree is built on the format of its pages (the `node`, `node_type` and `page` modules
+//! and the `encoding` of keys) which only needs `alloc`. Everything else, from the tree itself
+//! to the files its pages are kept in, needs the `std` feature (on by default).
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+/// with_page_size! evaluates a body generic over a page size `N` for the page size of
+/// `SUPPORTED_PAGE_SIZES` a size is, `PAGE_SIZE` for a size of none of them.
+#[cfg(feature = "std")]
+macro_rules! with_page_size {
+    ($size:expr, $n:ident => $body:expr) => {{
+        use $crate::page_layout::PAGE_SIZE;
+        match $size {
+            size if size == 2 * PAGE_SIZE => {
+                const $n: usize = 2 * PAGE_SIZE;
+                $body
+            }
+            size if size == 4 * PAGE_SIZE => {
+                const $n: usize = 4 * PAGE_SIZE;
+                $body
+            }
+            _ => {
+                const $n: usize = PAGE_SIZE;
+                $body
+            }
+        }
+    }};
+}
+
+#[cfg(feature = "std")]
+#[macro_use]
+mod trace;
+
+#[cfg(all(feature = "std", feature = "bitmap"))]
+pub mod bitmap;
+#[cfg(feature = "std")]
 pub mod btree;
+#[cfg(feature = "std")]
+pub mod bucket;
+#[cfg(all(feature = "std", feature = "capi"))]
+pub mod capi;
+#[cfg(feature = "std")]
+pub mod checkpoint;
+#[cfg(feature = "std")]
+pub mod collection;
+#[cfg(all(feature = "std", feature = "config"))]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod csv;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod durability;
+pub mod encoding;
+#[cfg(feature = "std")]
+mod epoch;
 pub mod error;
+#[cfg(feature = "std")]
+mod export;
+#[cfg(feature = "std")]
+pub mod header;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+pub mod inspect;
+#[cfg(feature = "std")]
+mod lock;
+#[cfg(feature = "std")]
+pub mod maintenance;
+#[cfg(feature = "std")]
+pub mod map;
+#[cfg(all(feature = "std", feature = "metrics"))]
+pub mod metrics;
 pub mod node;
 pub mod node_type;
 pub mod page;
 mod page_layout;
+#[cfg(feature = "std")]
 mod pager;
+#[cfg(feature = "std")]
+pub mod replication;
+#[cfg(feature = "std")]
+pub mod salvage;
+#[cfg(feature = "std")]
+pub mod shared;
+#[cfg(feature = "std")]
+pub mod slow_log;
+#[cfg(feature = "std")]
+pub mod stats;
+pub mod store;
+#[cfg(feature = "std")]
+pub mod table;
+#[cfg(feature = "std")]
+pub mod transaction;
+#[cfg(feature = "std")]
+pub mod value;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "std")]
+pub mod view;
+#[cfg(feature = "std")]
 mod wal;
+#[cfg(feature = "std")]
+pub mod watch;
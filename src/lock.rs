@@ -0,0 +1,261 @@
+use crate::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// DEFAULT_LEASE is how long a writer lock stays valid without being renewed.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(30);
+
+/// The lock file header: the PID of the holder followed by the time (in milliseconds
+/// since the unix epoch) the lease was last renewed, both 8 bytes big-endian.
+const LOCK_HEADER_SIZE: usize = 16;
+
+/// WriterLock is an advisory lock held by the single process allowed to write to a tree file.
+/// The lock is a file next to the tree file which is created exclusively when the lock
+/// is acquired and removed once the lock is dropped.
+/// The lock file records the PID of its holder and a heartbeat which a background thread
+/// renews as long as the lock is held, a lock whose heartbeat is older than the lease and whose
+/// holder is no longer running was left behind by a crashed writer and is reclaimed by the next
+/// writer. The holder stops writing well before its lease could be found expired, should
+/// renewing it fail or stall (see `is_held`).
+pub struct WriterLock {
+    /// The lock file, none for a tree held in memory.
+    path: Option<PathBuf>,
+    lease: Duration,
+    /// The time the heartbeat was last renewed, 0 once renewing it failed.
+    renewed: Arc<AtomicU64>,
+    stop: Option<Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl WriterLock {
+    pub fn acquire(tree_path: &Path, lease: Duration) -> Result<WriterLock, Error> {
+        let mut path = tree_path.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let mut file = match create(&path) {
+            Err(Error::DatabaseLocked) => {
+                reclaim_if_stale(&path, lease)?;
+                create(&path)?
+            }
+            res => res?,
+        };
+        let renewed = Arc::new(AtomicU64::new(write_header(&mut file)?));
+
+        // Renew the lease a few times per lease period.
+        let (stop, stopped) = mpsc::channel();
+        let heartbeat = {
+            let renewed = renewed.clone();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(lease / 3) {
+                    match write_header(&mut file) {
+                        Ok(now) => renewed.store(now, Ordering::SeqCst),
+                        Err(_) => {
+                            renewed.store(0, Ordering::SeqCst);
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+        Ok(WriterLock {
+            path: Some(path),
+            lease,
+            renewed,
+            stop: Some(stop),
+            heartbeat: Some(heartbeat),
+        })
+    }
+
+    /// in_memory returns the lock of a tree held in memory, which no other process can open.
+    pub fn in_memory() -> WriterLock {
+        WriterLock {
+            path: None,
+            lease: DEFAULT_LEASE,
+            renewed: Arc::new(AtomicU64::new(u64::MAX)),
+            stop: None,
+            heartbeat: None,
+        }
+    }
+
+    /// is_held returns whether the lock is still guaranteed to be held: its heartbeat was renewed
+    /// within the last two thirds of the lease. The remaining third leaves the holder time to finish
+    /// a write before another writer could find the lease expired. A lock whose renewal failed
+    /// is never held again, the tree has to be reopened.
+    pub fn is_held(&self) -> bool {
+        if self.path.is_none() {
+            return true;
+        }
+        let renewed = self.renewed.load(Ordering::SeqCst);
+        renewed != 0
+            && now_millis().saturating_sub(renewed) < (self.lease * 2 / 3).as_millis() as u64
+    }
+
+    /// check_held fails with `DatabaseLocked` once the lock is no longer guaranteed to be held.
+    pub fn check_held(&self) -> Result<(), Error> {
+        match self.is_held() {
+            true => Ok(()),
+            false => Err(Error::DatabaseLocked),
+        }
+    }
+
+    /// fail_renewal stops renewing the heartbeat, as a failure to write the lock file would.
+    #[cfg(test)]
+    pub(crate) fn fail_renewal(&mut self) {
+        drop(self.stop.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+        self.renewed.store(0, Ordering::SeqCst);
+    }
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+        if let Some(path) = self.path.as_ref() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn create(path: &Path) -> Result<File, Error> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(Error::DatabaseLocked),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// write_header writes the header of the lock file, returning the time of the heartbeat written.
+fn write_header(file: &mut File) -> Result<u64, Error> {
+    let now = now_millis();
+    let mut header = [0x00; LOCK_HEADER_SIZE];
+    header[..8].copy_from_slice(&(process::id() as u64).to_be_bytes());
+    header[8..].copy_from_slice(&now.to_be_bytes());
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    Ok(now)
+}
+
+fn read_header(path: &Path) -> Result<[u8; LOCK_HEADER_SIZE], Error> {
+    let mut header = [0x00; LOCK_HEADER_SIZE];
+    File::open(path)?.read_exact(&mut header)?;
+    Ok(header)
+}
+
+/// reclaim_if_stale removes a lock file whose lease expired and whose holder is no longer
+/// running, failing with `DatabaseLocked` if the lock may still be held. The stale file is first
+/// moved aside and checked to still be the one found stale, so two writers racing to reclaim
+/// the same lock cannot remove a lock freshly acquired by the other one.
+fn reclaim_if_stale(path: &Path, lease: Duration) -> Result<(), Error> {
+    // A lock file without a complete header is being created right now.
+    let header = read_header(path).map_err(|_| Error::DatabaseLocked)?;
+    let mut pid = [0x00; 8];
+    pid.copy_from_slice(&header[..8]);
+    let mut heartbeat = [0x00; 8];
+    heartbeat.copy_from_slice(&header[8..]);
+    let age = now_millis().saturating_sub(u64::from_be_bytes(heartbeat));
+    if age < lease.as_millis() as u64 || is_running(u64::from_be_bytes(pid)) {
+        return Err(Error::DatabaseLocked);
+    }
+
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".stale.{}", process::id()));
+    let aside = PathBuf::from(aside);
+    fs::rename(path, &aside).map_err(|_| Error::DatabaseLocked)?;
+    if read_header(&aside)? != header {
+        // Another writer reclaimed the lock in between, put its lock back.
+        let _ = fs::hard_link(&aside, path);
+        let _ = fs::remove_file(&aside);
+        return Err(Error::DatabaseLocked);
+    }
+    fs::remove_file(&aside)?;
+    Ok(())
+}
+
+/// is_running returns whether a process is running, from the processes listed in /proc.
+/// A process whose PID was reused by another one is taken to be running, its lock file
+/// has to be removed by hand.
+#[cfg(target_os = "linux")]
+fn is_running(pid: u64) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Elsewhere processes cannot be listed without libc, a stale lock stands for a crashed writer.
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u64) -> bool {
+    false
+}
+
+/// now_millis returns the current time in milliseconds since the epoch.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn now_millis() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The browser has no clock std can read, records of the log are stamped with the epoch.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn now_millis() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn stale_lock_is_reclaimed() -> Result<(), Error> {
+        use crate::lock::WriterLock;
+        use std::path::Path;
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/stale_lock")?;
+        let path = Path::new("/tmp/stale_lock/db");
+        let lease = Duration::from_millis(300);
+        let lock = WriterLock::acquire(path, lease)?;
+        // A held lock is renewed and so never goes stale.
+        thread::sleep(lease * 2);
+        assert!(matches!(
+            WriterLock::acquire(path, lease),
+            Err(Error::DatabaseLocked)
+        ));
+        drop(lock);
+
+        // Leave a lock file behind, as a crashed writer whose last heartbeat was long ago would.
+        let mut header = [0x00; 16];
+        header[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        std::fs::write("/tmp/stale_lock/db.lock", header)?;
+        let mut lock = WriterLock::acquire(path, lease)?;
+        assert!(lock.is_held());
+        // A holder failing to renew its lease stops holding the lock.
+        lock.fail_renewal();
+        assert!(matches!(lock.check_held(), Err(Error::DatabaseLocked)));
+        drop(lock);
+
+        // A lock whose holder is still running is not reclaimed, however old its heartbeat.
+        if cfg!(target_os = "linux") {
+            header[..8].copy_from_slice(&(std::process::id() as u64).to_be_bytes());
+            std::fs::write("/tmp/stale_lock/db.lock", header)?;
+            assert!(matches!(
+                WriterLock::acquire(path, lease),
+                Err(Error::DatabaseLocked)
+            ));
+            std::fs::remove_file("/tmp/stale_lock/db.lock")?;
+        }
+        Ok(())
+    }
+}
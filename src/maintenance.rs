@@ -0,0 +1,426 @@
+use crate::btree::{build_from_sorted, scan_sub_tree, BTree};
+use crate::error::Error;
+use crate::header::Header;
+use crate::node_type::Offset;
+use crate::page_layout::PAGE_SIZE;
+use crate::pager::Pager;
+use crate::wal::Wal;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Compaction rewrites the trees (the tree and its buckets) reachable from a snapshot
+/// of their roots into a fresh file.
+/// Pages are never modified once a committed root references them (copy-on-write),
+/// so the snapshot can be copied through a separate reader without holding on to the tree.
+pub(crate) struct Compaction<const N: usize = PAGE_SIZE> {
+    snapshot: Vec<Offset>,
+    reader: Pager<N>,
+    target: Pager<N>,
+    b: usize,
+}
+
+/// CompactedTree is the result of a compaction, waiting to be swapped in for the tree file.
+pub(crate) struct CompactedTree<const N: usize = PAGE_SIZE> {
+    pub(crate) snapshot: Vec<Offset>,
+    pub(crate) pager: Pager<N>,
+    pub(crate) roots: Vec<Offset>,
+}
+
+impl<const N: usize> Compaction<N> {
+    pub(crate) fn new(
+        snapshot: Vec<Offset>,
+        reader: Pager<N>,
+        target_path: &Path,
+        header: &Header,
+    ) -> Result<Compaction<N>, Error> {
+        let mut target = Pager::new(target_path)?;
+        target.write_page(header.encode()?)?;
+        Ok(Compaction {
+            snapshot,
+            reader,
+            target,
+            b: header.b,
+        })
+    }
+
+    /// run copies the live pairs of the snapshot into densely packed pages,
+    /// invoking the throttle after every page written. Failing the throttle interrupts the
+    /// compaction; a compaction failing (or interrupted) removes the file it was writing.
+    pub(crate) fn run(
+        mut self,
+        throttle: &mut dyn FnMut() -> Result<(), Error>,
+    ) -> Result<CompactedTree<N>, Error> {
+        match self.copy(throttle) {
+            Ok(roots) => Ok(CompactedTree {
+                snapshot: self.snapshot,
+                pager: self.target,
+                roots,
+            }),
+            Err(e) => {
+                fs::remove_file(self.target.path())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn copy(
+        &mut self,
+        throttle: &mut dyn FnMut() -> Result<(), Error>,
+    ) -> Result<Vec<Offset>, Error> {
+        let mut roots = Vec::with_capacity(self.snapshot.len());
+        for snapshot_root in self.snapshot.iter() {
+            let mut pairs = Vec::new();
+            scan_sub_tree(&self.reader, snapshot_root, &(..), &mut |kv| pairs.push(kv))?;
+            roots.push(build_from_sorted(
+                &mut self.target,
+                self.b,
+                pairs,
+                throttle,
+            )?);
+        }
+        self.target.sync()?;
+        Ok(roots)
+    }
+}
+
+/// compaction_target returns the path of the file a compaction of the tree file at a path writes.
+pub(crate) fn compaction_target(path: &Path) -> PathBuf {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".compact");
+    PathBuf::from(target)
+}
+
+/// compaction_marker returns the path of the marker of a compaction of the tree file at a path,
+/// holding the roots of the compacted file from before it is swapped in until its roots are
+/// committed. Its presence means the swap is to be resumed.
+pub(crate) fn compaction_marker(path: &Path) -> PathBuf {
+    let mut marker = compaction_target(path).into_os_string();
+    marker.push(".roots");
+    PathBuf::from(marker)
+}
+
+/// write_compaction_marker durably writes the marker of a compaction, the number of roots
+/// followed by the roots (as big-endian u64s). It is written aside and renamed into place
+/// so it is either whole or missing.
+pub(crate) fn write_compaction_marker(path: &Path, roots: &[Offset]) -> Result<(), Error> {
+    let marker = compaction_marker(path);
+    let mut written = marker.clone().into_os_string();
+    written.push(".tmp");
+    let mut bytes = Vec::with_capacity((roots.len() + 1) * 8);
+    bytes.extend_from_slice(&(roots.len() as u64).to_be_bytes());
+    for root in roots {
+        bytes.extend_from_slice(&(root.0 as u64).to_be_bytes());
+    }
+    let mut file = fs::File::create(&written)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&written, &marker)?;
+    Ok(())
+}
+
+/// read_compaction_marker reads the roots of the marker of a compaction, None if the marker
+/// is missing or does not hold as many roots as it says.
+fn read_compaction_marker(path: &Path) -> Result<Option<Vec<Offset>>, Error> {
+    let bytes = match fs::read(compaction_marker(path)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let word = |idx: usize| {
+        let word = bytes.get(idx * 8..(idx + 1) * 8)?;
+        <[u8; 8]>::try_from(word).ok().map(u64::from_be_bytes)
+    };
+    let len = match word(0).and_then(|len| usize::try_from(len).ok()) {
+        Some(len)
+            if len.checked_add(1).and_then(|words| words.checked_mul(8)) == Some(bytes.len()) =>
+        {
+            len
+        }
+        _ => return Ok(None),
+    };
+    Ok((1..=len)
+        .map(|idx| {
+            word(idx)
+                .and_then(|root| usize::try_from(root).ok())
+                .map(Offset)
+        })
+        .collect())
+}
+
+/// has_compaction_marker returns whether the tree file at a path is in the middle of having
+/// a compacted file swapped in, see `resume_compaction`.
+pub(crate) fn has_compaction_marker(path: &Path) -> bool {
+    compaction_marker(path).exists()
+}
+
+/// resume_compaction finishes or discards a compaction of the tree file at a path interrupted
+/// by a crash, returning the roots the tree file is left to commit if it was finished:
+/// - a compaction which wrote its marker had its compacted file complete and synced, the file
+///   is swapped in (unless it already was) and the roots of the marker are returned,
+/// - a compaction which did not is discarded, the tree file was never touched by it.
+///
+/// The caller commits the roots and then removes the marker, see `remove_compaction_marker`.
+pub(crate) fn resume_compaction(path: &Path) -> Result<Option<Vec<Offset>>, Error> {
+    match read_compaction_marker(path)? {
+        Some(roots) => {
+            let target = compaction_target(path);
+            if target.exists() {
+                fs::rename(target, path)?;
+            }
+            Ok(Some(roots))
+        }
+        None => {
+            discard_compaction(path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// remove_compaction_marker removes the marker of a compaction once its roots are committed.
+pub(crate) fn remove_compaction_marker(path: &Path) -> Result<(), Error> {
+    remove_if_present(&compaction_marker(path))
+}
+
+/// discard_compaction removes what an unfinished compaction of the tree file at a path left.
+pub(crate) fn discard_compaction(path: &Path) -> Result<(), Error> {
+    remove_if_present(&compaction_target(path))?;
+    remove_if_present(&compaction_marker(path))
+}
+
+fn remove_if_present(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// export_tree writes the tree reachable from a root into a standalone tree file (along with
+/// the log of its root, next to the file) holding the live pages of the tree only.
+pub(crate) fn export_tree<const N: usize>(
+    reader: Pager<N>,
+    root: Offset,
+    b: usize,
+    target: &Path,
+) -> Result<(), Error> {
+    let target_directory = target.parent().unwrap_or_else(|| Path::new("/tmp"));
+    let source_directory = reader.path().parent().map(Path::to_path_buf);
+    // The log of the exported tree would replace the log of the source tree.
+    if Some(target_directory.to_path_buf()) == source_directory {
+        return Err(Error::Unsupported(
+            "exporting a tree into the directory of its source",
+        ));
+    }
+    let compaction = Compaction::new(vec![root], reader, target, &Header::new(b))?;
+    let exported = compaction.run(&mut || Ok(()))?;
+    let mut wal = Wal::new(target_directory.to_path_buf())?;
+    let root = exported.roots.into_iter().next();
+    wal.set_root(root.ok_or(Error::UnexpectedError)?)?;
+    wal.sync()
+}
+
+/// MaintenanceConfig configures the background maintenance task.
+#[derive(Clone, Debug)]
+pub struct MaintenanceConfig {
+    /// How often the tree is checked for changes worth compacting.
+    pub interval: Duration,
+    /// A pause taken after every page written by a compaction,
+    /// limiting the I/O bandwidth taken away from the foreground.
+    pub io_throttle: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    // A default MaintenanceConfig checks the tree every minute
+    // and does not throttle compactions.
+    fn default() -> Self {
+        MaintenanceConfig {
+            interval: Duration::from_secs(60),
+            io_throttle: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Maintenance is a handle to a background thread periodically compacting a shared tree.
+/// The tree is only locked to take a snapshot of its root and to swap in the compacted file,
+/// if the tree was written to in between, the compaction is discarded and retried on the next round.
+/// Stopping the thread interrupts a compaction in progress, discarding it.
+pub struct Maintenance {
+    stop: Sender<()>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl Maintenance {
+    pub fn start(tree: Arc<Mutex<BTree>>, config: MaintenanceConfig) -> Maintenance {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut compacted_root = None;
+            loop {
+                match stopped.recv_timeout(config.interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        match run_pass(&tree, &config, &stopped, &mut compacted_root) {
+                            Err(Error::Interrupted) => return Ok(()),
+                            res => res?,
+                        }
+                    }
+                    _ => return Ok(()),
+                }
+            }
+        });
+        Maintenance { stop, handle }
+    }
+
+    /// stop stops the maintenance thread, returning the error it failed with (if any).
+    pub fn stop(self) -> Result<(), Error> {
+        // The thread might have already exited on error, dropping the receiving end.
+        let _ = self.stop.send(());
+        self.handle.join().map_err(|_| Error::ThreadPanicked)?
+    }
+}
+
+/// run_pass compacts the tree unless it has not changed since it was last compacted,
+/// interrupting the compaction with `Interrupted` once the thread is asked to stop.
+fn run_pass(
+    tree: &Mutex<BTree>,
+    config: &MaintenanceConfig,
+    stopped: &Receiver<()>,
+    compacted_root: &mut Option<Offset>,
+) -> Result<(), Error> {
+    let compaction = {
+        let mut tree = tree.lock().map_err(|_| Error::Poisoned)?;
+        if compacted_root.as_ref() == Some(&tree.root_offset()?) {
+            return Ok(());
+        }
+        tree.begin_compaction()?
+    };
+    let compacted = compaction.run(&mut || {
+        thread::sleep(config.io_throttle);
+        match stopped.try_recv() {
+            Err(TryRecvError::Empty) => Ok(()),
+            _ => Err(Error::Interrupted),
+        }
+    })?;
+    let mut tree = tree.lock().map_err(|_| Error::Poisoned)?;
+    if tree.finish_compaction(compacted)? {
+        *compacted_root = Some(tree.root_offset()?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn maintenance_compacts_in_the_background() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::maintenance::{Maintenance, MaintenanceConfig};
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/maintenance")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/maintenance/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+        let before = std::fs::metadata("/tmp/maintenance/db")?.len();
+
+        let tree = Arc::new(Mutex::new(btree));
+        let maintenance = Maintenance::start(
+            tree.clone(),
+            MaintenanceConfig {
+                interval: Duration::from_millis(5),
+                io_throttle: Duration::from_millis(1),
+            },
+        );
+        let mut after = before;
+        for _ in 0..200 {
+            thread::sleep(Duration::from_millis(5));
+            after = std::fs::metadata("/tmp/maintenance/db")?.len();
+            if after < before {
+                break;
+            }
+        }
+        maintenance.stop()?;
+        assert!(after < before);
+
+        let mut btree = tree.lock().unwrap();
+        for i in 0..30 {
+            let kv = btree.search(format!("{:02}", i))?;
+            assert_eq!(kv.value, format!("v{}", i));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_compactions_are_resumed_or_discarded() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::maintenance::{
+            compaction_target, has_compaction_marker, write_compaction_marker,
+        };
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        let path = Path::new("/tmp/compaction_resume/db");
+        std::fs::create_dir_all("/tmp/compaction_resume")?;
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+        for i in 10..20 {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        let before = std::fs::metadata(path)?.len();
+        let reopen = || BTreeBuilder::new().path(path).open_existing(true).build();
+        let check = |btree: &mut crate::btree::BTree| -> Result<(), Error> {
+            assert_eq!(btree.range(..)?.len(), 20);
+            assert_eq!(btree.search("25".to_string())?.value, "v25");
+            btree.check_invariants()
+        };
+
+        // A crash before the marker is written discards the compaction.
+        let compacted = btree.begin_compaction()?.run(&mut || Ok(()))?;
+        drop((btree, compacted));
+        let mut btree = reopen()?;
+        assert!(!compaction_target(path).exists());
+        assert_eq!(std::fs::metadata(path)?.len(), before);
+        check(&mut btree)?;
+
+        // A crash after the marker is written resumes it, before or after the swap.
+        for swapped in [false, true] {
+            let compacted = btree.begin_compaction()?.run(&mut || Ok(()))?;
+            write_compaction_marker(path, &compacted.roots)?;
+            if swapped {
+                std::fs::rename(compaction_target(path), path)?;
+            }
+            drop((btree, compacted));
+            assert!(BTreeBuilder::new()
+                .path(path)
+                .read_only(true)
+                .build()
+                .is_err());
+            btree = reopen()?;
+            assert!(!has_compaction_marker(path) && !compaction_target(path).exists());
+            assert!(std::fs::metadata(path)?.len() < before);
+            check(&mut btree)?;
+        }
+
+        // A failed compaction removes the file it was writing.
+        let res = btree
+            .begin_compaction()?
+            .run(&mut || Err(Error::Interrupted));
+        assert!(matches!(res, Err(Error::Interrupted)));
+        assert!(!compaction_target(path).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use crate::btree::BTree;
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair};
+use std::ops::RangeBounds;
+
+/// PersistentBTreeMap is a tree with the methods of a `std::collections::BTreeMap<String, String>`,
+/// so code written against an in-memory map can move to a persistent one by little more than
+/// adding `?` to its calls. The differences follow from the tree: every method may fail, reads
+/// take `&mut self` (every read looks up the latest committed root), and values are returned
+/// by value rather than by reference, as they live on disk rather than in the map.
+pub struct PersistentBTreeMap {
+    tree: BTree,
+}
+
+/// Iter iterates over the pairs of a map, in key order.
+pub type Iter = std::vec::IntoIter<(String, String)>;
+
+impl PersistentBTreeMap {
+    pub fn new(tree: BTree) -> PersistentBTreeMap {
+        PersistentBTreeMap { tree }
+    }
+
+    /// into_inner returns the tree of the map.
+    pub fn into_inner(self) -> BTree {
+        self.tree
+    }
+
+    /// insert inserts a pair, returning the value the key had, if any.
+    pub fn insert(&mut self, key: String, value: String) -> Result<Option<String>, Error> {
+        Ok(self
+            .tree
+            .put(KeyValuePair::new(key, value))?
+            .map(|old| old.value))
+    }
+
+    /// get returns the value of a key, if any.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, Error> {
+        match self.tree.search(key.to_string()) {
+            Ok(kv) => Ok(Some(kv.value)),
+            Err(Error::KeyNotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn contains_key(&mut self, key: &str) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// remove removes a key, returning the value it had, if any.
+    pub fn remove(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.tree.delete(Key(key.to_string()))?;
+        }
+        Ok(value)
+    }
+
+    /// range returns the pairs of a range of keys, in key order.
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Result<Iter, Error> {
+        Ok(self
+            .tree
+            .range(range)?
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect::<Vec<_>>()
+            .into_iter())
+    }
+
+    /// iter returns every pair of the map, in key order.
+    pub fn iter(&mut self) -> Result<Iter, Error> {
+        self.range(..)
+    }
+
+    /// len returns the number of pairs of the map, reading every leaf of the tree.
+    pub fn len(&mut self) -> Result<usize, Error> {
+        Ok(self.tree.range(..)?.len())
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// pop_first removes the pair of the smallest key, returning it.
+    pub fn pop_first(&mut self) -> Result<Option<(String, String)>, Error> {
+        Ok(self
+            .tree
+            .pop_first_if(|_, _| true)?
+            .map(|kv| (kv.key, kv.value)))
+    }
+
+    /// extend inserts every pair of an iterator, stopping at the first failed insert.
+    /// It is not `Extend::extend`, which cannot fail.
+    pub fn extend<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        pairs: I,
+    ) -> Result<(), Error> {
+        for (key, value) in pairs {
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// clear removes every pair of the map.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        for kv in self.tree.range(..)? {
+            self.tree.delete(Key(kv.key))?;
+        }
+        Ok(())
+    }
+
+    /// entry returns the entry of a key, present or not, for in-place manipulation.
+    pub fn entry(&mut self, key: String) -> Result<Entry<'_>, Error> {
+        Ok(match self.get(&key)? {
+            Some(value) => Entry::Occupied(OccupiedEntry {
+                map: self,
+                key,
+                value,
+            }),
+            None => Entry::Vacant(VacantEntry { map: self, key }),
+        })
+    }
+}
+
+/// Entry is the entry of a key in a map, see `PersistentBTreeMap::entry`.
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+impl<'a> Entry<'a> {
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// or_insert inserts a value if the key is absent, returning the value of the key.
+    pub fn or_insert(self, default: String) -> Result<String, Error> {
+        self.or_insert_with(|| default)
+    }
+
+    /// or_insert_with inserts the value of a function if the key is absent,
+    /// returning the value of the key.
+    pub fn or_insert_with<F: FnOnce() -> String>(self, default: F) -> Result<String, Error> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.value),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// or_default inserts an empty value if the key is absent, returning the value of the key.
+    pub fn or_default(self) -> Result<String, Error> {
+        self.or_insert_with(String::new)
+    }
+
+    /// and_modify modifies the value of a present key, writing the modified value to the tree.
+    pub fn and_modify<F: FnOnce(&mut String)>(self, f: F) -> Result<Entry<'a>, Error> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get().to_string();
+                f(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            vacant => Ok(vacant),
+        }
+    }
+}
+
+/// OccupiedEntry is the entry of a present key.
+pub struct OccupiedEntry<'a> {
+    map: &'a mut PersistentBTreeMap,
+    key: String,
+    value: String,
+}
+
+impl OccupiedEntry<'_> {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn get(&self) -> &str {
+        &self.value
+    }
+
+    /// insert sets the value of the key, returning the value it had.
+    pub fn insert(&mut self, value: String) -> Result<String, Error> {
+        self.map.insert(self.key.clone(), value.clone())?;
+        Ok(std::mem::replace(&mut self.value, value))
+    }
+
+    /// remove removes the key, returning its value.
+    pub fn remove(self) -> Result<String, Error> {
+        self.map.tree.delete(Key(self.key))?;
+        Ok(self.value)
+    }
+}
+
+/// VacantEntry is the entry of an absent key.
+pub struct VacantEntry<'a> {
+    map: &'a mut PersistentBTreeMap,
+    key: String,
+}
+
+impl VacantEntry<'_> {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// insert sets the value of the key, returning it.
+    pub fn insert(self, value: String) -> Result<String, Error> {
+        self.map.insert(self.key, value.clone())?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn persistent_btree_map_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::map::{Entry, PersistentBTreeMap};
+        use std::collections::BTreeMap;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/map")?;
+        let tree = BTreeBuilder::new()
+            .path(Path::new("/tmp/map/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut map = PersistentBTreeMap::new(tree);
+        let mut expected = BTreeMap::new();
+        for i in 0..20 {
+            let (key, value) = (format!("{:02}", i), format!("v{}", i));
+            assert_eq!(
+                map.insert(key.clone(), value.clone())?,
+                expected.insert(key, value)
+            );
+        }
+        assert_eq!(
+            map.insert("03".to_string(), "w".to_string())?,
+            expected.insert("03".to_string(), "w".to_string())
+        );
+        assert_eq!(map.get("03")?.as_deref(), Some("w"));
+        assert_eq!(map.get("99")?, None);
+        assert_eq!(map.remove("04")?, expected.remove("04"));
+        assert_eq!(map.remove("04")?, None);
+        assert_eq!(map.len()?, expected.len());
+        let range = "05".to_string().."08".to_string();
+        assert!(map
+            .range(range.clone())?
+            .eq(expected.range(range).map(|(k, v)| (k.clone(), v.clone()))));
+
+        assert_eq!(
+            map.entry("10".to_string())?.or_insert("x".to_string())?,
+            "v10"
+        );
+        assert_eq!(
+            map.entry("30".to_string())?.or_insert("x".to_string())?,
+            "x"
+        );
+        map.entry("11".to_string())?
+            .and_modify(|value| value.push('!'))?
+            .or_default()?;
+        assert_eq!(map.get("11")?.as_deref(), Some("v11!"));
+        if let Entry::Occupied(entry) = map.entry("12".to_string())? {
+            assert_eq!(entry.remove()?, "v12");
+        }
+        assert!(!map.contains_key("12")?);
+
+        assert_eq!(map.pop_first()?, Some(("00".to_string(), "v0".to_string())));
+        map.clear()?;
+        assert!(map.is_empty()?);
+        map.extend(vec![("a".to_string(), "b".to_string())])?;
+        assert_eq!(map.into_inner().search("a".to_string())?.value, "b");
+        Ok(())
+    }
+}
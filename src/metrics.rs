@@ -0,0 +1,215 @@
+//! Counters and latency histograms of the operations of every tree of the process,
+//! exposed in the Prometheus text format by `Metrics::render`.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The upper bounds (in seconds) of the buckets of the latency histograms.
+const BUCKETS: [f64; 10] = [
+    0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+static METRICS: Metrics = Metrics {
+    inserts: Counter::new(),
+    searches: Counter::new(),
+    deletes: Counter::new(),
+    splits: Counter::new(),
+    page_reads: Counter::new(),
+    page_writes: Counter::new(),
+    fsyncs: Counter::new(),
+    insert_latency: Histogram::new(),
+    search_latency: Histogram::new(),
+    delete_latency: Histogram::new(),
+    fsync_latency: Histogram::new(),
+};
+
+/// metrics returns the metrics of the process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Counter counts events since the start of the process.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Histogram counts the durations of an operation by bucket.
+pub struct Histogram {
+    /// The durations up to the bound of each bucket and above the bound of the previous one,
+    /// the last one holding the durations above all bounds.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// count returns the number of durations observed.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// sum returns the total of the durations observed.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// start_timer starts timing an operation, observed once the timer is dropped.
+    pub(crate) fn start_timer(&self) -> Timer<'_> {
+        Timer {
+            histogram: self,
+            start: Instant::now(),
+        }
+    }
+}
+
+pub(crate) struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed());
+    }
+}
+
+/// Metrics are the metrics of the operations of the trees of the process.
+pub struct Metrics {
+    /// Pairs written, by inserts and puts.
+    pub inserts: Counter,
+    pub searches: Counter,
+    pub deletes: Counter,
+    /// Nodes split by writes, roots included.
+    pub splits: Counter,
+    pub page_reads: Counter,
+    pub page_writes: Counter,
+    /// Syncs of tree files and of the logs of their roots to the disk.
+    pub fsyncs: Counter,
+    pub insert_latency: Histogram,
+    pub search_latency: Histogram,
+    pub delete_latency: Histogram,
+    pub fsync_latency: Histogram,
+}
+
+impl Metrics {
+    /// render renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "inserts",
+                "Pairs written by inserts and puts.",
+                &self.inserts,
+            ),
+            ("searches", "Searches for a key.", &self.searches),
+            ("deletes", "Deletes of a key.", &self.deletes),
+            ("splits", "Nodes split by writes.", &self.splits),
+            ("page_reads", "Pages read.", &self.page_reads),
+            ("page_writes", "Pages written.", &self.page_writes),
+            ("fsyncs", "Syncs of files to the disk.", &self.fsyncs),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP btree_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE btree_{}_total counter", name);
+            let _ = writeln!(out, "btree_{}_total {}", name, counter.get());
+        }
+        let histograms = [
+            ("insert", &self.insert_latency),
+            ("search", &self.search_latency),
+            ("delete", &self.delete_latency),
+            ("fsync", &self.fsync_latency),
+        ];
+        for (name, histogram) in histograms {
+            let metric = format!("btree_{}_duration_seconds", name);
+            let _ = writeln!(out, "# HELP {} Latency of {}s.", metric, name);
+            let _ = writeln!(out, "# TYPE {} histogram", metric);
+            let mut cumulative = 0;
+            for (idx, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let bound = match BUCKETS.get(idx) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", metric, bound, cumulative);
+            }
+            let _ = writeln!(out, "{}_sum {}", metric, histogram.sum().as_secs_f64());
+            let _ = writeln!(out, "{}_count {}", metric, histogram.count());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn metrics_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::metrics::metrics;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/metrics")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/metrics/db"))
+            .b_parameter(2)
+            .build()?;
+        // The metrics are shared with the trees of the other tests.
+        let (inserts, splits, fsyncs) = (
+            metrics().inserts.get(),
+            metrics().splits.get(),
+            metrics().fsyncs.get(),
+        );
+        let searches = metrics().search_latency.count();
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+        }
+        btree.search("3".to_string())?;
+        btree.delete(Key("3".to_string()))?;
+        btree.sync()?;
+        assert!(metrics().inserts.get() >= inserts + 10);
+        assert!(metrics().splits.get() > splits);
+        assert!(metrics().fsyncs.get() >= fsyncs + 2);
+        assert!(metrics().search_latency.count() > searches);
+
+        let rendered = metrics().render();
+        assert!(rendered.contains("# TYPE btree_inserts_total counter\n"));
+        assert!(rendered.contains("btree_search_duration_seconds_bucket{le=\"0.001\"} "));
+        assert!(rendered.contains("btree_search_duration_seconds_bucket{le=\"+Inf\"} "));
+        assert!(rendered.contains("btree_fsync_duration_seconds_count "));
+        Ok(())
+    }
+}
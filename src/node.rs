@@ -6,11 +6,11 @@ use crate::page_layout::{
     KEY_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET,
     PARENT_POINTER_OFFSET, PTR_SIZE, VALUE_SIZE,
 };
-use std::convert::TryFrom;
-use std::str;
+use core::convert::TryFrom;
 
 /// Node represents a node in the BTree occupied by a single page in memory.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Node {
     pub node_type: NodeType,
     pub is_root: bool,
@@ -69,12 +69,30 @@ impl Node {
 }
 
 /// Implement TryFrom<Page> for Node allowing for easier
-/// deserialization of data from a Page.
-impl TryFrom<Page> for Node {
+/// deserialization of data from a Page, see `TryFrom<&Page> for Node`.
+impl<const N: usize> TryFrom<Page<N>> for Node {
     type Error = Error;
-    fn try_from(page: Page) -> Result<Node, Error> {
-        let raw = page.get_data();
+    fn try_from(page: Page<N>) -> Result<Node, Error> {
+        Node::try_from(&page)
+    }
+}
+
+/// Deserializes the node a page holds, reading the page in place. Nothing read from the page
+/// is trusted: the node type and is_root bytes, the counts and the child offsets are checked
+/// against what a page holds, a page failing a check is refused with `Corruption` naming its offset.
+impl<const N: usize> TryFrom<&Page<N>> for Node {
+    type Error = Error;
+    #[allow(clippy::needless_late_init)]
+    fn try_from(page: &Page<N>) -> Result<Node, Error> {
+        let corrupt = |reason| Error::Corruption {
+            offset: page.offset(),
+            reason,
+        };
+        let raw = page.as_bytes();
         let node_type = NodeType::from(raw[NODE_TYPE_OFFSET]);
+        if raw[IS_ROOT_OFFSET] > 0x01 {
+            return Err(corrupt("an is_root byte other than 0 or 1"));
+        }
         let is_root = raw[IS_ROOT_OFFSET].from_byte();
         let parent_offset: Option<Offset>;
         if is_root {
@@ -86,23 +104,30 @@ impl TryFrom<Page> for Node {
         match node_type {
             NodeType::Internal(mut children, mut keys) => {
                 let num_children = page.get_value_from_offset(INTERNAL_NODE_NUM_CHILDREN_OFFSET)?;
+                // A root being split is written without children before its children are.
+                if num_children > Page::<N>::MAX_INTERNAL_CHILDREN {
+                    return Err(corrupt("a count of children the page cannot hold"));
+                }
                 let mut offset = INTERNAL_NODE_HEADER_SIZE;
                 for _i in 1..=num_children {
                     let child_offset = page.get_value_from_offset(offset)?;
+                    // Pages are appended at page boundaries.
+                    if child_offset % N != 0 {
+                        return Err(corrupt("a child offset off a page boundary"));
+                    }
                     children.push(Offset(child_offset));
                     offset += PTR_SIZE;
                 }
 
                 // Number of keys is always one less than the number of children (i.e. branching factor)
                 for _i in 1..num_children {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
-                    let key = match str::from_utf8(key_raw) {
-                        Ok(key) => key,
-                        Err(_) => return Err(Error::UTF8Error),
-                    };
+                    let key = page.get_str_from_offset(offset, KEY_SIZE, "a key not in UTF-8")?;
                     offset += KEY_SIZE;
-                    // Trim leading or trailing zeros.
-                    keys.push(Key(key.trim_matches(char::from(0)).to_string()));
+                    keys.push(Key(key));
+                }
+                // Searches bisect the keys, keys out of order would send them astray.
+                if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err(corrupt("keys out of order"));
                 }
                 Ok(Node::new(
                     NodeType::Internal(children, keys),
@@ -114,33 +139,28 @@ impl TryFrom<Page> for Node {
             NodeType::Leaf(mut pairs) => {
                 let mut offset = LEAF_NODE_NUM_PAIRS_OFFSET;
                 let num_keys_val_pairs = page.get_value_from_offset(offset)?;
+                if num_keys_val_pairs > Page::<N>::MAX_LEAF_PAIRS {
+                    return Err(corrupt("a count of pairs the page cannot hold"));
+                }
                 offset = LEAF_NODE_HEADER_SIZE;
 
                 for _i in 0..num_keys_val_pairs {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
-                    let key = match str::from_utf8(key_raw) {
-                        Ok(key) => key,
-                        Err(_) => return Err(Error::UTF8Error),
-                    };
+                    let key = page.get_str_from_offset(offset, KEY_SIZE, "a key not in UTF-8")?;
                     offset += KEY_SIZE;
 
-                    let value_raw = page.get_ptr_from_offset(offset, VALUE_SIZE);
-                    let value = match str::from_utf8(value_raw) {
-                        Ok(val) => val,
-                        Err(_) => return Err(Error::UTF8Error),
-                    };
+                    let value =
+                        page.get_str_from_offset(offset, VALUE_SIZE, "a value not in UTF-8")?;
                     offset += VALUE_SIZE;
 
-                    // Trim leading or trailing zeros.
-                    pairs.push(KeyValuePair::new(
-                        key.trim_matches(char::from(0)).to_string(),
-                        value.trim_matches(char::from(0)).to_string(),
-                    ))
+                    pairs.push(KeyValuePair::new(key, value))
+                }
+                if pairs.windows(2).any(|pair| pair[0].key >= pair[1].key) {
+                    return Err(corrupt("keys out of order"));
                 }
                 Ok(Node::new(NodeType::Leaf(pairs), is_root, parent_offset))
             }
 
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+            NodeType::Unexpected => Err(corrupt("an unknown node type")),
         }
     }
 }
@@ -152,6 +172,11 @@ impl TryFrom<Page> for Node {
 ////////////////////
 
 #[cfg(test)]
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::get_first,
+    clippy::empty_line_after_doc_comments
+)]
 mod tests {
     use crate::error::Error;
     use crate::node::{
@@ -230,6 +255,98 @@ mod tests {
         Err(Error::UnexpectedError)
     }
 
+    #[test]
+    fn page_to_node_refuses_corrupt_pages() -> Result<(), Error> {
+        use crate::node_type::{KeyValuePair, Offset};
+        use crate::page_layout::{
+            INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET, NODE_TYPE_OFFSET,
+        };
+
+        let corrupt = |mut raw: [u8; PAGE_SIZE], offset: usize, value: usize| {
+            raw[offset..offset + PTR_SIZE].copy_from_slice(&value.to_be_bytes());
+            match Node::try_from(Page::new_at(raw, 3 * PAGE_SIZE)) {
+                Err(Error::Corruption { offset, .. }) => offset == 3 * PAGE_SIZE,
+                _ => false,
+            }
+        };
+        let leaf = Node::new(
+            NodeType::Leaf(vec![KeyValuePair::new("a".to_string(), "b".to_string())]),
+            true,
+            None,
+        );
+        let mut raw = Page::try_from(&leaf)?.get_data();
+        raw[NODE_TYPE_OFFSET] = 0x07;
+        assert!(matches!(
+            Node::try_from(Page::new(raw)),
+            Err(Error::Corruption { offset: 0, .. })
+        ));
+        let mut raw = Page::try_from(&leaf)?.get_data();
+        raw[IS_ROOT_OFFSET] = 0x02;
+        assert!(Node::try_from(Page::new(raw)).is_err());
+
+        let internal = Node::new(
+            NodeType::Internal(
+                vec![Offset(PAGE_SIZE), Offset(2 * PAGE_SIZE)],
+                vec![Key("a".to_string())],
+            ),
+            true,
+            None,
+        );
+        let raw = Page::try_from(&internal)?.get_data();
+        assert!(corrupt(raw, INTERNAL_NODE_NUM_CHILDREN_OFFSET, 1000));
+        assert!(corrupt(raw, INTERNAL_NODE_HEADER_SIZE, PAGE_SIZE + 1));
+
+        // Keys out of order, or twice, in a leaf and in an internal node.
+        for keys in [["b", "a"], ["a", "a"]] {
+            let leaf = Node::new(
+                NodeType::Leaf(
+                    keys.iter()
+                        .map(|key| KeyValuePair::new(key.to_string(), "v".to_string()))
+                        .collect(),
+                ),
+                false,
+                Some(Offset(0)),
+            );
+            let raw = Page::try_from(&leaf)?.get_data();
+            assert!(matches!(
+                Node::try_from(Page::new_at(raw, PAGE_SIZE)),
+                Err(Error::Corruption { offset, reason: "keys out of order" }) if offset == PAGE_SIZE
+            ));
+            let internal = Node::new(
+                NodeType::Internal(
+                    vec![Offset(PAGE_SIZE); 3],
+                    keys.iter().map(|key| Key(key.to_string())).collect(),
+                ),
+                true,
+                None,
+            );
+            let raw = Page::try_from(&internal)?.get_data();
+            assert!(Node::try_from(Page::new(raw)).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn page_to_node_refuses_invalid_utf8() -> Result<(), Error> {
+        use crate::node_type::KeyValuePair;
+
+        let leaf = Node::new(
+            NodeType::Leaf(vec![KeyValuePair::new("a".to_string(), "b".to_string())]),
+            true,
+            None,
+        );
+        let mut raw = Page::try_from(&leaf)?.get_data();
+        raw[LEAF_NODE_HEADER_SIZE + KEY_SIZE] = 0xff;
+        assert!(matches!(
+            Node::try_from(Page::new_at(raw, PAGE_SIZE)),
+            Err(Error::Corruption {
+                offset: PAGE_SIZE,
+                reason: "a value not in UTF-8"
+            })
+        ));
+        Ok(())
+    }
+
     #[test]
     fn split_leaf_works() -> Result<(), Error> {
         use crate::node::Node;
@@ -1,10 +1,13 @@
 use crate::error::Error;
-use crate::page_layout::PTR_SIZE;
-use std::cmp::{Eq, Ord, Ordering, PartialOrd};
-use std::convert::From;
-use std::convert::TryFrom;
+use crate::page_layout::{KEY_SIZE, PTR_SIZE, VALUE_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::{Eq, Ord, Ordering, PartialOrd};
+use core::convert::From;
+use core::convert::TryFrom;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Offset(pub usize);
 
 /// Converts an array of length len(usize) to a usize as a BigEndian integer.
@@ -17,9 +20,11 @@ impl TryFrom<[u8; PTR_SIZE]> for Offset {
 }
 
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Key(pub String);
 
 #[derive(Clone, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct KeyValuePair {
     pub key: String,
     pub value: String,
@@ -47,10 +52,23 @@ impl KeyValuePair {
     pub fn new(key: String, value: String) -> KeyValuePair {
         KeyValuePair { key, value }
     }
+
+    /// check_size checks the pair fits the cells of a page. Cells are sized in bytes of UTF-8
+    /// rather than in chars: an accented letter takes two bytes of a cell, an emoji four.
+    pub fn check_size(&self) -> Result<(), Error> {
+        if self.key.len() > KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        if self.value.len() > VALUE_SIZE {
+            return Err(Error::ValueOverflowError);
+        }
+        Ok(())
+    }
 }
 
 // NodeType Represents different node types in the BTree.
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum NodeType {
     /// Internal nodes contain a vector of pointers to their children and a vector of keys.
     Internal(Vec<Offset>, Vec<Key>),
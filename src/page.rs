@@ -1,45 +1,129 @@
 use crate::error::Error;
 use crate::node::Node;
-use crate::node_type::{Key, NodeType, Offset};
+use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
 use crate::page_layout::{
-    ToByte, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET,
-    INTERNAL_NODE_NUM_CHILDREN_SIZE, IS_ROOT_OFFSET, KEY_SIZE, LEAF_NODE_HEADER_SIZE,
-    LEAF_NODE_NUM_PAIRS_OFFSET, LEAF_NODE_NUM_PAIRS_SIZE, NODE_TYPE_OFFSET, PAGE_SIZE,
-    PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE, PTR_SIZE, VALUE_SIZE,
+    max_b_parameter, max_internal_children, max_leaf_pairs, ToByte, INTERNAL_NODE_HEADER_SIZE,
+    INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET, KEY_SIZE, LEAF_NODE_HEADER_SIZE,
+    LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET, PAGE_SIZE, PARENT_POINTER_OFFSET, PTR_SIZE,
+    VALUE_SIZE,
 };
-use std::convert::TryFrom;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::Range;
+use core::str;
 
 /// Value is a wrapper for a value in the page.
 pub struct Value(pub usize);
 
+/// The size of the cell of a pair of a leaf.
+const CELL_SIZE: usize = KEY_SIZE + VALUE_SIZE;
+
+/// cell_offset returns the offset of the cell of the pair at an index of a leaf.
+fn cell_offset(idx: usize) -> usize {
+    LEAF_NODE_HEADER_SIZE + idx * CELL_SIZE
+}
+
+/// PackedKey is a key packed into an integer, its bytes padded with zeros (which sort before
+/// any other byte) in the high bytes, so keys compare as integers in a single instruction
+/// rather than byte by byte. A key longer than a cell compares past every key of a cell it
+/// starts with, by setting the byte following them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PackedKey(u128);
+
+impl PackedKey {
+    pub(crate) fn new(key: &[u8]) -> PackedKey {
+        let mut bytes = [0x00; 16];
+        let len = key.len().min(KEY_SIZE);
+        bytes[..len].copy_from_slice(&key[..len]);
+        bytes[KEY_SIZE] = u8::from(key.len() > KEY_SIZE);
+        PackedKey(u128::from_be_bytes(bytes))
+    }
+}
+
+/// KeyIndex is the keys of an internal node packed, see `PackedKey`, kept along with the node
+/// by the node cache so routing a search through the node reads one contiguous array.
+#[cfg(feature = "std")]
+pub(crate) struct KeyIndex(Vec<PackedKey>);
+
+#[cfg(feature = "std")]
+impl KeyIndex {
+    pub(crate) fn new(keys: &[Key]) -> KeyIndex {
+        KeyIndex(
+            keys.iter()
+                .map(|key| PackedKey::new(key.0.as_bytes()))
+                .collect(),
+        )
+    }
+
+    /// route returns the index of the child of the node whose keys a key is in.
+    pub(crate) fn route(&self, key: PackedKey) -> usize {
+        self.0.partition_point(|packed| *packed < key)
+    }
+}
+
 /// Page is a wrapper for a single page of memory
 /// providing some helpful helpers for quick access.
-pub struct Page {
-    data: Box<[u8; PAGE_SIZE]>,
+///
+/// Pages are generic over their size, `PAGE_SIZE` bytes unless given otherwise: the buffer of
+/// a page is an array of its size and the limits of the nodes it holds (see `MAX_LEAF_PAIRS`)
+/// are constants of it, so a page of another size costs nothing at run time.
+pub struct Page<const N: usize = PAGE_SIZE> {
+    data: Box<[u8; N]>,
+    /// The offset the page was read from, naming it in `Corruption` errors
+    /// (0 for pages not read from a file).
+    offset: usize,
 }
 
-impl Page {
-    pub fn new(data: [u8; PAGE_SIZE]) -> Page {
-        Page {
-            data: Box::new(data),
-        }
+impl<const N: usize> Page<N> {
+    /// The size of the page in bytes.
+    pub const SIZE: usize = N;
+    /// The most pairs a leaf of the page holds.
+    pub const MAX_LEAF_PAIRS: usize = max_leaf_pairs(N);
+    /// The most children an internal node of the page holds.
+    pub const MAX_INTERNAL_CHILDREN: usize = max_internal_children(N);
+    /// The largest b parameter of a tree of pages of the size.
+    pub const MAX_B_PARAMETER: usize = max_b_parameter(N);
+
+    pub fn new(data: [u8; N]) -> Page<N> {
+        Page::new_at(data, 0)
+    }
+
+    /// new_at returns the page read from a given offset of a file.
+    pub fn new_at(data: [u8; N], offset: usize) -> Page<N> {
+        Page::from_buffer(Box::new(data), offset)
+    }
+
+    /// from_buffer returns the page a buffer holds, read from a given offset of a file.
+    /// Unlike `new_at` the bytes are not copied.
+    pub fn from_buffer(data: Box<[u8; N]>, offset: usize) -> Page<N> {
+        Page { data, offset }
+    }
+
+    /// into_buffer returns the buffer holding the page, to reuse for another page.
+    pub fn into_buffer(self) -> Box<[u8; N]> {
+        self.data
+    }
+
+    /// offset returns the offset the page was read from.
+    pub fn offset(&self) -> usize {
+        self.offset
     }
 
     /// write_value_at_offset writes a given value (as BigEndian) at a certain offset
     /// overriding values at that offset.
     pub fn write_value_at_offset(&mut self, offset: usize, value: usize) -> Result<(), Error> {
-        if offset > PAGE_SIZE - PTR_SIZE {
-            return Err(Error::UnexpectedError);
-        }
-        let bytes = value.to_be_bytes();
-        self.data[offset..offset + PTR_SIZE].clone_from_slice(&bytes);
-        Ok(())
+        self.write_bytes_at_offset(&value.to_be_bytes(), offset, PTR_SIZE)
     }
 
     /// get_value_from_offset Fetches a value calculated as BigEndian, sized to usize.
     /// This function may error as the value might not fit into a usize.
     pub fn get_value_from_offset(&self, offset: usize) -> Result<usize, Error> {
-        let bytes = &self.data[offset..offset + PTR_SIZE];
+        let bytes = self.get_ptr_from_offset(offset, PTR_SIZE)?;
         let Value(res) = Value::try_from(bytes)?;
         Ok(res)
     }
@@ -53,129 +137,361 @@ impl Page {
         end_offset: usize,
         size: usize,
     ) -> Result<(), Error> {
-        // This Should not occur - better verify.
-        if end_offset + size > self.data.len() {
+        if bytes.len() != size {
             return Err(Error::UnexpectedError);
         }
-        for idx in (offset..=end_offset).rev() {
-            self.data[idx + size] = self.data[idx]
+        if offset > end_offset || bounds::<N>(end_offset, size.saturating_add(1)).is_none() {
+            return Err(Error::PageFull);
         }
-        self.data[offset..offset + size].clone_from_slice(bytes);
-        Ok(())
+        self.data.copy_within(offset..=end_offset, offset + size);
+        self.write_bytes_at_offset(bytes, offset, size)
     }
 
-    /// write_bytes_at_offset write bytes at a certain offset overriding previous values.
+    /// write_bytes_at_offset write bytes at a certain offset overriding previous values,
+    /// refusing writes running past the end of the page.
     pub fn write_bytes_at_offset(
         &mut self,
         bytes: &[u8],
         offset: usize,
         size: usize,
     ) -> Result<(), Error> {
-        self.data[offset..offset + size].clone_from_slice(bytes);
+        if bytes.len() != size {
+            return Err(Error::UnexpectedError);
+        }
+        let range = bounds::<N>(offset, size).ok_or(Error::PageFull)?;
+        self.data[range].copy_from_slice(bytes);
         Ok(())
     }
 
-    /// get_ptr_from_offset Fetches a slice of bytes from certain offset and of certain size.
-    pub fn get_ptr_from_offset(&self, offset: usize, size: usize) -> &[u8] {
-        &self.data[offset..offset + size]
+    /// get_ptr_from_offset Fetches a slice of bytes from certain offset and of certain size,
+    /// refusing reads running past the end of the page.
+    pub fn get_ptr_from_offset(&self, offset: usize, size: usize) -> Result<&[u8], Error> {
+        let range = bounds::<N>(offset, size)
+            .ok_or(Error::TryFromSliceError("bytes past the end of the page"))?;
+        Ok(&self.data[range])
+    }
+
+    /// get_str_from_offset reads a string padded with zeros from certain offset and of
+    /// certain size. Bytes which are not UTF-8 were not written by the tree, they are refused
+    /// with `Corruption` naming what was read rather than replaced or passed on.
+    pub fn get_str_from_offset(
+        &self,
+        offset: usize,
+        size: usize,
+        what: &'static str,
+    ) -> Result<String, Error> {
+        let raw = self.get_ptr_from_offset(offset, size)?;
+        let s = str::from_utf8(raw).map_err(|_| Error::Corruption {
+            offset: self.offset,
+            reason: what,
+        })?;
+        // Trim leading or trailing zeros.
+        Ok(s.trim_matches(char::from(0)).to_string())
+    }
+
+    /// str_range returns the range of the bytes of a string padded with zeros at a certain
+    /// offset and of certain size, its leading and trailing zeros aside, checked like
+    /// `get_str_from_offset` checks the string.
+    fn str_range(
+        &self,
+        offset: usize,
+        size: usize,
+        what: &'static str,
+    ) -> Result<Range<usize>, Error> {
+        let raw = self.get_ptr_from_offset(offset, size)?;
+        if str::from_utf8(raw).is_err() {
+            return Err(Error::Corruption {
+                offset: self.offset,
+                reason: what,
+            });
+        }
+        let start = raw.iter().position(|byte| *byte != 0).unwrap_or(raw.len());
+        let end = raw
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(start, |end| end + 1);
+        Ok(offset + start..offset + end)
     }
 
-    /// get_data returns the underlying array.
-    pub fn get_data(&self) -> [u8; PAGE_SIZE] {
+    /// str_at returns the string of a range of the page, such as the one of `find_in_leaf`.
+    pub fn str_at(&self, range: Range<usize>) -> Result<&str, Error> {
+        let raw = self
+            .data
+            .get(range)
+            .ok_or(Error::TryFromSliceError("bytes past the end of the page"))?;
+        str::from_utf8(raw).map_err(|_| Error::UTF8Error)
+    }
+
+    /// get_data returns a copy of the underlying array, see `as_bytes`.
+    pub fn get_data(&self) -> [u8; N] {
         *self.data
     }
+
+    /// as_bytes returns the underlying array, without copying it.
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.data
+    }
+
+    /// leaf_len returns the number of pairs of the leaf the page holds,
+    /// None if the page holds another node.
+    pub fn leaf_len(&self) -> Result<Option<usize>, Error> {
+        if !matches!(
+            NodeType::from(self.data[NODE_TYPE_OFFSET]),
+            NodeType::Leaf(_)
+        ) {
+            return Ok(None);
+        }
+        match self.get_value_from_offset(LEAF_NODE_NUM_PAIRS_OFFSET)? {
+            len if len > Self::MAX_LEAF_PAIRS => Err(Error::Corruption {
+                offset: self.offset,
+                reason: "a count of pairs the page cannot hold",
+            }),
+            len => Ok(Some(len)),
+        }
+    }
+
+    /// insert_into_leaf inserts a pair into the leaf the page holds without decoding it,
+    /// shifting the cells of the larger keys by a cell. If replace is set the pair of the same
+    /// key (if any) is overwritten instead, and returned, otherwise a key the leaf holds already
+    /// is refused with `DuplicateKey`, leaving the page as it is. Only the cells the search bisects
+    /// are read, the others are not checked the way decoding the leaf checks them.
+    pub fn insert_into_leaf(
+        &mut self,
+        kv: &KeyValuePair,
+        replace: bool,
+    ) -> Result<Option<KeyValuePair>, Error> {
+        kv.check_size()?;
+        let len = self.leaf_len()?.ok_or(Error::UnexpectedError)?;
+        let (found, at) = match self.search_leaf(kv.key.as_bytes(), len)? {
+            Ok(idx) => (Some(idx), idx),
+            Err(idx) => (None, idx),
+        };
+        if found.is_some() && !replace {
+            return Err(Error::DuplicateKey {
+                key: kv.key.clone(),
+            });
+        }
+        let mut cell = [0x00; CELL_SIZE];
+        cell[..kv.key.len()].copy_from_slice(kv.key.as_bytes());
+        cell[KEY_SIZE..KEY_SIZE + kv.value.len()].copy_from_slice(kv.value.as_bytes());
+        if let (Some(idx), true) = (found, replace) {
+            let offset = cell_offset(idx);
+            let value =
+                self.get_str_from_offset(offset + KEY_SIZE, VALUE_SIZE, "a value not in UTF-8")?;
+            self.write_bytes_at_offset(&cell, offset, CELL_SIZE)?;
+            return Ok(Some(KeyValuePair::new(kv.key.clone(), value)));
+        }
+        let (offset, end) = (cell_offset(at), cell_offset(len));
+        if len == Self::MAX_LEAF_PAIRS {
+            return Err(Error::PageFull);
+        }
+        self.data.copy_within(offset..end, offset + CELL_SIZE);
+        self.write_bytes_at_offset(&cell, offset, CELL_SIZE)?;
+        self.write_value_at_offset(LEAF_NODE_NUM_PAIRS_OFFSET, len + 1)?;
+        Ok(None)
+    }
+
+    /// find_in_leaf returns the range of the bytes of the value of a key in the leaf the page
+    /// holds without decoding it (see `str_at`), None if the leaf holds no such key. Like
+    /// `insert_into_leaf` only the cells the search bisects are read.
+    pub fn find_in_leaf(&self, key: &str) -> Result<Option<Range<usize>>, Error> {
+        let len = self.leaf_len()?.ok_or(Error::UnexpectedError)?;
+        match self.search_leaf(key.as_bytes(), len)? {
+            Ok(idx) => self
+                .str_range(
+                    cell_offset(idx) + KEY_SIZE,
+                    VALUE_SIZE,
+                    "a value not in UTF-8",
+                )
+                .map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// search_leaf binary searches the cells of a leaf of a given number of pairs for a key,
+    /// returning the index of its cell, or the index its cell would be inserted at.
+    fn search_leaf(&self, key: &[u8], len: usize) -> Result<Result<usize, usize>, Error> {
+        if len == 0 {
+            return Ok(Err(0));
+        }
+        // The halving does not depend on the comparisons, only the index does, so the search
+        // compiles to conditional moves rather than branches mispredicted half of the time.
+        let key = PackedKey::new(key);
+        let (mut low, mut size) = (0, len);
+        while size > 1 {
+            let half = size / 2;
+            if self.packed_key(low + half)? <= key {
+                low += half;
+            }
+            size -= half;
+        }
+        Ok(match self.packed_key(low)?.cmp(&key) {
+            Ordering::Equal => Ok(low),
+            Ordering::Less => Err(low + 1),
+            Ordering::Greater => Err(low),
+        })
+    }
+
+    /// packed_key returns the key of the cell at an index of a leaf, packed.
+    fn packed_key(&self, idx: usize) -> Result<PackedKey, Error> {
+        Ok(PackedKey::new(
+            self.get_ptr_from_offset(cell_offset(idx), KEY_SIZE)?,
+        ))
+    }
+
+    /// decode decodes the node held by the page, see `TryFrom<&Page> for Node`.
+    pub fn decode(&self) -> Result<Node, Error> {
+        Node::try_from(self)
+    }
+
+    /// validate decodes the node held by the page (refusing keys out of order) and checks
+    /// an internal node holds one more child than keys, but for the empty internal node a
+    /// root being split is written as. See `BTreeBuilder::strict`.
+    pub fn validate(&self) -> Result<(), Error> {
+        let corrupt = |reason| Error::Corruption {
+            offset: self.offset,
+            reason,
+        };
+        match &self.decode()?.node_type {
+            NodeType::Internal(children, keys)
+                if children.len() != keys.len() + 1 && !children.is_empty() =>
+            {
+                Err(corrupt("an internal node without one more child than keys"))
+            }
+            NodeType::Unexpected => Err(corrupt("a node of an unknown type")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Pages print as the node they hold, a page not holding a node prints
+/// its node header bytes and the reason it does not decode.
+impl<const N: usize> fmt::Debug for Page<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.decode() {
+            Ok(node) => f.debug_tuple("Page").field(&node).finish(),
+            Err(e) => f
+                .debug_struct("Page")
+                .field(
+                    "is_root",
+                    &format_args!("{:#04x}", self.data[IS_ROOT_OFFSET]),
+                )
+                .field(
+                    "node_type",
+                    &format_args!("{:#04x}", self.data[NODE_TYPE_OFFSET]),
+                )
+                .field("error", &e)
+                .finish(),
+        }
+    }
+}
+
+/// Pages serialize as the node they hold.
+#[cfg(feature = "serde")]
+impl<const N: usize> serde::Serialize for Page<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error as _;
+        let node = self
+            .decode()
+            .map_err(|e| S::Error::custom(format_args!("the page holds no node: {:?}", e)))?;
+        node.serialize(serializer)
+    }
 }
 
 /// Implement TryFrom<Box<Node>> for Page allowing for easier
-/// serialization of data from a Node to an on-disk formatted page.
+/// serialization of data from a Node to an on-disk formatted page,
+/// of `PAGE_SIZE` bytes (see `Page::encode` for pages of other sizes).
 impl TryFrom<&Node> for Page {
     type Error = Error;
     fn try_from(node: &Node) -> Result<Page, Error> {
-        let mut data: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
+        Page::encode(node)
+    }
+}
+
+impl<const N: usize> Page<N> {
+    /// encode serializes a node into a page of N bytes, see `TryFrom<&Node> for Page`.
+    pub fn encode(node: &Node) -> Result<Page<N>, Error> {
+        let mut page = Page::new([0x00; N]);
         // is_root byte
-        data[IS_ROOT_OFFSET] = node.is_root.to_byte();
+        page.data[IS_ROOT_OFFSET] = node.is_root.to_byte();
 
         // node_type byte
-        data[NODE_TYPE_OFFSET] = u8::from(&node.node_type);
+        page.data[NODE_TYPE_OFFSET] = u8::from(&node.node_type);
 
         // parent offest
         if !node.is_root {
             match node.parent_offset {
-                Some(Offset(parent_offset)) => data
-                    [PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE]
-                    .clone_from_slice(&parent_offset.to_be_bytes()),
+                Some(Offset(parent_offset)) => {
+                    page.write_value_at_offset(PARENT_POINTER_OFFSET, parent_offset)?
+                }
                 // Expected an offset of an inner / leaf node.
                 None => return Err(Error::UnexpectedError),
             };
         }
 
+        // Every write below is checked, a node outgrowing the page fails with `PageFull`.
+        // So does a node of more cells than the page holds whose last cells are short enough
+        // to fit the slack at the end of the page, which decoding would refuse.
+        match &node.node_type {
+            NodeType::Internal(child_offsets, _)
+                if child_offsets.len() > Self::MAX_INTERNAL_CHILDREN =>
+            {
+                return Err(Error::PageFull)
+            }
+            NodeType::Leaf(kv_pairs) if kv_pairs.len() > Self::MAX_LEAF_PAIRS => {
+                return Err(Error::PageFull)
+            }
+            _ => {}
+        }
         match &node.node_type {
             NodeType::Internal(child_offsets, keys) => {
-                data[INTERNAL_NODE_NUM_CHILDREN_OFFSET
-                    ..INTERNAL_NODE_NUM_CHILDREN_OFFSET + INTERNAL_NODE_NUM_CHILDREN_SIZE]
-                    .clone_from_slice(&child_offsets.len().to_be_bytes());
+                page.write_value_at_offset(INTERNAL_NODE_NUM_CHILDREN_OFFSET, child_offsets.len())?;
 
                 let mut page_offset = INTERNAL_NODE_HEADER_SIZE;
                 for Offset(child_offset) in child_offsets {
-                    data[page_offset..page_offset + PTR_SIZE]
-                        .clone_from_slice(&child_offset.to_be_bytes());
+                    page.write_value_at_offset(page_offset, *child_offset)?;
                     page_offset += PTR_SIZE;
                 }
 
                 for Key(key) in keys {
                     let key_bytes = key.as_bytes();
-                    let mut raw_key: [u8; KEY_SIZE] = [0x00; KEY_SIZE];
                     if key_bytes.len() > KEY_SIZE {
                         return Err(Error::KeyOverflowError);
-                    } else {
-                        for (i, byte) in key_bytes.iter().enumerate() {
-                            raw_key[i] = *byte;
-                        }
                     }
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
+                    // Shorter keys are padded with zeros.
+                    page.write_bytes_at_offset(key_bytes, page_offset, key_bytes.len())?;
                     page_offset += KEY_SIZE
                 }
             }
             NodeType::Leaf(kv_pairs) => {
                 // num of pairs
-                data[LEAF_NODE_NUM_PAIRS_OFFSET
-                    ..LEAF_NODE_NUM_PAIRS_OFFSET + LEAF_NODE_NUM_PAIRS_SIZE]
-                    .clone_from_slice(&kv_pairs.len().to_be_bytes());
+                page.write_value_at_offset(LEAF_NODE_NUM_PAIRS_OFFSET, kv_pairs.len())?;
 
                 let mut page_offset = LEAF_NODE_HEADER_SIZE;
                 for pair in kv_pairs {
+                    pair.check_size()?;
                     let key_bytes = pair.key.as_bytes();
-                    let mut raw_key: [u8; KEY_SIZE] = [0x00; KEY_SIZE];
-                    if key_bytes.len() > KEY_SIZE {
-                        return Err(Error::KeyOverflowError);
-                    } else {
-                        for (i, byte) in key_bytes.iter().enumerate() {
-                            raw_key[i] = *byte;
-                        }
-                    }
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
+                    page.write_bytes_at_offset(key_bytes, page_offset, key_bytes.len())?;
                     page_offset += KEY_SIZE;
 
                     let value_bytes = pair.value.as_bytes();
-                    let mut raw_value: [u8; VALUE_SIZE] = [0x00; VALUE_SIZE];
-                    if value_bytes.len() > VALUE_SIZE {
-                        return Err(Error::ValueOverflowError);
-                    } else {
-                        for (i, byte) in value_bytes.iter().enumerate() {
-                            raw_value[i] = *byte;
-                        }
-                    }
-                    data[page_offset..page_offset + VALUE_SIZE].clone_from_slice(&raw_value);
+                    page.write_bytes_at_offset(value_bytes, page_offset, value_bytes.len())?;
                     page_offset += VALUE_SIZE;
                 }
             }
             NodeType::Unexpected => return Err(Error::UnexpectedError),
         }
 
-        Ok(Page::new(data))
+        Ok(page)
     }
 }
 
+/// bounds returns the range of #size bytes from offset, if it lies within a page of N bytes.
+fn bounds<const N: usize>(offset: usize, size: usize) -> Option<Range<usize>> {
+    let end = offset.checked_add(size)?;
+    (end <= N).then_some(offset..end)
+}
+
 /// Attempts to convert a slice to an array of a fixed size (PTR_SIZE),
 /// and then return the BigEndian value of the byte array.
 impl TryFrom<&[u8]> for Value {
@@ -195,6 +511,7 @@ impl TryFrom<&[u8]> for Value {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use crate::error::Error;
 
@@ -207,9 +524,9 @@ mod tests {
 
         let some_leaf = Node::new(
             NodeType::Leaf(vec![
+                KeyValuePair::new("ariana".to_string(), "grande".to_string()),
                 KeyValuePair::new("foo".to_string(), "bar".to_string()),
                 KeyValuePair::new("lebron".to_string(), "james".to_string()),
-                KeyValuePair::new("ariana".to_string(), "grande".to_string()),
             ]),
             true,
             None,
@@ -223,6 +540,41 @@ mod tests {
         assert_eq!(res.is_root, some_leaf.is_root);
         assert_eq!(res.node_type, some_leaf.node_type);
         assert_eq!(res.parent_offset, some_leaf.parent_offset);
+
+        // A leaf of more pairs than a page fits is refused rather than truncated.
+        let pairs = (0..300)
+            .map(|i| KeyValuePair::new(format!("{:03}", i), "v".to_string()))
+            .collect();
+        let overfull_leaf = Node::new(NodeType::Leaf(pairs), true, None);
+        assert!(matches!(
+            Page::try_from(&overfull_leaf),
+            Err(Error::PageFull)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn page_accessors_refuse_out_of_bounds() -> Result<(), Error> {
+        use crate::page::Page;
+        use crate::page_layout::{PAGE_SIZE, PTR_SIZE};
+
+        let mut page = Page::new([0x00; PAGE_SIZE]);
+        page.write_value_at_offset(PAGE_SIZE - PTR_SIZE, 7)?;
+        assert_eq!(page.get_value_from_offset(PAGE_SIZE - PTR_SIZE)?, 7);
+        assert!(matches!(
+            page.write_value_at_offset(PAGE_SIZE - 1, 7),
+            Err(Error::PageFull)
+        ));
+        assert!(matches!(
+            page.write_bytes_at_offset(b"ab", usize::MAX, 2),
+            Err(Error::PageFull)
+        ));
+        assert!(page.write_bytes_at_offset(b"ab", 0, 3).is_err());
+        assert!(page.get_ptr_from_offset(PAGE_SIZE, 1).is_err());
+        assert!(page.get_value_from_offset(usize::MAX - 2).is_err());
+        assert!(page
+            .insert_bytes_at_offset(b"ab", 10, PAGE_SIZE - 2, 2)
+            .is_err());
         Ok(())
     }
 
@@ -243,9 +595,9 @@ mod tests {
                     Offset(PAGE_SIZE * 4),
                 ],
                 vec![
+                    Key("ariana".to_string()),
                     Key("foo bar".to_string()),
                     Key("lebron".to_string()),
-                    Key("ariana".to_string()),
                 ],
             ),
             true,
@@ -262,4 +614,184 @@ mod tests {
         assert_eq!(res.parent_offset, internal_node.parent_offset);
         Ok(())
     }
+
+    #[test]
+    fn page_debug_and_serialize_work() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType, Offset};
+        use crate::page::Page;
+        use crate::page_layout::{LEAF_NODE_NUM_PAIRS_OFFSET, PAGE_SIZE};
+        use std::convert::TryFrom;
+
+        let leaf = Node::new(
+            NodeType::Leaf(vec![KeyValuePair::new("a".to_string(), "b".to_string())]),
+            false,
+            Some(Offset(PAGE_SIZE)),
+        );
+        let mut page = Page::try_from(&leaf)?;
+        assert_eq!(
+            format!("{:?}", page),
+            "Page(Node { node_type: Leaf([KeyValuePair { key: \"a\", value: \"b\" }]), \
+             is_root: false, parent_offset: Some(Offset(4096)) })"
+        );
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::to_string(&page).map_err(|_| Error::UnexpectedError)?,
+            r#"{"node_type":{"Leaf":[{"key":"a","value":"b"}]},"is_root":false,"parent_offset":4096}"#
+        );
+
+        // A count of pairs past the end of the page is refused rather than read.
+        page.write_value_at_offset(LEAF_NODE_NUM_PAIRS_OFFSET, 1000)?;
+        assert_eq!(
+            format!("{:?}", page),
+            "Page { is_root: 0x00, node_type: 0x02, error: Corruption { offset: 0, \
+             reason: \"a count of pairs the page cannot hold\" } }"
+        );
+        #[cfg(feature = "serde")]
+        assert!(serde_json::to_string(&page).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn insert_into_leaf_works() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType, Offset};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use std::convert::TryFrom;
+
+        // Editing the page in place matches inserting into the decoded leaf.
+        let kv = |key: &str, value: &str| KeyValuePair::new(key.to_string(), value.to_string());
+        let mut pairs = Vec::new();
+        let mut page = Page::try_from(&Node::new(NodeType::Leaf(vec![]), false, Some(Offset(0))))?;
+        for (key, value, replace) in [
+            ("m", "1", false),
+            ("c", "2", false),
+            ("x", "3", false),
+            ("abcdefghij", "4", false),
+            ("c", "5", true),
+            ("d", "6", true),
+        ] {
+            let replaced = page.insert_into_leaf(&kv(key, value), replace)?;
+            let expected = match pairs.binary_search(&kv(key, value)) {
+                Ok(idx) => Some(std::mem::replace(&mut pairs[idx], kv(key, value))),
+                Err(idx) => {
+                    pairs.insert(idx, kv(key, value));
+                    None
+                }
+            };
+            assert_eq!(replaced, expected);
+            assert_eq!(
+                Node::try_from(Page::new(page.get_data()))?.node_type,
+                NodeType::Leaf(pairs.clone())
+            );
+        }
+        assert_eq!(page.leaf_len()?, Some(5));
+        for pair in &pairs {
+            let value = page
+                .find_in_leaf(&pair.key)?
+                .ok_or(Error::UnexpectedError)?;
+            assert_eq!(page.str_at(value)?, pair.value);
+        }
+        assert_eq!(page.find_in_leaf("e")?, None);
+        assert!(matches!(
+            page.insert_into_leaf(&kv("m", "7"), false),
+            Err(Error::DuplicateKey { key }) if key == "m"
+        ));
+        assert_eq!(
+            page.str_at(page.find_in_leaf("m")?.ok_or(Error::UnexpectedError)?)?,
+            "1"
+        );
+        assert!(matches!(
+            page.insert_into_leaf(&kv("abcdefghijk", "v"), false),
+            Err(Error::KeyOverflowError)
+        ));
+
+        // A key longer than a cell sorts past the keys it starts with.
+        assert_eq!(page.find_in_leaf("abcdefghijk")?, None);
+        assert_eq!(page.find_in_leaf("")?, None);
+
+        // A full page refuses another pair.
+        let full = (0..Page::<PAGE_SIZE>::MAX_LEAF_PAIRS)
+            .map(|i| kv(&format!("{:04}", i), "v"))
+            .collect();
+        let mut page = Page::try_from(&Node::new(NodeType::Leaf(full), true, None))?;
+        assert!(matches!(
+            page.insert_into_leaf(&kv("a", "v"), false),
+            Err(Error::PageFull)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn packed_keys_order_like_keys() -> Result<(), Error> {
+        use crate::node_type::Key;
+        use crate::page::{KeyIndex, PackedKey};
+
+        let keys = [
+            "",
+            "a",
+            "a\u{1}",
+            "ab",
+            "abcdefghij",
+            "abcdefghijk",
+            "b",
+            "\u{7f}",
+            "é",
+            "zzzzzzzzzz",
+        ];
+        for a in keys {
+            for b in keys {
+                let (packed_a, packed_b) =
+                    (PackedKey::new(a.as_bytes()), PackedKey::new(b.as_bytes()));
+                assert_eq!(packed_a.cmp(&packed_b), a.cmp(b), "{:?} {:?}", a, b);
+            }
+        }
+
+        // Routing through the packed keys of a node matches routing through its keys.
+        let separators: Vec<Key> = ["b", "d", "f"].iter().map(|k| Key(k.to_string())).collect();
+        let index = KeyIndex::new(&separators);
+        for search in ["", "a", "b", "c", "d", "e", "f", "g", "ffffffffffff"] {
+            let expected = separators
+                .binary_search_by(|key| key.0.as_str().cmp(search))
+                .unwrap_or_else(|x| x);
+            assert_eq!(index.route(PackedKey::new(search.as_bytes())), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pages_of_other_sizes_work() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page::Page;
+        use std::convert::TryFrom;
+
+        type SmallPage = Page<512>;
+        assert_eq!(SmallPage::SIZE, 512);
+        assert_eq!(SmallPage::MAX_LEAF_PAIRS, 24);
+        assert_eq!(SmallPage::MAX_INTERNAL_CHILDREN, 28);
+        assert_eq!(SmallPage::MAX_B_PARAMETER, 12);
+        assert_eq!(Page::<4096>::MAX_B_PARAMETER, 100);
+        assert_eq!(Page::<8192>::MAX_B_PARAMETER, 200);
+
+        let kv = |i: usize| KeyValuePair::new(format!("{:02}", i), "v".to_string());
+        let leaf = |len: usize| Node::new(NodeType::Leaf((0..len).map(kv).collect()), true, None);
+        let mut page = SmallPage::encode(&leaf(SmallPage::MAX_LEAF_PAIRS - 1))?;
+        assert_eq!(page.insert_into_leaf(&kv(99), false)?, None);
+        assert_eq!(
+            Node::try_from(&page)?.node_type,
+            NodeType::Leaf((0..23).chain([99]).map(kv).collect())
+        );
+        assert!(matches!(
+            page.insert_into_leaf(&kv(50), false),
+            Err(Error::PageFull)
+        ));
+        assert!(matches!(
+            SmallPage::encode(&leaf(SmallPage::MAX_LEAF_PAIRS + 1)),
+            Err(Error::PageFull)
+        ));
+        assert!(page.write_value_at_offset(510, 7).is_err());
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
-use crate::btree::MAX_BRANCHING_FACTOR;
-use std::mem::size_of;
+// The layout of the file header is only used by the tree, built with the `std` feature.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use core::mem::size_of;
 
 /// A single page size.
 /// Each page represents a node in the BTree.
@@ -7,6 +9,81 @@ pub const PAGE_SIZE: usize = 4096;
 
 pub const PTR_SIZE: usize = size_of::<usize>();
 
+pub const MAX_BRANCHING_FACTOR: usize = 200;
+
+/// The largest tree file supported, in whole pages. Offsets are `usize` (written in `PTR_SIZE`
+/// bytes in nodes, and as u64 in the root logs) and files are seeked with i64 offsets, so
+/// trees are bounded to 8 EiB on 64-bit targets and to 4 GiB on 32-bit targets (wasm32).
+pub const MAX_FILE_SIZE: u64 = {
+    let largest = if (usize::MAX as u64) < i64::MAX as u64 {
+        usize::MAX as u64
+    } else {
+        i64::MAX as u64
+    };
+    largest - largest % PAGE_SIZE as u64
+};
+
+/// File header layout (Thirty two bytes in total)
+///
+/// The file header occupies the first page of the tree file
+/// describing the format the rest of the pages were written in.
+pub const MAGIC: [u8; MAGIC_SIZE] = *b"BTREEDB\0";
+pub const MAGIC_OFFSET: usize = 0;
+pub const MAGIC_SIZE: usize = 8;
+pub const FORMAT_VERSION: usize = 1;
+pub const FORMAT_VERSION_OFFSET: usize = MAGIC_OFFSET + MAGIC_SIZE;
+pub const FORMAT_VERSION_SIZE: usize = PTR_SIZE;
+pub const HEADER_PAGE_SIZE_OFFSET: usize = FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE;
+pub const HEADER_PAGE_SIZE_SIZE: usize = PTR_SIZE;
+pub const B_PARAMETER_OFFSET: usize = HEADER_PAGE_SIZE_OFFSET + HEADER_PAGE_SIZE_SIZE;
+pub const B_PARAMETER_SIZE: usize = PTR_SIZE;
+pub const FILE_HEADER_SIZE: usize = B_PARAMETER_OFFSET + B_PARAMETER_SIZE;
+
+/// Bucket catalog layout, following the file header:
+/// the number of buckets followed by their names, each taking a key slot.
+pub const CATALOG_LEN_OFFSET: usize = FILE_HEADER_SIZE;
+pub const CATALOG_OFFSET: usize = CATALOG_LEN_OFFSET + PTR_SIZE;
+pub const MAX_BUCKETS: usize = 64;
+
+/// Sequence table layout, following the bucket catalog:
+/// the number of sequences followed by their names (each taking a key slot) and last values.
+pub const SEQUENCES_LEN_OFFSET: usize = CATALOG_OFFSET + MAX_BUCKETS * KEY_SIZE;
+pub const SEQUENCES_OFFSET: usize = SEQUENCES_LEN_OFFSET + PTR_SIZE;
+pub const SEQUENCE_SIZE: usize = KEY_SIZE + PTR_SIZE;
+pub const MAX_SEQUENCES: usize = 128;
+
+/// Tag table layout, following the sequence table:
+/// the number of tags followed by their names (each taking a key slot) and root offsets.
+pub const TAGS_LEN_OFFSET: usize = SEQUENCES_OFFSET + MAX_SEQUENCES * SEQUENCE_SIZE;
+pub const TAGS_OFFSET: usize = TAGS_LEN_OFFSET + PTR_SIZE;
+pub const TAG_SIZE: usize = KEY_SIZE + PTR_SIZE;
+pub const MAX_TAGS: usize = 32;
+
+/// The LSN of the last replication record a follower applied, following the tag table.
+pub const REPLICATED_LSN_OFFSET: usize = TAGS_OFFSET + MAX_TAGS * TAG_SIZE;
+
+/// The feature bits of the file, following the replicated LSN: the optional capabilities the
+/// pages of the file use. A build refuses files using a capability it lacks, files written
+/// before feature bits were recorded use none of them.
+pub const FEATURES_OFFSET: usize = REPLICATED_LSN_OFFSET + PTR_SIZE;
+/// Pairs are held by the leaves only, internal nodes holding the keys separating them.
+pub const FEATURE_BPLUS_LAYOUT: u64 = 1 << 0;
+/// The file holds buckets, see `BTree::open_bucket`.
+pub const FEATURE_BUCKETS: u64 = 1 << 1;
+/// Pages are compressed.
+pub const FEATURE_COMPRESSION: u64 = 1 << 2;
+/// Pages are encrypted.
+pub const FEATURE_ENCRYPTION: u64 = 1 << 3;
+/// The names of the feature bits, in order of the bits.
+pub const FEATURE_NAMES: [(u64, &str); 4] = [
+    (FEATURE_BPLUS_LAYOUT, "the B+ layout"),
+    (FEATURE_BUCKETS, "buckets"),
+    (FEATURE_COMPRESSION, "compression"),
+    (FEATURE_ENCRYPTION, "encryption"),
+];
+/// The feature bits this build supports.
+pub const SUPPORTED_FEATURES: u64 = FEATURE_BPLUS_LAYOUT | FEATURE_BUCKETS;
+
 /// Common Node header layout (Ten bytes in total)
 pub const IS_ROOT_SIZE: usize = 1;
 pub const IS_ROOT_OFFSET: usize = 0;
@@ -49,8 +126,60 @@ pub const MAX_SPACE_FOR_KEYS: usize =
 pub const KEY_SIZE: usize = 10;
 pub const VALUE_SIZE: usize = 10;
 
+/// The page sizes the tree is built for, see `BTreeBuilder::page_size`. The pager and the tree
+/// are generic over their page size (see `BTree`), `PAGE_SIZE` being the default one.
+pub const SUPPORTED_PAGE_SIZES: [usize; 3] = [PAGE_SIZE, 2 * PAGE_SIZE, 4 * PAGE_SIZE];
+
+/// The largest b parameter whose full nodes fit a page of `PAGE_SIZE` bytes: a leaf of 2b - 1
+/// pairs of the largest keys and values, and an internal node of 2b children, both along with
+/// their node header. It is bounded by the branching factor as well, 100 for 4096 byte pages.
+pub const MAX_B_PARAMETER: usize = max_b_parameter(PAGE_SIZE);
+
+/// max_leaf_pairs returns the most pairs a leaf of pages of a given size holds: the most cells
+/// a page physically holds are the pairs of a leaf, and the children of an internal node (along
+/// with one key less). Like the other limits below it is evaluated at compile time for the size
+/// of a `Page`, a size too small for a node header failing to compile.
+pub const fn max_leaf_pairs(page_size: usize) -> usize {
+    (page_size - LEAF_NODE_HEADER_SIZE) / (KEY_SIZE + VALUE_SIZE)
+}
+
+/// max_internal_children returns the most children an internal node of pages of a given size holds.
+pub const fn max_internal_children(page_size: usize) -> usize {
+    (page_size - INTERNAL_NODE_HEADER_SIZE + KEY_SIZE) / (PTR_SIZE + KEY_SIZE)
+}
+
+/// max_b_parameter returns the largest b parameter of a tree of pages of a given size.
+/// The branching factor bounding it grows along with the page size, from `MAX_BRANCHING_FACTOR`
+/// for pages of `PAGE_SIZE` bytes.
+pub const fn max_b_parameter(page_size: usize) -> usize {
+    min(
+        min(
+            max_leaf_pairs(page_size).div_ceil(2),
+            max_internal_children(page_size) / 2,
+        ),
+        MAX_BRANCHING_FACTOR * page_size / PAGE_SIZE / 2,
+    )
+}
+
+const fn min(a: usize, b: usize) -> usize {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+/// is_node_page checks whether an offset is that of a whole page of a file of some length and
+/// page size, past its header page. A corrupt offset near the largest one cannot wrap around past it.
+pub fn is_node_page(offset: usize, len: usize, page_size: usize) -> bool {
+    offset != 0
+        && offset.is_multiple_of(page_size)
+        && offset.checked_add(page_size).is_some_and(|end| end <= len)
+}
+
 /// Wrappers for converting byte to bool and back.
 /// The convention used throughout the index file is: one is true; otherwise - false.
+#[allow(clippy::wrong_self_convention)]
 pub trait FromByte {
     fn from_byte(&self) -> bool;
 }
@@ -1,50 +1,704 @@
 use crate::error::Error;
-use crate::node_type::Offset;
-use crate::page::Page;
-use crate::page_layout::PAGE_SIZE;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
-
-pub struct Pager {
-    file: File,
+use crate::node::Node;
+use crate::node_type::{NodeType, Offset};
+use crate::page::{KeyIndex, PackedKey, Page};
+use crate::page_layout::{MAX_FILE_SIZE, PAGE_SIZE};
+use crate::store::{path_identity, FileStore, PageStore};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of page buffers a pager holds on to for reuse, see `BufferPool`.
+const POOL_SIZE: usize = 32;
+
+/// The number of internal nodes a pager caches unless told otherwise, see `set_node_cache_size`.
+pub const DEFAULT_NODE_CACHE_SIZE: usize = 64;
+
+pub struct Pager<const N: usize = PAGE_SIZE> {
+    store: Box<dyn PageStore>,
     curser: usize,
+    /// The path of the tree file, empty for a tree held in memory.
+    path: PathBuf,
+    io: IoCounters,
+    /// Validate every node page read, see `set_strict`.
+    strict: bool,
+    /// The page writes of the operation underway, see `stage`.
+    staged: Option<Staged<N>>,
+    /// The size the store may grow to, see `set_max_size`.
+    max_size: u64,
+    pool: BufferPool<N>,
+    nodes: NodeCache,
+    /// The page allocator shared with the other pagers appending to the file, see `writer`.
+    allocator: Option<Arc<AtomicUsize>>,
+    /// The end of the run of pages reserved from the shared allocator, see `reserve`.
+    reserved: usize,
+}
+
+/// BufferPool holds on to the buffers of the pages written, the pages read are read into
+/// them rather than into a buffer allocated for every page read. The pool, like the node cache,
+/// belongs to a single pager: every `Reader` of a shared tree reads through a pager of its own.
+#[derive(Default)]
+struct BufferPool<const N: usize>(Mutex<Vec<Box<[u8; N]>>>);
+
+impl<const N: usize> BufferPool<N> {
+    fn take(&self) -> Box<[u8; N]> {
+        let buffer = self.0.lock().ok().and_then(|mut buffers| buffers.pop());
+        buffer.unwrap_or_else(|| Box::new([0x00; N]))
+    }
+
+    fn give(&self, page: Page<N>) {
+        if let Ok(mut buffers) = self.0.lock() {
+            if buffers.len() < POOL_SIZE {
+                buffers.push(page.into_buffer());
+            }
+        }
+    }
+}
+
+/// NodeCache holds the internal nodes read most recently, by offset, so walking down the top
+/// levels of the tree does not decode their pages over and over. Leaves are not cached, each
+/// one is read by few walks. A node is dropped from the cache once its page is written over.
+struct NodeCache(Mutex<CachedNodes>);
+
+#[derive(Default)]
+struct CachedNodes {
+    capacity: usize,
+    /// The number of uses of the cache so far, telling the least recently used node.
+    uses: u64,
+    /// The nodes by offset, along with their packed keys and the use they were last used at.
+    nodes: BTreeMap<usize, (Node, KeyIndex, u64)>,
+    /// The offsets of the nodes by the use they were last used at.
+    used: BTreeMap<u64, usize>,
+    hits: u64,
 }
 
-impl Pager {
-    pub fn new(path: &Path) -> Result<Pager, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
+impl NodeCache {
+    fn new(capacity: usize) -> NodeCache {
+        NodeCache(Mutex::new(CachedNodes {
+            capacity,
+            ..CachedNodes::default()
+        }))
+    }
+
+    fn lock(&self) -> Option<std::sync::MutexGuard<'_, CachedNodes>> {
+        // A poisoned cache is left unused rather than failing the reads.
+        self.0.lock().ok()
+    }
+
+    fn get(&self, offset: usize) -> Option<Node> {
+        let mut cache = self.lock()?;
+        let (node, _) = cache.used(offset)?;
+        Some(node.clone())
+    }
+
+    fn route(&self, offset: usize, key: &str, bounds: bool) -> Option<Route> {
+        let mut cache = self.lock()?;
+        let (node, index) = cache.used(offset)?;
+        let (children, keys) = match &node.node_type {
+            NodeType::Internal(children, keys) => (children, keys),
+            _ => return None,
+        };
+        let idx = index.route(PackedKey::new(key.as_bytes()));
+        let bound = |idx: Option<usize>| {
+            idx.filter(|_| bounds)
+                .and_then(|idx| keys.get(idx))
+                .map(|key| key.0.clone())
+        };
+        Some(Route {
+            child: children.get(idx)?.clone(),
+            lower: bound(idx.checked_sub(1)),
+            upper: bound(Some(idx)),
+        })
+    }
+
+    fn insert(&self, offset: usize, node: &Node) {
+        let mut cache = match self.lock() {
+            Some(cache) if cache.capacity > 0 => cache,
+            _ => return,
+        };
+        cache.remove(offset);
+        while cache.nodes.len() >= cache.capacity {
+            match cache.used.pop_first() {
+                Some((_, least_used)) => cache.nodes.remove(&least_used),
+                None => break,
+            };
+        }
+        cache.uses += 1;
+        let uses = cache.uses;
+        let index = match &node.node_type {
+            NodeType::Internal(_, keys) => KeyIndex::new(keys),
+            _ => KeyIndex::new(&[]),
+        };
+        cache.nodes.insert(offset, (node.clone(), index, uses));
+        cache.used.insert(uses, offset);
+    }
+
+    fn remove(&self, offset: usize) {
+        if let Some(mut cache) = self.lock() {
+            cache.remove(offset);
+        }
+    }
 
+    fn clear(&self) {
+        if let Some(mut cache) = self.lock() {
+            cache.nodes.clear();
+            cache.used.clear();
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.lock().map_or(0, |cache| cache.capacity)
+    }
+
+    fn set_capacity(&self, capacity: usize) {
+        if let Some(mut cache) = self.lock() {
+            cache.capacity = capacity;
+            while cache.nodes.len() > capacity {
+                match cache.used.pop_first() {
+                    Some((_, least_used)) => cache.nodes.remove(&least_used),
+                    None => break,
+                };
+            }
+        }
+    }
+
+    fn hits(&self) -> u64 {
+        self.lock().map_or(0, |cache| cache.hits)
+    }
+}
+
+impl CachedNodes {
+    /// used returns the node at an offset (and its packed keys), marking it used.
+    fn used(&mut self, offset: usize) -> Option<(&Node, &KeyIndex)> {
+        let (node, index, used) = self.nodes.get_mut(&offset)?;
+        self.uses += 1;
+        self.used.remove(used);
+        self.used.insert(self.uses, offset);
+        *used = self.uses;
+        self.hits += 1;
+        Some((node, index))
+    }
+
+    fn remove(&mut self, offset: usize) {
+        if let Some((_, _, used)) = self.nodes.remove(&offset) {
+            self.used.remove(&used);
+        }
+    }
+}
+
+/// Route is the way through an internal node to the child whose keys a search key is in,
+/// see `Pager::cached_route`, along with the keys bounding the keys of the child (if asked for):
+/// the key before the child's (exclusive), and the key following them (inclusive).
+pub(crate) struct Route {
+    pub(crate) child: Offset,
+    pub(crate) lower: Option<String>,
+    pub(crate) upper: Option<String>,
+}
+
+/// Staged are the page writes held back until an operation succeeds, by offset,
+/// along with the cursor the operation started at and the savepoints of the operations
+/// staged within it (see `stage`), innermost last.
+struct Staged<const N: usize> {
+    cursor: usize,
+    pages: BTreeMap<usize, Page<N>>,
+    savepoints: Vec<Savepoint<N>>,
+}
+
+/// Savepoint is where an operation staged within another one started: the cursor and,
+/// as they were, the staged pages it wrote over, so discarding it leaves the staged pages
+/// of the enclosing operation as they were.
+struct Savepoint<const N: usize> {
+    cursor: usize,
+    overwritten: BTreeMap<usize, Page<N>>,
+}
+
+impl<const N: usize> Staged<N> {
+    /// insert stages a page at an offset, saving the staged page it writes over
+    /// for the innermost savepoint which started past it.
+    fn insert(&mut self, offset: usize, page: Page<N>) {
+        if let Some(old) = self.pages.insert(offset, page) {
+            if let Some(savepoint) = self.savepoints.last_mut() {
+                if offset < savepoint.cursor {
+                    savepoint.overwritten.entry(offset).or_insert(old);
+                }
+            }
+        }
+    }
+}
+
+/// IoStats is the I/O done by a pager since it was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub pages_read: u64,
+    pub read_time: Duration,
+    pub pages_written: u64,
+    pub write_time: Duration,
+    pub sync_time: Duration,
+    /// The nodes read from the node cache rather than decoded from their page.
+    pub node_cache_hits: u64,
+}
+
+/// IoCounters counts the I/O of a pager, which may be read from by many threads at once.
+#[derive(Default)]
+struct IoCounters {
+    pages_read: AtomicU64,
+    read_nanos: AtomicU64,
+    pages_written: AtomicU64,
+    write_nanos: AtomicU64,
+    sync_nanos: AtomicU64,
+}
+
+/// timed runs a closure adding the time it took to a counter of nanoseconds.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn timed<T>(nanos: &AtomicU64, body: impl FnOnce() -> T) -> T {
+    let start = std::time::Instant::now();
+    let res = body();
+    nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    res
+}
+
+/// The browser has no clock to time I/O with.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn timed<T>(_: &AtomicU64, body: impl FnOnce() -> T) -> T {
+    body()
+}
+
+impl<const N: usize> Pager<N> {
+    pub fn new(path: &Path) -> Result<Pager<N>, Error> {
         Ok(Pager {
-            file: fd,
+            store: Box::new(FileStore::create(path)?),
             curser: 0,
+            path: path.to_path_buf(),
+            io: IoCounters::default(),
+            strict: false,
+            staged: None,
+            max_size: MAX_FILE_SIZE,
+            pool: BufferPool::default(),
+            nodes: NodeCache::new(DEFAULT_NODE_CACHE_SIZE),
+            allocator: None,
+            reserved: 0,
+        })
+    }
+
+    /// open opens an existing file for reading and appending pages.
+    pub fn open(path: &Path) -> Result<Pager<N>, Error> {
+        Pager::from_store(Box::new(FileStore::open(path)?), path)
+    }
+
+    /// open_read_only opens an existing file for reading pages only.
+    pub fn open_read_only(path: &Path) -> Result<Pager<N>, Error> {
+        Pager::from_store(Box::new(FileStore::open_read_only(path)?), path)
+    }
+
+    /// from_store creates a pager over the pages of a store, appending pages at its end
+    /// (past any partially written page left behind by a crash).
+    pub fn from_store(store: Box<dyn PageStore>, path: &Path) -> Result<Pager<N>, Error> {
+        let size = store.size()?;
+        let len = usize::try_from(size)
+            .ok()
+            .and_then(|size| size.checked_next_multiple_of(N))
+            .ok_or(Error::OffsetOverflow {
+                offset: size as usize,
+            })?;
+        Ok(Pager {
+            store,
+            curser: len,
+            path: path.to_path_buf(),
+            io: IoCounters::default(),
+            strict: false,
+            staged: None,
+            max_size: MAX_FILE_SIZE,
+            pool: BufferPool::default(),
+            nodes: NodeCache::new(DEFAULT_NODE_CACHE_SIZE),
+            allocator: None,
+            reserved: 0,
         })
     }
 
-    pub fn get_page(&mut self, offset: &Offset) -> Result<Page, Error> {
-        let mut page: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.read_exact(&mut page)?;
-        Ok(Page::new(page))
+    /// reader opens an additional read-only handle to the same pages,
+    /// allowing pages to be read concurrently to this pager.
+    pub fn reader(&self) -> Result<Pager<N>, Error> {
+        let mut reader = Pager::from_store(self.store.reader()?, &self.path)?;
+        reader.strict = self.strict;
+        reader.set_node_cache_size(self.node_cache_size());
+        Ok(reader)
+    }
+
+    /// writer opens another handle appending pages to the same file, for a tree written to
+    /// concurrently to the tree of this pager (see `BTree::detach_bucket`). From then on both
+    /// pagers reserve the pages they append from a shared allocator, so their pages never
+    /// overlap, and the pages of a discarded operation are left unused rather than appended over.
+    pub fn writer(&mut self) -> Result<Pager<N>, Error> {
+        if self.allocator.is_none() {
+            self.allocator = Some(Arc::new(AtomicUsize::new(self.curser)));
+            self.reserved = self.curser;
+        }
+        let store = match self.is_in_memory() {
+            true => self.store.reader()?,
+            false => Box::new(FileStore::open(&self.path)?),
+        };
+        let mut writer = Pager::from_store(store, &self.path)?;
+        writer.strict = self.strict;
+        writer.max_size = self.max_size;
+        writer.set_node_cache_size(self.node_cache_size());
+        writer.allocator = self.allocator.clone();
+        // The run reserved by the writer starts (empty) past every page of the file.
+        writer.curser = self
+            .allocator
+            .as_ref()
+            .map_or(0, |a| a.load(Ordering::SeqCst));
+        writer.reserved = writer.curser;
+        Ok(writer)
+    }
+
+    /// set_strict validates every node page read (see `Page::validate`), failing the read with
+    /// `Corruption` rather than returning a page holding a broken node. Readers inherit it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        // The nodes cached so far were not validated.
+        self.nodes.clear();
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// set_max_size limits the size the store may grow to by appending pages, leaving it at
+    /// `MAX_FILE_SIZE` at most. Appending past it fails with `DatabaseFull`.
+    pub fn set_max_size(&mut self, bytes: u64) {
+        self.max_size = bytes.min(MAX_FILE_SIZE);
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// set_node_cache_size sets the number of internal nodes cached (see `get_node`), dropping
+    /// the least recently used ones past it. No node is cached at 0. Readers inherit it.
+    pub fn set_node_cache_size(&mut self, nodes: usize) {
+        self.nodes.set_capacity(nodes);
+    }
+
+    pub fn node_cache_size(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    /// get_node returns the node at a given offset, from the node cache should it hold it.
+    pub fn get_node(&self, offset: &Offset) -> Result<Node, Error> {
+        match self.cached_node(offset) {
+            Some(node) => Ok(node),
+            None => self.decode(self.get_page(offset)?),
+        }
+    }
+
+    /// cached_node returns the node at a given offset should the node cache hold it.
+    pub fn cached_node(&self, offset: &Offset) -> Option<Node> {
+        self.nodes.get(offset.0)
+    }
+
+    /// cached_route routes a search for a key through the internal node at a given offset
+    /// should the node cache hold it, comparing the key to the packed keys of the node rather
+    /// than to a copy of the node. The keys bounding the child are copied if bounds is set.
+    pub(crate) fn cached_route(&self, offset: &Offset, key: &str, bounds: bool) -> Option<Route> {
+        self.nodes.route(offset.0, key, bounds)
+    }
+
+    /// decode decodes the node a page read holds, caching it if it is an internal node,
+    /// and recycles the page.
+    pub fn decode(&self, page: Page<N>) -> Result<Node, Error> {
+        let node = page.decode();
+        if let Ok(node) = &node {
+            if matches!(node.node_type, NodeType::Internal(..)) {
+                self.nodes.insert(page.offset(), node);
+            }
+        }
+        self.recycle(page);
+        node
+    }
+
+    /// recycle hands the buffer of a page done with back to the pager, to read another page into.
+    pub fn recycle(&self, page: Page<N>) {
+        self.pool.give(page);
+    }
+
+    /// get_page reads the page at a given offset, a pager can be read from by many threads at once.
+    pub fn get_page(&self, offset: &Offset) -> Result<Page<N>, Error> {
+        let page = self.read_page(offset)?;
+        // The header is the only page not holding a node.
+        if self.strict && offset.0 != 0 {
+            page.validate()?;
+        }
+        Ok(page)
+    }
+
+    /// read_page reads the page at a given offset, of strict pagers too.
+    fn read_page(&self, offset: &Offset) -> Result<Page<N>, Error> {
+        let mut buffer = self.pool.take();
+        if let Some(page) = self.staged.as_ref().and_then(|s| s.pages.get(&offset.0)) {
+            buffer.copy_from_slice(page.as_bytes());
+            return Ok(Page::from_buffer(buffer, offset.0));
+        }
+        // A read either fills the whole buffer or fails, handing no page out.
+        timed(&self.io.read_nanos, || {
+            self.store.read_at(&mut buffer[..], offset.0 as u64)
+        })?;
+        self.io.pages_read.fetch_add(1, Ordering::Relaxed);
+        trace!(offset = offset.0, "read page");
+        count!(page_reads);
+        Ok(Page::from_buffer(buffer, offset.0))
+    }
+
+    /// same_file checks whether two pagers read the same file, which a pager replaced
+    /// by a compaction no longer does even though the path remains the same.
+    pub fn same_file(&self, other: &Pager<N>) -> Result<bool, Error> {
+        Ok(self.store.identity()? == other.store.identity()?)
+    }
+
+    /// is_replaced checks whether the file under the path of the pager is no longer the one
+    /// it reads, as once a compaction swapped a compacted file in, at the cost of a stat.
+    pub fn is_replaced(&self) -> Result<bool, Error> {
+        if self.is_in_memory() {
+            return Ok(false);
+        }
+        Ok(self.store.identity()? != path_identity(&self.path)?)
+    }
+
+    /// path returns the path of the underlying file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// is_in_memory tells whether the pages are held in memory rather than in a file.
+    pub fn is_in_memory(&self) -> bool {
+        self.path.as_os_str().is_empty()
+    }
+
+    /// size returns the size in bytes of the pages written.
+    pub fn size(&self) -> Result<u64, Error> {
+        self.store.size()
+    }
+
+    /// rename_to moves the underlying file to a new path, replacing any file already there.
+    pub fn rename_to(&mut self, path: &Path) -> Result<(), Error> {
+        fs::rename(&self.path, path)?;
+        self.store = Box::new(FileStore::open(path)?);
+        self.path = path.to_path_buf();
+        self.nodes.clear();
+        Ok(())
+    }
+
+    /// sync flushes all written pages to the disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        debug_span!("fsync", file = %self.path.display());
+        count!(fsyncs);
+        time!(fsync_latency);
+        timed(&self.io.sync_nanos, || self.store.sync())
+    }
+
+    /// io returns the I/O done by the pager so far.
+    pub fn io(&self) -> IoStats {
+        let duration = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed));
+        IoStats {
+            pages_read: self.io.pages_read.load(Ordering::Relaxed),
+            read_time: duration(&self.io.read_nanos),
+            pages_written: self.io.pages_written.load(Ordering::Relaxed),
+            write_time: duration(&self.io.write_nanos),
+            sync_time: duration(&self.io.sync_nanos),
+            node_cache_hits: self.nodes.hits(),
+        }
+    }
+
+    /// torn_tail returns the offset and the length of the torn pages at the end of the store,
+    /// if any: a partially written page, along with the whole pages before it (the header
+    /// page aside) which do not decode as a node.
+    pub fn torn_tail(&self) -> Result<Option<(usize, usize)>, Error> {
+        let len = self.store.size()? as usize;
+        let mut offset = len - len % N;
+        while offset > N && self.read_page(&Offset(offset - N))?.decode().is_err() {
+            offset -= N;
+        }
+        Ok((offset < len).then_some((offset, len - offset)))
+    }
+
+    /// truncate truncates the store down to a length, the next pages being appended there.
+    pub fn truncate(&mut self, len: usize) -> Result<(), Error> {
+        self.store.truncate(len as u64)?;
+        self.curser = len;
+        self.reserved = len;
+        self.nodes.clear();
+        Ok(())
+    }
+
+    /// cursor returns the offset at which the next page will be appended. The pages of a pager
+    /// sharing its allocator (see `writer`) land past the pages of the other pagers once it used
+    /// up the run of pages it reserved, which no page appended before the cursor ever does.
+    pub fn cursor(&self) -> Offset {
+        Offset(self.curser)
+    }
+
+    /// reserve makes sure the next pages appended land one after the other from the cursor on,
+    /// failing with `DatabaseFull` past the size limit of the store and with `OffsetOverflow`
+    /// rather than letting the cursor wrap around to the header page. A pager sharing its
+    /// allocator reserves a new run of pages from it should the run it holds be too short.
+    pub fn reserve(&mut self, pages: usize) -> Result<(), Error> {
+        let end = |cursor: usize| -> Result<usize, Error> {
+            let end = pages
+                .checked_mul(N)
+                .and_then(|len| cursor.checked_add(len))
+                .ok_or(Error::OffsetOverflow { offset: cursor })?;
+            match end as u64 > self.max_size {
+                true => Err(Error::DatabaseFull {
+                    limit: self.max_size,
+                }),
+                false => Ok(end),
+            }
+        };
+        let allocator = match &self.allocator {
+            Some(allocator) if end(self.curser)? > self.reserved => allocator,
+            _ => return end(self.curser).map(|_| ()),
+        };
+        let mut start = allocator.load(Ordering::SeqCst);
+        loop {
+            let next = end(start)?;
+            match allocator.compare_exchange_weak(start, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    (self.curser, self.reserved) = (start, next);
+                    return Ok(());
+                }
+                Err(current) => start = current,
+            }
+        }
+    }
+
+    /// stage holds back the page writes which follow in memory, where they are read from,
+    /// until `apply_staged` writes them or `discard_staged` drops them, so an operation
+    /// failing halfway leaves the pages of the store (and the cursor) as they were.
+    /// An operation staged within another one is applied to (or discarded from) the pages
+    /// staged by the enclosing operation, which only the outermost operation writes.
+    pub fn stage(&mut self) {
+        match &mut self.staged {
+            Some(staged) => staged.savepoints.push(Savepoint {
+                cursor: self.curser,
+                overwritten: BTreeMap::new(),
+            }),
+            None => {
+                self.staged = Some(Staged {
+                    cursor: self.curser,
+                    pages: BTreeMap::new(),
+                    savepoints: Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// is_staged returns whether the page at an offset was appended by the (outermost) staged
+    /// operation underway, a copy no committed version of the tree reaches, which may be written over.
+    pub fn is_staged(&self, offset: &Offset) -> bool {
+        self.staged
+            .as_ref()
+            .is_some_and(|staged| offset.0 >= staged.cursor)
+    }
+
+    /// apply_staged writes the staged pages to the store, in the order of their offsets.
+    /// Should a write fail the staged pages are discarded, see `discard_staged`.
+    pub fn apply_staged(&mut self) -> Result<(), Error> {
+        if let Some(staged) = &mut self.staged {
+            if let Some(savepoint) = staged.savepoints.pop() {
+                // The enclosing operation may still be discarded, down to its own savepoint.
+                if let Some(outer) = staged.savepoints.last_mut() {
+                    for (offset, page) in savepoint.overwritten {
+                        if offset < outer.cursor {
+                            outer.overwritten.entry(offset).or_insert(page);
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        }
+        let staged = match self.staged.take() {
+            Some(staged) => staged,
+            None => return Ok(()),
+        };
+        for (offset, page) in staged.pages {
+            if let Err(e) = self.write_page_at_offset(page, &Offset(offset)) {
+                // The pages appended so far are overwritten by the next pages appended
+                // (unless other pagers may have reserved pages past them since).
+                if self.allocator.is_none() {
+                    self.curser = staged.cursor;
+                }
+                self.nodes.clear();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// discard_staged drops the pages staged by the innermost operation, moving the cursor back
+    /// to where it was (the cursor of a pager sharing its allocator only ever moves forward).
+    pub fn discard_staged(&mut self) {
+        if let Some(staged) = &mut self.staged {
+            if let Some(savepoint) = staged.savepoints.pop() {
+                staged.pages.split_off(&savepoint.cursor);
+                staged.pages.extend(savepoint.overwritten);
+                if self.allocator.is_none() {
+                    self.curser = savepoint.cursor;
+                }
+                self.nodes.clear();
+                return;
+            }
+        }
+        if let Some(staged) = self.staged.take() {
+            if self.allocator.is_none() {
+                self.curser = staged.cursor;
+            }
+            // Nodes may have been cached from the pages dropped.
+            self.nodes.clear();
+        }
     }
 
-    pub fn write_page(&mut self, page: Page) -> Result<Offset, Error> {
-        self.file.seek(SeekFrom::Start(self.curser as u64))?;
-        self.file.write_all(&page.get_data())?;
-        let res = Offset(self.curser);
-        self.curser += PAGE_SIZE;
-        Ok(res)
+    /// write_page appends a page at the cursor, see `reserve` for the failures.
+    pub fn write_page(&mut self, page: Page<N>) -> Result<Offset, Error> {
+        self.reserve(1)?;
+        let offset = self.curser;
+        self.nodes.remove(offset);
+        if let Some(staged) = &mut self.staged {
+            staged.insert(offset, page);
+            self.curser += N;
+            return Ok(Offset(offset));
+        }
+        let store = &mut self.store;
+        timed(&self.io.write_nanos, || {
+            store.write_at(page.as_bytes(), offset as u64)
+        })?;
+        self.pool.give(page);
+        self.io.pages_written.fetch_add(1, Ordering::Relaxed);
+        trace!(offset = offset, "write page");
+        count!(page_writes);
+        self.curser += N;
+        Ok(Offset(offset))
     }
 
-    pub fn write_page_at_offset(&mut self, page: Page, offset: &Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.write_all(&page.get_data())?;
+    pub fn write_page_at_offset(&mut self, page: Page<N>, offset: &Offset) -> Result<(), Error> {
+        if offset.0.checked_add(N).is_none() {
+            return Err(Error::OffsetOverflow { offset: offset.0 });
+        }
+        self.nodes.remove(offset.0);
+        if let Some(staged) = &mut self.staged {
+            staged.insert(offset.0, page);
+            return Ok(());
+        }
+        trace!(offset = offset.0, "write page");
+        count!(page_writes);
+        let store = &mut self.store;
+        timed(&self.io.write_nanos, || {
+            store.write_at(page.as_bytes(), offset.0 as u64)
+        })?;
+        self.pool.give(page);
+        self.io.pages_written.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 }
@@ -0,0 +1,422 @@
+use crate::btree::scan_sub_tree;
+use crate::diff::diff_sub_trees;
+use crate::error::Error;
+use crate::export::{read_string, read_u64, write_string};
+use crate::node_type::{Key, KeyValuePair, Offset};
+use crate::page_layout::PAGE_SIZE;
+use crate::pager::Pager;
+use crate::wal::WalRecord;
+use std::io::{Read, Write};
+
+/// The tags of the changes of an encoded record.
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+/// Change is a key-level change committed to a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Put(KeyValuePair),
+    Delete(Key),
+}
+
+/// ReplicationRecord holds the changes a single commit of a tree made, numbered by the LSN
+/// of the commit (see `BTree::replication_stream`). A snapshot record, the first of the stream
+/// from LSN 0, holds every pair of the tree as of its commit instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationRecord {
+    pub lsn: u64,
+    pub snapshot: bool,
+    pub changes: Vec<Change>,
+}
+
+impl ReplicationRecord {
+    /// encode encodes the record for shipping to a follower: the LSN, whether it is a snapshot
+    /// and the number of changes followed by the changes, every key and value prefixed by its length.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut raw = Vec::new();
+        raw.write_all(&self.lsn.to_be_bytes())?;
+        raw.write_all(&[self.snapshot as u8])?;
+        raw.write_all(&(self.changes.len() as u64).to_be_bytes())?;
+        for change in self.changes.iter() {
+            match change {
+                Change::Put(kv) => {
+                    raw.write_all(&[PUT])?;
+                    write_string(&mut raw, &kv.key)?;
+                    write_string(&mut raw, &kv.value)?;
+                }
+                Change::Delete(key) => {
+                    raw.write_all(&[DELETE])?;
+                    write_string(&mut raw, &key.0)?;
+                }
+            }
+        }
+        Ok(raw)
+    }
+
+    /// decode decodes an encoded record.
+    pub fn decode(mut raw: &[u8]) -> Result<ReplicationRecord, Error> {
+        let lsn = read_u64(&mut raw)?;
+        let mut snapshot = [0u8; 1];
+        raw.read_exact(&mut snapshot)?;
+        let snapshot = match snapshot[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidFormat("replication record")),
+        };
+        let len = read_u64(&mut raw)?;
+        let mut changes = Vec::new();
+        for _ in 0..len {
+            let mut tag = [0u8; 1];
+            raw.read_exact(&mut tag)?;
+            changes.push(match tag[0] {
+                PUT => {
+                    let key = read_string(&mut raw)?;
+                    Change::Put(KeyValuePair::new(key, read_string(&mut raw)?))
+                }
+                DELETE => Change::Delete(Key(read_string(&mut raw)?)),
+                _ => return Err(Error::InvalidFormat("replication record")),
+            });
+        }
+        if !raw.is_empty() {
+            return Err(Error::InvalidFormat("replication record"));
+        }
+        Ok(ReplicationRecord {
+            lsn,
+            snapshot,
+            changes,
+        })
+    }
+}
+
+/// ReplicationStream iterates over the records of the commits of a tree following an LSN,
+/// in order. The changes of a commit are found by comparing its root with the root of
+/// the commit preceding it, which share every page the commit did not touch.
+pub struct ReplicationStream<const N: usize = PAGE_SIZE> {
+    pager: Pager<N>,
+    base: Option<Offset>,
+    records: std::vec::IntoIter<WalRecord>,
+}
+
+impl<const N: usize> ReplicationStream<N> {
+    pub(crate) fn new(
+        pager: Pager<N>,
+        base: Option<Offset>,
+        records: Vec<WalRecord>,
+    ) -> ReplicationStream<N> {
+        ReplicationStream {
+            pager,
+            base,
+            records: records.into_iter(),
+        }
+    }
+
+    /// changes returns the changes turning the tree rooted at a base (the empty tree if None)
+    /// into the tree rooted at a root.
+    fn changes(&self, base: Option<&Offset>, root: &Offset) -> Result<Vec<Change>, Error> {
+        let mut changes = Vec::new();
+        match base {
+            Some(base) => {
+                diff_sub_trees(
+                    (&self.pager, base),
+                    (&self.pager, root),
+                    true,
+                    &mut |difference| {
+                        changes.push(match difference.right {
+                            Some(value) => Change::Put(KeyValuePair::new(difference.key, value)),
+                            None => Change::Delete(Key(difference.key)),
+                        });
+                        true
+                    },
+                )?;
+            }
+            None => scan_sub_tree(&self.pager, root, &(..), &mut |kv| {
+                changes.push(Change::Put(kv))
+            })?,
+        }
+        Ok(changes)
+    }
+}
+
+impl<const N: usize> Iterator for ReplicationStream<N> {
+    type Item = Result<ReplicationRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        let changes = self.changes(self.base.as_ref(), &record.root);
+        let lsn = record.lsn;
+        let snapshot = self.base.is_none();
+        self.base = Some(record.root);
+        Some(changes.map(|changes| ReplicationRecord {
+            lsn,
+            snapshot,
+            changes,
+        }))
+    }
+}
+
+/// Changes iterates over the key-level changes committed to a tree following an LSN, in order,
+/// each along with the LSN of the commit that made it (see `BTree::changes_since`).
+pub struct Changes<const N: usize = PAGE_SIZE> {
+    stream: ReplicationStream<N>,
+    lsn: u64,
+    pending: std::vec::IntoIter<Change>,
+}
+
+impl<const N: usize> Changes<N> {
+    pub(crate) fn new(stream: ReplicationStream<N>) -> Changes<N> {
+        Changes {
+            stream,
+            lsn: 0,
+            pending: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<const N: usize> Iterator for Changes<N> {
+    type Item = Result<(u64, Change), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.pending.next() {
+                return Some(Ok((self.lsn, change)));
+            }
+            match self.stream.next()? {
+                Ok(record) => {
+                    self.lsn = record.lsn;
+                    self.pending = record.changes.into_iter();
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn replication_stream_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::replication::{Change, ReplicationRecord};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/replication_stream")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_stream/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.put(KeyValuePair::new("03".to_string(), "w".to_string()))?;
+        btree.delete(Key("04".to_string()))?;
+
+        let records = btree
+            .replication_stream(0)?
+            .map(|record| ReplicationRecord::decode(&record?.encode()?))
+            .collect::<Result<Vec<_>, Error>>()?;
+        // The empty tree, ten inserts, a put and a delete.
+        assert_eq!(records.len(), 13);
+        assert!(records[0].snapshot && records[0].changes.is_empty());
+        assert!(records[1..].iter().all(|record| !record.snapshot));
+        assert_eq!(
+            records[1].changes,
+            vec![Change::Put(KeyValuePair::new(
+                "00".to_string(),
+                "v".to_string()
+            ))]
+        );
+        let tail = btree
+            .replication_stream(11)?
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(
+            tail,
+            vec![
+                ReplicationRecord {
+                    lsn: 12,
+                    snapshot: false,
+                    changes: vec![Change::Put(KeyValuePair::new(
+                        "03".to_string(),
+                        "w".to_string()
+                    ))],
+                },
+                ReplicationRecord {
+                    lsn: 13,
+                    snapshot: false,
+                    changes: vec![Change::Delete(Key("04".to_string()))],
+                },
+            ]
+        );
+        assert_eq!(btree.replication_stream(13)?.count(), 0);
+
+        // A follower behind the last checkpoint has to be reseeded.
+        btree.checkpoint()?;
+        assert!(matches!(
+            btree.replication_stream(11),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn followers_catch_up() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::replication::ReplicationRecord;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/replication_follower/leader")?;
+        std::fs::create_dir_all("/tmp/replication_follower/follower")?;
+        let mut leader = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_follower/leader/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut follower = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_follower/follower/db"))
+            .b_parameter(3)
+            .build()?;
+        for i in 0..10 {
+            leader.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let applied = follower.catch_up(leader.replication_stream(follower.replicated_lsn())?)?;
+        assert_eq!(applied, 11);
+        assert!(follower.content_eq(&mut leader)?);
+
+        leader.put(KeyValuePair::new("03".to_string(), "w".to_string()))?;
+        leader.delete(Key("04".to_string()))?;
+        let mut stream = leader.replication_stream(follower.replicated_lsn())?;
+        let put = stream.next().ok_or(Error::UnexpectedError)??;
+        let delete = stream.next().ok_or(Error::UnexpectedError)??;
+        // Out of order records are refused.
+        assert!(matches!(
+            follower.apply_replication_record(&delete),
+            Err(Error::Conflict)
+        ));
+        follower.apply_replication_record(&ReplicationRecord::decode(&put.encode()?)?)?;
+        follower.apply_replication_record(&delete)?;
+        assert!(matches!(
+            follower.apply_replication_record(&delete),
+            Err(Error::Conflict)
+        ));
+        assert!(follower.content_eq(&mut leader)?);
+
+        // An empty follower only bootstraps from a snapshot, not from the changes of a commit.
+        std::fs::create_dir_all("/tmp/replication_follower/empty")?;
+        let mut empty = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_follower/empty/db"))
+            .b_parameter(3)
+            .build()?;
+        let delta = leader
+            .replication_stream(5)?
+            .next()
+            .ok_or(Error::UnexpectedError)??;
+        assert!(!delta.snapshot);
+        assert!(matches!(
+            empty.apply_replication_record(&delta),
+            Err(Error::Conflict)
+        ));
+        assert_eq!(empty.replicated_lsn(), 0);
+
+        // The applied LSN survives reopening the follower.
+        let reader = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_follower/follower/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(reader.replicated_lsn(), 13);
+        Ok(())
+    }
+
+    #[test]
+    fn followers_bootstrap_after_a_checkpoint() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/replication_bootstrap/leader")?;
+        std::fs::create_dir_all("/tmp/replication_bootstrap/follower")?;
+        let mut leader = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_bootstrap/leader/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..5 {
+            leader.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        leader.checkpoint()?;
+        leader.delete(Key("01".to_string()))?;
+        leader.insert(KeyValuePair::new("05".to_string(), "v".to_string()))?;
+
+        // The stream from LSN 0 starts at the checkpointed commit, holding every pair.
+        let mut follower = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_bootstrap/follower/db"))
+            .b_parameter(3)
+            .build()?;
+        let mut stream = leader.replication_stream(0)?;
+        let base = stream.next().ok_or(Error::UnexpectedError)??;
+        assert_eq!((base.lsn, base.changes.len()), (6, 5));
+        follower.apply_replication_record(&base)?;
+        assert_eq!(follower.catch_up(stream)?, 8);
+        assert!(follower.content_eq(&mut leader)?);
+
+        // Only an empty follower bootstraps, from its first record.
+        leader.put(KeyValuePair::new("00".to_string(), "w".to_string()))?;
+        let mut stream = leader.replication_stream(0)?;
+        let base = stream.next().ok_or(Error::UnexpectedError)??;
+        assert!(matches!(
+            follower.apply_replication_record(&base),
+            Err(Error::Conflict)
+        ));
+        drop(follower);
+        let mut other = BTreeBuilder::new()
+            .path(Path::new("/tmp/replication_bootstrap/follower/db"))
+            .b_parameter(3)
+            .build()?;
+        other.insert(KeyValuePair::new("zz".to_string(), "v".to_string()))?;
+        assert!(matches!(
+            other.apply_replication_record(&base),
+            Err(Error::Conflict)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn changes_since_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::replication::Change;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/changes_since")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/changes_since/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.put(KeyValuePair::new("03".to_string(), "w".to_string()))?;
+        btree.delete(Key("04".to_string()))?;
+
+        assert_eq!(btree.changes_since(0)?.count(), 12);
+        let changes = btree
+            .changes_since(10)?
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    11,
+                    Change::Put(KeyValuePair::new("09".to_string(), "v".to_string()))
+                ),
+                (
+                    12,
+                    Change::Put(KeyValuePair::new("03".to_string(), "w".to_string()))
+                ),
+                (13, Change::Delete(Key("04".to_string()))),
+            ]
+        );
+        assert_eq!(btree.changes_since(13)?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use crate::btree::BTreeBuilder;
+use crate::error::Error;
+use crate::header::{self, Header};
+use crate::node::Node;
+use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+use crate::page_layout::{is_node_page, PAGE_SIZE};
+use crate::pager::Pager;
+use crate::verify::Problem;
+use crate::wal::Wal;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// SalvageReport is the outcome of salvaging a damaged tree file, see `salvage`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// The number of pages of the file, the header included.
+    pub pages: usize,
+    /// The b parameter of the salvaged tree.
+    pub b: usize,
+    /// The number of pairs recovered from the committed tree.
+    pub recovered: usize,
+    /// The number of pairs recovered from leaves the committed tree does not reach, for the
+    /// keys of the parts of the tree which could not be read.
+    pub recovered_from_orphans: usize,
+    /// The pages skipped as damaged, and the parts of the file which could not be read.
+    pub skipped: Vec<Problem>,
+}
+
+/// Lost is a part of the committed tree which could not be read, holding keys in the bounds
+/// (lower, upper], unbounded on None.
+type Lost = (Option<String>, Option<String>);
+
+/// salvage builds a new tree file out of whatever pairs of a damaged tree file can be recovered,
+/// without opening it as a tree. The committed tree is walked from its root, skipping the pages
+/// which do not decode as well-formed nodes (pages carry no checksums). The keys of the skipped
+/// subtrees (all keys, if the root cannot be read) are then looked for in every other intact leaf
+/// of the file, left behind by copy-on-write, the most recently written leaf holding a key winning.
+/// Those leaves may hold pairs since deleted or overwritten. Buckets and tags are not salvaged.
+/// The salvaged tree keeps the b parameter of the file, or the smallest one fitting its intact
+/// pages if the header is damaged. The salvaged tree has the page size of the file, `PAGE_SIZE`
+/// if the header is damaged.
+pub fn salvage(src: &Path, dst: &Path) -> Result<SalvageReport, Error> {
+    let page_size = header::page_size(src).unwrap_or(PAGE_SIZE);
+    with_page_size!(page_size, N => salvage_sized::<N>(src, dst))
+}
+
+/// salvage_sized salvages a tree file of pages of N bytes, see `salvage`.
+fn salvage_sized<const N: usize>(src: &Path, dst: &Path) -> Result<SalvageReport, Error> {
+    let pager = Pager::<N>::open_read_only(src)?;
+    let len = std::fs::metadata(src)?.len() as usize;
+    let mut report = SalvageReport {
+        pages: len / N,
+        ..SalvageReport::default()
+    };
+    let skip = |report: &mut SalvageReport, offset: usize, message: String| {
+        report.skipped.push(Problem { offset, message });
+    };
+    if !len.is_multiple_of(N) {
+        let message = format!("a partial page of {} bytes", len % N);
+        skip(&mut report, len - len % N, message);
+    }
+    let header = match pager
+        .get_page(&Offset(0))
+        .and_then(|page| Header::decode(&page))
+    {
+        Ok(header) if header.b >= 1 => Some(header),
+        Ok(_) | Err(_) => {
+            skip(&mut report, 0, "the header is damaged".to_string());
+            None
+        }
+    };
+
+    // Decode every page up front, the intact ones only are kept.
+    let mut nodes = BTreeMap::new();
+    for offset in (N..report.pages * N).step_by(N) {
+        match decode(&pager, offset, len) {
+            Ok(node) => {
+                nodes.insert(offset, node);
+            }
+            Err(message) => skip(&mut report, offset, message),
+        }
+    }
+
+    // Walk the committed tree, noting the parts which cannot be read.
+    let (mut pairs, mut lost, mut visited) = (BTreeMap::new(), Vec::new(), HashSet::new());
+    let parent_directory = src.parent().unwrap_or_else(|| Path::new("/tmp"));
+    match Wal::open_read_only(parent_directory.to_path_buf()).and_then(|mut wal| wal.get_root()) {
+        Ok(root) => {
+            let mut stack = vec![(root, (None, None))];
+            while let Some((offset, bounds)) = stack.pop() {
+                let node = match nodes.get(&offset.0) {
+                    Some(node) if visited.insert(offset.0) => node,
+                    _ => {
+                        lost.push(bounds);
+                        continue;
+                    }
+                };
+                match &node.node_type {
+                    NodeType::Internal(children, keys) => {
+                        for (idx, child) in children.iter().enumerate() {
+                            let lower = match idx {
+                                0 => bounds.0.clone(),
+                                _ => Some(keys[idx - 1].0.clone()),
+                            };
+                            let upper = keys.get(idx).map(|Key(key)| key.clone());
+                            stack.push((child.clone(), (lower, upper.or(bounds.1.clone()))));
+                        }
+                    }
+                    NodeType::Leaf(leaf) => {
+                        for kv in leaf.iter().filter(|kv| within(&bounds, &kv.key)) {
+                            pairs.insert(kv.key.clone(), kv.value.clone());
+                        }
+                    }
+                    NodeType::Unexpected => lost.push(bounds),
+                }
+            }
+            report.recovered = pairs.len();
+        }
+        Err(e) => {
+            skip(
+                &mut report,
+                0,
+                format!("the root log cannot be read: {}", e),
+            );
+            lost.push((None, None));
+        }
+    }
+    for (lower, upper) in lost.iter() {
+        let message = format!(
+            "the pairs of the tree in ({}, {}] could not be read",
+            lower.as_deref().unwrap_or("-"),
+            upper.as_deref().unwrap_or("-")
+        );
+        skip(&mut report, 0, message);
+    }
+
+    // Look for the lost keys in the other leaves, newest first.
+    if !lost.is_empty() {
+        let orphans = nodes
+            .iter()
+            .rev()
+            .filter(|(offset, _)| !visited.contains(*offset));
+        for (_, node) in orphans {
+            if let NodeType::Leaf(leaf) = &node.node_type {
+                for kv in leaf.iter() {
+                    if lost.iter().any(|lost| within(lost, &kv.key)) && !pairs.contains_key(&kv.key)
+                    {
+                        pairs.insert(kv.key.clone(), kv.value.clone());
+                        report.recovered_from_orphans += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    report.b = match header {
+        Some(header) => header.b,
+        None => nodes
+            .values()
+            .map(|node| match &node.node_type {
+                NodeType::Internal(_, keys) => keys.len(),
+                NodeType::Leaf(pairs) => pairs.len(),
+                NodeType::Unexpected => 0,
+            })
+            .max()
+            .map_or(2, |keys| (keys / 2 + 1).max(2)),
+    };
+    let mut tree = BTreeBuilder::new()
+        .path(dst)
+        .b_parameter(report.b)
+        .build_sized::<N>()?;
+    tree.bulk_load(
+        pairs
+            .into_iter()
+            .map(|(key, value)| KeyValuePair::new(key, value))
+            .collect(),
+    )?;
+    tree.sync()?;
+    Ok(report)
+}
+
+/// within checks whether a key lies in the bounds of a part of the tree.
+fn within(bounds: &Lost, key: &str) -> bool {
+    bounds.0.as_deref().is_none_or(|lower| key > lower)
+        && bounds.1.as_deref().is_none_or(|upper| key <= upper)
+}
+
+/// decode decodes the node of a page, refusing pages which are not well-formed nodes:
+/// keys out of order (see `TryFrom<Page> for Node`) or children outside of the node pages
+/// of the file.
+fn decode<const N: usize>(pager: &Pager<N>, offset: usize, len: usize) -> Result<Node, String> {
+    let page = pager
+        .get_page(&Offset(offset))
+        .map_err(|e| format!("an unreadable page: {}", e))?;
+    let node = page
+        .decode()
+        .map_err(|_| "a page which is not a node".to_string())?;
+    match &node.node_type {
+        NodeType::Internal(children, keys)
+            if children.len() != keys.len() + 1
+                || children.iter().any(|child| !is_node_page(child.0, len, N)) =>
+        {
+            Err("an internal node of invalid children".to_string())
+        }
+        NodeType::Unexpected => Err("a page which is not a node".to_string()),
+        _ => Ok(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn salvage_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+        use crate::page_layout::{NODE_TYPE_OFFSET, PAGE_SIZE};
+        use crate::pager::Pager;
+        use crate::salvage::salvage;
+        use std::os::unix::fs::FileExt;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/salvage/src")?;
+        std::fs::create_dir_all("/tmp/salvage/dst")?;
+        std::fs::create_dir_all("/tmp/salvage/intact")?;
+        let path = Path::new("/tmp/salvage/src/db");
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        btree.delete(Key("05".to_string()))?;
+        btree.sync()?;
+
+        // An intact file is salvaged whole.
+        let report = salvage(path, Path::new("/tmp/salvage/intact/db"))?;
+        assert_eq!((report.recovered, report.recovered_from_orphans), (39, 0));
+        let mut intact = BTreeBuilder::new()
+            .path(Path::new("/tmp/salvage/intact/db"))
+            .read_only(true)
+            .build()?;
+        assert!(intact.content_eq(&mut btree)?);
+        drop(btree);
+
+        // Damage the leaf holding "30", the newest leaf holding it, and tear the end of the file.
+        let pager = Pager::<PAGE_SIZE>::open_read_only(path)?;
+        let pages = pager.size()? as usize / PAGE_SIZE;
+        let leaf = (1..pages)
+            .rev()
+            .map(|page| page * PAGE_SIZE)
+            .find(
+                |offset| match pager.get_page(&Offset(*offset)).and_then(|p| p.decode()) {
+                    Ok(node) => match node.node_type {
+                        NodeType::Leaf(pairs) => pairs.iter().any(|kv| kv.key == "30"),
+                        _ => false,
+                    },
+                    Err(_) => false,
+                },
+            )
+            .unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(path)?;
+        file.write_all_at(&[0xFF], (leaf + NODE_TYPE_OFFSET) as u64)?;
+        file.set_len((pages * PAGE_SIZE + 100) as u64)?;
+
+        let report = salvage(path, Path::new("/tmp/salvage/dst/db"))?;
+        assert!(report.recovered_from_orphans > 0);
+        assert_eq!(report.recovered + report.recovered_from_orphans, 39);
+        assert!(report.skipped.iter().any(|problem| problem.offset == leaf));
+        assert!(report
+            .skipped
+            .iter()
+            .any(|problem| problem.offset == pages * PAGE_SIZE));
+        let mut salvaged = BTreeBuilder::new()
+            .path(Path::new("/tmp/salvage/dst/db"))
+            .read_only(true)
+            .build()?;
+        assert_eq!(salvaged.search("30".to_string())?.value, "v");
+        assert!(salvaged.search("05".to_string()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1124 @@
+use crate::btree::{scan_sub_tree, search_sub_tree, BTree};
+use crate::durability::{Durability, Durable};
+use crate::epoch::{Epochs, Participant};
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair, Offset};
+use crate::pager::Pager;
+use crate::transaction::{CommitHook, LockTable, Transaction, TransactionConfig};
+use crate::view::ReadView;
+use crate::watch::{Event, Subscription};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::ops::RangeBounds;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Version is a committed version of the tree: its root and the file its pages live in.
+/// Pages reachable from a committed root are never modified (copy-on-write), a version
+/// can only be invalidated by a compaction replacing the file, which is why every version
+/// holds on to a handle of its own file.
+struct Version {
+    root: Offset,
+    pager: Arc<Pager>,
+    generation: u64,
+}
+
+/// RetiredVersion owns a version that was published to readers and has since been replaced.
+struct RetiredVersion(*mut Version);
+
+// SAFETY: a retired version is only ever dropped, once, by whichever thread reclaims it.
+unsafe impl Send for RetiredVersion {}
+
+impl Drop for RetiredVersion {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was created by Box::into_raw when the version was published,
+        // and reclamation guarantees no reader holds a reference to it anymore.
+        drop(unsafe { Box::from_raw(self.0) });
+    }
+}
+
+/// DEFAULT_STRIPES is the number of key lock stripes of a shared tree.
+const DEFAULT_STRIPES: usize = 64;
+
+struct Shared {
+    tree: Mutex<BTree>,
+    current: AtomicPtr<Version>,
+    epochs: Epochs<RetiredVersion>,
+    stripes: Vec<Mutex<()>>,
+    locks: LockTable,
+    hooks: Mutex<Vec<CommitHook>>,
+    /// The keys written outside of transactions since the last sync, see `on_commit`.
+    unsynced: Mutex<Vec<String>>,
+    durability: Arc<Durability>,
+    buckets: Mutex<Vec<Arc<DetachedBucket>>>,
+}
+
+/// DetachedBucket is a bucket taken out of a shared tree, see `SharedBTree::bucket`. The bucket
+/// is handed back to the tree for the tree to be compacted, the next write to the bucket takes
+/// it out again.
+struct DetachedBucket {
+    name: String,
+    latch: Mutex<Option<BTree>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        drop(RetiredVersion(*self.current.get_mut()));
+    }
+}
+
+/// SharedBTree shares a tree between threads.
+/// Writes are serialized by a lock and, once committed, publish the new version of the tree
+/// (the writes to its buckets have a lock of their own, see `bucket`).
+/// Reads take no lock the writer or other readers take: every `Reader` searches the latest
+/// published version through a pager of its own (a handle to the file with its own buffer pool
+/// and node cache), and replaced versions (along with the files a compaction replaced) are
+/// reclaimed using epoch-based reclamation, only after every reader that could still observe
+/// them is done.
+#[derive(Clone)]
+pub struct SharedBTree {
+    shared: Arc<Shared>,
+}
+
+impl SharedBTree {
+    pub fn new(tree: BTree) -> Result<SharedBTree, Error> {
+        SharedBTree::with_stripes(tree, DEFAULT_STRIPES)
+    }
+
+    /// with_stripes shares a tree guarding its keys with a given number of lock stripes.
+    /// Every write holds the stripe its key hashes to (a committing transaction the stripes of
+    /// every key it writes), read-modify-write operations (see `update`) hold it for their whole
+    /// duration, so operations on keys of different
+    /// stripes never contend on each other while the tree itself, and with it any structural
+    /// change such as a split or a merge, is only locked for the duration of the actual write.
+    pub fn with_stripes(mut tree: BTree, stripes: usize) -> Result<SharedBTree, Error> {
+        let version = Version {
+            root: tree.root_offset()?,
+            pager: Arc::new(tree.reader()?),
+            generation: tree.generation(),
+        };
+        Ok(SharedBTree {
+            shared: Arc::new(Shared {
+                tree: Mutex::new(tree),
+                current: AtomicPtr::new(Box::into_raw(Box::new(version))),
+                epochs: Epochs::new(),
+                stripes: (0..stripes.max(1)).map(|_| Mutex::new(())).collect(),
+                locks: LockTable::default(),
+                hooks: Mutex::new(Vec::new()),
+                unsynced: Mutex::new(Vec::new()),
+                durability: Arc::new(Durability::default()),
+                buckets: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// insert a key value pair into the tree.
+    pub fn insert(&self, kv: KeyValuePair) -> Result<(), Error> {
+        let _stripe = self.stripe(&kv.key)?;
+        let key = kv.key.clone();
+        self.write_keys(&[&key], |tree| tree.insert(kv))
+    }
+
+    /// put inserts a key value pair into the tree replacing the value of an existing key.
+    pub fn put(&self, kv: KeyValuePair) -> Result<Option<KeyValuePair>, Error> {
+        let _stripe = self.stripe(&kv.key)?;
+        let key = kv.key.clone();
+        self.write_keys(&[&key], |tree| tree.put(kv))
+    }
+
+    /// put_durable puts a key value pair (see `put`) and returns a future
+    /// resolving once the write is durable, see `sync`.
+    pub fn put_durable(&self, kv: KeyValuePair) -> Result<Durable, Error> {
+        let _stripe = self.stripe(&kv.key)?;
+        let key = kv.key.clone();
+        let seq = self
+            .write_keys(&[&key], |tree| tree.put(kv))
+            .and_then(|_| self.shared.durability.last_written())?;
+        Ok(Durable::new(self.shared.durability.clone(), seq))
+    }
+
+    /// delete_durable deletes a given key and returns a future
+    /// resolving once the deletion is durable, see `sync`.
+    pub fn delete_durable(&self, key: Key) -> Result<Durable, Error> {
+        let _stripe = self.stripe(&key.0)?;
+        let seq = self
+            .write_keys(&[&key.0.clone()], |tree| tree.delete(key))
+            .and_then(|_| self.shared.durability.last_written())?;
+        Ok(Durable::new(self.shared.durability.clone(), seq))
+    }
+
+    /// sync makes every write committed so far durable with a single fsync,
+    /// resolving the futures waiting for any of them, then runs the commit hooks
+    /// with the keys of the writes made durable (see `on_commit`).
+    pub fn sync(&self) -> Result<(), Error> {
+        let (seq, keys) = {
+            let mut tree = self.lock()?;
+            tree.sync()?;
+            (
+                self.shared.durability.last_written()?,
+                self.take_unsynced()?,
+            )
+        };
+        self.shared.durability.synced(seq)?;
+        match keys.is_empty() {
+            true => Ok(()),
+            false => self.run_commit_hooks(keys),
+        }
+    }
+
+    /// delete deletes a given key from the tree.
+    pub fn delete(&self, key: Key) -> Result<(), Error> {
+        let _stripe = self.stripe(&key.0)?;
+        self.write_keys(&[&key.0.clone()], |tree| tree.delete(key))
+    }
+
+    /// increment atomically adds a delta to the counter stored under a key and returns its new value,
+    /// see `BTree::increment`. Concurrent increments of a key are never lost.
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64, Error> {
+        let _stripe = self.stripe(&key)?;
+        self.write_keys(&[&key.clone()], |tree| tree.increment(key, delta))
+    }
+
+    /// rename atomically moves the pair of a key to another key, see `BTree::rename`.
+    pub fn rename(&self, old: String, new: String) -> Result<(), Error> {
+        let _stripes = self.stripes([&old, &new])?;
+        self.write_keys(&[&old.clone(), &new.clone()], |tree| tree.rename(old, new))
+    }
+
+    /// update atomically replaces the value of a key with the result of applying a function
+    /// to its current value (None if the key is absent), returning None deletes the key.
+    /// The function runs holding the stripe of the key but not the tree itself.
+    pub fn update<F>(&self, key: String, f: F) -> Result<Option<String>, Error>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let _stripe = self.stripe(&key)?;
+        let current = match self.lock()?.search(key.clone()) {
+            Ok(kv) => Some(kv.value),
+            Err(Error::KeyNotFound { .. }) => None,
+            Err(e) => return Err(e),
+        };
+        let exists = current.is_some();
+        match f(current) {
+            Some(value) => {
+                let kv = KeyValuePair::new(key.clone(), value.clone());
+                self.write_keys(&[&key], |tree| tree.put(kv))?;
+                Ok(Some(value))
+            }
+            None if exists => {
+                self.write_keys(&[&key.clone()], |tree| tree.delete(Key(key)))?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// pop_first_if atomically claims and removes the pair with the smallest key matching
+    /// a predicate, or returns None if no pair matches. Concurrent callers never claim
+    /// the same pair, which allows consuming the tree as a work queue.
+    pub fn pop_first_if<P>(&self, mut pred: P) -> Result<Option<KeyValuePair>, Error>
+    where
+        P: FnMut(&str, &str) -> bool,
+    {
+        loop {
+            let candidate = match self.lock()?.first_if(&mut pred)? {
+                Some(kv) => kv,
+                None => return Ok(None),
+            };
+            // Claim the key and make sure no one changed or removed it in the meantime.
+            let _stripe = self.stripe(&candidate.key)?;
+            let key = candidate.key.clone();
+            let claimed = self.write_keys(&[&key], |tree| match tree.search(candidate.key) {
+                Ok(kv) if pred(&kv.key, &kv.value) => {
+                    tree.delete(Key(kv.key.clone()))?;
+                    Ok(Some(kv))
+                }
+                Ok(_) | Err(Error::KeyNotFound { .. }) => Ok(None),
+                Err(e) => Err(e),
+            })?;
+            if claimed.is_some() {
+                return Ok(claimed);
+            }
+        }
+    }
+
+    /// compare_and_swap replaces the value of a key with new (None deletes the key) if its
+    /// current value is the expected one (None meaning the key is absent),
+    /// failing with `Conflict` otherwise.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<(), Error> {
+        match self.try_swap(key, expected, new)? {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::Conflict),
+        }
+    }
+
+    /// compare_and_swap_with is a compare_and_swap which, instead of failing on a mismatch,
+    /// invokes a merge callback with the current and the attempted values and retries
+    /// swapping in its result, until a swap succeeds. It returns the value finally swapped in.
+    /// This allows counters or sets to be updated under contention with no retry loop at the caller.
+    pub fn compare_and_swap_with<F>(
+        &self,
+        key: String,
+        mut expected: Option<String>,
+        mut new: Option<String>,
+        mut merge: F,
+    ) -> Result<Option<String>, Error>
+    where
+        F: FnMut(Option<&str>, Option<&str>) -> Option<String>,
+    {
+        loop {
+            match self.try_swap(key.clone(), expected, new.clone())? {
+                Ok(()) => return Ok(new),
+                Err(current) => {
+                    new = merge(current.as_deref(), new.as_deref());
+                    expected = current;
+                }
+            }
+        }
+    }
+
+    /// try_swap swaps in a new value for a key holding the expected value,
+    /// returning the current value of the key on a mismatch.
+    fn try_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<Result<(), Option<String>>, Error> {
+        let _stripe = self.stripe(&key)?;
+        let current = match self.lock()?.search(key.clone()) {
+            Ok(kv) => Some(kv.value),
+            Err(Error::KeyNotFound { .. }) => None,
+            Err(e) => return Err(e),
+        };
+        if current != expected {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(value) => {
+                let kv = KeyValuePair::new(key.clone(), value);
+                self.write_keys(&[&key], |tree| tree.put(kv))?;
+            }
+            None if current.is_some() => {
+                self.write_keys(&[&key.clone()], |tree| tree.delete(Key(key)))?
+            }
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    /// compact compacts the tree along with its buckets, readers move over to the compacted file
+    /// as soon as they are done with the versions of the replaced file.
+    pub fn compact(&self) -> Result<(), Error> {
+        let buckets = self.shared.buckets.lock().map_err(|_| Error::Poisoned)?;
+        let mut latches = buckets
+            .iter()
+            .map(|bucket| bucket.latch.lock().map_err(|_| Error::Poisoned))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.write(|tree| {
+            for (bucket, latch) in buckets.iter().zip(latches.iter_mut()) {
+                if let Some(detached) = latch.take() {
+                    tree.attach_bucket(&bucket.name, detached);
+                }
+            }
+            tree.compact()
+        })
+    }
+
+    /// bucket returns a handle to a bucket of the tree (created if it does not exist yet),
+    /// written to concurrently to the tree and to the other buckets, see `SharedBucket`.
+    /// From then on the bucket is only written to through its handles.
+    pub fn bucket(&self, name: &str) -> Result<SharedBucket, Error> {
+        let mut buckets = self.shared.buckets.lock().map_err(|_| Error::Poisoned)?;
+        if let Some(bucket) = buckets.iter().find(|bucket| bucket.name == name) {
+            return Ok(SharedBucket {
+                tree: self.clone(),
+                bucket: bucket.clone(),
+            });
+        }
+        let detached = self.lock()?.detach_bucket(name)?;
+        let bucket = Arc::new(DetachedBucket {
+            name: name.to_string(),
+            latch: Mutex::new(Some(detached)),
+        });
+        buckets.push(bucket.clone());
+        Ok(SharedBucket {
+            tree: self.clone(),
+            bucket,
+        })
+    }
+
+    /// on_commit registers a hook invoked with the keys changed by every batch of writes to the
+    /// tree once the batch is durable, sorted and with no duplicates: a committed transaction
+    /// along with the writes outside of transactions its fsync makes durable, or the writes
+    /// outside of transactions a `sync` (as by a `GroupCommit`) makes durable. A hook runs exactly
+    /// once per batch, a transaction running it before its locks are released, so the hooks of
+    /// transactions changing the same key run in commit order. The writes of buckets run no hooks.
+    ///
+    /// Hooks run outside of any lock of the tree. A panicking hook does not keep the other hooks
+    /// from running, the panic is resumed once they did, after the batch is durable.
+    pub fn on_commit<F>(&self, hook: F) -> Result<(), Error>
+    where
+        F: Fn(&[String]) + Send + Sync + 'static,
+    {
+        self.shared
+            .hooks
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .push(Arc::new(hook));
+        Ok(())
+    }
+
+    /// run_commit_hooks invokes the commit hooks with the keys changed by a batch of writes.
+    pub(crate) fn run_commit_hooks(&self, mut keys: Vec<String>) -> Result<(), Error> {
+        keys.sort_unstable();
+        keys.dedup();
+        let hooks = self
+            .shared
+            .hooks
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .clone();
+        let mut panicked = None;
+        for hook in hooks.iter() {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| hook(&keys))) {
+                panicked.get_or_insert(panic);
+            }
+        }
+        if let Some(panic) = panicked {
+            panic::resume_unwind(panic);
+        }
+        Ok(())
+    }
+
+    /// take_unsynced takes the keys written outside of transactions since the last sync,
+    /// to be passed to the commit hooks once the sync is done, see `on_commit`.
+    pub(crate) fn take_unsynced(&self) -> Result<Vec<String>, Error> {
+        let mut unsynced = self.shared.unsynced.lock().map_err(|_| Error::Poisoned)?;
+        Ok(std::mem::take(&mut *unsynced))
+    }
+
+    /// checkpoint truncates the root log of the tree, see `BTree::checkpoint`.
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        self.lock()?.checkpoint()
+    }
+
+    /// watch_prefix subscribes to the changes of the keys starting with a prefix.
+    pub fn watch_prefix(&self, prefix: String) -> Result<Subscription, Error> {
+        let mut tree = self.lock()?;
+        Ok(tree.watch_prefix(prefix))
+    }
+
+    /// wait_for blocks until a given key is present in the tree, for at most timeout.
+    /// This allows simple hand-offs between threads sharing the tree:
+    /// one thread waits for a key another one is about to write.
+    pub fn wait_for(&self, key: String, timeout: Duration) -> Result<KeyValuePair, Error> {
+        let deadline = Instant::now() + timeout;
+        // Subscribe while holding the writer lock so no write can slip in between.
+        let subscription = {
+            let mut tree = self.lock()?;
+            match tree.search(key.clone()) {
+                Err(Error::KeyNotFound { .. }) => tree.watch_prefix(key.clone()),
+                res => return res,
+            }
+        };
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match subscription.recv_timeout(remaining) {
+                Some(Event::Insert(kv)) if kv.key == key => return Ok(kv),
+                Some(_) => continue,
+                None => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    /// transaction begins a pessimistic transaction, see `Transaction`.
+    pub fn transaction(&self, config: TransactionConfig) -> Transaction {
+        Transaction::new(self.clone(), config)
+    }
+
+    /// read_view returns a view pinned to the latest published version of the tree,
+    /// unlike a `Reader` the view keeps observing that version for as long as it is held.
+    pub fn read_view(&self) -> ReadView {
+        let mut reader = self.reader();
+        let (root, pager, generation) = reader.read(|version, _| {
+            (
+                version.root.clone(),
+                version.pager.clone(),
+                version.generation,
+            )
+        });
+        // The view reads through the pager the reader opened, of its own too.
+        match reader.pager.take() {
+            Some((opened, Some(own))) if opened == generation => ReadView::new(root, Arc::new(own)),
+            _ => ReadView::new(root, pager),
+        }
+    }
+
+    /// reader creates a new lock-free reader of the tree.
+    pub fn reader(&self) -> Reader {
+        Reader {
+            shared: self.shared.clone(),
+            participant: self.shared.epochs.register(),
+            pager: None,
+        }
+    }
+
+    /// stripe takes the lock stripe a key hashes to.
+    fn stripe(&self, key: &str) -> Result<MutexGuard<'_, ()>, Error> {
+        self.shared.stripes[self.stripe_of(key)]
+            .lock()
+            .map_err(|_| Error::Poisoned)
+    }
+
+    /// stripes takes the lock stripes several keys hash to. Stripes are always taken in order,
+    /// so concurrent writes of several keys never deadlock.
+    pub(crate) fn stripes<'a, I>(&self, keys: I) -> Result<Vec<MutexGuard<'_, ()>>, Error>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let stripes: BTreeSet<usize> = keys.into_iter().map(|key| self.stripe_of(key)).collect();
+        stripes
+            .into_iter()
+            .map(|stripe| {
+                self.shared.stripes[stripe]
+                    .lock()
+                    .map_err(|_| Error::Poisoned)
+            })
+            .collect()
+    }
+
+    /// stripe_of returns the index of the lock stripe a key hashes to.
+    fn stripe_of(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shared.stripes.len() as u64) as usize
+    }
+
+    /// lock takes the writer lock.
+    fn lock(&self) -> Result<MutexGuard<'_, BTree>, Error> {
+        self.shared.tree.lock().map_err(|_| Error::Poisoned)
+    }
+
+    /// locks returns the key locks of the transactions of the tree.
+    pub(crate) fn locks(&self) -> &LockTable {
+        &self.shared.locks
+    }
+
+    /// write runs a mutation under the writer lock and publishes the version it committed.
+    pub(crate) fn write<T>(
+        &self,
+        mutation: impl FnOnce(&mut BTree) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut tree = self.lock()?;
+        let res = mutation(&mut tree)?;
+        self.publish(&mut tree)?;
+        self.shared.durability.written()?;
+        Ok(res)
+    }
+
+    /// write_keys runs a write of some keys outside of a transaction (see `write`), leaving the
+    /// keys to the commit hooks of the sync making the write durable, see `on_commit`.
+    fn write_keys<T>(
+        &self,
+        keys: &[&String],
+        mutation: impl FnOnce(&mut BTree) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.write(|tree| {
+            let res = mutation(tree)?;
+            let mut unsynced = self.shared.unsynced.lock().map_err(|_| Error::Poisoned)?;
+            unsynced.extend(keys.iter().map(|key| key.to_string()));
+            Ok(res)
+        })
+    }
+
+    /// publish makes the currently committed root of the tree visible to readers.
+    fn publish(&self, tree: &mut BTree) -> Result<(), Error> {
+        // SAFETY: versions are only replaced (and so retired) by the writer, which we are.
+        let current = unsafe { &*self.shared.current.load(Ordering::SeqCst) };
+        let pager = match current.generation == tree.generation() {
+            true => current.pager.clone(),
+            false => Arc::new(tree.reader()?),
+        };
+        let version = Version {
+            root: tree.root_offset()?,
+            pager,
+            generation: tree.generation(),
+        };
+        let replaced = self
+            .shared
+            .current
+            .swap(Box::into_raw(Box::new(version)), Ordering::SeqCst);
+        self.shared.epochs.retire(RetiredVersion(replaced));
+        Ok(())
+    }
+}
+
+/// SharedBucket is a handle to a bucket of a shared tree, see `SharedBTree::bucket`.
+/// The bucket is taken out of the tree and written to under a latch of its own, its writes
+/// only share the pages of the file (appended through a page allocator of their own, see
+/// `Pager::writer`) with the writes of the tree and of the other buckets: writers of different
+/// buckets, such as the buckets of different tenants, never wait on each other.
+/// Reads of the bucket take its latch too.
+#[derive(Clone)]
+pub struct SharedBucket {
+    tree: SharedBTree,
+    bucket: Arc<DetachedBucket>,
+}
+
+impl SharedBucket {
+    /// name returns the name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.bucket.name
+    }
+
+    /// insert a key value pair into the bucket.
+    pub fn insert(&self, kv: KeyValuePair) -> Result<(), Error> {
+        self.latched(|tree| tree.insert(kv))
+    }
+
+    /// put inserts a key value pair into the bucket replacing the value of an existing key.
+    pub fn put(&self, kv: KeyValuePair) -> Result<Option<KeyValuePair>, Error> {
+        self.latched(|tree| tree.put(kv))
+    }
+
+    /// delete deletes a given key from the bucket.
+    pub fn delete(&self, key: Key) -> Result<(), Error> {
+        self.latched(|tree| tree.delete(key))
+    }
+
+    /// search searches for a specific key in the bucket.
+    pub fn search(&self, key: String) -> Result<KeyValuePair, Error> {
+        self.latched(|tree| tree.search(key))
+    }
+
+    /// range returns all the key-value pairs of the bucket whose keys fall in the given range,
+    /// in ascending order.
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<KeyValuePair>, Error> {
+        self.latched(|tree| tree.range(range))
+    }
+
+    /// sync makes every write to the bucket committed so far durable.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.latched(|tree| tree.sync())
+    }
+
+    /// latched runs an operation against the bucket holding its latch,
+    /// taking the bucket out of the tree again should a compaction have handed it back.
+    fn latched<T>(
+        &self,
+        operation: impl FnOnce(&mut BTree) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut latch = self.bucket.latch.lock().map_err(|_| Error::Poisoned)?;
+        if latch.is_none() {
+            *latch = Some(self.tree.lock()?.detach_bucket(&self.bucket.name)?);
+        }
+        operation(latch.as_mut().ok_or(Error::UnexpectedError)?)
+    }
+}
+
+/// Reader reads the latest version of a shared tree without taking any locks but those of
+/// its own pager. Each thread is expected to use a reader of its own.
+pub struct Reader {
+    shared: Arc<Shared>,
+    participant: Participant,
+    /// The pager of the reader, for the file of a generation of the tree, see `pager`.
+    pager: Option<(u64, Option<Pager>)>,
+}
+
+impl Reader {
+    /// search searches for a specific key in the latest version of the tree.
+    pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
+        self.read(|version, pager| search_sub_tree(pager, &version.root, &key))
+    }
+
+    /// range returns all the key-value pairs of the latest version of the tree
+    /// whose keys fall in the given range, in ascending order.
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<KeyValuePair>, Error> {
+        self.read(|version, pager| {
+            let mut pairs = Vec::new();
+            scan_sub_tree(pager, &version.root, &range, &mut |kv| pairs.push(kv))?;
+            Ok(pairs)
+        })
+    }
+
+    fn read<T>(&mut self, read: impl FnOnce(&Version, &Pager) -> T) -> T {
+        self.shared.epochs.pin(&self.participant);
+        // SAFETY: a version is only reclaimed after every reader pinned
+        // before it was replaced has unpinned, so it outlives this read.
+        let version = unsafe { &*self.shared.current.load(Ordering::SeqCst) };
+        let res = read(version, pager(&mut self.pager, version));
+        self.shared.epochs.unpin(&self.participant);
+        res
+    }
+}
+
+/// pager returns the pager a reader reads a version through: the pager of the reader for the
+/// file of the version, opened on the first read of the version of a new file. A reader opening
+/// the path of a file already replaced by a compaction opens the file swapped in instead, the
+/// versions of such a file are read through the pager they hold, shared by every reader.
+fn pager<'a>(own: &'a mut Option<(u64, Option<Pager>)>, version: &'a Version) -> &'a Pager {
+    if own.as_ref().map(|(generation, _)| *generation) != Some(version.generation) {
+        let opened = version
+            .pager
+            .reader()
+            .ok()
+            .filter(|pager| pager.same_file(&version.pager).unwrap_or(false));
+        *own = Some((version.generation, opened));
+    }
+    match own {
+        Some((_, Some(pager))) => pager,
+        _ => &version.pager,
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.shared.epochs.unregister(&self.participant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn readers_do_not_block_on_writes() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        std::fs::create_dir_all("/tmp/shared")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/shared/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        let committed = Arc::new(AtomicUsize::new(0));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut reader = shared.reader();
+                let committed = committed.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    loop {
+                        let n = committed.load(Ordering::SeqCst);
+                        if n > 0 {
+                            // Every committed key is visible to the readers.
+                            let kv = reader.search(format!("{:03}", n - 1))?;
+                            assert_eq!(kv.value, format!("v{}", n - 1));
+                        }
+                        if n == 100 {
+                            return Ok(());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..100 {
+            shared.insert(KeyValuePair::new(format!("{:03}", i), format!("v{}", i)))?;
+            if i == 50 {
+                shared.compact()?;
+            }
+            committed.store(i + 1, Ordering::SeqCst);
+        }
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        assert_eq!(shared.reader().range(..)?.len(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn buckets_are_written_concurrently() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::thread;
+
+        std::fs::create_dir_all("/tmp/shared_buckets")?;
+        let path = Path::new("/tmp/shared_buckets/db");
+        let btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let shared = SharedBTree::new(btree)?;
+
+        // A bucket is written to while the tree itself is busy.
+        let acme = shared.bucket("acme")?;
+        thread::scope(|scope| -> Result<(), Error> {
+            let _busy = shared.lock()?;
+            let acme = acme.clone();
+            scope
+                .spawn(move || acme.insert(KeyValuePair::new("a".to_string(), "b".to_string())))
+                .join()
+                .map_err(|_| Error::ThreadPanicked)?
+        })?;
+        acme.delete(Key("a".to_string()))?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    let bucket = shared.bucket(&format!("tenant{}", i))?;
+                    for j in 0..100 {
+                        bucket.insert(KeyValuePair::new(format!("{:03}", j), i.to_string()))?;
+                        shared.put(KeyValuePair::new(format!("{}{:03}", i, j), i.to_string()))?;
+                        if i == 0 && j == 50 {
+                            shared.compact()?;
+                        }
+                    }
+                    bucket.sync()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().map_err(|_| Error::ThreadPanicked)??;
+        }
+        for i in 0..4 {
+            let pairs = shared.bucket(&format!("tenant{}", i))?.range(..)?;
+            assert_eq!(pairs.len(), 100);
+            assert!(pairs.iter().all(|kv| kv.value == i.to_string()));
+        }
+        assert_eq!(shared.reader().range(..)?.len(), 400);
+        assert!(matches!(
+            shared.lock()?.open_bucket("tenant0"),
+            Err(Error::Unsupported(_))
+        ));
+        shared.sync()?;
+        drop((acme, shared));
+
+        // The pages of the buckets and of the tree never landed on each other.
+        let mut btree = BTreeBuilder::new().path(path).open_existing(true).build()?;
+        btree.check_invariants()?;
+        assert_eq!(btree.range(..)?.len(), 400);
+        for i in 0..4 {
+            let mut bucket = btree.open_bucket(&format!("tenant{}", i))?;
+            assert_eq!(bucket.range(..)?.len(), 100);
+        }
+        assert!(btree.open_bucket("acme")?.range(..)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn readers_read_through_pagers_of_their_own() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/shared_reader_pagers")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/shared_reader_pagers/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        for i in 0..20 {
+            shared.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let mut reader = shared.reader();
+        assert_eq!(reader.search("05".to_string())?.value, "v");
+        assert_eq!(reader.range(..)?.len(), 20);
+        // The pager of the published version, shared by every reader, read nothing.
+        assert_eq!(reader.read(|version, _| version.pager.io().pages_read), 0);
+        assert!(matches!(&reader.pager, Some((0, Some(pager))) if pager.io().pages_read > 0));
+
+        // The reader moves over to a pager of the compacted file.
+        shared.compact()?;
+        assert_eq!(reader.range(..)?.len(), 20);
+        assert!(matches!(&reader.pager, Some((1, Some(_)))));
+        assert_eq!(reader.read(|version, _| version.pager.io().pages_read), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/wait_for")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/wait_for/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+
+        let res = shared.wait_for("job".to_string(), Duration::from_millis(10));
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        let producer = shared.clone();
+        let handle = thread::spawn(move || -> Result<(), Error> {
+            thread::sleep(Duration::from_millis(20));
+            producer.insert(KeyValuePair::new("jobs".to_string(), "x".to_string()))?;
+            producer.insert(KeyValuePair::new("job".to_string(), "done".to_string()))
+        });
+        let kv = shared.wait_for("job".to_string(), Duration::from_secs(10))?;
+        assert_eq!(kv.value, "done");
+        handle.join().unwrap()?;
+
+        // Keys already present are returned right away.
+        let kv = shared.wait_for("job".to_string(), Duration::from_millis(0))?;
+        assert_eq!(kv.value, "done");
+        Ok(())
+    }
+
+    #[test]
+    fn striped_updates_are_atomic() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::thread;
+
+        std::fs::create_dir_all("/tmp/striped_updates")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/striped_updates/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::with_stripes(btree, 8)?;
+        let increment = |value: Option<String>| {
+            let n: usize = value.map_or(0, |v| v.parse().unwrap());
+            Some((n + 1).to_string())
+        };
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    for _ in 0..25 {
+                        shared.update("counter".to_string(), increment)?;
+                        shared.update(format!("counter{}", i), increment)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        let mut reader = shared.reader();
+        assert_eq!(reader.search("counter".to_string())?.value, "100");
+        for i in 0..4 {
+            assert_eq!(reader.search(format!("counter{}", i))?.value, "25");
+        }
+        shared.update("counter".to_string(), |_| None)?;
+        assert!(matches!(
+            reader.search("counter".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn updates_observe_committed_transactions() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use crate::transaction::{IsolationLevel, TransactionConfig};
+        use crate::watch::Event;
+        use std::collections::HashMap;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/updates_and_transactions")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/updates_and_transactions/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        shared.put(KeyValuePair::new("key".to_string(), "init".to_string()))?;
+        let subscription = shared.watch_prefix("key".to_string())?;
+        // The value every update replaced, by the value it swapped in.
+        let replaced = Arc::new(Mutex::new(HashMap::new()));
+
+        let updates: Vec<_> = (0..2)
+            .map(|i| {
+                let (shared, replaced) = (shared.clone(), replaced.clone());
+                thread::spawn(move || -> Result<(), Error> {
+                    for j in 0..50 {
+                        let value = format!("u{}-{}", i, j);
+                        shared.update("key".to_string(), |current| {
+                            // Leave the transactions time to commit in between, should they.
+                            thread::sleep(Duration::from_millis(1));
+                            replaced.lock().unwrap().insert(value.clone(), current);
+                            Some(value.clone())
+                        })?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        let transactions: Vec<_> = (0..2)
+            .map(|i| {
+                let shared = shared.clone();
+                let config = TransactionConfig {
+                    isolation: IsolationLevel::ReadCommitted,
+                    ..TransactionConfig::default()
+                };
+                thread::spawn(move || -> Result<(), Error> {
+                    for j in 0..50 {
+                        let mut txn = shared.transaction(config.clone());
+                        txn.put(KeyValuePair::new(
+                            "key".to_string(),
+                            format!("t{}-{}", i, j),
+                        ))?;
+                        txn.commit()?;
+                    }
+                    Ok(())
+                })
+            })
+            .chain(updates)
+            .collect();
+        for handle in transactions {
+            handle.join().unwrap()?;
+        }
+
+        // Every update replaced the value committed right before it, a transaction included.
+        let replaced = replaced.lock().unwrap();
+        let mut previous = "init".to_string();
+        let mut updates = 0;
+        while let Some(event) = subscription.try_recv() {
+            let value = match event {
+                Event::Insert(kv) | Event::Update(kv) => kv.value,
+                Event::Delete(_) => return Err(Error::UnexpectedError),
+            };
+            if value.starts_with('u') {
+                assert_eq!(replaced[&value].as_deref(), Some(previous.as_str()));
+                updates += 1;
+            }
+            previous = value;
+        }
+        assert_eq!(updates, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn pop_first_if_claims_once() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::thread;
+
+        std::fs::create_dir_all("/tmp/pop_first_if_shared")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/pop_first_if_shared/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        for i in 0..100 {
+            shared.insert(KeyValuePair::new(
+                format!("job{:03}", i),
+                "todo".to_string(),
+            ))?;
+        }
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || -> Result<Vec<String>, Error> {
+                    let mut claimed = Vec::new();
+                    while let Some(kv) = shared.pop_first_if(|k, _| k.starts_with("job"))? {
+                        claimed.push(kv.key);
+                    }
+                    Ok(claimed)
+                })
+            })
+            .collect();
+        let mut claimed = Vec::new();
+        for handle in handles {
+            claimed.extend(handle.join().unwrap()?);
+        }
+
+        claimed.sort();
+        let expected: Vec<String> = (0..100).map(|i| format!("job{:03}", i)).collect();
+        assert_eq!(claimed, expected);
+        assert!(shared.reader().range(..)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::shared::SharedBTree;
+        use std::path::Path;
+        use std::thread;
+
+        std::fs::create_dir_all("/tmp/compare_and_swap")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/compare_and_swap/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        shared.compare_and_swap("a".to_string(), None, Some("1".to_string()))?;
+        assert!(matches!(
+            shared.compare_and_swap("a".to_string(), None, Some("2".to_string())),
+            Err(Error::Conflict)
+        ));
+        shared.compare_and_swap("a".to_string(), Some("1".to_string()), None)?;
+        assert!(matches!(
+            shared.reader().search("a".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+
+        // Every thread blindly attempts to swap in 1 and adds its increment on a conflict.
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    for _ in 0..25 {
+                        shared.compare_and_swap_with(
+                            "counter".to_string(),
+                            None,
+                            Some("1".to_string()),
+                            |current, _| {
+                                let n: usize = current.map_or(0, |v| v.parse().unwrap());
+                                Some((n + 1).to_string())
+                            },
+                        )?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(shared.reader().search("counter".to_string())?.value, "100");
+
+        // Increments apply the delta to the current value, so none is lost either.
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    for _ in 0..25 {
+                        shared.increment("counter".to_string(), -1)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(shared.reader().search("counter".to_string())?.value, "0");
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Logging of the operations taking longer than a threshold, see
+//! `BTreeBuilder::slow_operation_threshold`, to catch pathological splits and I/O stalls in the field.
+use crate::pager::IoStats;
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// SlowOperation is the report of an operation that took longer than the threshold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowOperation {
+    /// The operation: "insert", "search", "delete" or "sync".
+    pub operation: &'static str,
+    /// The key operated on, None for a sync.
+    pub key: Option<String>,
+    /// How long the whole operation took.
+    pub elapsed: Duration,
+    pub pages_read: u64,
+    /// How long reading the pages took.
+    pub read_time: Duration,
+    pub pages_written: u64,
+    /// How long writing the pages took.
+    pub write_time: Duration,
+    /// How long syncing the tree file took.
+    pub sync_time: Duration,
+}
+
+impl fmt::Display for SlowOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slow {}", self.operation)?;
+        if let Some(key) = &self.key {
+            write!(f, " of {:?}", key)?;
+        }
+        write!(
+            f,
+            ": {:?} ({} pages read in {:?}, {} pages written in {:?}, synced in {:?})",
+            self.elapsed,
+            self.pages_read,
+            self.read_time,
+            self.pages_written,
+            self.write_time,
+            self.sync_time
+        )
+    }
+}
+
+/// SlowLog writes a line for every operation taking longer than a threshold.
+#[derive(Clone)]
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl SlowLog {
+    pub(crate) fn new(threshold: Duration, out: Arc<Mutex<Box<dyn Write + Send>>>) -> SlowLog {
+        SlowLog { threshold, out }
+    }
+
+    /// record logs an operation if it took longer than the threshold, given the I/O
+    /// of the pager before and after it. Failing to log does not fail the operation.
+    pub(crate) fn record(
+        &self,
+        operation: &'static str,
+        key: Option<&str>,
+        elapsed: Duration,
+        before: IoStats,
+        after: IoStats,
+    ) {
+        if elapsed < self.threshold {
+            return;
+        }
+        let report = SlowOperation {
+            operation,
+            key: key.map(String::from),
+            elapsed,
+            // A compaction in between replaces the pager, and its statistics with it.
+            pages_read: after.pages_read.saturating_sub(before.pages_read),
+            read_time: after.read_time.saturating_sub(before.read_time),
+            pages_written: after.pages_written.saturating_sub(before.pages_written),
+            write_time: after.write_time.saturating_sub(before.write_time),
+            sync_time: after.sync_time.saturating_sub(before.sync_time),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%report, "slow operation");
+        if let Ok(mut out) = self.out.lock() {
+            let _ = writeln!(out, "{}", report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn slow_operations_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::io::Write;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        #[derive(Clone, Default)]
+        struct Log(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        std::fs::create_dir_all("/tmp/slow_log")?;
+        let log = Log::default();
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/slow_log/db"))
+            .b_parameter(2)
+            .slow_operation_threshold(Duration::ZERO)
+            .slow_operation_log(log.clone())
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.search("a".to_string())?;
+        btree.delete(Key("a".to_string()))?;
+        btree.sync()?;
+
+        let logged =
+            String::from_utf8(log.0.lock().unwrap().clone()).map_err(|_| Error::UTF8Error)?;
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("slow insert of \"a\": "));
+        assert!(lines[0].contains("1 pages read"));
+        assert!(lines[1].contains("0 pages written"));
+        assert!(lines[2].starts_with("slow delete of \"a\""));
+        assert!(lines[3].starts_with("slow sync: "));
+
+        let mut quiet = BTreeBuilder::new()
+            .path(Path::new("/tmp/slow_log/quiet"))
+            .b_parameter(2)
+            .slow_operation_threshold(Duration::from_secs(60))
+            .slow_operation_log(log.clone())
+            .build()?;
+        quiet.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        assert_eq!(log.0.lock().unwrap().len(), logged.len());
+        Ok(())
+    }
+}
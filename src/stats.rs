@@ -0,0 +1,84 @@
+use crate::error::Error;
+use crate::node::Node;
+use crate::node_type::{KeyValuePair, NodeType, Offset};
+use crate::pager::Pager;
+use std::cmp;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+/// TreeStats describes the shape of a tree and the use it makes of its file, see `BTree::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of levels of the tree, a tree made of a single leaf has a height of 1.
+    pub height: usize,
+    /// The number of internal and of leaf pages of the tree.
+    pub internal_pages: usize,
+    pub leaf_pages: usize,
+    /// The number of key-value pairs of the tree.
+    pub entries: usize,
+    /// The number of pages of the file no longer reachable from the tree or any of its buckets,
+    /// left behind by copy-on-write until the file is compacted.
+    pub free_pages: usize,
+    /// The number of overflow pages, values always fit their pages so there are none.
+    pub overflow_pages: usize,
+    /// The size of the file.
+    pub total_bytes: u64,
+    /// The number of pages of the tree by how full they are, in steps of a tenth of the
+    /// 2*b-1 keys they hold at most: the first count is of the pages less than 10% full,
+    /// the last one of those 90% full or more.
+    pub fill_histogram: [usize; 10],
+    /// The longest key of the tree, and the pair of the longest value.
+    pub largest_key: Option<String>,
+    pub largest_value: Option<KeyValuePair>,
+}
+
+/// measure adds the pages and the pairs of the subtree rooted at a node at some depth
+/// to the stats, and its pages to the live pages. Pages already live are shared with
+/// another tree of the file along with their subtrees, which are skipped.
+pub(crate) fn measure<const N: usize>(
+    pager: &Pager<N>,
+    b: usize,
+    offset: &Offset,
+    depth: usize,
+    stats: &mut TreeStats,
+    live: &mut HashSet<usize>,
+) -> Result<(), Error> {
+    if !live.insert(offset.0) {
+        return Ok(());
+    }
+    stats.height = stats.height.max(depth);
+    let fill = |keys: usize| cmp::min(keys * 10 / (2 * b - 1).max(1), 9);
+    match Node::try_from(pager.get_page(offset)?)?.node_type {
+        NodeType::Internal(children, keys) => {
+            stats.internal_pages += 1;
+            stats.fill_histogram[fill(keys.len())] += 1;
+            for child_offset in children.iter() {
+                measure(pager, b, child_offset, depth + 1, stats, live)?;
+            }
+            Ok(())
+        }
+        NodeType::Leaf(pairs) => {
+            stats.leaf_pages += 1;
+            stats.entries += pairs.len();
+            stats.fill_histogram[fill(pairs.len())] += 1;
+            for kv in pairs {
+                if stats
+                    .largest_key
+                    .as_ref()
+                    .is_none_or(|key| kv.key.len() > key.len())
+                {
+                    stats.largest_key = Some(kv.key.clone());
+                }
+                if stats
+                    .largest_value
+                    .as_ref()
+                    .is_none_or(|largest| kv.value.len() > largest.value.len())
+                {
+                    stats.largest_value = Some(kv);
+                }
+            }
+            Ok(())
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+}
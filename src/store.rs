@@ -0,0 +1,506 @@
+use crate::error::Error;
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use std::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+/// PageStore holds the bytes of a tree file or of the log of its roots.
+/// Trees are kept in files (`FileStore`) unless built in memory (`MemoryStore`),
+/// which is where trees are kept on targets without a file system such as the browser.
+/// Without the `std` feature stores (of flash memory, say) are brought by the user.
+pub trait PageStore: Send + Sync {
+    /// read_at fills a buffer with the bytes at an offset, failing if the store ends before.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error>;
+
+    /// write_at writes bytes at an offset, growing the store as needed.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error>;
+
+    /// size returns the size of the store in bytes.
+    fn size(&self) -> Result<u64, Error>;
+
+    /// truncate shrinks the store down to a size.
+    fn truncate(&mut self, size: u64) -> Result<(), Error>;
+
+    /// sync makes the written bytes durable.
+    fn sync(&self) -> Result<(), Error>;
+
+    /// reader opens another handle reading the same bytes.
+    fn reader(&self) -> Result<Box<dyn PageStore>, Error>;
+
+    /// identity identifies the bytes of the store, the handles of the same bytes
+    /// (and of a file replaced under the same path, only them) share an identity.
+    fn identity(&self) -> Result<(u64, u64), Error>;
+}
+
+/// FileStore is a store of the bytes of a file.
+#[cfg(feature = "std")]
+pub struct FileStore {
+    file: File,
+    path: PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileStore {
+    /// create creates a file, truncating any file already there.
+    pub fn create(path: &Path) -> Result<FileStore, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(FileStore::new(file, path))
+    }
+
+    /// open opens an existing file for reading and writing.
+    pub fn open(path: &Path) -> Result<FileStore, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(FileStore::new(file, path))
+    }
+
+    /// open_read_only opens an existing file for reading only.
+    pub fn open_read_only(path: &Path) -> Result<FileStore, Error> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(FileStore::new(file, path))
+    }
+
+    fn new(file: File, path: &Path) -> FileStore {
+        FileStore {
+            file,
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl PageStore for FileStore {
+    /// Bytes are read positionally (without moving the cursor of the file)
+    /// so a file can be read from by many threads at once.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        read_exact_at(&self.file, buf, offset)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        use std::io::{Seek, SeekFrom, Write};
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), Error> {
+        self.file.set_len(size)?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Box<dyn PageStore>, Error> {
+        Ok(Box::new(FileStore::open_read_only(&self.path)?))
+    }
+
+    #[cfg(unix)]
+    fn identity(&self) -> Result<(u64, u64), Error> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = self.file.metadata()?;
+        Ok((metadata.dev(), metadata.ino()))
+    }
+
+    /// Without inodes to tell files apart, a replaced file passes for the original one.
+    #[cfg(not(unix))]
+    fn identity(&self) -> Result<(u64, u64), Error> {
+        Ok((0, 0))
+    }
+}
+
+/// path_identity returns the identity of the file under a path, that of the `FileStore`
+/// of the file, see `PageStore::identity`.
+#[cfg(all(feature = "std", unix))]
+pub(crate) fn path_identity(path: &Path) -> Result<(u64, u64), Error> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(all(feature = "std", not(unix)))]
+pub(crate) fn path_identity(_path: &Path) -> Result<(u64, u64), Error> {
+    Ok((0, 0))
+}
+
+/// MemoryStore is a store of bytes held in memory, lost once its last handle is dropped.
+/// Clones of a store are handles of the same bytes.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    bytes: Arc<RwLock<Vec<u8>>>,
+}
+
+#[cfg(feature = "std")]
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+/// range returns the range of the bytes of a given length at an offset of memory,
+/// None if it runs past the end of the address space.
+#[cfg(feature = "std")]
+fn range(offset: u64, len: usize) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    Some(start..start.checked_add(len)?)
+}
+
+#[cfg(feature = "std")]
+impl PageStore for MemoryStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let bytes = self.bytes.read().map_err(|_| Error::Poisoned)?;
+        match range(offset, buf.len()).and_then(|range| bytes.get(range)) {
+            Some(src) => buf.copy_from_slice(src),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let mut bytes = self.bytes.write().map_err(|_| Error::Poisoned)?;
+        let range = range(offset, buf.len()).ok_or(Error::OffsetOverflow {
+            offset: offset as usize,
+        })?;
+        if bytes.len() < range.end {
+            bytes.resize(range.end, 0x00);
+        }
+        bytes[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        let bytes = self.bytes.read().map_err(|_| Error::Poisoned)?;
+        Ok(bytes.len() as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), Error> {
+        let mut bytes = self.bytes.write().map_err(|_| Error::Poisoned)?;
+        bytes.truncate(size as usize);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Box<dyn PageStore>, Error> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn identity(&self) -> Result<(u64, u64), Error> {
+        Ok((0, Arc::as_ptr(&self.bytes) as u64))
+    }
+}
+
+/// Fault is what a `FaultyStore` does to a write it was programmed to fault.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail the write, leaving the store untouched.
+    Fail,
+    /// Delay the write, which then goes through.
+    Delay(Duration),
+    /// Write only the first bytes of the write (a torn write) and fail it.
+    Truncate(usize),
+    /// Fail the write and every later write and sync, as if the process crashed before the write.
+    Crash,
+}
+
+/// FaultPlan holds the faults programmed into the stores sharing it, see `FaultyStore`.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct FaultPlan {
+    /// The faults and the numbers of the writes they hit.
+    faults: Vec<(u64, Fault)>,
+    writes: u64,
+    crashed: bool,
+}
+
+/// FaultyStore wraps a store, failing, delaying or truncating the writes it was programmed to,
+/// for testing crash recovery deterministically:
+///
+/// ```
+/// use btree::store::{Fault, FaultyStore, MemoryStore};
+///
+/// let pages = MemoryStore::new();
+/// let faulty = FaultyStore::new(pages.clone());
+/// faulty.fault_write(3, Fault::Crash);
+/// // Build a tree on faulty, write until the crash and reopen the tree on pages.
+/// ```
+///
+/// The writes are numbered from 1 and counted across the stores sharing the faults of the store
+/// (see `sharing_faults`), so a crash hits the files of a tree at once. Reads are never faulted.
+/// Clones of a store are handles of the same store.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct FaultyStore {
+    inner: Arc<RwLock<Box<dyn PageStore>>>,
+    plan: Arc<Mutex<FaultPlan>>,
+}
+
+#[cfg(feature = "std")]
+impl FaultyStore {
+    pub fn new(inner: impl PageStore + 'static) -> FaultyStore {
+        FaultyStore {
+            inner: Arc::new(RwLock::new(Box::new(inner))),
+            plan: Arc::new(Mutex::new(FaultPlan::default())),
+        }
+    }
+
+    /// sharing_faults wraps another store, sharing the faults (and the count of writes) of this one.
+    pub fn sharing_faults(&self, inner: impl PageStore + 'static) -> FaultyStore {
+        FaultyStore {
+            inner: Arc::new(RwLock::new(Box::new(inner))),
+            plan: self.plan.clone(),
+        }
+    }
+
+    /// fault_write programs a fault into the n-th write from now on, 1 being the next one.
+    pub fn fault_write(&self, n: u64, fault: Fault) {
+        if let Ok(mut plan) = self.plan.lock() {
+            let at = plan.writes + n;
+            plan.faults.push((at, fault));
+        }
+    }
+
+    /// writes returns the number of writes made so far, faulted ones included.
+    pub fn writes(&self) -> u64 {
+        self.plan.lock().map(|plan| plan.writes).unwrap_or(0)
+    }
+
+    /// has_crashed tells whether a `Fault::Crash` was hit.
+    pub fn has_crashed(&self) -> bool {
+        self.plan.lock().map(|plan| plan.crashed).unwrap_or(true)
+    }
+
+    /// next_fault counts a write and returns the fault it was programmed with, if any.
+    fn next_fault(&self) -> Result<Option<Fault>, Error> {
+        let mut plan = self.plan.lock().map_err(|_| Error::Poisoned)?;
+        if plan.crashed {
+            return Err(Error::InjectedFault);
+        }
+        plan.writes += 1;
+        let n = plan.writes;
+        let fault = plan
+            .faults
+            .iter()
+            .position(|(at, _)| *at == n)
+            .map(|idx| plan.faults.remove(idx).1);
+        if fault == Some(Fault::Crash) {
+            plan.crashed = true;
+        }
+        Ok(fault)
+    }
+
+    fn check_crashed(&self) -> Result<(), Error> {
+        match self.has_crashed() {
+            true => Err(Error::InjectedFault),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl PageStore for FaultyStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let inner = self.inner.read().map_err(|_| Error::Poisoned)?;
+        inner.read_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let fault = self.next_fault()?;
+        let mut inner = self.inner.write().map_err(|_| Error::Poisoned)?;
+        match fault {
+            None => inner.write_at(buf, offset),
+            Some(Fault::Delay(delay)) => {
+                std::thread::sleep(delay);
+                inner.write_at(buf, offset)
+            }
+            Some(Fault::Truncate(len)) => {
+                inner.write_at(&buf[..len.min(buf.len())], offset)?;
+                Err(Error::InjectedFault)
+            }
+            Some(Fault::Fail) | Some(Fault::Crash) => Err(Error::InjectedFault),
+        }
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        let inner = self.inner.read().map_err(|_| Error::Poisoned)?;
+        inner.size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), Error> {
+        self.check_crashed()?;
+        let mut inner = self.inner.write().map_err(|_| Error::Poisoned)?;
+        inner.truncate(size)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.check_crashed()?;
+        let inner = self.inner.read().map_err(|_| Error::Poisoned)?;
+        inner.sync()
+    }
+
+    fn reader(&self) -> Result<Box<dyn PageStore>, Error> {
+        let inner = self.inner.read().map_err(|_| Error::Poisoned)?;
+        inner.reader()
+    }
+
+    fn identity(&self) -> Result<(u64, u64), Error> {
+        let inner = self.inner.read().map_err(|_| Error::Poisoned)?;
+        inner.identity()
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(all(feature = "std", windows))]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Targets without positional reads (the file system of which, if any, is unsupported anyway)
+/// read through another handle of the file.
+#[cfg(all(feature = "std", not(any(unix, windows))))]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn faulty_store_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::store::{Fault, FaultyStore, MemoryStore};
+
+        // Crash a tree at every write of a run of inserts in turn, the reopened tree holds
+        // exactly the pairs inserted before the crash.
+        for crash_at in 1..40 {
+            let (pages, roots) = (MemoryStore::new(), MemoryStore::new());
+            let faulty = FaultyStore::new(pages.clone());
+            let faulty_roots = faulty.sharing_faults(roots.clone());
+            let mut btree = BTreeBuilder::new()
+                .b_parameter(2)
+                .stores(faulty.clone(), faulty_roots)
+                .build()?;
+            faulty.fault_write(crash_at, Fault::Crash);
+            let mut inserted = 0;
+            while btree
+                .insert(KeyValuePair::new(inserted.to_string(), "v".to_string()))
+                .is_ok()
+            {
+                inserted += 1;
+            }
+            assert!(faulty.has_crashed());
+            assert!(btree.sync().is_err());
+
+            let mut btree = BTreeBuilder::new()
+                .stores(pages, roots)
+                .open_existing(true)
+                .build()?;
+            let pairs = btree.range(..)?;
+            assert_eq!(pairs.len(), inserted);
+            btree.insert(KeyValuePair::new("z".to_string(), "v".to_string()))?;
+            assert_eq!(btree.search("z".to_string())?.value, "v");
+        }
+
+        // A torn page is left behind by a failed write, which halts the writes of the tree.
+        let (pages, roots) = (MemoryStore::new(), MemoryStore::new());
+        let faulty = FaultyStore::new(pages.clone());
+        let mut btree = BTreeBuilder::new()
+            .b_parameter(2)
+            .stores(faulty.clone(), roots.clone())
+            .build()?;
+        faulty.fault_write(1, Fault::Truncate(100));
+        let kv = KeyValuePair::new("a".to_string(), "shalom".to_string());
+        assert!(btree.insert(kv.clone()).is_err());
+        assert!(btree.halted().is_some());
+        assert!(matches!(
+            btree.insert(kv.clone()),
+            Err(Error::Halted { .. })
+        ));
+        drop(btree);
+
+        // Reopened past the torn page, the tree takes writes again.
+        let faulty = FaultyStore::new(pages);
+        let mut btree = BTreeBuilder::new()
+            .stores(faulty.clone(), roots)
+            .open_existing(true)
+            .truncate_torn_pages(true)
+            .build()?;
+        let writes = faulty.writes();
+        faulty.fault_write(1, Fault::Delay(std::time::Duration::from_millis(1)));
+        btree.insert(kv)?;
+        // The copy of the root leaf is staged, and written, once.
+        assert_eq!(faulty.writes(), writes + 1);
+        assert_eq!(btree.search("a".to_string())?.value, "shalom");
+        Ok(())
+    }
+
+    #[test]
+    fn offsets_do_not_wrap_around() -> Result<(), Error> {
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::page_layout::{is_node_page, PAGE_SIZE};
+        use crate::pager::Pager;
+        use crate::store::{MemoryStore, PageStore};
+        use std::path::Path;
+
+        let mut store = MemoryStore::new();
+        let mut buf = [0x00; 8];
+        assert!(store.write_at(&buf, u64::MAX - 4).is_err());
+        assert!(store.read_at(&mut buf, u64::MAX - 4).is_err());
+        assert_eq!(store.size()?, 0);
+
+        let mut pager = Pager::from_store(Box::new(store), Path::new(""))?;
+        let offset = Offset(usize::MAX - PAGE_SIZE + 1);
+        assert!(matches!(
+            pager.write_page_at_offset(Page::new([0x00; PAGE_SIZE]), &offset),
+            Err(Error::OffsetOverflow { .. })
+        ));
+        assert!(pager.get_page(&offset).is_err());
+        assert!(!is_node_page(offset.0, usize::MAX, PAGE_SIZE));
+        assert!(is_node_page(PAGE_SIZE, 2 * PAGE_SIZE, PAGE_SIZE));
+        Ok(())
+    }
+}
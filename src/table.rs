@@ -0,0 +1,155 @@
+use crate::btree::BTree;
+use crate::error::Error;
+use crate::index::IndexDefinition;
+use crate::node_type::{Key, KeyValuePair};
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// Record is a row of a table: it knows its primary key and how to
+/// encode itself into (and decode itself from) the value stored under that key.
+pub trait Record: Sized {
+    fn primary_key(&self) -> String;
+    fn encode(&self) -> String;
+    fn decode(key: &str, value: &str) -> Result<Self, Error>;
+}
+
+/// Table is a typed view of a tree whose pairs are the records of a single type.
+pub struct Table<R: Record> {
+    tree: BTree,
+    records: PhantomData<R>,
+}
+
+impl<R: Record + 'static> Table<R> {
+    pub fn new(tree: BTree) -> Table<R> {
+        Table {
+            tree,
+            records: PhantomData,
+        }
+    }
+
+    /// with_index registers a secondary index of the table over a key extracted from every record.
+    pub fn with_index<F>(mut self, name: &str, unique: bool, extract: F) -> Result<Table<R>, Error>
+    where
+        F: Fn(&R) -> Option<String> + Send + Sync + 'static,
+    {
+        let index = IndexDefinition::new(name, move |key, value| {
+            R::decode(key, value)
+                .ok()
+                .and_then(|record| extract(&record))
+        });
+        self.tree.register_index(index.unique(unique))?;
+        Ok(self)
+    }
+
+    /// put_record writes a record, replacing the record with the same primary key (if any).
+    pub fn put_record(&mut self, record: &R) -> Result<Option<R>, Error> {
+        let kv = KeyValuePair::new(record.primary_key(), record.encode());
+        match self.tree.put(kv)? {
+            Some(replaced) => Ok(Some(R::decode(&replaced.key, &replaced.value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// get_record returns the record with a given primary key.
+    pub fn get_record(&mut self, key: String) -> Result<R, Error> {
+        let kv = self.tree.search(key)?;
+        R::decode(&kv.key, &kv.value)
+    }
+
+    /// delete_record deletes the record with a given primary key.
+    pub fn delete_record(&mut self, key: String) -> Result<(), Error> {
+        self.tree.delete(Key(key))
+    }
+
+    /// scan_records returns the records whose primary keys fall in the given range,
+    /// in ascending order.
+    pub fn scan_records<B: RangeBounds<String>>(&mut self, range: B) -> Result<Vec<R>, Error> {
+        self.tree
+            .range(range)?
+            .iter()
+            .map(|kv| R::decode(&kv.key, &kv.value))
+            .collect()
+    }
+
+    /// get_by_index returns the records an index maps a given index key to.
+    pub fn get_by_index(&mut self, index: &str, key: &str) -> Result<Vec<R>, Error> {
+        self.tree
+            .get_by_index(index, key)?
+            .iter()
+            .map(|kv| R::decode(&kv.key, &kv.value))
+            .collect()
+    }
+
+    /// into_inner returns the underlying tree.
+    pub fn into_inner(self) -> BTree {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::table::Record;
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    impl Record for User {
+        fn primary_key(&self) -> String {
+            self.name.clone()
+        }
+
+        fn encode(&self) -> String {
+            self.age.to_string()
+        }
+
+        fn decode(key: &str, value: &str) -> Result<User, Error> {
+            Ok(User {
+                name: key.to_string(),
+                age: value.parse().map_err(|_| Error::InvalidFormat("row"))?,
+            })
+        }
+    }
+
+    #[test]
+    fn table_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::table::Table;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/table")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/table/db"))
+            .b_parameter(2)
+            .build()?;
+        let mut users = Table::<User>::new(btree)
+            .with_index("by_age", false, |user| Some(format!("{:03}", user.age)))?;
+        let user = |name: &str, age| User {
+            name: name.to_string(),
+            age,
+        };
+        users.put_record(&user("alice", 30))?;
+        users.put_record(&user("bob", 25))?;
+        users.put_record(&user("carol", 30))?;
+        assert_eq!(users.put_record(&user("bob", 26))?, Some(user("bob", 25)));
+
+        assert_eq!(users.get_record("bob".to_string())?, user("bob", 26));
+        assert_eq!(
+            users.scan_records("b".to_string()..)?,
+            vec![user("bob", 26), user("carol", 30)]
+        );
+        assert_eq!(
+            users.get_by_index("by_age", "030")?,
+            vec![user("alice", 30), user("carol", 30)]
+        );
+        users.delete_record("alice".to_string())?;
+        assert_eq!(
+            users.get_by_index("by_age", "030")?,
+            vec![user("carol", 30)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Instrumentation of the tree with `tracing` spans and events and with metrics, compiled out
+//! entirely without the `tracing` and `metrics` features. The tracing macros mirror those of `tracing`.
+
+/// debug_span enters a span at the debug level for the rest of the enclosing block.
+macro_rules! debug_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($args)*).entered();
+    };
+}
+
+/// debug records an event at the debug level.
+macro_rules! debug {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($args)*);
+    };
+}
+
+/// trace records an event at the trace level.
+macro_rules! trace {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($args)*);
+    };
+}
+
+/// count increments a counter of the metrics.
+macro_rules! count {
+    ($counter:ident) => {
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().$counter.inc();
+    };
+}
+
+/// time times the rest of the enclosing block into a histogram of the metrics.
+macro_rules! time {
+    ($histogram:ident) => {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::metrics().$histogram.start_timer();
+    };
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn tracing_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fmt::Debug;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Recorder records the names of the spans and the messages of the events.
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        struct Message<'a>(&'a mut Vec<String>);
+
+        impl Visit for Message<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                if field.name() == "message" {
+                    self.0.push(format!("{:?}", value));
+                }
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut recorded = self.0.lock().unwrap();
+                recorded.push(span.metadata().name().to_string());
+                Id::from_u64(recorded.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                event.record(&mut Message(&mut self.0.lock().unwrap()));
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        std::fs::create_dir_all("/tmp/tracing")?;
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(recorded.clone()), || {
+            let mut btree = BTreeBuilder::new()
+                .path(Path::new("/tmp/tracing/db"))
+                .b_parameter(2)
+                .build()?;
+            for i in 0..10 {
+                btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+            }
+            btree.search("3".to_string())?;
+            btree.sync()
+        })?;
+        let recorded = recorded.lock().unwrap();
+        for expected in [
+            "insert",
+            "search",
+            "fsync",
+            "split root",
+            "split node",
+            "read page",
+            "write page",
+        ] {
+            assert!(recorded.iter().any(|r| r == expected), "{}", expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair};
+use crate::shared::{Reader, SharedBTree};
+use crate::view::ReadView;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// CommitHook is invoked with the keys changed by a durable batch of writes, see `SharedBTree::on_commit`.
+pub type CommitHook = Arc<dyn Fn(&[String]) + Send + Sync>;
+
+/// IsolationLevel determines which committed writes of others the reads of a transaction observe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Every read observes the latest committed version of the tree.
+    /// Reads are as cheap as reads outside of a transaction.
+    ReadCommitted,
+    /// Every read observes the version of the tree committed when the transaction began,
+    /// writing a key which was changed since then fails the write with a conflict.
+    /// The version is pinned (see `ReadView`) for the lifetime of the transaction.
+    Snapshot,
+}
+
+/// TransactionConfig configures a transaction.
+#[derive(Clone, Debug)]
+pub struct TransactionConfig {
+    /// How long to wait for a key locked by another transaction before giving up with a timeout.
+    pub lock_timeout: Duration,
+    pub isolation: IsolationLevel,
+}
+
+impl Default for TransactionConfig {
+    // A default TransactionConfig waits up to a second for a lock
+    // and uses snapshot isolation.
+    fn default() -> Self {
+        TransactionConfig {
+            lock_timeout: Duration::from_secs(1),
+            isolation: IsolationLevel::Snapshot,
+        }
+    }
+}
+
+/// LockTable holds the key locks taken by the transactions of a shared tree,
+/// along with a wait-for graph of the transactions waiting on each other used to detect deadlocks.
+#[derive(Default)]
+pub(crate) struct LockTable {
+    next_id: AtomicU64,
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    /// The transaction holding the lock of every locked key.
+    owners: HashMap<String, u64>,
+    /// The transaction every waiting transaction waits for, as a transaction
+    /// waits on a single key at a time it waits for a single transaction.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// deadlocked follows the wait-for graph starting from a transaction
+    /// and reports whether it leads back to the transaction.
+    fn deadlocked(&self, txn: u64) -> bool {
+        let mut current = txn;
+        // Every other cycle would have been detected when it was formed, bound the walk anyway.
+        for _ in 0..self.waits_for.len() {
+            match self.waits_for.get(&current) {
+                Some(&next) if next == txn => return true,
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockTable {
+    /// begin allocates the id of a new transaction.
+    fn begin(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// acquire takes the lock of a key on behalf of a transaction, waiting for its holder
+    /// to release it for at most the given timeout. Waiting on a transaction which (possibly indirectly)
+    /// waits on this one returns a deadlock error right away.
+    fn acquire(&self, txn: u64, key: &str, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+        loop {
+            let owner = match state.owners.get(key) {
+                Some(&owner) if owner != txn => owner,
+                Some(_) => return Ok(()),
+                None => {
+                    state.owners.insert(key.to_string(), txn);
+                    state.waits_for.remove(&txn);
+                    return Ok(());
+                }
+            };
+            state.waits_for.insert(txn, owner);
+            if state.deadlocked(txn) {
+                state.waits_for.remove(&txn);
+                return Err(Error::Deadlock);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&txn);
+                return Err(Error::Timeout);
+            }
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .map_err(|_| Error::Poisoned)?
+                .0;
+        }
+    }
+
+    /// release releases every lock held by a transaction and wakes up the waiting transactions.
+    fn release(&self, txn: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.owners.retain(|_, owner| *owner != txn);
+            state.waits_for.remove(&txn);
+        }
+        self.released.notify_all();
+    }
+}
+
+/// Transaction is a pessimistic transaction over a shared tree.
+/// Every key the transaction writes (or reads using `get_for_update`) is locked until the
+/// transaction ends and reads observe the versions allowed by its isolation level.
+/// Writes are buffered and applied to the tree at once on commit,
+/// so readers observe either all of them or none of them.
+/// Dropping a transaction without committing it rolls it back.
+pub struct Transaction {
+    tree: SharedBTree,
+    reader: Reader,
+    /// The version reads observe under snapshot isolation.
+    snapshot: Option<ReadView>,
+    id: u64,
+    config: TransactionConfig,
+    /// The buffered writes, None marks a deletion.
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(tree: SharedBTree, config: TransactionConfig) -> Transaction {
+        let snapshot = match config.isolation {
+            IsolationLevel::Snapshot => Some(tree.read_view()),
+            IsolationLevel::ReadCommitted => None,
+        };
+        Transaction {
+            reader: tree.reader(),
+            snapshot,
+            id: tree.locks().begin(),
+            tree,
+            config,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// get returns the value of a key as seen by the transaction, None if the key is absent.
+    pub fn get(&mut self, key: String) -> Result<Option<String>, Error> {
+        if let Some(write) = self.writes.get(&key) {
+            return Ok(write.clone());
+        }
+        match &self.snapshot {
+            Some(snapshot) => found(snapshot.search(key)),
+            None => found(self.reader.search(key)),
+        }
+    }
+
+    /// get_for_update locks a key and returns its value as seen by the transaction,
+    /// so no other transaction can change the key until this one ends.
+    pub fn get_for_update(&mut self, key: String) -> Result<Option<String>, Error> {
+        self.lock(&key)?;
+        self.get(key)
+    }
+
+    /// put writes a key value pair replacing the value of an existing key.
+    pub fn put(&mut self, kv: KeyValuePair) -> Result<(), Error> {
+        self.lock(&kv.key)?;
+        self.writes.insert(kv.key, Some(kv.value));
+        Ok(())
+    }
+
+    /// delete deletes a given key.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        if self.get_for_update(key.0.clone())?.is_none() {
+            return Err(Error::KeyNotFound { key: key.0 });
+        }
+        self.writes.insert(key.0, None);
+        Ok(())
+    }
+
+    /// commit applies the writes of the transaction to the tree as a single write (see
+    /// `BTree::atomically`), makes them durable, runs the commit hooks (along with the writes
+    /// outside of transactions made durable too, see `SharedBTree::on_commit`) and releases
+    /// the locks of the transaction. Should any write fail, none of them is applied.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let writes = std::mem::take(&mut self.writes);
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<String> = writes.keys().cloned().collect();
+        // The stripes keep the read-modify-write operations of the keys out (see `SharedBTree::update`).
+        let stripes = self.tree.stripes(&keys)?;
+        let unsynced = self.tree.write(|tree| {
+            tree.atomically(|tree| {
+                for (key, write) in writes {
+                    match write {
+                        Some(value) => {
+                            tree.put(KeyValuePair::new(key, value))?;
+                        }
+                        // The key may have been deleted outside of any transaction in the meantime.
+                        None => match tree.delete(Key(key)) {
+                            Ok(()) | Err(Error::KeyNotFound { .. }) => {}
+                            Err(e) => return Err(e),
+                        },
+                    }
+                }
+                Ok(())
+            })?;
+            tree.sync()?;
+            self.tree.take_unsynced()
+        })?;
+        drop(stripes);
+        self.tree
+            .run_commit_hooks(keys.into_iter().chain(unsynced).collect())
+    }
+
+    /// rollback discards the writes of the transaction and releases its locks.
+    pub fn rollback(self) {}
+
+    /// lock locks a key, under snapshot isolation the key must not have changed since the snapshot.
+    fn lock(&mut self, key: &str) -> Result<(), Error> {
+        self.tree
+            .locks()
+            .acquire(self.id, key, self.config.lock_timeout)?;
+        if let Some(snapshot) = &self.snapshot {
+            if !self.writes.contains_key(key) {
+                let seen = found(snapshot.search(key.to_string()))?;
+                if seen != found(self.reader.search(key.to_string()))? {
+                    return Err(Error::Conflict);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.tree.locks().release(self.id);
+    }
+}
+
+/// found turns a missing key into None.
+fn found(res: Result<KeyValuePair, Error>) -> Result<Option<String>, Error> {
+    match res {
+        Ok(kv) => Ok(Some(kv.value)),
+        Err(Error::KeyNotFound { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+////////////////////
+//              //
+//  Unit Tests. //
+//              //
+////////////////////
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn transaction_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::shared::SharedBTree;
+        use crate::transaction::TransactionConfig;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/transaction")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/transaction/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        shared.insert(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        let mut reader = shared.reader();
+
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("b".to_string(), "2".to_string()))?;
+        txn.delete(Key("a".to_string()))?;
+        assert_eq!(txn.get("b".to_string())?, Some("2".to_string()));
+        assert_eq!(txn.get("a".to_string())?, None);
+        // Nothing is visible before the commit.
+        assert_eq!(reader.search("a".to_string())?.value, "1");
+        txn.commit()?;
+        assert_eq!(reader.search("b".to_string())?.value, "2");
+        assert!(matches!(
+            reader.search("a".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("c".to_string(), "3".to_string()))?;
+        txn.rollback();
+        assert!(matches!(
+            reader.search("c".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_commit_changes_nothing() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use crate::transaction::TransactionConfig;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/failed_commit")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/failed_commit/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        let mut reader = shared.reader();
+
+        // The first write of the commit succeeds, the second one is oversize.
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        txn.put(KeyValuePair::new("b".to_string(), "x".repeat(64)))?;
+        assert!(matches!(txn.commit(), Err(Error::ValueOverflowError)));
+
+        // Neither the failed commit nor the next write make the first one visible.
+        shared.put(KeyValuePair::new("c".to_string(), "3".to_string()))?;
+        assert!(matches!(
+            reader.search("a".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert_eq!(reader.range(..)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_timeout_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use crate::transaction::TransactionConfig;
+        use std::path::Path;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/lock_timeout")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/lock_timeout/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        let config = TransactionConfig {
+            lock_timeout: Duration::from_millis(50),
+            ..TransactionConfig::default()
+        };
+
+        let mut first = shared.transaction(config.clone());
+        first.put(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        let mut second = shared.transaction(config.clone());
+        assert!(matches!(
+            second.put(KeyValuePair::new("a".to_string(), "2".to_string())),
+            Err(Error::Timeout)
+        ));
+        first.commit()?;
+        second.rollback();
+        let mut third = shared.transaction(config);
+        third.put(KeyValuePair::new("a".to_string(), "2".to_string()))?;
+        third.commit()?;
+        assert_eq!(shared.reader().search("a".to_string())?.value, "2");
+        Ok(())
+    }
+
+    #[test]
+    fn deadlock_detection_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use crate::transaction::TransactionConfig;
+        use std::path::Path;
+        use std::thread;
+        use std::time::Duration;
+
+        std::fs::create_dir_all("/tmp/deadlock")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/deadlock/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        let config = TransactionConfig {
+            lock_timeout: Duration::from_secs(10),
+            ..TransactionConfig::default()
+        };
+
+        let mut first = shared.transaction(config.clone());
+        let mut second = shared.transaction(config);
+        first.put(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        second.put(KeyValuePair::new("b".to_string(), "2".to_string()))?;
+        let handle = thread::spawn(move || -> Result<(), Error> {
+            // Blocks until the second transaction is rolled back.
+            first.put(KeyValuePair::new("b".to_string(), "1".to_string()))?;
+            first.commit()
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            second.put(KeyValuePair::new("a".to_string(), "2".to_string())),
+            Err(Error::Deadlock)
+        ));
+        second.rollback();
+        handle.join().unwrap()?;
+
+        let mut reader = shared.reader();
+        assert_eq!(reader.search("a".to_string())?.value, "1");
+        assert_eq!(reader.search("b".to_string())?.value, "1");
+        Ok(())
+    }
+
+    #[test]
+    fn isolation_levels_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::shared::SharedBTree;
+        use crate::transaction::{IsolationLevel, TransactionConfig};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/isolation_levels")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/isolation_levels/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        shared.insert(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+
+        let mut snapshot = shared.transaction(TransactionConfig::default());
+        let mut read_committed = shared.transaction(TransactionConfig {
+            isolation: IsolationLevel::ReadCommitted,
+            ..TransactionConfig::default()
+        });
+        shared.put(KeyValuePair::new("a".to_string(), "2".to_string()))?;
+
+        assert_eq!(snapshot.get("a".to_string())?, Some("1".to_string()));
+        assert_eq!(read_committed.get("a".to_string())?, Some("2".to_string()));
+        // The key changed since the snapshot was taken.
+        assert!(matches!(
+            snapshot.put(KeyValuePair::new("a".to_string(), "3".to_string())),
+            Err(Error::Conflict)
+        ));
+        snapshot.rollback();
+        read_committed.put(KeyValuePair::new("a".to_string(), "3".to_string()))?;
+        read_committed.commit()?;
+        assert_eq!(shared.reader().search("a".to_string())?.value, "3");
+        Ok(())
+    }
+
+    #[test]
+    fn commit_hooks_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::shared::SharedBTree;
+        use crate::transaction::TransactionConfig;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+
+        std::fs::create_dir_all("/tmp/commit_hooks")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/commit_hooks/db"))
+            .b_parameter(2)
+            .build()?;
+        let shared = SharedBTree::new(btree)?;
+        shared.insert(KeyValuePair::new("a".to_string(), "1".to_string()))?;
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let hook_committed = committed.clone();
+        shared.on_commit(move |keys| hook_committed.lock().unwrap().push(keys.to_vec()))?;
+
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("b".to_string(), "2".to_string()))?;
+        txn.delete(Key("a".to_string()))?;
+        txn.commit()?;
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("c".to_string(), "3".to_string()))?;
+        txn.rollback();
+        shared.transaction(TransactionConfig::default()).commit()?;
+
+        assert_eq!(
+            *committed.lock().unwrap(),
+            vec![vec!["a".to_string(), "b".to_string()]]
+        );
+
+        // Writes outside of transactions run the hooks once a sync makes them durable.
+        shared.put(KeyValuePair::new("d".to_string(), "4".to_string()))?;
+        shared.rename("b".to_string(), "e".to_string())?;
+        shared.put(KeyValuePair::new("d".to_string(), "5".to_string()))?;
+        assert_eq!(committed.lock().unwrap().len(), 1);
+        shared.sync()?;
+        shared.sync()?;
+        assert_eq!(
+            committed.lock().unwrap()[1..],
+            [vec!["b".to_string(), "d".to_string(), "e".to_string()]]
+        );
+
+        // A panicking hook neither keeps the others from running nor fails later commits.
+        shared.on_commit(|_| panic!("hook"))?;
+        shared.put(KeyValuePair::new("f".to_string(), "6".to_string()))?;
+        assert!(std::panic::catch_unwind(|| shared.sync()).is_err());
+        let mut txn = shared.transaction(TransactionConfig::default());
+        txn.put(KeyValuePair::new("g".to_string(), "7".to_string()))?;
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| txn.commit())).is_err());
+        assert_eq!(
+            committed.lock().unwrap()[2..],
+            [vec!["f".to_string()], vec!["g".to_string()]]
+        );
+        assert_eq!(shared.reader().search("g".to_string())?.value, "7");
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::page::Page;
+use crate::page_layout::PAGE_SIZE;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// ValueRef is a value read by `BTree::search_value`: a slice of the page of its leaf, shared
+/// by reference counting, rather than a `String` of its own. Cloning it clones the reference to
+/// the page, it derefs to `str` and `to_string` makes an owned copy.
+#[derive(Clone)]
+pub struct ValueRef<const N: usize = PAGE_SIZE> {
+    page: Arc<Page<N>>,
+    /// The range of the bytes of the value in the page, checked to be UTF-8.
+    range: Range<usize>,
+}
+
+impl<const N: usize> ValueRef<N> {
+    pub(crate) fn new(page: Arc<Page<N>>, range: Range<usize>) -> ValueRef<N> {
+        ValueRef { page, range }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The range was checked when the value was found, see `Page::find_in_leaf`.
+        self.page.str_at(self.range.clone()).unwrap_or_default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.as_str().as_bytes()
+    }
+}
+
+impl<const N: usize> Deref for ValueRef<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> AsRef<str> for ValueRef<N> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for ValueRef<N> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<const N: usize> fmt::Display for ValueRef<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for ValueRef<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> PartialEq for ValueRef<N> {
+    fn eq(&self, other: &ValueRef<N>) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl<const N: usize> Eq for ValueRef<N> {}
+
+impl<const N: usize> PartialEq<str> for ValueRef<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&str> for ValueRef<N> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl<const N: usize> PartialEq<String> for ValueRef<N> {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<const N: usize> From<ValueRef<N>> for String {
+    fn from(value: ValueRef<N>) -> String {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn search_value_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/search_value")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/search_value/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), format!("v{}", i)))?;
+        }
+        for i in 0..20 {
+            let key = format!("{:02}", i);
+            let value = btree.search_value(&key)?;
+            assert_eq!(value, btree.search(key)?.value);
+        }
+        let value = btree.search_value("07")?;
+        let shared = value.clone();
+        drop(value);
+        assert_eq!(shared, "v7");
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared.to_string(), "v7");
+        assert_eq!(format!("{:?}", shared), "\"v7\"");
+        // The value outlives the writes which follow.
+        btree.put(KeyValuePair::new("07".to_string(), "w".to_string()))?;
+        assert_eq!(shared, "v7");
+        assert_eq!(btree.search_value("07")?, "w");
+        assert!(matches!(
+            btree.search_value("99"),
+            Err(Error::KeyNotFound { key }) if key == "99"
+        ));
+        Ok(())
+    }
+}
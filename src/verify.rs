@@ -0,0 +1,350 @@
+use crate::error::Error;
+use crate::header::{self, Header};
+use crate::node::Node;
+use crate::node_type::{Key, NodeType, Offset};
+use crate::page::Page;
+use crate::page_layout::{
+    is_node_page, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, KEY_SIZE,
+    LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET, PAGE_SIZE, PTR_SIZE,
+    VALUE_SIZE,
+};
+use crate::pager::Pager;
+use crate::wal::Wal;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+
+/// Problem is an inconsistency found in a tree file, at the page of an offset
+/// (the header page for problems of the file as a whole).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: {}", self.offset, self.message)
+    }
+}
+
+/// VerifyReport is the outcome of verifying a tree file, see `verify`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of pages of the file, the header included.
+    pub pages: usize,
+    /// The number of pages reachable from the roots of the file.
+    pub live_pages: usize,
+    /// The number of pages no root reaches, left behind by copy-on-write.
+    pub free_pages: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, offset: usize, message: String) {
+        self.problems.push(Problem { offset, message });
+    }
+}
+
+/// verify checks a tree file without opening it as a tree, so a damaged file is reported on
+/// rather than refused: the file length and header, then every tree of the file (the tree, its
+/// buckets and its tags) from its committed root down. Every reachable page has to be a node
+/// within the file (pages carry no checksums, a page is checked by decoding it) holding no more
+/// keys than the b parameter allows, in order and within the bounds the keys of its parent
+/// set, with every leaf at the same depth. Pages live in no free list, the pages no root
+/// reaches are the free pages of the file. The pages of the file are of the page size its header
+/// records, of `PAGE_SIZE` bytes if the header cannot be read.
+pub fn verify(path: &Path) -> Result<VerifyReport, Error> {
+    let page_size = header::page_size(path).unwrap_or(PAGE_SIZE);
+    with_page_size!(page_size, N => verify_sized::<N>(path))
+}
+
+/// verify_sized verifies a tree file of pages of N bytes, see `verify`.
+fn verify_sized<const N: usize>(path: &Path) -> Result<VerifyReport, Error> {
+    let pager = Pager::<N>::open_read_only(path)?;
+    let len = std::fs::metadata(path)?.len() as usize;
+    let mut report = VerifyReport {
+        pages: len / N,
+        ..VerifyReport::default()
+    };
+    if !len.is_multiple_of(N) {
+        report.problem(
+            len - len % N,
+            format!("the file ends with a partial page of {} bytes", len % N),
+        );
+    }
+    if len < N {
+        report.problem(0, "the file has no header page".to_string());
+        return Ok(report);
+    }
+    let header = match Header::decode(&pager.get_page(&Offset(0))?) {
+        Ok(header) if header.b >= 1 => header,
+        Ok(header) => {
+            report.problem(0, format!("invalid b parameter {}", header.b));
+            return Ok(report);
+        }
+        Err(e) => {
+            report.problem(0, format!("invalid header: {}", e));
+            return Ok(report);
+        }
+    };
+
+    let parent_directory = path.parent().unwrap_or_else(|| Path::new("/tmp"));
+    let mut roots = Vec::new();
+    match Wal::open_read_only(parent_directory.to_path_buf()).and_then(|mut wal| wal.get_root()) {
+        Ok(root) => roots.push(("the tree".to_string(), root)),
+        Err(e) => report.problem(0, format!("the root log cannot be read: {}", e)),
+    }
+    for name in header.buckets.iter() {
+        match Wal::open_read_only_bucket(parent_directory.to_path_buf(), name)
+            .and_then(|mut wal| wal.get_root())
+        {
+            Ok(root) => roots.push((format!("bucket {}", name), root)),
+            Err(e) => report.problem(
+                0,
+                format!("the root log of bucket {} cannot be read: {:?}", name, e),
+            ),
+        }
+    }
+    for (name, root) in header.tags.iter() {
+        roots.push((format!("tag {}", name), root.clone()));
+    }
+
+    let mut live = HashSet::new();
+    for (name, root) in roots {
+        let mut walk = Walk {
+            pager: &pager,
+            b: header.b,
+            len,
+            tree: name,
+            visited: HashSet::new(),
+            leaf_depth: None,
+        };
+        walk.node(&root, true, 1, (None, None), &mut report);
+        live.extend(walk.visited);
+    }
+    report.live_pages = live.len();
+    report.free_pages = report.pages.saturating_sub(1 + live.len());
+    Ok(report)
+}
+
+/// check_tree verifies a single tree of a file from its root down, like `verify` does,
+/// for `BTree::check_invariants`.
+pub(crate) fn check_tree<const N: usize>(
+    pager: &Pager<N>,
+    b: usize,
+    tree: String,
+    root: &Offset,
+) -> Result<Vec<Problem>, Error> {
+    let mut report = VerifyReport::default();
+    let mut walk = Walk {
+        pager,
+        b,
+        len: pager.size()? as usize,
+        tree,
+        visited: HashSet::new(),
+        leaf_depth: None,
+    };
+    walk.node(root, true, 1, (None, None), &mut report);
+    Ok(report.problems)
+}
+
+/// Walk verifies the pages of a single tree of the file.
+struct Walk<'a, const N: usize> {
+    pager: &'a Pager<N>,
+    b: usize,
+    len: usize,
+    /// The name of the tree in problems found.
+    tree: String,
+    visited: HashSet<usize>,
+    /// The depth of the first leaf reached.
+    leaf_depth: Option<usize>,
+}
+
+impl<const N: usize> Walk<'_, N> {
+    /// node verifies the subtree rooted at a node (the root of the tree or not) at some depth
+    /// whose keys lie in the bounds (lower, upper], unbounded on None.
+    fn node(
+        &mut self,
+        offset: &Offset,
+        is_root: bool,
+        depth: usize,
+        bounds: (Option<&str>, Option<&str>),
+        report: &mut VerifyReport,
+    ) {
+        if !is_node_page(offset.0, self.len, N) {
+            report.problem(
+                offset.0,
+                format!(
+                    "{} points to an offset outside of the node pages",
+                    self.tree
+                ),
+            );
+            return;
+        }
+        if !self.visited.insert(offset.0) {
+            report.problem(offset.0, format!("{} reaches the page twice", self.tree));
+            return;
+        }
+        let node = match self.decode(offset) {
+            Ok(node) => node,
+            Err(message) => {
+                report.problem(offset.0, format!("{} holds {}", self.tree, message));
+                return;
+            }
+        };
+        // Parent offsets are not checked against the parent: copy-on-write leaves
+        // them pointing at an earlier copy of the parent.
+        if node.is_root != is_root || (!is_root && node.parent_offset.is_none()) {
+            report.problem(
+                offset.0,
+                format!(
+                    "{} holds a {} marked otherwise",
+                    self.tree,
+                    if is_root { "root" } else { "non-root node" }
+                ),
+            );
+        }
+        let (keys, kind): (Vec<&str>, _) = match &node.node_type {
+            NodeType::Internal(_, keys) => {
+                (keys.iter().map(|Key(key)| key.as_str()).collect(), "keys")
+            }
+            NodeType::Leaf(pairs) => (pairs.iter().map(|kv| kv.key.as_str()).collect(), "pairs"),
+            NodeType::Unexpected => return,
+        };
+        let max_keys = 2 * self.b - 1;
+        if keys.len() > max_keys {
+            report.problem(
+                offset.0,
+                format!(
+                    "{} holds {} {} in a node, more than the {} b={} allows",
+                    self.tree,
+                    keys.len(),
+                    kind,
+                    max_keys,
+                    self.b
+                ),
+            );
+        }
+        let (lower, upper) = bounds;
+        if keys.iter().any(|key| {
+            lower.is_some_and(|lower| *key <= lower) || upper.is_some_and(|upper| *key > upper)
+        }) {
+            report.problem(
+                offset.0,
+                format!("{} holds keys outside the bounds of its parent", self.tree),
+            );
+        }
+        match &node.node_type {
+            NodeType::Internal(children, keys) => {
+                if children.len() < 2 || children.len() != keys.len() + 1 {
+                    report.problem(
+                        offset.0,
+                        format!(
+                            "{} holds an internal node of {} children and {} keys",
+                            self.tree,
+                            children.len(),
+                            keys.len()
+                        ),
+                    );
+                }
+                for (idx, child) in children.iter().enumerate() {
+                    let lower = idx
+                        .checked_sub(1)
+                        .and_then(|i| keys.get(i))
+                        .map(|Key(key)| key.as_str())
+                        .or(lower);
+                    let upper = keys.get(idx).map(|Key(key)| key.as_str()).or(upper);
+                    self.node(child, false, depth + 1, (lower, upper), report);
+                }
+            }
+            _ => match self.leaf_depth {
+                Some(leaf_depth) if leaf_depth != depth => report.problem(
+                    offset.0,
+                    format!(
+                        "{} holds a leaf at depth {}, other leaves are at depth {}",
+                        self.tree, depth, leaf_depth
+                    ),
+                ),
+                Some(_) => (),
+                None => self.leaf_depth = Some(depth),
+            },
+        }
+    }
+
+    /// decode decodes the node of a page, checking first that its counts fit the page.
+    fn decode(&self, offset: &Offset) -> Result<Node, String> {
+        let page = self
+            .pager
+            .get_page(offset)
+            .map_err(|e| format!("an unreadable page: {}", e))?;
+        let fits = match page.as_bytes()[NODE_TYPE_OFFSET] {
+            0x01 => count(&page, INTERNAL_NODE_NUM_CHILDREN_OFFSET).is_some_and(|n| {
+                INTERNAL_NODE_HEADER_SIZE + n * PTR_SIZE + n.saturating_sub(1) * KEY_SIZE <= N
+            }),
+            0x02 => count(&page, LEAF_NODE_NUM_PAIRS_OFFSET)
+                .is_some_and(|n| LEAF_NODE_HEADER_SIZE + n * (KEY_SIZE + VALUE_SIZE) <= N),
+            node_type => return Err(format!("a page of unknown node type {:#04x}", node_type)),
+        };
+        if !fits {
+            return Err("a node whose entries overflow its page".to_string());
+        }
+        Node::try_from(page).map_err(|e| format!("an undecodable node: {}", e))
+    }
+}
+
+/// count reads the count of entries of a node, None if it cannot be one.
+fn count<const N: usize>(page: &Page<N>, offset: usize) -> Option<usize> {
+    page.get_value_from_offset(offset).ok().filter(|n| *n <= N)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn verify_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::{NODE_TYPE_OFFSET, PAGE_SIZE};
+        use crate::verify::verify;
+        use std::os::unix::fs::FileExt;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/verify")?;
+        let path = Path::new("/tmp/verify/db");
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        for i in (0..50).step_by(3) {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        btree
+            .open_bucket("users")?
+            .insert(KeyValuePair::new("a".to_string(), "b".to_string()))?;
+        btree.tag("v1")?;
+        let stats = btree.stats()?;
+        drop(btree);
+
+        let report = verify(path)?;
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.free_pages, stats.free_pages);
+
+        // Corrupt the node type of the last page written, the root of the bucket.
+        let file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let last_page = (report.pages - 1) * PAGE_SIZE;
+        file.write_all_at(&[0xFF], (last_page + NODE_TYPE_OFFSET) as u64)?;
+        file.set_len((report.pages * PAGE_SIZE + 100) as u64)?;
+        let report = verify(path)?;
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(report.problems[0].offset, report.pages * PAGE_SIZE);
+        assert_eq!(report.problems[1].offset, last_page);
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use crate::btree::{scan_sub_tree, search_sub_tree};
+use crate::diff::{diff_sub_trees, Difference};
+use crate::error::Error;
+use crate::header::Header;
+use crate::maintenance::export_tree;
+use crate::node_type::{KeyValuePair, Offset};
+use crate::page_layout::PAGE_SIZE;
+use crate::pager::Pager;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+/// ReadView is a read-only handle pinned to a committed root of a tree.
+/// Pages reachable from a committed root are never modified, and the view holds on to
+/// a handle of the file the root lives in (which a compaction replacing the file does not affect),
+/// so every query of the view observes the same version of the tree regardless of later writes.
+/// Holding a view is cheap: it is a root offset and a file handle, nothing is copied.
+#[derive(Clone)]
+pub struct ReadView<const N: usize = PAGE_SIZE> {
+    root: Offset,
+    pager: Arc<Pager<N>>,
+}
+
+impl<const N: usize> ReadView<N> {
+    pub(crate) fn new(root: Offset, pager: Arc<Pager<N>>) -> ReadView<N> {
+        ReadView { root, pager }
+    }
+
+    /// search searches for a specific key in the pinned version of the tree.
+    pub fn search(&self, key: String) -> Result<KeyValuePair, Error> {
+        search_sub_tree(&self.pager, &self.root, &key)
+    }
+
+    /// range returns all the key-value pairs of the pinned version of the tree
+    /// whose keys fall in the given range, in ascending order.
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<KeyValuePair>, Error> {
+        let mut pairs = Vec::new();
+        scan_sub_tree(&self.pager, &self.root, &range, &mut |kv| pairs.push(kv))?;
+        Ok(pairs)
+    }
+
+    /// export writes the pinned version of the tree into a standalone tree file, in another
+    /// directory: a compacted copy holding the live pages of the version only, under a fresh header.
+    pub fn export(&self, path: &Path) -> Result<(), Error> {
+        let header = Header::decode(&self.pager.get_page(&Offset(0))?)?;
+        export_tree(self.pager.reader()?, self.root.clone(), header.b, path)
+    }
+
+    /// diff reports the keys whose values differ between this version of the tree
+    /// and another, in key order. The subtrees two versions of the same file share are skipped.
+    pub fn diff<F>(&self, other: &ReadView<N>, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Difference),
+    {
+        let same_file = self.pager.same_file(&other.pager)?;
+        diff_sub_trees(
+            (&self.pager, &self.root),
+            (&other.pager, &other.root),
+            same_file,
+            &mut |difference| {
+                callback(difference);
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    /// content_eq checks whether this version of the tree holds the same pairs as another,
+    /// regardless of how the pairs are laid out in pages. It stops at the first difference.
+    pub fn content_eq(&self, other: &ReadView<N>) -> Result<bool, Error> {
+        let same_file = self.pager.same_file(&other.pager)?;
+        diff_sub_trees(
+            (&self.pager, &self.root),
+            (&other.pager, &other.root),
+            same_file,
+            &mut |_| false,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn read_view_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/read_view")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/read_view/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "old".to_string()))?;
+        }
+        let view = btree.read_view()?;
+
+        btree.put(KeyValuePair::new("03".to_string(), "new".to_string()))?;
+        btree.delete(Key("04".to_string()))?;
+        btree.insert(KeyValuePair::new("20".to_string(), "new".to_string()))?;
+        btree.compact()?;
+
+        assert_eq!(view.search("03".to_string())?.value, "old");
+        assert_eq!(view.search("04".to_string())?.value, "old");
+        assert!(matches!(
+            view.search("20".to_string()),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert_eq!(view.range(..)?.len(), 20);
+        assert_eq!(btree.read_view()?.range(..)?.len(), 20);
+        assert_eq!(btree.read_view()?.search("03".to_string())?.value, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn read_view_export_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/view_export/source")?;
+        std::fs::create_dir_all("/tmp/view_export/target")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/view_export/source/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v".to_string()))?;
+        }
+        let view = btree.read_view()?;
+        btree.delete(Key("05".to_string()))?;
+
+        let path = Path::new("/tmp/view_export/target/db");
+        view.export(path)?;
+        let mut exported = BTreeBuilder::new().path(path).read_only(true).build()?;
+        assert_eq!(exported.range(..)?, view.range(..)?);
+        assert_eq!(exported.stats()?.free_pages, 0);
+        assert!(matches!(
+            view.export(Path::new("/tmp/view_export/source/copy")),
+            Err(Error::Unsupported(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,42 +1,336 @@
 use crate::error::Error;
+use crate::lock::now_millis;
 use crate::node_type::Offset;
-use crate::page_layout::PTR_SIZE;
+use crate::page_layout::KEY_SIZE;
+use crate::store::{FileStore, MemoryStore, PageStore};
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// The size of a field of a record, 8 bytes big-endian whatever the width of a pointer.
+const FIELD_SIZE: usize = 8;
+
+/// The size of a record of the log: its LSN, its timestamp and the root it logged.
+pub(crate) const RECORD_SIZE: usize = 3 * FIELD_SIZE;
+
+/// WalRecord is a record of the log, logging a committed root.
+/// Records are numbered by their log sequence number (LSN), which grows by one with every
+/// committed root and survives checkpoints, and stamped with the time of the commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WalRecord {
+    pub(crate) lsn: u64,
+    /// Milliseconds since the epoch.
+    pub(crate) timestamp: u64,
+    pub(crate) root: Offset,
+}
+
+impl WalRecord {
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut raw = [0x00; RECORD_SIZE];
+        raw[..FIELD_SIZE].copy_from_slice(&self.lsn.to_be_bytes());
+        raw[FIELD_SIZE..2 * FIELD_SIZE].copy_from_slice(&self.timestamp.to_be_bytes());
+        raw[2 * FIELD_SIZE..].copy_from_slice(&(self.root.0 as u64).to_be_bytes());
+        raw
+    }
+
+    pub(crate) fn from_bytes(raw: &[u8]) -> Result<WalRecord, Error> {
+        let field = |idx: usize| -> Result<u64, Error> {
+            <[u8; FIELD_SIZE]>::try_from(&raw[idx * FIELD_SIZE..(idx + 1) * FIELD_SIZE])
+                .map(u64::from_be_bytes)
+                .map_err(|_| Error::UnexpectedError)
+        };
+        Ok(WalRecord {
+            lsn: field(0)?,
+            timestamp: field(1)?,
+            root: Offset(usize::try_from(field(2)?).map_err(|_| Error::UnexpectedError)?),
+        })
+    }
+}
+
+/// MarkedRoot is a root committed by a group of writes committing several roots at once
+/// (see `BTree::atomically`): the bucket whose log it goes to, empty for the tree itself,
+/// and the LSN it is logged at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MarkedRoot {
+    pub(crate) bucket: String,
+    pub(crate) lsn: u64,
+    pub(crate) root: Offset,
+}
+
+/// The size of a marked root in a commit marker: its bucket, zero padded, its LSN and its root.
+const MARKED_ROOT_SIZE: usize = KEY_SIZE + 2 * FIELD_SIZE;
+
+/// commit_marker returns the path of the marker of a commit of several roots of the tree file
+/// at a path, holding the roots from before the first is logged until the last is durable.
+/// Its presence means the roots are to be logged again, see `read_commit_marker`.
+fn commit_marker(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".commit");
+    PathBuf::from(marker)
+}
+
+/// write_commit_marker durably writes the marker of a commit of several roots,
+/// the number of roots followed by the roots, swapped in whole by a rename.
+pub(crate) fn write_commit_marker(path: &Path, roots: &[MarkedRoot]) -> Result<(), Error> {
+    let marker = commit_marker(path);
+    let mut written = marker.clone().into_os_string();
+    written.push(".tmp");
+    let mut bytes = Vec::with_capacity(FIELD_SIZE + roots.len() * MARKED_ROOT_SIZE);
+    bytes.extend_from_slice(&(roots.len() as u64).to_be_bytes());
+    for root in roots {
+        let mut bucket = [0x00; KEY_SIZE];
+        let name = root.bucket.as_bytes();
+        bucket
+            .get_mut(..name.len())
+            .ok_or(Error::InvalidBucketName)?
+            .copy_from_slice(name);
+        bytes.extend_from_slice(&bucket);
+        bytes.extend_from_slice(&root.lsn.to_be_bytes());
+        bytes.extend_from_slice(&(root.root.0 as u64).to_be_bytes());
+    }
+    let mut file = fs::File::create(&written)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&written, &marker)?;
+    Ok(())
+}
+
+/// read_commit_marker reads the roots of the marker of a commit, None if the marker
+/// is missing or does not hold as many roots as it says.
+pub(crate) fn read_commit_marker(path: &Path) -> Result<Option<Vec<MarkedRoot>>, Error> {
+    let bytes = match fs::read(commit_marker(path)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let field = |idx: usize| {
+        let field = bytes.get(idx..idx + FIELD_SIZE)?;
+        <[u8; FIELD_SIZE]>::try_from(field)
+            .ok()
+            .map(u64::from_be_bytes)
+    };
+    let len = match field(0).and_then(|len| usize::try_from(len).ok()) {
+        Some(len)
+            if len
+                .checked_mul(MARKED_ROOT_SIZE)
+                .and_then(|size| size.checked_add(FIELD_SIZE))
+                == Some(bytes.len()) =>
+        {
+            len
+        }
+        _ => return Ok(None),
+    };
+    Ok((0..len)
+        .map(|idx| {
+            let start = FIELD_SIZE + idx * MARKED_ROOT_SIZE;
+            let bucket = &bytes[start..start + KEY_SIZE];
+            let bucket = std::str::from_utf8(bucket).ok()?.trim_end_matches('\0');
+            Some(MarkedRoot {
+                bucket: bucket.to_string(),
+                lsn: field(start + KEY_SIZE)?,
+                root: Offset(usize::try_from(field(start + KEY_SIZE + FIELD_SIZE)?).ok()?),
+            })
+        })
+        .collect())
+}
+
+/// remove_commit_marker removes the marker of a commit once its roots are durable.
+pub(crate) fn remove_commit_marker(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(commit_marker(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
 
 pub struct Wal {
-    file: File,
+    store: Box<dyn PageStore>,
+    /// The LSN of the last record, known to the writer only.
+    last_lsn: u64,
+    /// The root of the group of writes underway, committed along with the group,
+    /// see `BTree::atomically`.
+    deferred: Option<Offset>,
 }
 
 impl Wal {
     pub fn new(parent_directoy: PathBuf) -> Result<Self, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(parent_directoy.join("wal"))?;
+        Wal::new_named(parent_directoy, "wal")
+    }
+
+    /// new_bucket creates the log of the roots of a bucket.
+    pub fn new_bucket(parent_directoy: PathBuf, bucket: &str) -> Result<Self, Error> {
+        Wal::new_named(parent_directoy, &format!("wal.{}", bucket))
+    }
+
+    fn new_named(parent_directoy: PathBuf, name: &str) -> Result<Self, Error> {
+        Ok(Self {
+            store: Box::new(FileStore::create(&parent_directoy.join(name))?),
+            last_lsn: 0,
+            deferred: None,
+        })
+    }
+
+    /// in_memory creates a log held in memory, for a tree held in memory.
+    pub fn in_memory() -> Self {
+        Self {
+            store: Box::new(MemoryStore::new()),
+            last_lsn: 0,
+            deferred: None,
+        }
+    }
+
+    /// from_store opens the log of a store for appending roots, creating it if the store is empty.
+    pub fn from_store(store: Box<dyn PageStore>) -> Result<Self, Error> {
+        let mut wal = Self {
+            store,
+            last_lsn: 0,
+            deferred: None,
+        };
+        if wal.store.size()? >= RECORD_SIZE as u64 {
+            wal.last_lsn = wal.last_record()?.lsn;
+        }
+        Ok(wal)
+    }
 
-        Ok(Self { file: fd })
+    /// open opens an existing log for appending roots, numbering them following its last record.
+    pub fn open(parent_directoy: PathBuf) -> Result<Self, Error> {
+        Wal::open_named(parent_directoy, "wal")
     }
 
+    /// open_bucket opens the existing log of the roots of a bucket for appending roots.
+    pub fn open_bucket(parent_directoy: PathBuf, bucket: &str) -> Result<Self, Error> {
+        Wal::open_named(parent_directoy, &format!("wal.{}", bucket))
+    }
+
+    fn open_named(parent_directoy: PathBuf, name: &str) -> Result<Self, Error> {
+        let mut wal = Self {
+            store: Box::new(FileStore::open(&parent_directoy.join(name))?),
+            last_lsn: 0,
+            deferred: None,
+        };
+        wal.last_lsn = wal.last_record()?.lsn;
+        Ok(wal)
+    }
+
+    /// open_read_only opens an existing log for reading the latest root only.
+    pub fn open_read_only(parent_directoy: PathBuf) -> Result<Self, Error> {
+        Wal::open_read_only_named(parent_directoy, "wal")
+    }
+
+    /// open_read_only_bucket opens the existing log of a bucket for reading the latest root only.
+    pub fn open_read_only_bucket(parent_directoy: PathBuf, bucket: &str) -> Result<Self, Error> {
+        Wal::open_read_only_named(parent_directoy, &format!("wal.{}", bucket))
+    }
+
+    fn open_read_only_named(parent_directoy: PathBuf, name: &str) -> Result<Self, Error> {
+        Ok(Self {
+            store: Box::new(FileStore::open_read_only(&parent_directoy.join(name))?),
+            last_lsn: 0,
+            deferred: None,
+        })
+    }
+
+    /// get_root returns the latest root, that of the group of writes underway if any.
     pub fn get_root(&mut self) -> Result<Offset, Error> {
-        let mut buff: [u8; PTR_SIZE] = [0x00; PTR_SIZE];
-        let file_len = self.file.seek(SeekFrom::End(0))? as usize;
-        let mut root_offset: usize = 0;
+        if let Some(root) = &self.deferred {
+            return Ok(root.clone());
+        }
+        Ok(self.last_record()?.root)
+    }
+
+    /// defer_root sets the root of the group of writes underway, logged by `commit_deferred`.
+    pub(crate) fn defer_root(&mut self, offset: Offset) {
+        self.deferred = Some(offset);
+    }
+
+    /// deferred returns the root of the group of writes underway (if any)
+    /// along with the LSN `commit_deferred` logs it at.
+    pub(crate) fn deferred(&self) -> Option<(u64, Offset)> {
+        self.deferred
+            .as_ref()
+            .map(|root| (self.last_lsn + 1, root.clone()))
+    }
+
+    /// commit_deferred logs the root of the group of writes underway (if any).
+    pub(crate) fn commit_deferred(&mut self) -> Result<(), Error> {
+        match self.deferred.take() {
+            Some(root) => self.set_root(root),
+            None => Ok(()),
+        }
+    }
+
+    /// discard_deferred drops the root of the group of writes underway.
+    pub(crate) fn discard_deferred(&mut self) {
+        self.deferred = None;
+    }
+
+    /// last_record returns the last record of the log.
+    pub(crate) fn last_record(&mut self) -> Result<WalRecord, Error> {
+        // A checkpoint may shrink the log in between finding its end and reading
+        // the last record, the last record is then simply read again.
+        let mut attempts = 0;
+        loop {
+            match self.read_last_record() {
+                Err(Error::Io(_)) | Err(Error::UnexpectedError) if attempts < 3 => attempts += 1,
+                res => return res,
+            }
+        }
+    }
+
+    fn read_last_record(&mut self) -> Result<WalRecord, Error> {
+        let mut buff: [u8; RECORD_SIZE] = [0x00; RECORD_SIZE];
+        let file_len = self.store.size()? as usize;
+        let mut record_offset: usize = 0;
         if file_len > 0 {
-            root_offset = (file_len / PTR_SIZE - 1) * PTR_SIZE;
+            record_offset = (file_len / RECORD_SIZE).saturating_sub(1) * RECORD_SIZE;
         }
-        self.file.seek(SeekFrom::Start(root_offset as u64))?;
-        self.file.read_exact(&mut buff)?;
-        Offset::try_from(buff)
+        self.store.read_at(&mut buff, record_offset as u64)?;
+        WalRecord::from_bytes(&buff)
+    }
+
+    /// records returns the records of the log, in order. A partially written record
+    /// at the end of the log (left behind by a crash) is ignored.
+    pub(crate) fn records(&mut self) -> Result<Vec<WalRecord>, Error> {
+        let mut raw = vec![0x00; self.store.size()? as usize];
+        self.store.read_at(&mut raw, 0)?;
+        raw.chunks_exact(RECORD_SIZE)
+            .map(WalRecord::from_bytes)
+            .collect()
+    }
+
+    /// size returns the size of the log in bytes.
+    pub fn size(&self) -> Result<u64, Error> {
+        self.store.size()
+    }
+
+    /// checkpoint truncates the log down to its latest record.
+    /// The latest record is first copied to the start of the log (while still also being
+    /// the last record of the log) so the log holds the latest root at any point.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        let record = self.last_record()?;
+        self.store.write_at(&record.to_bytes(), 0)?;
+        self.store.sync()?;
+        self.store.truncate(RECORD_SIZE as u64)?;
+        self.store.sync()
+    }
+
+    /// sync flushes the logged roots to the disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        debug_span!("fsync", file = "wal");
+        count!(fsyncs);
+        time!(fsync_latency);
+        self.store.sync()
     }
 
     pub fn set_root(&mut self, offset: Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(&offset.0.to_be_bytes())?;
-        Ok(())
+        self.last_lsn += 1;
+        let record = WalRecord {
+            lsn: self.last_lsn,
+            timestamp: now_millis(),
+            root: offset,
+        };
+        // A partially written record at the end of the log (left behind by a crash) is overwritten.
+        let end = self.store.size()? / RECORD_SIZE as u64 * RECORD_SIZE as u64;
+        self.store.write_at(&record.to_bytes(), end)
     }
 }
@@ -0,0 +1,274 @@
+use crate::node_type::{Key, KeyValuePair, Offset};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// Event describes a committed change to a key of the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Insert(KeyValuePair),
+    Update(KeyValuePair),
+    Delete(Key),
+}
+
+impl Event {
+    /// key returns the key the event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Insert(kv) | Event::Update(kv) => &kv.key,
+            Event::Delete(Key(key)) => key,
+        }
+    }
+}
+
+/// StructuralEvent describes a committed change to the structure of the tree, see `BTree::watch_structure`.
+/// Pages are freed once the committed root no longer reaches them, although tags and read views
+/// may still read them until the tree is compacted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StructuralEvent {
+    /// A full node was split into the node at offset and its new sibling.
+    Split {
+        offset: Offset,
+        sibling: Offset,
+    },
+    /// Two sibling nodes were merged into the node at offset.
+    Merge {
+        left: Offset,
+        right: Offset,
+        offset: Offset,
+    },
+    PageAllocated(Offset),
+    PageFreed(Offset),
+    RootChanged {
+        old: Offset,
+        new: Offset,
+    },
+}
+
+/// Subscription receives the events of the keys matching the prefix it was created for
+/// (or the structural events of the tree), in the order they were committed.
+/// Dropping the subscription unsubscribes it.
+pub struct Subscription<E = Event> {
+    events: Receiver<E>,
+}
+
+impl<E> Subscription<E> {
+    /// recv blocks until the next event arrives,
+    /// returns None once the tree is dropped and all events were received.
+    pub fn recv(&self) -> Option<E> {
+        self.events.recv().ok()
+    }
+
+    /// try_recv returns the next event if one has already arrived.
+    pub fn try_recv(&self) -> Option<E> {
+        self.events.try_recv().ok()
+    }
+
+    /// recv_timeout blocks for at most timeout until the next event arrives.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<E> {
+        self.events.recv_timeout(timeout).ok()
+    }
+}
+
+/// Watchers keeps track of the subscriptions made on a tree, holding on to the events
+/// of a group of writes until the group is committed (see `hold`).
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Vec<(String, Sender<Event>)>,
+    held: Option<Vec<Event>>,
+}
+
+impl Watchers {
+    pub(crate) fn subscribe(&mut self, prefix: String) -> Subscription {
+        let (sender, events) = mpsc::channel();
+        self.watchers.push((prefix, sender));
+        Subscription { events }
+    }
+
+    /// watches checks whether any subscription is interested in a key.
+    pub(crate) fn watches(&self, key: &str) -> bool {
+        self.watchers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// notify delivers an event to every matching subscription,
+    /// forgetting the subscriptions which were dropped.
+    pub(crate) fn notify(&mut self, event: Event) {
+        if let Some(held) = &mut self.held {
+            held.push(event);
+            return;
+        }
+        self.watchers.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix.as_str()) || sender.send(event.clone()).is_ok()
+        });
+    }
+
+    /// hold holds on to the events notified from then on, until `release` delivers them
+    /// or `discard` drops them.
+    pub(crate) fn hold(&mut self) {
+        self.held = Some(Vec::new());
+    }
+
+    /// release delivers the events held, in order.
+    pub(crate) fn release(&mut self) {
+        for event in self.held.take().unwrap_or_default() {
+            self.notify(event);
+        }
+    }
+
+    /// discard drops the events held.
+    pub(crate) fn discard(&mut self) {
+        self.held = None;
+    }
+}
+
+/// StructureWatchers keeps track of the subscriptions to the structural events of a tree,
+/// holding on to the events of a write until it is committed.
+#[derive(Default)]
+pub(crate) struct StructureWatchers {
+    watchers: Vec<Sender<StructuralEvent>>,
+    pending: Vec<StructuralEvent>,
+}
+
+impl StructureWatchers {
+    pub(crate) fn subscribe(&mut self) -> Subscription<StructuralEvent> {
+        let (sender, events) = mpsc::channel();
+        self.watchers.push(sender);
+        Subscription { events }
+    }
+
+    /// is_watched checks whether there is any subscription, the structure of a write is only
+    /// worked out if there is.
+    pub(crate) fn is_watched(&self) -> bool {
+        !self.watchers.is_empty()
+    }
+
+    /// record holds on to an event of the current write.
+    pub(crate) fn record(&mut self, event: StructuralEvent) {
+        if self.is_watched() {
+            self.pending.push(event);
+        }
+    }
+
+    /// discard drops the events of a write which was not committed.
+    pub(crate) fn discard(&mut self) {
+        self.pending.clear();
+    }
+
+    /// notify delivers the events of the committed write to every subscription,
+    /// forgetting the subscriptions which were dropped.
+    pub(crate) fn notify(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.watchers.retain(|sender| {
+            pending
+                .iter()
+                .all(|event| sender.send(event.clone()).is_ok())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn watch_prefix_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::watch::Event;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/watch_prefix")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/watch_prefix/db"))
+            .b_parameter(2)
+            .build()?;
+        let users = btree.watch_prefix("user:".to_string());
+        let dropped = btree.watch_prefix("".to_string());
+        drop(dropped);
+
+        btree.insert(KeyValuePair::new("user:1".to_string(), "ada".to_string()))?;
+        btree.insert(KeyValuePair::new("item:1".to_string(), "pen".to_string()))?;
+        btree.delete(Key("user:1".to_string()))?;
+
+        assert_eq!(
+            users.try_recv(),
+            Some(Event::Insert(KeyValuePair::new(
+                "user:1".to_string(),
+                "ada".to_string()
+            )))
+        );
+        assert_eq!(
+            users.try_recv(),
+            Some(Event::Delete(Key("user:1".to_string())))
+        );
+        assert_eq!(users.try_recv(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn watch_structure_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::watch::StructuralEvent;
+        use std::collections::HashSet;
+        use std::path::Path;
+
+        std::fs::create_dir_all("/tmp/watch_structure")?;
+        let mut btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/watch_structure/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..3 {
+            btree.insert(KeyValuePair::new(i.to_string(), "v".to_string()))?;
+        }
+        let structure = btree.watch_structure();
+        let root = btree.root_offset()?;
+        btree.insert(KeyValuePair::new("3".to_string(), "v".to_string()))?;
+
+        let events: Vec<StructuralEvent> = std::iter::from_fn(|| structure.try_recv()).collect();
+        assert!(matches!(events[0], StructuralEvent::Split { .. }));
+        let allocated: Vec<_> = events
+            .iter()
+            .filter(|e| matches!(e, StructuralEvent::PageAllocated(_)))
+            .collect();
+        // The new root and the two halves of the old root, the one page freed.
+        assert_eq!(allocated.len(), 3);
+        let freed: Vec<_> = events
+            .iter()
+            .filter(|e| matches!(e, StructuralEvent::PageFreed(_)))
+            .collect();
+        assert_eq!(freed, vec![&StructuralEvent::PageFreed(root.clone())]);
+        let new = btree.root_offset()?;
+        assert_eq!(
+            events.last(),
+            Some(&StructuralEvent::RootChanged { old: root, new })
+        );
+
+        // Deleting shrinks the tree back, merging the two leaves.
+        for i in 0..3 {
+            btree.delete(Key(i.to_string()))?;
+        }
+        let events: Vec<StructuralEvent> = std::iter::from_fn(|| structure.try_recv()).collect();
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, StructuralEvent::Merge { .. })));
+        // Every page allocated since subscribing is either freed or live.
+        let mut live = HashSet::new();
+        for event in events {
+            match event {
+                StructuralEvent::PageAllocated(offset) => assert!(live.insert(offset.0)),
+                StructuralEvent::PageFreed(offset) => {
+                    live.remove(&offset.0);
+                }
+                _ => (),
+            }
+        }
+        assert!(live.contains(&btree.root_offset()?.0));
+
+        assert_eq!(btree.search("3".to_string())?.value, "v");
+        assert!(btree.delete(Key("none".to_string())).is_err());
+        assert_eq!(structure.try_recv(), None);
+        Ok(())
+    }
+}